@@ -0,0 +1,138 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use flate2::read::GzDecoder;
+use sevenz_rust::{Password, SevenZReader};
+use zip::ZipArchive;
+use crate::cartridge::LoadError;
+
+// ROM files can also be stored in a zip, gzip or 7z archive, recognized by their magic bytes
+// from archives with several files, the first one with a ROM extension is loaded
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const SEVEN_ZIP_MAGIC: &[u8] = &[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C];
+
+const ROM_EXTENSIONS: [&str; 3] = [".gba", ".agb", ".bin"];
+
+// the game pak address space, no ROM is larger
+// unpacking stops one byte after that, so a small archive can't fill the memory
+pub(crate) const MAX_ROM_SIZE: usize = 32 * 1024 * 1024;
+
+// the kind of archive read_rom would unpack, None for anything else
+pub fn archive_kind(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(ZIP_MAGIC) {
+        return Some("zip");
+    }
+    else if bytes.starts_with(GZIP_MAGIC) {
+        return Some("gzip");
+    }
+    else if bytes.starts_with(SEVEN_ZIP_MAGIC) {
+        return Some("7z");
+    }
+    return None;
+}
+
+// the content of the file, unpacked if it's an archive
+pub fn read_rom(filename: &str) -> Result<Vec<u8>, LoadError> {
+    let bytes = fs::read(filename)?;
+    if bytes.starts_with(ZIP_MAGIC) {
+        return read_zip(filename, bytes);
+    }
+    else if bytes.starts_with(GZIP_MAGIC) {
+        // gzip only holds a single file, so there is nothing to choose
+        let mut rom = Vec::new();
+        GzDecoder::new(&bytes[..]).take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom)?;
+        return check_size(rom);
+    }
+    else if bytes.starts_with(SEVEN_ZIP_MAGIC) {
+        return read_seven_zip(filename);
+    }
+    return Ok(bytes);
+}
+
+fn check_size(rom: Vec<u8>) -> Result<Vec<u8>, LoadError> {
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(LoadError::TooLarge(rom.len(), MAX_ROM_SIZE));
+    }
+    return Ok(rom);
+}
+
+fn is_rom(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    return ROM_EXTENSIONS.iter().any(|extension| name.ends_with(extension));
+}
+
+// picks the first candidate and tells which one when there is a choice
+fn choose(filename: &str, candidates: &[String]) -> Result<String, LoadError> {
+    match candidates {
+        [] => Err(LoadError::NoRomInArchive(filename.to_string())),
+        [only] => Ok(only.clone()),
+        [first, ..] => {
+            eprintln!("[WARNING] {} contains several ROMs ({}), loading {}", filename, candidates.join(", "), first);
+            Ok(first.clone())
+        },
+    }
+}
+
+fn read_zip(filename: &str, bytes: Vec<u8>) -> Result<Vec<u8>, LoadError> {
+    let archive_error = |e: zip::result::ZipError| LoadError::InvalidArchive(filename.to_string(), e.to_string());
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(archive_error)?;
+    let candidates: Vec<String> = (0..archive.len())
+        .filter_map(|index| archive.name_for_index(index))
+        .filter(|name| is_rom(name))
+        .map(String::from)
+        .collect();
+    let name = choose(filename, &candidates)?;
+    let file = archive.by_name(&name).map_err(archive_error)?;
+    // the size in the archive is only a hint
+    let mut rom = Vec::with_capacity(file.size().min(MAX_ROM_SIZE as u64) as usize);
+    file.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom)?;
+    check_size(rom)
+}
+
+fn read_seven_zip(filename: &str) -> Result<Vec<u8>, LoadError> {
+    let archive_error = |e: sevenz_rust::Error| LoadError::InvalidArchive(filename.to_string(), e.to_string());
+    let mut archive = SevenZReader::open(filename, Password::empty()).map_err(archive_error)?;
+    let candidates: Vec<String> = archive.archive().files.iter()
+        .filter(|entry| entry.has_stream && !entry.is_directory && is_rom(&entry.name))
+        .map(|entry| entry.name.clone())
+        .collect();
+    let name = choose(filename, &candidates)?;
+    let mut rom = Vec::new();
+    archive.for_each_entries(|entry, reader| {
+        if entry.name != name {
+            return Ok(true);
+        }
+        reader.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom)?;
+        Ok(false)
+    }).map_err(archive_error)?;
+    check_size(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use crate::cartridge::LoadError;
+    use super::{read_rom, MAX_ROM_SIZE};
+
+    fn gzip_of_zeros(name: &str, size: usize) -> String {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        io::copy(&mut io::repeat(0).take(size as u64), &mut encoder).unwrap();
+        let path = std::env::temp_dir().join(format!("rust_gba_emu_{}_{}", std::process::id(), name));
+        fs::File::create(&path).unwrap().write_all(&encoder.finish().unwrap()).unwrap();
+        return path.to_string_lossy().into_owned();
+    }
+
+    #[test]
+    fn gzip_size_limit() {
+        let fits = gzip_of_zeros("fits.gba.gz", MAX_ROM_SIZE);
+        assert_eq!(read_rom(&fits).unwrap().len(), MAX_ROM_SIZE);
+        fs::remove_file(&fits).unwrap();
+        let too_large = gzip_of_zeros("large.gba.gz", MAX_ROM_SIZE + 1);
+        assert!(matches!(read_rom(&too_large), Err(LoadError::TooLarge(_, MAX_ROM_SIZE))));
+        fs::remove_file(&too_large).unwrap();
+    }
+}
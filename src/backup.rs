@@ -0,0 +1,353 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::fmt;
+use serde::{Serialize, Serializer};
+use eeprom::{EEPROM, EEPROM_8K};
+use flash::{Flash, FlashChip};
+use sram::SRAM;
+
+pub mod eeprom;
+pub mod flash;
+pub mod sram;
+
+// the save memory of the game pak, together with the .sav file it is persisted in
+// the chip is accessed byte wise, the memory bus takes care of the 8 bit bus quirks
+
+// after the game stopped writing for this many frames, the save file is updated
+// waiting a bit avoids writing half finished saves and hammering the disk while a game saves
+const FLUSH_DELAY_FRAMES: u32 = 30;
+
+// the kinds of save memory a game pak can have, used to pick the chip
+// flash chips of the same size differ in their ID, games that check it only save to the chip they know
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SaveType {
+    None,
+    SRAM,
+    Flash(FlashChip),
+    EEPROM,
+}
+
+impl SaveType {
+    // the ID strings of Nintendo's save libraries, games have them in the ROM to identify the chip they expect
+    // see https://problemkaputt.de/gbatek.htm#gbacartbackupidstrings
+    const ID_STRINGS: [(&'static [u8], SaveType); 6] = [
+        (b"SRAM_V", SaveType::SRAM),
+        (b"SRAM_F_V", SaveType::SRAM),
+        (b"FLASH_V", SaveType::Flash(FlashChip::Panasonic64K)),
+        (b"FLASH512_V", SaveType::Flash(FlashChip::Panasonic64K)),
+        (b"FLASH1M_V", SaveType::Flash(FlashChip::Sanyo128K)),
+        (b"EEPROM_V", SaveType::EEPROM),
+    ];
+
+    // looks for the first ID string in the ROM, the strings are word aligned
+    pub fn detect(rom: &[u8]) -> SaveType {
+        for offset in (0..rom.len()).step_by(4) {
+            for (id, save_type) in SaveType::ID_STRINGS.iter() {
+                if rom[offset..].starts_with(id) {
+                    return *save_type;
+                }
+            }
+        }
+        return SaveType::None;
+    }
+
+    pub fn chip(&self) -> BackupChip {
+        match self {
+            SaveType::None => BackupChip::None,
+            SaveType::SRAM => BackupChip::SRAM(SRAM::new()),
+            SaveType::Flash(chip) => BackupChip::Flash(Flash::new(*chip)),
+            SaveType::EEPROM => BackupChip::EEPROM(EEPROM::new()),
+        }
+    }
+}
+
+impl std::str::FromStr for SaveType {
+    type Err = String;
+
+    // flash can name the maker of the chip, e.g. flash64k-macronix, by default it's the most common one
+    fn from_str(s: &str) -> Result<SaveType, String> {
+        let lowercase = s.to_ascii_lowercase();
+        let (kind, maker) = lowercase.split_once('-').unwrap_or((&lowercase, ""));
+        match (kind, maker) {
+            ("none", "") => Ok(SaveType::None),
+            ("sram", "") => Ok(SaveType::SRAM),
+            ("flash64k" | "flash512", "" | "panasonic") => Ok(SaveType::Flash(FlashChip::Panasonic64K)),
+            ("flash64k" | "flash512", "macronix") => Ok(SaveType::Flash(FlashChip::Macronix64K)),
+            ("flash64k" | "flash512", "sst") => Ok(SaveType::Flash(FlashChip::SST64K)),
+            ("flash128k" | "flash1m", "" | "sanyo") => Ok(SaveType::Flash(FlashChip::Sanyo128K)),
+            ("flash128k" | "flash1m", "macronix") => Ok(SaveType::Flash(FlashChip::Macronix128K)),
+            ("eeprom", "") => Ok(SaveType::EEPROM),
+            _ => Err(format!("Unknown save type {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SaveType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SaveType::None => "none",
+            SaveType::SRAM => "SRAM",
+            SaveType::Flash(chip) => return write!(f, "Flash {}K ({})", chip.size() / 1024, chip.maker()),
+            SaveType::EEPROM => "EEPROM",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// the JSON names the kind of save like the ID strings do, without the maker of the chip
+impl Serialize for SaveType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let name = match self {
+            SaveType::None => "None",
+            SaveType::SRAM => "SRAM",
+            SaveType::Flash(FlashChip::Macronix128K | FlashChip::Sanyo128K) => "Flash128K",
+            SaveType::Flash(_) => "Flash64K",
+            SaveType::EEPROM => "EEPROM",
+        };
+        serializer.serialize_str(name)
+    }
+}
+
+pub enum BackupChip {
+    None,
+    SRAM(SRAM),
+    Flash(Flash),
+    EEPROM(EEPROM),
+}
+
+pub struct Backup {
+    pub chip: BackupChip,
+    path: Option<PathBuf>,
+    dirty: bool,
+    idle_frames: u32,
+}
+
+impl Backup {
+    pub fn new(chip: BackupChip) -> Backup {
+        Backup {
+            chip,
+            path: None,
+            dirty: false,
+            idle_frames: 0,
+        }
+    }
+
+    // the .sav file for a ROM sits next to it, with the same name
+    pub fn save_path(rom_path: &str) -> PathBuf {
+        return Path::new(rom_path).with_extension("sav");
+    }
+
+    fn data(&self) -> Option<&[u8]> {
+        match &self.chip {
+            BackupChip::None => None,
+            BackupChip::SRAM(sram) => Some(&sram.data),
+            BackupChip::Flash(flash) => Some(&flash.data),
+            BackupChip::EEPROM(eeprom) => Some(&eeprom.data),
+        }
+    }
+
+    fn data_mut(&mut self) -> Option<&mut [u8]> {
+        match &mut self.chip {
+            BackupChip::None => None,
+            BackupChip::SRAM(sram) => Some(&mut sram.data),
+            BackupChip::Flash(flash) => Some(&mut flash.data),
+            BackupChip::EEPROM(eeprom) => Some(&mut eeprom.data),
+        }
+    }
+
+    // accesses to the SRAM area
+    pub fn read(&self, address: u32) -> u8 {
+        match &self.chip {
+            BackupChip::None | BackupChip::EEPROM(_) => 0xFF,
+            BackupChip::SRAM(sram) => sram.read(address),
+            BackupChip::Flash(flash) => flash.read(address),
+        }
+    }
+
+    pub fn write(&mut self, address: u32, value: u8) {
+        // commands sent to a flash chip don't change the save
+        let modified = match &mut self.chip {
+            BackupChip::None | BackupChip::EEPROM(_) => false,
+            BackupChip::SRAM(sram) => {
+                sram.write(address, value);
+                true
+            },
+            BackupChip::Flash(flash) => flash.write(address, value),
+        };
+        if modified {
+            self.modified();
+        }
+    }
+
+    pub fn is_eeprom(&self) -> bool {
+        return matches!(self.chip, BackupChip::EEPROM(_));
+    }
+
+    // accesses to the EEPROM area, now is the current cycle count
+    pub fn read_eeprom(&mut self, now: u128) -> u8 {
+        match &mut self.chip {
+            BackupChip::EEPROM(eeprom) => eeprom.read(now),
+            _ => 0,
+        }
+    }
+
+    // what the next EEPROM read returns, for debuggers
+    pub fn peek_eeprom(&self, now: u128) -> u8 {
+        match &self.chip {
+            BackupChip::EEPROM(eeprom) => eeprom.peek(now),
+            _ => 0,
+        }
+    }
+
+    pub fn write_eeprom(&mut self, bit: u8, now: u128) {
+        if let BackupChip::EEPROM(eeprom) = &mut self.chip {
+            if eeprom.write(bit, now) {
+                self.modified();
+            }
+        }
+    }
+
+    // DMA3 tells the length of transfers into the EEPROM area, the EEPROM derives its size from it
+    pub fn eeprom_transfer_length(&mut self, count: u32) {
+        if let BackupChip::EEPROM(eeprom) = &mut self.chip {
+            eeprom.transfer_length(count);
+        }
+    }
+
+    fn modified(&mut self) {
+        self.dirty = true;
+        self.idle_frames = 0;
+    }
+
+    // ties the backup to a save file, if the file already exists its content is loaded
+    pub fn attach_file(&mut self, path: PathBuf) -> std::io::Result<()> {
+        match fs::read(&path) {
+            Ok(bytes) => {
+                // a save of the large EEPROM tells its size, the game might not get to send a request before reading
+                if let BackupChip::EEPROM(eeprom) = &mut self.chip {
+                    if bytes.len() >= EEPROM_8K {
+                        eeprom.set_address_bits(14);
+                    }
+                }
+                if let Some(data) = self.data_mut() {
+                    // save files of other emulators are sometimes padded or cut short, take what fits
+                    let length = bytes.len().min(data.len());
+                    data[..length].copy_from_slice(&bytes[..length]);
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        self.path = Some(path);
+        self.dirty = false;
+        Ok(())
+    }
+
+    // writes the save file if anything changed
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let (Some(path), Some(data)) = (&self.path, self.data()) {
+            fs::write(path, data)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    // called once per frame, flushes the save file once the game is done writing
+    pub fn end_frame(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.idle_frames += 1;
+        if self.idle_frames >= FLUSH_DELAY_FRAMES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Backup {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("[WARNING] Could not write save file: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use super::{Backup, BackupChip, SaveType, FLUSH_DELAY_FRAMES};
+    use super::flash::FlashChip;
+    use super::sram::SRAM_SIZE;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rust_gba_emu_{}_{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        return path;
+    }
+
+    #[test]
+    fn sram_save_round_trip() {
+        let path = temp_path("sram.sav");
+        let mut backup = Backup::new(SaveType::SRAM.chip());
+        backup.attach_file(path.clone()).unwrap();
+        backup.write(0x0010, 0x42);
+        // the file is written once the game stopped writing for a while
+        for _ in 1..FLUSH_DELAY_FRAMES {
+            backup.end_frame().unwrap();
+        }
+        assert!(!path.exists());
+        backup.end_frame().unwrap();
+        let saved = fs::read(&path).unwrap();
+        assert_eq!(saved.len(), SRAM_SIZE);
+        assert_eq!(saved[0x10], 0x42);
+
+        // on exit right away, and the whole 64 KB come back
+        backup.write(0xFFFF, 0x24);
+        backup.flush().unwrap();
+        let mut reloaded = Backup::new(SaveType::SRAM.chip());
+        reloaded.attach_file(path.clone()).unwrap();
+        assert_eq!((reloaded.read(0x10), reloaded.read(0xFFFF), reloaded.read(0x20)), (0x42, 0x24, 0xFF));
+        // mirrored over the whole area
+        assert_eq!(reloaded.read(0x10010), 0x42);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn short_save_files_load() {
+        // 32 KB saves of other emulators fill the first half
+        let path = temp_path("short.sav");
+        fs::write(&path, vec![0x11; 0x8000]).unwrap();
+        let mut backup = Backup::new(SaveType::SRAM.chip());
+        backup.attach_file(path.clone()).unwrap();
+        assert_eq!((backup.read(0x7FFF), backup.read(0x8000)), (0x11, 0xFF));
+        // nothing changed, the file stays as it is
+        backup.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), 0x8000);
+        fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn flash_chip_from_the_command_line() {
+        assert_eq!("flash64k".parse(), Ok(SaveType::Flash(FlashChip::Panasonic64K)));
+        assert_eq!("FLASH1M".parse(), Ok(SaveType::Flash(FlashChip::Sanyo128K)));
+        assert_eq!("flash64k-macronix".parse(), Ok(SaveType::Flash(FlashChip::Macronix64K)));
+        assert_eq!("flash512-sst".parse(), Ok(SaveType::Flash(FlashChip::SST64K)));
+        assert_eq!("flash128k-macronix".parse(), Ok(SaveType::Flash(FlashChip::Macronix128K)));
+        assert!("flash128k-sst".parse::<SaveType>().is_err());
+        assert!("sram-sanyo".parse::<SaveType>().is_err());
+        // the game sees the ID of the chosen chip
+        let save_type: SaveType = "flash64k-macronix".parse().unwrap();
+        match save_type.chip() {
+            BackupChip::Flash(flash) => assert_eq!(flash.chip().id(), (0xC2, 0x1C)),
+            _ => panic!("not a flash chip"),
+        }
+        assert_eq!(save_type.to_string(), "Flash 64K (Macronix)");
+        // the JSON keeps the kind of save only
+        assert_eq!(serde_json::to_string(&save_type).unwrap(), "\"Flash64K\"");
+    }
+}
@@ -0,0 +1,199 @@
+// serial EEPROM, see https://problemkaputt.de/gbatek.htm#gbacartbackupeeprom
+// bits are transferred one per halfword access (bit 0) in the 0x0D000000 area, games use DMA3 for this
+// read request: 0b11, address, 0 - after that 68 bits can be read: 4 junk bits and 64 data bits
+// write request: 0b10, address, 64 data bits, 0 - the chip then stays busy for a while
+// all values are sent most significant bit first
+
+pub const EEPROM_512: usize = 0x200;
+pub const EEPROM_8K: usize = 0x2000;
+
+// time a write takes until the chip reports ready again, about 6.5 ms
+const WRITE_CYCLES: u128 = 108368;
+
+const READ_JUNK_BITS: usize = 4;
+const READ_BITS: usize = READ_JUNK_BITS + 64;
+
+pub struct EEPROM {
+    pub data: Vec<u8>,
+    // bits of the current request, received so far
+    buffer: u128,
+    received: usize,
+    // block being read and the number of bits already read from it, None if no read is in progress
+    read_block: Option<usize>,
+    read_position: usize,
+    busy_until: u128,
+}
+
+impl EEPROM {
+    // the size isn't known upfront, it starts at 512 bytes and grows once a 14 bit address shows up
+    pub fn new() -> EEPROM {
+        EEPROM {
+            data: vec![0xFF; EEPROM_512],
+            buffer: 0,
+            received: 0,
+            read_block: None,
+            read_position: 0,
+            busy_until: 0,
+        }
+    }
+
+    // 512 byte chips use 6 bit addresses, 8 KB chips 14 bit addresses of which only the lower 10 are used
+    fn address_bits(&self) -> usize {
+        return if self.data.len() == EEPROM_512 {6} else {14};
+    }
+
+    pub fn set_address_bits(&mut self, bits: usize) {
+        if bits == 14 && self.data.len() < EEPROM_8K {
+            self.data.resize(EEPROM_8K, 0xFF);
+        }
+    }
+
+    // the address width can be told from the length of the DMA that sends a request
+    // read requests are 2 + address + 1 bits, write requests 2 + address + 64 + 1 bits
+    pub fn transfer_length(&mut self, count: u32) {
+        match count {
+            9 | 73 => self.set_address_bits(6),
+            17 | 81 => self.set_address_bits(14),
+            _ => {},
+        }
+    }
+
+    // now is the current cycle count, to tell whether a write is still in progress
+    pub fn read(&mut self, now: u128) -> u8 {
+        let value = self.peek(now);
+        if self.read_block.is_some() {
+            self.read_position += 1;
+            if self.read_position == READ_BITS {
+                self.read_block = None;
+            }
+        }
+        return value;
+    }
+
+    // the bit the next read returns, without shifting it out
+    pub fn peek(&self, now: u128) -> u8 {
+        if let Some(block) = self.read_block {
+            let position = self.read_position;
+            if position < READ_JUNK_BITS {
+                return 0;
+            }
+            let bit = position - READ_JUNK_BITS;
+            let byte = self.data[block * 8 + bit / 8];
+            return (byte >> (7 - bit % 8)) & 1;
+        }
+        // 1 when ready, 0 while a write is still busy
+        return if now < self.busy_until {0} else {1};
+    }
+
+    // returns whether the content of the chip changed
+    pub fn write(&mut self, bit: u8, now: u128) -> bool {
+        // a new request cancels a read that isn't done yet
+        self.read_block = None;
+        self.buffer = (self.buffer << 1) | (bit & 1) as u128;
+        self.received += 1;
+
+        let address_bits = self.address_bits();
+        let address_mask = (1 << address_bits) - 1;
+        let blocks = self.data.len() / 8;
+        if self.received == 2 && self.buffer & 0b10 == 0 {
+            // not a valid request, start over
+            self.reset_request();
+        }
+        else if self.received == 2 + address_bits + 1 && self.buffer >> (address_bits + 1) == 0b11 {
+            let address = (self.buffer >> 1) as usize & address_mask;
+            self.read_block = Some(address % blocks);
+            self.read_position = 0;
+            self.reset_request();
+        }
+        else if self.received == 2 + address_bits + 64 + 1 {
+            let address = (self.buffer >> 65) as usize & address_mask;
+            let value = (self.buffer >> 1) as u64;
+            let start = (address % blocks) * 8;
+            self.data[start..start + 8].copy_from_slice(&value.to_be_bytes());
+            self.busy_until = now + WRITE_CYCLES;
+            self.reset_request();
+            return true;
+        }
+        return false;
+    }
+
+    fn reset_request(&mut self) {
+        self.buffer = 0;
+        self.received = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EEPROM, EEPROM_512, EEPROM_8K, WRITE_CYCLES};
+
+    // one bit per access, most significant first
+    fn send(eeprom: &mut EEPROM, bits: u128, count: usize, now: u128) -> bool {
+        let mut modified = false;
+        for bit in (0..count).rev() {
+            modified = eeprom.write((bits >> bit) as u8 & 1, now);
+        }
+        return modified;
+    }
+
+    fn write_block(eeprom: &mut EEPROM, address_bits: usize, block: u128, value: u64, now: u128) -> bool {
+        let request = (((0b10 << address_bits) | block) << 65) | ((value as u128) << 1);
+        return send(eeprom, request, 2 + address_bits + 64 + 1, now);
+    }
+
+    fn read_block(eeprom: &mut EEPROM, address_bits: usize, block: u128) -> u64 {
+        send(eeprom, ((0b11 << address_bits) | block) << 1, 2 + address_bits + 1, 0);
+        let junk: Vec<u8> = (0..4).map(|_| eeprom.read(0)).collect();
+        assert_eq!(junk, [0, 0, 0, 0]);
+        return (0..64).fold(0, |value, _| (value << 1) | eeprom.read(0) as u64);
+    }
+
+    #[test]
+    fn address_width_from_the_dma_length() {
+        // read and write requests of the 512 byte chip keep it small
+        let mut eeprom = EEPROM::new();
+        eeprom.transfer_length(9);
+        eeprom.transfer_length(73);
+        assert_eq!(eeprom.data.len(), EEPROM_512);
+        // the 14 bit ones make it 8 KB, it never shrinks again
+        eeprom.transfer_length(17);
+        assert_eq!(eeprom.data.len(), EEPROM_8K);
+        eeprom.transfer_length(9);
+        assert_eq!(eeprom.data.len(), EEPROM_8K);
+        let mut eeprom = EEPROM::new();
+        eeprom.transfer_length(81);
+        assert_eq!(eeprom.data.len(), EEPROM_8K);
+    }
+
+    #[test]
+    fn small_chip_protocol() {
+        let mut eeprom = EEPROM::new();
+        assert!(write_block(&mut eeprom, 6, 5, 0x0123456789ABCDEF, 1000));
+        assert_eq!(&eeprom.data[40..48], &[0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF]);
+        // busy while the write is going on, ready after that
+        assert_eq!(eeprom.read(1000 + WRITE_CYCLES - 1), 0);
+        assert_eq!(eeprom.read(1000 + WRITE_CYCLES), 1);
+        assert_eq!(read_block(&mut eeprom, 6, 5), 0x0123456789ABCDEF);
+        // erased blocks and blocks after a finished read
+        assert_eq!(read_block(&mut eeprom, 6, 6), 0xFFFFFFFFFFFFFFFF);
+        assert_eq!(eeprom.read(u128::MAX), 1);
+        // a request has to start with a 1
+        assert!(!send(&mut eeprom, 0b01, 2, 0));
+        assert_eq!(read_block(&mut eeprom, 6, 5), 0x0123456789ABCDEF);
+    }
+
+    #[test]
+    fn large_chip_protocol() {
+        let mut eeprom = EEPROM::new();
+        eeprom.transfer_length(81);
+        // only the lower 10 of the 14 address bits count
+        assert!(write_block(&mut eeprom, 14, 0x3FFF, 0x1122334455667788, 0));
+        assert_eq!(&eeprom.data[EEPROM_8K - 8..], &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+        assert_eq!(read_block(&mut eeprom, 14, 0x03FF), 0x1122334455667788);
+        // a new request cancels a read that isn't done
+        send(&mut eeprom, 0b11 << 15, 17, 0);
+        eeprom.read(0);
+        assert!(write_block(&mut eeprom, 14, 1, 0, 0));
+        assert_eq!(read_block(&mut eeprom, 14, 1), 0);
+    }
+}
@@ -0,0 +1,245 @@
+// Flash save memory, see https://problemkaputt.de/gbatek.htm#gbacartbackupflashrom
+// commands are written to 0x5555 after the unlock sequence 0xAA to 0x5555 and 0x55 to 0x2AAA
+// 128 KB chips are split into two 64 KB banks, only one of them is visible at a time
+
+const UNLOCK_ADDRESS_1: u32 = 0x5555;
+const UNLOCK_ADDRESS_2: u32 = 0x2AAA;
+const BANK_SIZE: usize = 0x10000;  // 64 KB
+const SECTOR_SIZE: usize = 0x1000;  // 4 KB
+
+// commands
+const CHIP_ERASE: u8 = 0x10;
+const SECTOR_ERASE: u8 = 0x30;
+const ERASE: u8 = 0x80;
+const ENTER_ID_MODE: u8 = 0x90;
+const PROGRAM_BYTE: u8 = 0xA0;
+const SWITCH_BANK: u8 = 0xB0;
+const EXIT_ID_MODE: u8 = 0xF0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FlashChip {
+    Macronix64K,
+    Panasonic64K,
+    SST64K,
+    Macronix128K,
+    Sanyo128K,
+}
+
+impl FlashChip {
+    // (manufacturer, device), games check these to find out how to talk to the chip
+    pub fn id(&self) -> (u8, u8) {
+        match self {
+            FlashChip::Macronix64K => (0xC2, 0x1C),
+            FlashChip::Panasonic64K => (0x32, 0x1B),
+            FlashChip::SST64K => (0xBF, 0xD4),
+            FlashChip::Macronix128K => (0xC2, 0x09),
+            FlashChip::Sanyo128K => (0x62, 0x13),
+        }
+    }
+
+    pub fn maker(&self) -> &'static str {
+        match self {
+            FlashChip::Macronix64K | FlashChip::Macronix128K => "Macronix",
+            FlashChip::Panasonic64K => "Panasonic",
+            FlashChip::SST64K => "SST",
+            FlashChip::Sanyo128K => "Sanyo",
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            FlashChip::Macronix128K | FlashChip::Sanyo128K => 2 * BANK_SIZE,
+            _ => BANK_SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FlashState {
+    Ready,
+    // first and second write of the unlock sequence done
+    Unlock1,
+    Unlock2,
+    // the next write gets stored
+    Program,
+    // the next write to address 0 selects the bank
+    BankSwitch,
+}
+
+pub struct Flash {
+    pub data: Vec<u8>,
+    chip: FlashChip,
+    state: FlashState,
+    id_mode: bool,
+    // erase commands need their own unlock sequence after the ERASE command
+    erase_armed: bool,
+    bank: usize,
+}
+
+impl Flash {
+    pub fn new(chip: FlashChip) -> Flash {
+        Flash {
+            // erased flash reads as 0xFF
+            data: vec![0xFF; chip.size()],
+            chip,
+            state: FlashState::Ready,
+            id_mode: false,
+            erase_armed: false,
+            bank: 0,
+        }
+    }
+
+    pub fn chip(&self) -> FlashChip {
+        return self.chip;
+    }
+
+    pub fn read(&self, address: u32) -> u8 {
+        let address = address & 0xFFFF;
+        if self.id_mode && address < 2 {
+            let (manufacturer, device) = self.chip.id();
+            return if address == 0 {manufacturer} else {device};
+        }
+        return self.data[self.bank * BANK_SIZE + address as usize];
+    }
+
+    // returns whether the content of the chip changed
+    pub fn write(&mut self, address: u32, value: u8) -> bool {
+        let address = address & 0xFFFF;
+        match self.state {
+            FlashState::Program => {
+                self.state = FlashState::Ready;
+                self.data[self.bank * BANK_SIZE + address as usize] = value;
+                return true;
+            },
+            FlashState::BankSwitch => {
+                self.state = FlashState::Ready;
+                if address == 0 {
+                    self.bank = (value & 1) as usize;
+                }
+                return false;
+            },
+            FlashState::Ready if address == UNLOCK_ADDRESS_1 && value == 0xAA => self.state = FlashState::Unlock1,
+            FlashState::Unlock1 if address == UNLOCK_ADDRESS_2 && value == 0x55 => self.state = FlashState::Unlock2,
+            FlashState::Unlock2 if address == UNLOCK_ADDRESS_1 => {
+                self.state = FlashState::Ready;
+                return self.command(value);
+            },
+            FlashState::Unlock2 if value == SECTOR_ERASE && self.erase_armed => {
+                self.state = FlashState::Ready;
+                self.erase_armed = false;
+                let start = self.bank * BANK_SIZE + (address as usize & !(SECTOR_SIZE - 1));
+                self.data[start..start + SECTOR_SIZE].fill(0xFF);
+                return true;
+            },
+            _ => {
+                // anything out of sequence starts over, some games leave ID mode with a bare 0xF0
+                self.state = FlashState::Ready;
+                if value == EXIT_ID_MODE {
+                    self.id_mode = false;
+                }
+            },
+        }
+        return false;
+    }
+
+    fn command(&mut self, command: u8) -> bool {
+        let erase_armed = self.erase_armed;
+        self.erase_armed = false;
+        match command {
+            ENTER_ID_MODE => self.id_mode = true,
+            EXIT_ID_MODE => self.id_mode = false,
+            ERASE => self.erase_armed = true,
+            CHIP_ERASE if erase_armed => {
+                self.data.fill(0xFF);
+                return true;
+            },
+            PROGRAM_BYTE => self.state = FlashState::Program,
+            SWITCH_BANK if self.chip.size() > BANK_SIZE => self.state = FlashState::BankSwitch,
+            _ => {},
+        }
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Flash, FlashChip};
+
+    // unlock sequence and the command
+    fn command(flash: &mut Flash, command: u8) {
+        flash.write(0x5555, 0xAA);
+        flash.write(0x2AAA, 0x55);
+        flash.write(0x5555, command);
+    }
+
+    fn program(flash: &mut Flash, address: u32, value: u8) {
+        command(flash, 0xA0);
+        assert!(flash.write(address, value));
+    }
+
+    #[test]
+    fn id_mode() {
+        for chip in [FlashChip::Macronix64K, FlashChip::Panasonic64K, FlashChip::SST64K, FlashChip::Macronix128K, FlashChip::Sanyo128K] {
+            let mut flash = Flash::new(chip);
+            command(&mut flash, 0x90);
+            assert_eq!((flash.read(0), flash.read(1)), chip.id());
+            command(&mut flash, 0xF0);
+            assert_eq!((flash.read(0), flash.read(1)), (0xFF, 0xFF));
+            // some games leave ID mode with a bare 0xF0
+            command(&mut flash, 0x90);
+            flash.write(0x5555, 0xF0);
+            assert_eq!(flash.read(0), 0xFF);
+        }
+    }
+
+    #[test]
+    fn program_and_erase() {
+        let mut flash = Flash::new(FlashChip::Macronix64K);
+        program(&mut flash, 0x1000, 0x12);
+        program(&mut flash, 0x1FFF, 0x34);
+        program(&mut flash, 0x2000, 0x56);
+        // writes without the command don't reach the chip
+        assert!(!flash.write(0x3000, 0x78));
+        assert_eq!((flash.read(0x1000), flash.read(0x1FFF), flash.read(0x2000), flash.read(0x3000)), (0x12, 0x34, 0x56, 0xFF));
+
+        // the 4 KB sector at 0x1000, the one after it stays
+        command(&mut flash, 0x80);
+        flash.write(0x5555, 0xAA);
+        flash.write(0x2AAA, 0x55);
+        assert!(flash.write(0x1234, 0x30));
+        assert_eq!((flash.read(0x1000), flash.read(0x1FFF), flash.read(0x2000)), (0xFF, 0xFF, 0x56));
+        // erasing needs the erase command first
+        command(&mut flash, 0x10);
+        assert_eq!(flash.read(0x2000), 0x56);
+        command(&mut flash, 0x80);
+        command(&mut flash, 0x10);
+        assert_eq!(flash.read(0x2000), 0xFF);
+    }
+
+    #[test]
+    fn bank_switch() {
+        let mut flash = Flash::new(FlashChip::Sanyo128K);
+        program(&mut flash, 0x0010, 0x11);
+        command(&mut flash, 0xB0);
+        flash.write(0, 1);
+        assert_eq!(flash.read(0x0010), 0xFF);
+        program(&mut flash, 0x0010, 0x22);
+        assert_eq!(flash.data[0x10010], 0x22);
+        // sector erase stays in the bank
+        command(&mut flash, 0x80);
+        flash.write(0x5555, 0xAA);
+        flash.write(0x2AAA, 0x55);
+        flash.write(0x0000, 0x30);
+        assert_eq!((flash.data[0x10], flash.data[0x10010]), (0x11, 0xFF));
+        command(&mut flash, 0xB0);
+        flash.write(0, 0);
+        assert_eq!(flash.read(0x0010), 0x11);
+
+        // 64 KB chips have no banks
+        let mut small = Flash::new(FlashChip::Panasonic64K);
+        program(&mut small, 0x0010, 0x33);
+        command(&mut small, 0xB0);
+        small.write(0, 1);
+        assert_eq!(small.read(0x0010), 0x33);
+    }
+}
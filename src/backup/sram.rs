@@ -0,0 +1,27 @@
+// battery backed SRAM, see https://problemkaputt.de/gbatek.htm#gbacartbackupsramfram
+// sits on an 8 bit bus, the whole 0x0E000000 to 0x0FFFFFFF area mirrors it
+
+// the largest chips and the whole area of the bus, saves of the common 32 KB chips use the first half
+pub const SRAM_SIZE: usize = 0x10000;  // 64 KB
+
+pub struct SRAM {
+    pub data: Vec<u8>,
+}
+
+impl SRAM {
+    pub fn new() -> SRAM {
+        SRAM {
+            // unwritten SRAM reads as 0xFF on most carts, games use that to detect an empty save
+            data: vec![0xFF; SRAM_SIZE],
+        }
+    }
+
+    pub fn read(&self, address: u32) -> u8 {
+        return self.data[address as usize % self.data.len()];
+    }
+
+    pub fn write(&mut self, address: u32, value: u8) {
+        let index = address as usize % self.data.len();
+        self.data[index] = value;
+    }
+}
@@ -1,6 +1,194 @@
-use std::fs::{read, File};
-use std::io::Read;
+use std::error::Error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use crate::archive;
+use crate::elf;
+use crate::patch;
+use crate::backup::{flash::FlashChip, SaveType};
+use crate::rom_info::{Region, RomInfo};
 
+// the size of the header, anything shorter can't be a ROM
+const HEADER_SIZE: usize = 228;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    // archive file name and what's wrong with it
+    InvalidArchive(String, String),
+    // archive file name
+    NoRomInArchive(String),
+    // patch file name and what's wrong with it
+    InvalidPatch(String, String),
+    TooSmall(usize),
+    // size and the maximum
+    TooLarge(usize, usize),
+    // file name and what's wrong with it
+    InvalidElf(String, String),
+    // file name and what it is instead
+    NotPlainRom(String, String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::InvalidArchive(filename, e) => write!(f, "Could not read archive {}: {}", filename, e),
+            LoadError::NoRomInArchive(filename) => write!(f, "No .gba, .agb or .bin file in archive {}", filename),
+            LoadError::InvalidPatch(filename, e) => write!(f, "Could not apply patch {}: {}", filename, e),
+            LoadError::TooSmall(size) => write!(f, "ROM is too small with {} bytes, it doesn't even have a header", size),
+            LoadError::InvalidElf(filename, error) => write!(f, "{} is not a usable ELF file: {}", filename, error),
+            LoadError::TooLarge(size, maximum) => write!(f, "Image is too large with {} bytes, the maximum is {}", size, maximum),
+            LoadError::NotPlainRom(filename, kind) => write!(f, "{} is {}, only plain ROM files can be changed in place", filename, kind),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl From<std::io::Error> for LoadError {
+    fn from(e: std::io::Error) -> LoadError {
+        LoadError::Io(e)
+    }
+}
+
+// the compressed Nintendo logo every cart has at 0x04, the BIOS refuses to boot if it doesn't match
+const NINTENDO_LOGO: [u8; 156] = [
+    0x24, 0xFF, 0xAE, 0x51, 0x69, 0x9A, 0xA2, 0x21, 0x3D, 0x84, 0x82, 0x0A, 0x84, 0xE4, 0x09, 0xAD,
+    0x11, 0x24, 0x8B, 0x98, 0xC0, 0x81, 0x7F, 0x21, 0xA3, 0x52, 0xBE, 0x19, 0x93, 0x09, 0xCE, 0x20,
+    0x10, 0x46, 0x4A, 0x4A, 0xF8, 0x27, 0x31, 0xEC, 0x58, 0xC7, 0xE8, 0x33, 0x82, 0xE3, 0xCE, 0xBF,
+    0x85, 0xF4, 0xDF, 0x94, 0xCE, 0x4B, 0x09, 0xC1, 0x94, 0x56, 0x8A, 0xC0, 0x13, 0x72, 0xA7, 0xFC,
+    0x9F, 0x84, 0x4D, 0x73, 0xA3, 0xCA, 0x9A, 0x61, 0x58, 0x97, 0xA3, 0x27, 0xFC, 0x03, 0x98, 0x76,
+    0x23, 0x1D, 0xC7, 0x61, 0x03, 0x04, 0xAE, 0x56, 0xBF, 0x38, 0x84, 0x00, 0x40, 0xA7, 0x0E, 0xFD,
+    0xFF, 0x52, 0xFE, 0x03, 0x6F, 0x95, 0x30, 0xF1, 0x97, 0xFB, 0xC0, 0x85, 0x60, 0xD6, 0x80, 0x25,
+    0xA9, 0x63, 0xBE, 0x03, 0x01, 0x4E, 0x38, 0xE2, 0xF9, 0xA2, 0x34, 0xFF, 0xBB, 0x3E, 0x03, 0x44,
+    0x78, 0x00, 0x90, 0xCB, 0x88, 0x11, 0x3A, 0x94, 0x65, 0xC0, 0x7C, 0x63, 0x87, 0xF0, 0x3C, 0xAF,
+    0xD6, 0x25, 0xE4, 0x8B, 0x38, 0x0A, 0xAC, 0x72, 0x21, 0xD4, 0xF8, 0x07,
+];
+
+// the byte at 0xB2 has to be this
+const FIXED_VALUE: u8 = 0x96;
+
+// the complement check at 0xBD covers the header from 0xA0 to 0xBC
+const CHECKSUM_START: usize = 0xA0;
+const CHECKSUM_END: usize = 0xBC;
+const CHECKSUM_OFFSET: u64 = 0xBD;
+// the header up to the complement check and the two reserved bytes after it
+const CHECKSUM_HEADER_SIZE: usize = 0xC0;
+
+// result of checking the header, see https://problemkaputt.de/gbatek.htm#gbacartridgeheader
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HeaderReport {
+    pub logo_valid: bool,
+    pub fixed_value: u8,
+    pub checksum: u8,
+    pub expected_checksum: u8,
+}
+
+impl HeaderReport {
+    pub fn fixed_value_valid(&self) -> bool {
+        return self.fixed_value == FIXED_VALUE;
+    }
+
+    pub fn checksum_valid(&self) -> bool {
+        return self.checksum == self.expected_checksum;
+    }
+
+    // a real GBA only boots carts where all of this holds
+    pub fn is_valid(&self) -> bool {
+        return self.logo_valid && self.fixed_value_valid() && self.checksum_valid();
+    }
+}
+
+impl fmt::Display for HeaderReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut problems = Vec::new();
+        if !self.logo_valid {
+            problems.push(String::from("Nintendo logo doesn't match"));
+        }
+        if !self.fixed_value_valid() {
+            problems.push(format!("fixed value is {:02x} instead of {:02x}", self.fixed_value, FIXED_VALUE));
+        }
+        if !self.checksum_valid() {
+            problems.push(format!("header checksum is {:02x} instead of {:02x}", self.checksum, self.expected_checksum));
+        }
+        if problems.is_empty() {
+            return write!(f, "header is valid");
+        }
+        write!(f, "{}", problems.join(", "))
+    }
+}
+
+// the complement check as the BIOS computes it, over the header bytes from 0xA0 to 0xBC
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    let sum = rom[CHECKSUM_START..=CHECKSUM_END].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    return 0u8.wrapping_sub(sum).wrapping_sub(0x19);
+}
+
+// writes the correct complement check into a ROM file, returns whether it had to be changed
+// archives and ELF files are refused, their bytes at 0xBD aren't the header
+pub fn fix_header_checksum(filename: &str) -> Result<bool, LoadError> {
+    let mut file = OpenOptions::new().read(true).write(true).open(filename)?;
+    let mut header = Vec::with_capacity(CHECKSUM_HEADER_SIZE);
+    (&mut file).take(CHECKSUM_HEADER_SIZE as u64).read_to_end(&mut header)?;
+    if let Some(kind) = archive::archive_kind(&header) {
+        return Err(LoadError::NotPlainRom(filename.to_string(), format!("a {} archive", kind)));
+    }
+    if elf::is_elf(&header) {
+        return Err(LoadError::NotPlainRom(filename.to_string(), String::from("an ELF file")));
+    }
+    if header.len() < CHECKSUM_HEADER_SIZE {
+        return Err(LoadError::TooSmall(header.len()));
+    }
+    let checksum = header_checksum(&header);
+    if header[CHECKSUM_OFFSET as usize] == checksum {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(CHECKSUM_OFFSET))?;
+    file.write_all(&[checksum])?;
+    Ok(true)
+}
+
+// games that identify their save chip wrongly or not at all, keyed on the game code
+const SAVE_TYPE_OVERRIDES: [(&[u8; 4], SaveType); 7] = [
+    (b"ALFP", SaveType::EEPROM),  // Dragon Ball Z - The Legacy of Goku II (Europe)
+    (b"AWRE", SaveType::Flash(FlashChip::Panasonic64K)),  // Advance Wars (USA)
+    (b"AWRP", SaveType::Flash(FlashChip::Panasonic64K)),  // Advance Wars (Europe)
+    (b"AW2E", SaveType::Flash(FlashChip::Panasonic64K)),  // Advance Wars 2 (USA)
+    (b"AW2P", SaveType::Flash(FlashChip::Panasonic64K)),  // Advance Wars 2 (Europe)
+    (b"AX4P", SaveType::Flash(FlashChip::Sanyo128K)),  // Super Mario Advance 4 (Europe)
+    (b"A2YE", SaveType::None),  // Top Gun - Combat Zones (USA)
+];
+
+// games with a real time clock on the GPIO port, by the first three letters of the game code so all regions match
+const RTC_GAMES: [&[u8; 3]; 8] = [
+    b"AXV",  // Pokemon Ruby
+    b"AXP",  // Pokemon Sapphire
+    b"BPE",  // Pokemon Emerald
+    b"U3I",  // Boktai
+    b"U32",  // Boktai 2
+    b"U33",  // Boktai 3
+    b"BR4",  // Rockman EXE 4.5
+    b"BKA",  // Sennen Kazoku
+];
+
+const SOLAR_SENSOR_GAMES: [&[u8; 3]; 3] = [
+    b"U3I",  // Boktai
+    b"U32",  // Boktai 2
+    b"U33",  // Boktai 3
+];
+
+const GYRO_GAMES: [&[u8; 3]; 1] = [
+    b"RZW",  // WarioWare Twisted
+];
+
+const TILT_SENSOR_GAMES: [&[u8; 3]; 2] = [
+    b"KYG",  // Yoshi Topsy-Turvy
+    b"KHP",  // Koro Koro Puzzle
+];
+
+// the whole header is kept, not every field is used yet
+#[allow(dead_code)]
 pub struct Cartridge {
     entry: [u8; 4],
     logo: [u8; 156],
@@ -19,14 +207,34 @@ pub struct Cartridge {
     slave_id_number: u8,
     not_used: [u8; 26],
     joybus_entry_point: [u8; 4],
-    rom_data: Vec<u8>,
+    rom_data: Vec<u8>,  // the whole ROM, header included, the way it's mapped into memory
 }
 
 impl Cartridge {
-    pub fn new(filename: String) -> std::io::Result<Cartridge> {
-        let mut file = File::open(filename)?;
-        let mut bytes = Vec::new();
-        file.read_to_end(&mut bytes)?;
+    // loads a raw ROM or one packed into an archive
+    // a patch with the same name as the ROM gets applied, see with_patch
+    pub fn new(filename: String) -> Result<Cartridge, LoadError> {
+        return Cartridge::with_patch(filename, None);
+    }
+
+    // like new, but with an IPS, UPS or BPS patch to apply
+    // without one, a patch next to the ROM with the same name is used if there is one
+    pub fn with_patch(filename: String, patch_filename: Option<String>) -> Result<Cartridge, LoadError> {
+        let mut bytes = archive::read_rom(&filename)?;
+        let patch_filename = patch_filename.or_else(|| patch::find_patch(&filename).map(|path| path.to_string_lossy().into_owned()));
+        if let Some(patch_filename) = patch_filename {
+            println!("Applying patch {}", patch_filename);
+            let patch_data = std::fs::read(&patch_filename)?;
+            bytes = patch::apply(&bytes, &patch_data).map_err(|e| LoadError::InvalidPatch(patch_filename, e))?;
+        }
+        return Cartridge::from_bytes(bytes);
+    }
+
+    // a ROM image that is already in memory, e.g. put together from an ELF file
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Cartridge, LoadError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(LoadError::TooSmall(bytes.len()));
+        }
         let cart = Cartridge
         {
             entry: 
@@ -96,27 +304,146 @@ impl Cartridge {
                 tmp.copy_from_slice(&bytes[224..228]);
                 tmp 
             },
-            rom_data: 
-            {
-                let mut tmp = vec![0u8; bytes.len() - 228];
-                tmp.copy_from_slice(&bytes[228..]);
-                tmp
-            },
+            rom_data: bytes,
         };
         Ok(cart)
     }
 
+    pub fn rom_info(&self) -> RomInfo {
+        // text fields are padded with zeroes, some homebrew pads with spaces
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_end_matches(['\0', ' ']).to_string();
+        RomInfo {
+            title: text(&self.title),
+            game_code: text(&self.game_code),
+            maker_code: text(&self.maker_code),
+            region: Region::from_letter(self.game_code[3]),
+            version: self.software_version,
+            size: self.rom_data.len(),
+            save_type: self.save_type(),
+            gpio: self.has_rtc() || self.has_solar_sensor() || self.has_gyro(),
+            header_valid: self.validate().is_valid(),
+        }
+    }
+
     pub fn info(&self) {
-        println!("------------");
-        println!("ROM Info:");
-        println!("Title: {}", String::from_utf8_lossy(&self.title));
-        println!("Game code: {}", String::from_utf8_lossy(&self.game_code));
-        println!("Maker code: {}", String::from_utf8_lossy(&self.maker_code));
-        println!("Software version: {}", self.software_version);
-        println!("ROM size: {} MB", self.rom_data.len() / 1024 / 1024);
+        println!("{}", self.rom_info());
+    }
+
+    // the save type from the override table, or else from the ID string in the ROM
+    pub fn save_type(&self) -> SaveType {
+        for (game_code, save_type) in SAVE_TYPE_OVERRIDES.iter() {
+            if **game_code == self.game_code {
+                return *save_type;
+            }
+        }
+        return SaveType::detect(&self.rom_data);
+    }
+
+    fn game_code_in(&self, games: &[&[u8; 3]]) -> bool {
+        return games.iter().any(|prefix| **prefix == self.game_code[..3]);
+    }
+
+    pub fn validate(&self) -> HeaderReport {
+        HeaderReport {
+            logo_valid: self.logo == NINTENDO_LOGO,
+            fixed_value: self.fixed_value,
+            checksum: self.complement_check,
+            expected_checksum: header_checksum(&self.rom_data),
+        }
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        return self.game_code_in(&RTC_GAMES);
+    }
+
+    pub fn has_solar_sensor(&self) -> bool {
+        return self.game_code_in(&SOLAR_SENSOR_GAMES);
+    }
+
+    // the gyro sensor comes with a rumble motor
+    pub fn has_gyro(&self) -> bool {
+        return self.game_code_in(&GYRO_GAMES);
+    }
+
+    pub fn has_tilt_sensor(&self) -> bool {
+        return self.game_code_in(&TILT_SENSOR_GAMES);
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        return &self.rom_data;
     }
 
     pub fn read_adress(&self, adress: usize) -> u8 {
         return self.rom_data[adress];
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::backup::{flash::FlashChip, SaveType};
+    use super::{fix_header_checksum, header_checksum, Cartridge, LoadError, CHECKSUM_OFFSET};
+
+    fn temp_file(name: &str, bytes: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("rust_gba_emu_{}_{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        return path.to_string_lossy().into_owned();
+    }
+
+    #[test]
+    fn fix_header_only_changes_plain_roms() {
+        let rom = temp_file("fix.gba", &[0u8; 0x200]);
+        assert!(fix_header_checksum(&rom).unwrap());
+        assert!(!fix_header_checksum(&rom).unwrap());
+        let bytes = fs::read(&rom).unwrap();
+        assert_eq!(bytes[CHECKSUM_OFFSET as usize], header_checksum(&bytes));
+        fs::remove_file(&rom).unwrap();
+
+        for (name, magic) in [("fix.zip", &b"PK\x03\x04"[..]), ("fix.gz", &[0x1F, 0x8B]), ("fix.7z", &[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C]), ("fix.elf", b"\x7FELF")] {
+            let mut bytes = magic.to_vec();
+            bytes.resize(0x200, 0);
+            let path = temp_file(name, &bytes);
+            assert!(matches!(fix_header_checksum(&path), Err(LoadError::NotPlainRom(..))), "{}", name);
+            assert_eq!(fs::read(&path).unwrap(), bytes);
+            fs::remove_file(&path).unwrap();
+        }
+
+        let short = temp_file("short.gba", &[0u8; 0xBF]);
+        assert!(matches!(fix_header_checksum(&short), Err(LoadError::TooSmall(0xBF))));
+        fs::remove_file(&short).unwrap();
+    }
+    // the game code goes into the header, the ID string somewhere after it
+    fn rom_with(game_code: &[u8; 4], id: &[u8], offset: usize) -> Cartridge {
+        let mut rom = vec![0u8; 0x400];
+        rom[0xAC..0xB0].copy_from_slice(game_code);
+        rom[offset..offset + id.len()].copy_from_slice(id);
+        return Cartridge::from_bytes(rom).unwrap();
+    }
+
+    #[test]
+    fn save_type_from_id_strings() {
+        let cases: [(&[u8], SaveType); 7] = [
+            (b"SRAM_V113", SaveType::SRAM),
+            (b"SRAM_F_V100", SaveType::SRAM),
+            (b"FLASH_V126", SaveType::Flash(FlashChip::Panasonic64K)),
+            (b"FLASH512_V131", SaveType::Flash(FlashChip::Panasonic64K)),
+            (b"FLASH1M_V103", SaveType::Flash(FlashChip::Sanyo128K)),
+            (b"EEPROM_V124", SaveType::EEPROM),
+            (b"EEPROM", SaveType::None),
+        ];
+        for (id, save_type) in cases {
+            assert_eq!(rom_with(b"ABCE", id, 0x200).save_type(), save_type, "{}", String::from_utf8_lossy(id));
+        }
+        // the libraries keep the strings word aligned
+        assert_eq!(rom_with(b"ABCE", b"SRAM_V113", 0x201).save_type(), SaveType::None);
+    }
+
+    #[test]
+    fn save_type_overrides() {
+        // Top Gun - Combat Zones has an ID string but no save chip
+        assert_eq!(rom_with(b"A2YE", b"EEPROM_V124", 0x200).save_type(), SaveType::None);
+        // only the exact game code, the other regions go by the ID string
+        assert_eq!(rom_with(b"AX4P", b"EEPROM_V124", 0x200).save_type(), SaveType::Flash(FlashChip::Sanyo128K));
+        assert_eq!(rom_with(b"AX4E", b"EEPROM_V124", 0x200).save_type(), SaveType::EEPROM);
+    }
+}
@@ -1,10 +1,27 @@
 use crate::instructions::thumb::process_instruction_thumb;
-use crate::{instructions::arm::process_instruction_arm, not_implemented, instructions::masks_32bit::*, util::*};
+use crate::{instructions::arm::process_instruction_arm, instructions::masks_32bit::*, util::*};
+use crate::backup::{sram::SRAM, Backup, BackupChip};
+use crate::dma::DMAController;
+use crate::elf::SymbolTable;
+use crate::gpio::Gpio;
+use crate::hooks::{Access, AccessKind, Hooks};
+use crate::tilt::TiltSensor;
+#[cfg(feature = "logging")]
+use crate::trace::Tracer;
+use crate::interrupt::{Interrupt, InterruptController};
+use crate::io::{io_read, io_write, POSTFLG_HALTCNT, WAITCNT};
+use crate::keypad::{ButtonSet, Keypad};
+use crate::multiboot::{self, Sender};
+use crate::scheduler::{EventKind, Scheduler};
+use crate::sound::apu::{self, APU};
+use crate::timers::{self, Timers};
+use crate::video::{self, Video, CYCLES_PER_LINE};
 use std::ops::Index;
 use std::ops::IndexMut;
 
 // reference for the registers
 // this allows for easier array access into the CPU's register array
+#[allow(non_camel_case_types)]
 pub enum Registers {
     // Regular registers
     R0 = 0,
@@ -74,6 +91,18 @@ pub const LR_ABT: Registers = Registers::R14_ABT;
 pub const SP_UND: Registers = Registers::R13_UND;
 pub const LR_UND: Registers = Registers::R14_UND;
 
+// without a BIOS image this takes its place at the IRQ vector, it does what the real one does there
+// save the registers, call the game's handler from 0x03FFFFFC and return to the interrupted code
+pub const IRQ_VECTOR: u32 = 0x18;
+pub const IRQ_HANDLER: [u32; 6] = [
+    0xE92D500F,  // stmfd sp!, {r0-r3, r12, lr}
+    0xE3A00301,  // mov r0, #0x04000000
+    0xE28FE000,  // add lr, pc, #0
+    0xE510F004,  // ldr pc, [r0, #-4]
+    0xE8BD500F,  // ldmfd sp!, {r0-r3, r12, lr}
+    0xE25EF004,  // subs pc, lr, #4
+];
+
 
 // these impls are necessary for the enum above to work with array accesses
 impl<T> Index<Registers> for [T] {
@@ -92,7 +121,7 @@ impl<T> IndexMut<Registers> for [T] {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 pub enum CPUMode {
     // numbers from CPU manual p.35
     User = 0b10000,
@@ -106,12 +135,13 @@ pub enum CPUMode {
 
 #[repr(u32)]
 pub enum ConditionFlags {
-    V = 0x1000000,
-    C = 0x2000000,
-    Z = 0x4000000,
-    N = 0x8000000,
+    V = 0x10000000,
+    C = 0x20000000,
+    Z = 0x40000000,
+    N = 0x80000000,
 }
 
+#[derive(Clone, Copy)]
 #[repr(u32)]
 pub enum RWType {
     Byte = 0,
@@ -119,6 +149,16 @@ pub enum RWType {
     Word = 2,
 }
 
+impl RWType {
+    // in bytes
+    pub fn size(self) -> u32 {
+        return 1 << (self as u32);
+    }
+}
+
+// a frame lasts 228 lines of 1232 cycles each
+pub const CYCLES_PER_FRAME: u128 = 228 * CYCLES_PER_LINE;
+
 // emulation of a ARMT7DMI CPU
 // memory is included here, this mirrors the way it was manufactured in real life where the RAM is integrated into the CPU chip
 pub struct CPU {
@@ -132,32 +172,134 @@ pub struct CPU {
     pub palette_ram: [u32; 256],  // 1 KB
     pub video_ram: [u32; 24576],  // 96 KB
     pub obj_att: [u32; 256],  // 1 KB
-    pub game_pak_ram: [u32; 16384],  // 64 KB
+    pub game_pak_rom: Vec<u8>,  // up to 32 MB
+    pub backup: Backup,  // save memory of the game pak
+    pub io_registers: [u16; 512],  // backing storage for IO registers without special handling
+    // peripherals
+    pub interrupts: InterruptController,
+    pub keypad: Keypad,
+    pub timers: Timers,
+    pub dma: DMAController,
+    pub apu: APU,
+    pub video: Video,
+    pub gpio: Gpio,
+    pub tilt: Option<TiltSensor>,
+    // the other GBA on the link cable when the BIOS receives a multiboot image
+    pub multiboot_sender: Option<Sender>,
+    // from ELF files, empty for plain ROMs
+    pub symbols: SymbolTable,
+    #[cfg(feature = "logging")]
+    pub tracer: Option<Tracer>,
+    // callbacks and breakpoints on memory accesses
+    pub hooks: Hooks,
+    pub scheduler: Scheduler,
+    dispatching: bool,  // set while add_cycles runs the due events
+    // low power modes, entered by writing to HALTCNT
+    pub halted: bool,
+    pub stopped: bool,
 }
 
 impl CPU {
     pub fn new() -> CPU {
         let mut init: [u32; 37] = [0; 37];
         init[Registers::CPSR] = 16; // 16 == binary for user mode
-        CPU {
+        let mut cpu = CPU {
             cycles: 0,
             branch: false,
             registers: init,
-            bios: [0; 4096],  // TODO: load in bios into this
+            bios: [0; 4096],  // filled by load_bios
             board_ram: [0; 65536],
             chip_ram: [0; 8192],
             palette_ram: [0; 256],
             video_ram: [0; 24576],
             obj_att: [0; 256],
-            game_pak_ram: [0; 16384],
+            game_pak_rom: Vec::new(),
+            backup: Backup::new(BackupChip::SRAM(SRAM::new())),
+            io_registers: [0; 512],
+            interrupts: InterruptController::new(),
+            keypad: Keypad::new(),
+            timers: Timers::new(),
+            dma: DMAController::new(),
+            apu: APU::new(),
+            video: Video::new(),
+            gpio: Gpio::new(),
+            tilt: None,
+            multiboot_sender: None,
+            symbols: SymbolTable::new(),
+            #[cfg(feature = "logging")]
+            tracer: None,
+            hooks: Hooks::new(),
+            scheduler: Scheduler::new(),
+            dispatching: false,
+            halted: false,
+            stopped: false,
+        };
+        apu::schedule_events(&mut cpu);
+        video::schedule_events(&mut cpu);
+        cpu
+    }
+
+    // the BIOS image is 16 KB, anything beyond that is ignored
+    pub fn load_bios(&mut self, bios: &[u8]) {
+        for (index, word) in bios.chunks(4).take(self.bios.len()).enumerate() {
+            let mut bytes = [0u8; 4];
+            bytes[..word.len()].copy_from_slice(word);
+            self.bios[index] = u32::from_le_bytes(bytes);
+        }
+    }
+
+    // state after a reset, the BIOS starts at 0 in supervisor mode with interrupts disabled
+    pub fn boot_bios(&mut self) {
+        self.registers[Registers::CPSR] = 0xD3;
+        self.registers[15] = 0;
+    }
+
+    // state the BIOS leaves behind when it hands over to the game, to boot without running it
+    // entry is 0x08000000 for the game pak or 0x020000C0 for multiboot images
+    pub fn skip_bios(&mut self, entry: u32) {
+        self.registers[Registers::R13_SVC] = 0x03007FE0;
+        self.registers[Registers::R13_IRQ] = 0x03007FA0;
+        self.registers[Registers::R13] = 0x03007F00;
+        self.registers[Registers::CPSR] = 0x1F;  // system mode, ARM state, interrupts enabled
+        self.registers[15] = entry;
+        // POSTFLG tells that this isn't the first boot any more
+        io_write(self, POSTFLG_HALTCNT, 1, 0x000000FF);
+        // interrupts are enabled, so something has to be at the vector when no BIOS was loaded
+        if self.bios.iter().all(|word| *word == 0) {
+            let start = IRQ_VECTOR as usize / 4;
+            self.bios[start..start + IRQ_HANDLER.len()].copy_from_slice(&IRQ_HANDLER);
         }
     }
 
     pub fn cycle(&mut self) {
+        // STOP is only left through an interrupt from the keypad, the game pak or the serial port, HALT through any enabled one
+        // while waiting, nothing happens until the next event, so we skip right to it
+        if self.stopped {
+            if !self.interrupts.stop_wakeup() {
+                self.skip_to_next_event();
+                return;
+            }
+            self.stopped = false;
+        }
+        if self.halted {
+            if !self.interrupts.pending() {
+                self.skip_to_next_event();
+                return;
+            }
+            self.halted = false;
+        }
+        if self.interrupts.irq_line() && !self.get_irq_disable() {
+            self.enter_irq();
+        }
+
         let pc = self.registers[15];
+        // set by instructions that write the PC, also when a debugger moved it in the meantime
+        self.branch = false;
         if self.get_state()
         {
-            let instruction: u32 = self.memory_read(pc, RWType::HalfWord);
+            let instruction: u32 = self.fetch(pc, RWType::HalfWord);
+            #[cfg(feature = "logging")]
+            self.trace(pc, instruction, true);
             process_instruction_thumb(self, instruction);
             if self.branch {
                 self.branch = false;
@@ -168,7 +310,9 @@ impl CPU {
         }
         else
         {
-            let instruction: u32 = self.memory_read(pc, RWType::Word);
+            let instruction: u32 = self.fetch(pc, RWType::Word);
+            #[cfg(feature = "logging")]
+            self.trace(pc, instruction, false);
             // check if condition flags in instruction match with CPU state
             // if not then ignore the instruction
             if self.check_condition(instruction) {
@@ -181,16 +325,123 @@ impl CPU {
                 self.registers[15] = self.registers[15].wrapping_add(4);
             }
         }
+        // TODO: proper instruction timings, for now every instruction takes a single cycle
+        self.add_cycles(1);
+    }
+
+    // the tracer is taken out while it looks at the CPU, and dropped once it's done
+    #[cfg(feature = "logging")]
+    fn trace(&mut self, address: u32, instruction: u32, thumb: bool) {
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.trace(self, address, instruction, thumb);
+            if tracer.active() {
+                self.tracer = Some(tracer);
+            }
+        }
+    }
+
+    // writes out what the tracer has buffered, for when the emulator stops without dropping it
+    pub fn flush_trace(&mut self) {
+        #[cfg(feature = "logging")]
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush();
+        }
+    }
+
+    pub fn run_frame(&mut self) {
+        let end = self.cycles + CYCLES_PER_FRAME;
+        while self.cycles < end {
+            self.cycle();
+        }
+    }
+
+    pub fn cycles(&self) -> u128 {
+        return self.cycles;
+    }
+
+    // advances the clock and dispatches all events that became due in the meantime
+    pub fn add_cycles(&mut self, cycles: u128) {
+        self.cycles += cycles;
+        // events can take time themselves, like a DMA started by one, the loop below then picks up what became due
+        if self.dispatching {
+            return;
+        }
+        self.dispatching = true;
+        while let Some((timestamp, event)) = self.scheduler.pop_due(self.cycles) {
+            match event {
+                EventKind::TimerOverflow(index) => timers::overflow_event(self, index, timestamp),
+                EventKind::ApuSample => apu::sample_event(self, timestamp),
+                EventKind::ApuFrameSequencer => apu::frame_sequencer_event(self, timestamp),
+                EventKind::SerialTransfer => multiboot::transfer_event(self),
+                EventKind::HBlank => video::hblank_event(self, timestamp),
+                EventKind::Scanline => video::scanline_event(self, timestamp),
+            }
+        }
+        self.dispatching = false;
+    }
+
+    fn skip_to_next_event(&mut self) {
+        match self.scheduler.next_timestamp() {
+            Some(timestamp) if timestamp > self.cycles => self.add_cycles(timestamp - self.cycles),
+            _ => self.add_cycles(1),
+        }
+    }
+
+    // IRQ exception, ARM manual p.39
+    fn enter_irq(&mut self) {
+        let cpsr = self.registers[Registers::CPSR];
+        // R15 holds the address of the next instruction at this point
+        // the handler returns with SUBS PC, R14, #4, so the return address is stored with an offset of 4
+        let return_address = self.registers[15].wrapping_add(4);
+        self.set_mode(CPUMode::IRQ);
+        self.register_write(17, cpsr);
+        self.register_write(14, return_address);
+        self.set_state(false);
+        self.set_irq_disable(true);
+        self.registers[15] = 0x18;
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupts.request(interrupt);
+    }
+
+    // input API, buttons that are set in the given set are pressed, all others are released
+    pub fn set_buttons(&mut self, buttons: ButtonSet) {
+        self.keypad.set_buttons(buttons);
+        self.check_keypad_irq();
+    }
+
+    pub fn check_keypad_irq(&mut self) {
+        if self.keypad.irq_condition() {
+            self.interrupts.request(Interrupt::Keypad);
+        }
     }
 
     // checks the condition of an instruction with the state of the CPU, this is only for ARM mode
     #[inline]
     pub fn check_condition(&self, instruction: u32) -> bool {
-        if instruction & B_31_28 == self.get_all_condition_flags() {
-            return true;
-        }
-        else {
-            return false;
+        // condition field in bits 31-28, see https://problemkaputt.de/gbatek.htm#armconditionfield
+        let n = self.get_condition_flag(ConditionFlags::N);
+        let z = self.get_condition_flag(ConditionFlags::Z);
+        let c = self.get_condition_flag(ConditionFlags::C);
+        let v = self.get_condition_flag(ConditionFlags::V);
+        match (instruction & B_31_28) >> 28 {
+            0x0 => return z,
+            0x1 => return !z,
+            0x2 => return c,
+            0x3 => return !c,
+            0x4 => return n,
+            0x5 => return !n,
+            0x6 => return v,
+            0x7 => return !v,
+            0x8 => return c && !z,
+            0x9 => return !c || z,
+            0xA => return n == v,
+            0xB => return n != v,
+            0xC => return !z && n == v,
+            0xD => return z || n != v,
+            0xE => return true,
+            _ => return false,  // never, reserved on the ARM7TDMI
         }
     }
 
@@ -203,10 +454,10 @@ impl CPU {
     pub fn set_state(&mut self, set: bool) {
         // same as above
         if set {
-            self.registers[Registers::CPSR] = self.registers[Registers::CPSR] | (1 << 5);
+            self.registers[Registers::CPSR] |= 1 << 5;
         }
         else {
-            self.registers[Registers::CPSR] = self.registers[Registers::CPSR] & !(1 << 5);
+            self.registers[Registers::CPSR] &= !(1 << 5);
         }
     }
 
@@ -216,10 +467,10 @@ impl CPU {
 
     pub fn set_fiq_disable(&mut self, set: bool) {
         if set {
-            self.registers[Registers::CPSR] = self.registers[Registers::CPSR] | (1 << 6);
+            self.registers[Registers::CPSR] |= 1 << 6;
         }
         else {
-            self.registers[Registers::CPSR] = self.registers[Registers::CPSR] & !(1 << 6);
+            self.registers[Registers::CPSR] &= !(1 << 6);
         }
     }
 
@@ -229,10 +480,10 @@ impl CPU {
 
     pub fn set_irq_disable(&mut self, set: bool) {
         if set {
-            self.registers[Registers::CPSR] = self.registers[Registers::CPSR] | (1 << 7);
+            self.registers[Registers::CPSR] |= 1 << 7;
         }
         else {
-            self.registers[Registers::CPSR] = self.registers[Registers::CPSR] & !(1 << 7);
+            self.registers[Registers::CPSR] &= !(1 << 7);
         }
     }
 
@@ -279,7 +530,55 @@ impl CPU {
         return self.registers[Registers::CPSR] & 0xF0000000;
     }
 
-    pub fn memory_read(&self, address: u32, rw_type: RWType) -> u32 {
+    pub fn memory_read(&mut self, address: u32, rw_type: RWType) -> u32 {
+        // without hooks the access goes straight to memory
+        if self.hooks.is_empty() {
+            return self.bus_read(address, rw_type);
+        }
+        let value = self.bus_read(address, rw_type);
+        self.hooks.run(Access {
+            address,
+            kind: AccessKind::Read,
+            size: rw_type.size(),
+            value,
+        });
+        return value;
+    }
+
+    // reads an instruction, hooks see it as executed instead of read
+    fn fetch(&mut self, address: u32, rw_type: RWType) -> u32 {
+        if self.hooks.is_empty() {
+            return self.bus_read(address, rw_type);
+        }
+        let value = self.bus_read(address, rw_type);
+        self.hooks.run(Access {
+            address,
+            kind: AccessKind::Execute,
+            size: rw_type.size(),
+            value,
+        });
+        return value;
+    }
+
+    // for debuggers looking at memory, hooks aren't called and nothing on the bus changes state
+    pub fn debug_read(&self, address: u32, rw_type: RWType) -> u32 {
+        return self.peek(address, rw_type);
+    }
+
+    pub fn debug_write(&mut self, address: u32, rw_type: RWType, value: u32) {
+        self.bus_write(address, rw_type, value);
+    }
+
+    fn bus_read(&mut self, address: u32, rw_type: RWType) -> u32 {
+        let value = self.peek(address, rw_type);
+        if address >= 0x0D000000 && address <= 0x0DFFFFFF && self.backup.is_eeprom() {
+            // reading the EEPROM shifts the bit out
+            self.backup.read_eeprom(self.cycles);
+        }
+        return value;
+    }
+
+    fn peek(&self, address: u32, rw_type: RWType) -> u32 {
         /* 
             reads memory from address in RAM
             if read_type is 0, a single byte is loaded and placed into the lower 8 bits
@@ -296,21 +595,21 @@ impl CPU {
         // resolve address for the different areas of memory and get value
         // going by the memory map on https://problemkaputt.de/gbatek.htm#gbamemorymap 
         let value: u32;
-        if address >= 0x00000000 && address <= 0x00003FFF {
+        if address <= 0x00003FFF {
             // BIOS
             value = self.bios[w_address as usize];
         }
-        else if address >= 0x02000000 && address <= 0x0203FFFF {
-            // board RAM
-            value = self.board_ram[(w_address - 0x00800000) as usize];
+        else if address >= 0x02000000 && address <= 0x02FFFFFF {
+            // board RAM, mirrored every 256 KB
+            value = self.board_ram[((address & 0x3FFFF) / 4) as usize];
         }
-        else if address >= 0x03000000 && address <= 0x03007FFF {
-            // chip RAM
-            value = self.chip_ram[(w_address - 0x00C00000) as usize];
+        else if address >= 0x03000000 && address <= 0x03FFFFFF {
+            // chip RAM, mirrored every 32 KB, the BIOS IRQ handler reads the game's handler from the last word at 0x03FFFFFC
+            value = self.chip_ram[((address & 0x7FFF) / 4) as usize];
         }
         else if address >= 0x04000000 && address <= 0x040003FE {
             // IO registers
-            not_implemented!();
+            value = io_read(self, address);
         }
         else if address >= 0x05000000 && address <= 0x050003FF {
             // BG/OBJ palette
@@ -324,21 +623,24 @@ impl CPU {
             // OBJ attributes
             value = self.obj_att[(w_address - 0x01C00000) as usize];
         }
-        else if address >= 0x08000000 && address <= 0x09FFFFFF {
-            // Game Pak wait state 0
-            not_implemented!();
-        }
-        else if address >= 0x0A000000 && address <= 0x0BFFFFFF {
-            // Game Pak wait state 1
-            not_implemented!();
+        else if address >= 0x0D000000 && address <= 0x0DFFFFFF && self.backup.is_eeprom() {
+            // Game Pak EEPROM, one bit per access
+            value = self.backup.peek_eeprom(self.cycles) as u32;
         }
-        else if address >= 0x0C000000 && address <= 0x0DFFFFFF {
-            // Game Pak wait state 2
-            not_implemented!();
+        else if address >= 0x08000000 && address <= 0x0DFFFFFF {
+            // Game Pak ROM, the areas for wait states 0, 1 and 2 all mirror the same 32 MB
+            let offset = (w_address * 4) & 0x01FFFFFF;
+            value = self.gpio.read_word(offset, self.game_pak_rom_word(offset));
         }
-        else if address >= 0x0E000000 && address <= 0x0E00FFFF {
+        else if address >= 0x0E000000 && address <= 0x0FFFFFFF {
             // Game Pak SRAM
-            value = self.game_pak_ram[(w_address - 0x03800000) as usize];
+            // 8 bit bus, for halfword and word reads the byte is repeated over the whole value
+            let offset = address - 0x0E000000;
+            let byte = match &self.tilt {
+                Some(tilt) if TiltSensor::covers(offset) => tilt.read(offset),
+                _ => self.backup.read(offset),
+            };
+            value = byte as u32 * 0x01010101;
         }
         else {
             panic!("Read attempt in unused area of memory! Address: {:x}", address);
@@ -360,13 +662,61 @@ impl CPU {
                 // we need to rotate the value such that the addressed byte ends up at position 0 to 7 in the return value
                 // note: in contrast to half word loads, there is no masking or sign extend here
                 // using the inbuilt Rust rotate here because there are no side effects on the processor flags
-                return value.rotate_left(8 * w_byte);
+                return value.rotate_right(8 * w_byte);
+            },
+        }
+    }
+
+    // cycles a single access takes, see https://problemkaputt.de/gbatek.htm#gbamemorymap
+    // the game pak wait states are configured in WAITCNT, its 16 bit bus needs two accesses for a word
+    pub fn access_cycles(&self, address: u32, rw_type: RWType, sequential: bool) -> u128 {
+        const FIRST_ACCESS: [u128; 4] = [4, 3, 2, 8];
+        let waitcnt = io_read(self, WAITCNT);
+        let word = matches!(rw_type, RWType::Word);
+        match address >> 24 {
+            // board RAM has a 16 bit bus, so do palette RAM and VRAM
+            0x02 if word => 6,
+            0x02 => 3,
+            0x05 | 0x06 if word => 2,
+            0x08..=0x0D => {
+                // wait state 0, 1 or 2 depending on the mirror
+                let state = ((address >> 25) - 4) as usize;
+                let first_shift = [2, 5, 8][state];
+                let second = [[2, 1], [4, 1], [8, 1]][state][((waitcnt >> (first_shift + 2)) & 1) as usize];
+                let non_sequential = FIRST_ACCESS[((waitcnt >> first_shift) & 0b11) as usize] + 1;
+                let first = if sequential {second + 1} else {non_sequential};
+                if word {first + second + 1} else {first}
             },
-            _ => panic!("Invalid read type {} in memory read!", rw_type)
+            0x0E | 0x0F => FIRST_ACCESS[(waitcnt & 0b11) as usize] + 1,
+            // BIOS, chip RAM, IO and OAM, and halfwords from palette RAM and VRAM
+            _ => 1,
         }
     }
 
+    // reads past the end of the ROM see the halfword address, as the address and data lines are shared
+    fn game_pak_rom_word(&self, offset: u32) -> u32 {
+        let index = offset as usize;
+        if index + 4 <= self.game_pak_rom.len() {
+            let bytes = [self.game_pak_rom[index], self.game_pak_rom[index + 1], self.game_pak_rom[index + 2], self.game_pak_rom[index + 3]];
+            return u32::from_le_bytes(bytes);
+        }
+        let halfword = (offset >> 1) & 0xFFFF;
+        return halfword | (((halfword + 1) & 0xFFFF) << 16);
+    }
+
     pub fn memory_write(&mut self, address: u32, rw_type: RWType, value: u32) {
+        self.bus_write(address, rw_type, value);
+        if !self.hooks.is_empty() {
+            self.hooks.run(Access {
+                address,
+                kind: AccessKind::Write,
+                size: rw_type.size(),
+                value,
+            });
+        }
+    }
+
+    fn bus_write(&mut self, address: u32, rw_type: RWType, value: u32) {
         // writes to memory address in RAM
         // if write type is 0, a byte write is performed
         // value contains the byte in bits 0 to 7 and otherwise it's 0
@@ -399,55 +749,65 @@ impl CPU {
                 write_data = value;
                 write_mask = 0x0;
             },
-            _ => panic!("Invalid write mode {} in memory write!", rw_type)
         }
 
         // write value into memory
         // get old data, null out the sections to overwrite, or with new value
         // going by the memory map on https://problemkaputt.de/gbatek.htm#gbamemorymap 
-        if address >= 0x00000000 && address <= 0x00003FFF {
+        if address <= 0x00003FFF {
             // BIOS
             self.bios[w_address as usize] = (self.bios[w_address as usize] & write_mask) | write_data;
         }
-        else if address >= 0x02000000 && address <= 0x0203FFFF {
-            // board RAM
-            self.board_ram[w_address as usize] = (self.board_ram[w_address as usize] & write_mask) | write_data;
+        else if address >= 0x02000000 && address <= 0x02FFFFFF {
+            // board RAM, mirrored every 256 KB
+            let index = ((address & 0x3FFFF) / 4) as usize;
+            self.board_ram[index] = (self.board_ram[index] & write_mask) | write_data;
         }
-        else if address >= 0x03000000 && address <= 0x03007FFF {
-            // chip RAM
-            self.chip_ram[w_address as usize] = (self.chip_ram[w_address as usize] & write_mask) | write_data;
+        else if address >= 0x03000000 && address <= 0x03FFFFFF {
+            // chip RAM, mirrored every 32 KB
+            let index = ((address & 0x7FFF) / 4) as usize;
+            self.chip_ram[index] = (self.chip_ram[index] & write_mask) | write_data;
         }
         else if address >= 0x04000000 && address <= 0x040003FE {
             // IO registers
-            not_implemented!();
+            io_write(self, address, write_data, !write_mask);
         }
         else if address >= 0x05000000 && address <= 0x050003FF {
             // BG/OBJ palette
-            self.palette_ram[w_address as usize] = (self.palette_ram[w_address as usize] & write_mask) | write_data;
+            let index = (w_address - 0x01400000) as usize;
+            self.palette_ram[index] = (self.palette_ram[index] & write_mask) | write_data;
         }
         else if address >= 0x06000000 && address <= 0x06017FFF {
             // VRAM
-            self.video_ram[w_address as usize] = (self.video_ram[w_address as usize] & write_mask) | write_data;
+            let index = (w_address - 0x01800000) as usize;
+            self.video_ram[index] = (self.video_ram[index] & write_mask) | write_data;
         }
         else if address >= 0x07000000 && address <= 0x070003FF {
             // OBJ attributes
-            self.obj_att[w_address as usize] = (self.obj_att[w_address as usize] & write_mask) | write_data;
-        }
-        else if address >= 0x08000000 && address <= 0x09FFFFFF {
-            // Game Pak wait state 0
-            not_implemented!();
-        }
-        else if address >= 0x0A000000 && address <= 0x0BFFFFFF {
-            // Game Pak wait state 1
-            not_implemented!();
-        }
-        else if address >= 0x0C000000 && address <= 0x0DFFFFFF {
-            // Game Pak wait state 2
-            not_implemented!();
+            let index = (w_address - 0x01C00000) as usize;
+            self.obj_att[index] = (self.obj_att[index] & write_mask) | write_data;
+        }
+        else if address >= 0x0D000000 && address <= 0x0DFFFFFF && self.backup.is_eeprom() {
+            // Game Pak EEPROM, one bit per access
+            self.backup.write_eeprom(value as u8 & 1, self.cycles);
+        }
+        else if address >= 0x08000000 && address <= 0x0DFFFFFF {
+            // Game Pak ROM, read only apart from the GPIO port
+            let offset = (w_address * 4) & 0x01FFFFFF;
+            for halfword in 0..2 {
+                if !write_mask & (0xFFFF << (16 * halfword)) != 0 {
+                    self.gpio.write(offset + 2 * halfword, (write_data >> (16 * halfword)) as u16, self.cycles);
+                }
+            }
         }
-        else if address >= 0x0E000000 && address <= 0x0E00FFFF {
+        else if address >= 0x0E000000 && address <= 0x0FFFFFFF {
             // Game Pak SRAM
-            self.game_pak_ram[w_address as usize] = (self.game_pak_ram[w_address as usize] & write_mask) | write_data;
+            // 8 bit bus, of halfword and word writes only the lowest byte ends up in memory
+            let offset = address - 0x0E000000;
+            match &mut self.tilt {
+                Some(tilt) if TiltSensor::covers(offset) => tilt.write(offset, value as u8),
+                _ => self.backup.write(offset, value as u8),
+            }
         }
         else {
             panic!("Write attempt in unused area of memory! Address: {:x}", address);
@@ -460,7 +820,9 @@ impl CPU {
     pub fn register_read(&self, register: u32) -> u32 {
         let register: usize = register.try_into().unwrap();
         if register == 15 {
-            return self.registers[15];  // special case for R15, as it's shared across all modes
+            // special case for R15, as it's shared across all modes
+            // instructions see it two instructions ahead of the one being executed because of the prefetch
+            return self.registers[15].wrapping_add(if self.get_state() {4} else {8});
         }
         else if register == 16 {
             return self.registers[Registers::CPSR];  // special case for number 16, as it's supposed to be the CPSR
@@ -480,7 +842,7 @@ impl CPU {
     }
 
     pub fn register_read_custom(&self, register: u32, mode: CPUMode) -> u32 {
-        // same as above, but with user input for the mode
+        // same as above, but with user input for the mode, and R15 as it is
         let register: usize = register.try_into().unwrap();
         if register == 15 {
             return self.registers[15];  // special case for R15, as it's shared across all modes
@@ -506,9 +868,12 @@ impl CPU {
         let register: usize = register.try_into().unwrap();
         if register == 15 {
             self.registers[15] = value;  // special case for R15, as it's shared across all modes
+            self.branch = true;  // the next instruction comes from the new address
+            return;
         }
         else if register == 16 {
-            self.registers[16] = value;  // special case for element 16, as it's the CPSR
+            self.registers[Registers::CPSR] = value;  // special case for number 16, as it's supposed to be the CPSR
+            return;
         }
         match self.get_mode() {
             CPUMode::User | CPUMode::System => self.registers[register] = value,
@@ -524,9 +889,12 @@ impl CPU {
         let register: usize = register.try_into().unwrap();
         if register == 15 {
             self.registers[15] = value;  // special case for R15, as it's shared across all modes
+            self.branch = true;  // the next instruction comes from the new address
+            return;
         }
         else if register == 16 {
-            self.registers[16] = value;  // special case for element 16, as it's the CPSR
+            self.registers[Registers::CPSR] = value;  // special case for number 16, as it's supposed to be the CPSR
+            return;
         }
         match mode {
             CPUMode::User | CPUMode::System => self.registers[register] = value,
@@ -0,0 +1,590 @@
+use std::io::{self, BufRead, Write};
+use std::ops::RangeInclusive;
+use std::panic::{self, AssertUnwindSafe};
+use crate::cpu::{CPUMode, ConditionFlags, RWType, CPU};
+use crate::hooks::{Access, AccessFlags, AccessKind, HookId};
+use crate::instructions::disasm;
+
+// interactive debugger on the command line, started with --debug
+// addresses can be given as numbers (0x for hex) or as symbols of an ELF file, help lists the commands
+
+const HELP: &str = "\
+s, step [n]              execute n instructions (default 1)
+n, next                  step over calls (bl) and software interrupts (swi)
+c, continue              run until a breakpoint or watchpoint is hit
+b, break ADDR [if REG OP VALUE]
+                         stop at ADDR, optionally only if the register compares true (==, !=, <, <=, >, >=)
+watch ADDR [LEN]         stop after a write to ADDR..ADDR+LEN (default 1 byte)
+rwatch ADDR [LEN]        stop after a read
+awatch ADDR [LEN]        stop after a read or a write
+d, delete N              remove breakpoint or watchpoint N
+i, info                  list breakpoints and watchpoints
+r, regs [MODE]           show the registers, or those banked for usr, fiq, irq, svc, abt, und or sys
+x ADDR [LEN]             hexdump LEN bytes (default 64)
+dis [ADDR] [N]           disassemble N instructions at ADDR (default around the PC)
+set REG VALUE            write a register
+h, help                  show this text
+q, quit                  exit the emulator
+an empty line repeats the last command";
+
+// the CPU only knows these areas, anything else isn't shown
+const MAPPED: [RangeInclusive<u32>; 8] = [
+    0x00000000..=0x00003FFF,  // BIOS
+    0x02000000..=0x02FFFFFF,  // board RAM and its mirrors
+    0x03000000..=0x03FFFFFF,  // chip RAM and its mirrors
+    0x04000000..=0x040003FE,  // IO registers
+    0x05000000..=0x050003FF,  // palette RAM
+    0x06000000..=0x06017FFF,  // VRAM
+    0x07000000..=0x070003FF,  // OAM
+    0x08000000..=0x0FFFFFFF,  // game pak ROM and SRAM
+];
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+// a breakpoint with a condition only stops if the register compares true against the value
+struct Condition {
+    register: u32,
+    comparison: Comparison,
+    value: u32,
+}
+
+struct Breakpoint {
+    id: usize,
+    address: u32,
+    condition: Option<Condition>,
+}
+
+// a breakpoint hook on the memory bus, the debugger stops after the instruction that made the access
+struct Watchpoint {
+    id: usize,
+    hook: HookId,
+    range: RangeInclusive<u32>,
+    flags: AccessFlags,
+}
+
+// why running stopped
+enum Stop {
+    Done,
+    Breakpoint(usize),
+    Watchpoint(usize, Access),
+    // the emulator panicked, the state is kept to look at what led there
+    Panic(u32),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: usize,
+    // return address and stack pointer of the call stepped over by next
+    step_over: Option<(u32, u32)>,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+            step_over: None,
+            last_command: String::new(),
+        }
+    }
+
+    // reads commands until quit or the end of the input
+    pub fn run(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        println!("Type help for the list of commands");
+        show_location(cpu);
+        let stdin = io::stdin();
+        let mut line = String::new();
+        loop {
+            print!("(gba) ");
+            io::stdout().flush()?;
+            line.clear();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let command = line.trim();
+            if !command.is_empty() {
+                self.last_command = command.to_string();
+            }
+            let command = self.last_command.clone();
+            match self.execute(cpu, &command) {
+                Ok(true) => {},
+                Ok(false) => return Ok(()),
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    // false once the debugger should quit
+    fn execute(&mut self, cpu: &mut CPU, command: &str) -> Result<bool, String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(true),
+        };
+        match name {
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(count) => count.parse::<u64>().map_err(|_| format!("{} is not a number of instructions", count))?,
+                    None => 1,
+                };
+                let stop = self.resume(cpu, Some(count));
+                self.report(cpu, stop);
+            },
+            "n" | "next" => {
+                self.step_over = call_return(cpu).map(|address| (address, cpu.register_read(13)));
+                let stop = self.resume(cpu, if self.step_over.is_some() {None} else {Some(1)});
+                self.step_over = None;
+                self.report(cpu, stop);
+            },
+            "c" | "continue" => {
+                let stop = self.resume(cpu, None);
+                self.report(cpu, stop);
+            },
+            "b" | "break" => {
+                let address = parse_address(cpu, args.first().ok_or("break needs an address")?)?;
+                let condition = match args.get(1..) {
+                    Some(["if", register, comparison, value]) => Some(Condition {
+                        register: parse_register(register)?,
+                        comparison: parse_comparison(comparison)?,
+                        value: parse_address(cpu, value)?,
+                    }),
+                    Some([]) => None,
+                    _ => return Err(String::from("a condition looks like: if r0 == 0x10")),
+                };
+                let id = self.next_id();
+                println!("Breakpoint {} at {}", id, cpu.symbols.describe(address));
+                self.breakpoints.push(Breakpoint {
+                    id,
+                    address,
+                    condition,
+                });
+            },
+            "watch" | "rwatch" | "awatch" => {
+                let address = parse_address(cpu, args.first().ok_or("watchpoints need an address")?)?;
+                let length = match args.get(1) {
+                    Some(length) => parse_address(cpu, length)?.max(1),
+                    None => 1,
+                };
+                let range = address..=address.saturating_add(length - 1);
+                let flags = match name {
+                    "watch" => AccessFlags::WRITE,
+                    "rwatch" => AccessFlags::READ,
+                    _ => AccessFlags::READ_WRITE,
+                };
+                let id = self.next_id();
+                println!("Watchpoint {} on {} bytes at {}", id, length, cpu.symbols.describe(address));
+                self.watchpoints.push(Watchpoint {
+                    id,
+                    hook: cpu.hooks.add_breakpoint(range.clone(), flags),
+                    range,
+                    flags,
+                });
+            },
+            "d" | "delete" => {
+                let id: usize = args.first().and_then(|id| id.parse().ok()).ok_or("delete needs the number of a breakpoint or watchpoint")?;
+                let count = self.breakpoints.len();
+                self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+                if let Some(index) = self.watchpoints.iter().position(|watchpoint| watchpoint.id == id) {
+                    cpu.hooks.remove(self.watchpoints.remove(index).hook);
+                }
+                else if count == self.breakpoints.len() {
+                    return Err(format!("There is no breakpoint or watchpoint {}", id));
+                }
+            },
+            "i" | "info" => self.show_info(cpu),
+            "r" | "regs" => {
+                match args.first() {
+                    Some(mode) => show_banked_registers(cpu, parse_mode(mode)?),
+                    None => show_registers(cpu),
+                }
+            },
+            "x" => {
+                let address = parse_address(cpu, args.first().ok_or("x needs an address")?)?;
+                let length = match args.get(1) {
+                    Some(length) => parse_address(cpu, length)?,
+                    None => 64,
+                };
+                hexdump(cpu, address, length);
+            },
+            "dis" => {
+                let step = if cpu.get_state() {2} else {4};
+                let count = match args.get(1) {
+                    Some(count) => count.parse().map_err(|_| format!("{} is not a number of instructions", count))?,
+                    None => 10,
+                };
+                let start = match args.first() {
+                    Some(address) => parse_address(cpu, address)?,
+                    // a few instructions before the PC for context
+                    None => cpu.registers[15].wrapping_sub(4 * step),
+                };
+                self.disassemble(cpu, start, count);
+            },
+            "set" => {
+                let (register, value) = match args {
+                    [register, value] => (parse_register(register)?, parse_address(cpu, value)?),
+                    _ => return Err(String::from("set needs a register and a value")),
+                };
+                cpu.register_write(register, value);
+            },
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(false),
+            _ => return Err(format!("Unknown command {}, type help for the list", name)),
+        }
+        Ok(true)
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        return self.next_id - 1;
+    }
+
+    // runs until something stops it, or the given number of instructions is done
+    fn resume(&mut self, cpu: &mut CPU, limit: Option<u64>) -> Stop {
+        let mut executed = 0;
+        cpu.hooks.take_hit();
+        loop {
+            let pc = cpu.registers[15];
+            if panic::catch_unwind(AssertUnwindSafe(|| cpu.cycle())).is_err() {
+                return Stop::Panic(pc);
+            }
+            executed += 1;
+            // hooks of others that asked to stop aren't the debugger's business
+            if let Some(hit) = cpu.hooks.take_hit() {
+                if let Some(watchpoint) = self.watchpoints.iter().find(|watchpoint| watchpoint.hook == hit.id) {
+                    return Stop::Watchpoint(watchpoint.id, hit.access);
+                }
+            }
+            if let Some(id) = self.breakpoint_hit(cpu) {
+                return Stop::Breakpoint(id);
+            }
+            if let Some((address, stack_pointer)) = self.step_over {
+                // recursive calls reach the return address with a deeper stack first
+                if cpu.registers[15] == address && cpu.register_read(13) >= stack_pointer {
+                    return Stop::Done;
+                }
+            }
+            if Some(executed) == limit {
+                return Stop::Done;
+            }
+        }
+    }
+
+    fn breakpoint_hit(&self, cpu: &CPU) -> Option<usize> {
+        let pc = cpu.registers[15];
+        let breakpoint = self.breakpoints.iter().find(|breakpoint| {
+            if breakpoint.address != pc {
+                return false;
+            }
+            match &breakpoint.condition {
+                Some(condition) => return condition.holds(cpu),
+                None => return true,
+            }
+        })?;
+        return Some(breakpoint.id);
+    }
+
+    fn report(&self, cpu: &mut CPU, stop: Stop) {
+        match stop {
+            Stop::Done => {},
+            Stop::Breakpoint(id) => println!("Breakpoint {}", id),
+            Stop::Watchpoint(id, access) => {
+                let kind = if access.kind == AccessKind::Write {"write of"} else {"read of"};
+                println!("Watchpoint {}: {} {:#x} at {}", id, kind, access.value, cpu.symbols.describe(access.address));
+            },
+            Stop::Panic(address) => println!("The emulator panicked on the instruction at {}", cpu.symbols.describe(address)),
+        }
+        show_location(cpu);
+    }
+
+    fn show_info(&self, cpu: &CPU) {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            println!("No breakpoints or watchpoints");
+        }
+        for breakpoint in &self.breakpoints {
+            let mut line = format!("{:<3} break   {}", breakpoint.id, cpu.symbols.describe(breakpoint.address));
+            if let Some(condition) = &breakpoint.condition {
+                line.push_str(&format!(" if {} {} {:#x}", register_name(condition.register), condition.comparison.symbol(), condition.value));
+            }
+            println!("{}", line);
+        }
+        for watchpoint in &self.watchpoints {
+            let kind = match watchpoint.flags {
+                AccessFlags::READ_WRITE => "awatch",
+                AccessFlags::READ => "rwatch",
+                _ => "watch",
+            };
+            println!("{:<3} {:<7} {:#010x}-{:#010x}", watchpoint.id, kind, watchpoint.range.start(), watchpoint.range.end());
+        }
+    }
+
+    fn disassemble(&self, cpu: &mut CPU, start: u32, count: u32) {
+        let thumb = cpu.get_state();
+        let step = if thumb {2} else {4};
+        let pc = cpu.registers[15];
+        let mut address = start & !(step - 1);
+        for _ in 0..count {
+            if let Some((symbol, 0)) = cpu.symbols.lookup(address) {
+                println!("{}:", symbol.name);
+            }
+            let marker = if address == pc {"=>"} else if self.breakpoints.iter().any(|breakpoint| breakpoint.address == address) {" *"} else {"  "};
+            println!("{} {}", marker, disassemble_at(cpu, address, thumb));
+            address = address.wrapping_add(step);
+        }
+    }
+}
+
+impl Condition {
+    fn holds(&self, cpu: &CPU) -> bool {
+        let register = cpu.register_read_custom(self.register, cpu.get_mode());
+        match self.comparison {
+            Comparison::Equal => return register == self.value,
+            Comparison::NotEqual => return register != self.value,
+            Comparison::Less => return register < self.value,
+            Comparison::LessEqual => return register <= self.value,
+            Comparison::Greater => return register > self.value,
+            Comparison::GreaterEqual => return register >= self.value,
+        }
+    }
+}
+
+impl Comparison {
+    fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Equal => return "==",
+            Comparison::NotEqual => return "!=",
+            Comparison::Less => return "<",
+            Comparison::LessEqual => return "<=",
+            Comparison::Greater => return ">",
+            Comparison::GreaterEqual => return ">=",
+        }
+    }
+}
+
+pub(crate) fn is_mapped(address: u32) -> bool {
+    return MAPPED.iter().any(|area| area.contains(&address));
+}
+
+// where bl and swi return to, next stops there instead of following them
+fn call_return(cpu: &mut CPU) -> Option<u32> {
+    let pc = cpu.registers[15];
+    if !is_mapped(pc) {
+        return None;
+    }
+    if cpu.get_state() {
+        let instruction = cpu.debug_read(pc, RWType::HalfWord);
+        if instruction & 0xF800 == 0xF000 {
+            // first half of the long branch with link, the second one follows
+            return Some(pc.wrapping_add(4));
+        }
+        if instruction & 0xFF00 == 0xDF00 {
+            return Some(pc.wrapping_add(2));
+        }
+        return None;
+    }
+    // the condition doesn't matter, if it fails the next instruction is reached anyway
+    let instruction = cpu.debug_read(pc, RWType::Word);
+    if instruction & 0x0F000000 == 0x0B000000 || instruction & 0x0F000000 == 0x0F000000 {
+        return Some(pc.wrapping_add(4));
+    }
+    return None;
+}
+
+// address, opcode and the instruction, for THUMB long branches both halves are decoded together
+fn disassemble_at(cpu: &mut CPU, address: u32, thumb: bool) -> String {
+    if !is_mapped(address) {
+        return format!("{:08X}: ????????", address);
+    }
+    if thumb {
+        let opcode = cpu.debug_read(address, RWType::HalfWord);
+        let mut instruction = opcode;
+        if opcode & 0xF800 == 0xF000 && is_mapped(address.wrapping_add(2)) {
+            instruction |= cpu.debug_read(address.wrapping_add(2), RWType::HalfWord) << 16;
+        }
+        return format!("{:08X}:     {:04X}  {}", address, opcode, disasm::thumb(instruction, address));
+    }
+    let opcode = cpu.debug_read(address, RWType::Word);
+    return format!("{:08X}: {:08X}  {}", address, opcode, disasm::arm(opcode, address));
+}
+
+fn show_location(cpu: &mut CPU) {
+    let pc = cpu.registers[15];
+    println!("{}", cpu.symbols.describe(pc));
+    println!("=> {}", disassemble_at(cpu, pc, cpu.get_state()));
+}
+
+fn register_name(register: u32) -> String {
+    match register {
+        13 => return String::from("sp"),
+        14 => return String::from("lr"),
+        15 => return String::from("pc"),
+        16 => return String::from("cpsr"),
+        17 => return String::from("spsr"),
+        _ => return format!("r{}", register),
+    }
+}
+
+fn mode_name(mode: &CPUMode) -> &'static str {
+    match mode {
+        CPUMode::User => return "User",
+        CPUMode::FIQ => return "FIQ",
+        CPUMode::IRQ => return "IRQ",
+        CPUMode::Supervisor => return "Supervisor",
+        CPUMode::Abort => return "Abort",
+        CPUMode::Undefined => return "Undefined",
+        CPUMode::System => return "System",
+    }
+}
+
+// get_mode panics on mode bits that don't belong to a mode, which a broken program can still write
+fn current_mode(cpu: &CPU) -> Option<CPUMode> {
+    match cpu.register_read(16) & 0x1F {
+        0b10000 | 0b10001 | 0b10010 | 0b10011 | 0b10111 | 0b11011 | 0b11111 => return Some(cpu.get_mode()),
+        _ => return None,
+    }
+}
+
+// flags in capitals when set, then the mode and the state
+fn describe_cpsr(cpu: &CPU, mode: Option<CPUMode>) -> String {
+    let cpsr = cpu.register_read(16);
+    let flag = |set: bool, name: char| if set {name} else {name.to_ascii_lowercase()};
+    let flags: String = [
+        flag(cpu.get_condition_flag(ConditionFlags::N), 'N'),
+        flag(cpu.get_condition_flag(ConditionFlags::Z), 'Z'),
+        flag(cpu.get_condition_flag(ConditionFlags::C), 'C'),
+        flag(cpu.get_condition_flag(ConditionFlags::V), 'V'),
+        ' ',
+        flag(cpu.get_irq_disable(), 'I'),
+        flag(cpu.get_fiq_disable(), 'F'),
+        flag(cpu.get_state(), 'T'),
+    ].iter().collect();
+    let mode = mode.as_ref().map_or("invalid mode", mode_name);
+    let state = if cpu.get_state() {"THUMB"} else {"ARM"};
+    return format!("{:08X}  [{}]  {}, {}", cpsr, flags, mode, state);
+}
+
+fn show_registers(cpu: &CPU) {
+    for row in 0..4 {
+        let line: Vec<String> = (row * 4..row * 4 + 4)
+            .map(|register| format!("{:<4} {:08X}", register_name(register), cpu.register_read_custom(register, cpu.get_mode())))
+            .collect();
+        println!("{}", line.join("   "));
+    }
+    let mode = current_mode(cpu);
+    println!("cpsr {}", describe_cpsr(cpu, mode));
+    // user and system mode have no SPSR
+    if matches!(mode, Some(mode) if mode != CPUMode::User && mode != CPUMode::System) {
+        println!("spsr {:08X}", cpu.register_read(17));
+    }
+}
+
+// the registers as the given mode sees them, with the ones banked for it marked
+fn show_banked_registers(cpu: &CPU, mode: CPUMode) {
+    let first_banked = match mode {
+        CPUMode::User | CPUMode::System => 16,
+        CPUMode::FIQ => 8,
+        _ => 13,
+    };
+    println!("{} mode", mode_name(&mode));
+    for row in 0..4 {
+        let line: Vec<String> = (row * 4..row * 4 + 4)
+            .map(|register| {
+                let marker = if register >= first_banked && register < 15 {"*"} else {" "};
+                format!("{:<4}{}{:08X}", register_name(register), marker, cpu.register_read_custom(register, mode))
+            })
+            .collect();
+        println!("{}", line.join("   "));
+    }
+    if first_banked != 16 {
+        println!("spsr {:08X}", cpu.register_read_custom(17, mode));
+    }
+}
+
+// 16 bytes per line with their ASCII characters, bytes outside of memory show as ??
+fn hexdump(cpu: &mut CPU, address: u32, length: u32) {
+    let mut line_start = address & !0xF;
+    let end = address.saturating_add(length);
+    while line_start < end {
+        let mut hex = String::new();
+        let mut text = String::new();
+        for offset in 0..16 {
+            let byte_address = line_start + offset;
+            if byte_address < address || byte_address >= end {
+                hex.push_str("   ");
+                text.push(' ');
+            }
+            else if is_mapped(byte_address) {
+                let byte = cpu.debug_read(byte_address, RWType::Byte) as u8;
+                hex.push_str(&format!("{:02X} ", byte));
+                text.push(if byte.is_ascii_graphic() || byte == b' ' {byte as char} else {'.'});
+            }
+            else {
+                hex.push_str("?? ");
+                text.push('?');
+            }
+            if offset == 7 {
+                hex.push(' ');
+            }
+        }
+        println!("{:08X}: {} {}", line_start, hex, text);
+        line_start = match line_start.checked_add(16) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+}
+
+// numbers in decimal or with 0x in hex, otherwise a symbol
+fn parse_address(cpu: &CPU, text: &str) -> Result<u32, String> {
+    let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    };
+    return value.or_else(|| cpu.symbols.address_of(text)).ok_or_else(|| format!("{} is neither a number nor a symbol", text));
+}
+
+fn parse_register(text: &str) -> Result<u32, String> {
+    let register = match text {
+        "sp" => 13,
+        "lr" => 14,
+        "pc" => 15,
+        "cpsr" => 16,
+        _ => text.strip_prefix('r').and_then(|number| number.parse().ok()).filter(|number| *number < 16).ok_or_else(|| format!("{} is not a register", text))?,
+    };
+    return Ok(register);
+}
+
+fn parse_comparison(text: &str) -> Result<Comparison, String> {
+    match text {
+        "==" => return Ok(Comparison::Equal),
+        "!=" => return Ok(Comparison::NotEqual),
+        "<" => return Ok(Comparison::Less),
+        "<=" => return Ok(Comparison::LessEqual),
+        ">" => return Ok(Comparison::Greater),
+        ">=" => return Ok(Comparison::GreaterEqual),
+        _ => return Err(format!("{} is not a comparison", text)),
+    }
+}
+
+fn parse_mode(text: &str) -> Result<CPUMode, String> {
+    match text {
+        "usr" => return Ok(CPUMode::User),
+        "fiq" => return Ok(CPUMode::FIQ),
+        "irq" => return Ok(CPUMode::IRQ),
+        "svc" => return Ok(CPUMode::Supervisor),
+        "abt" => return Ok(CPUMode::Abort),
+        "und" => return Ok(CPUMode::Undefined),
+        "sys" => return Ok(CPUMode::System),
+        _ => return Err(format!("{} is not a mode, use usr, fiq, irq, svc, abt, und or sys", text)),
+    }
+}
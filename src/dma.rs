@@ -0,0 +1,410 @@
+use crate::cpu::{RWType, CPU};
+use crate::interrupt::Interrupt;
+
+// the four DMA channels, see https://problemkaputt.de/gbatek.htm#gbadmatransfers
+// a transfer runs in one go once it's triggered, the CPU is stalled for the time it would take on hardware
+// DMA0 has the highest priority and DMA3 the lowest: a channel started by the writes of a running transfer
+// interrupts it if it has a higher priority, otherwise it waits until the running one is done
+
+// DMAxCNT_H bits
+const DEST_CONTROL: u16 = 0b11 << 5;
+const SOURCE_CONTROL: u16 = 0b11 << 7;
+const REPEAT: u16 = 1 << 9;
+const WORD_TRANSFER: u16 = 1 << 10;
+const GAME_PAK_DRQ: u16 = 1 << 11;  // only DMA3
+const START_TIMING: u16 = 0b11 << 12;
+const IRQ_ENABLE: u16 = 1 << 14;
+const ENABLE: u16 = 1 << 15;
+
+// address control values, for both source (bits 7-8) and destination (bits 5-6)
+const INCREMENT: u16 = 0;
+const DECREMENT: u16 = 1;
+const FIXED: u16 = 2;
+const INCREMENT_RELOAD: u16 = 3;  // destination only, prohibited for the source
+
+// DMA0 is restricted to the internal memory, DMA3 is the only one that can write into the game pak
+const SOURCE_MASKS: [u32; 4] = [0x07FFFFFF, 0x0FFFFFFF, 0x0FFFFFFF, 0x0FFFFFFF];
+const DEST_MASKS: [u32; 4] = [0x07FFFFFF, 0x07FFFFFF, 0x07FFFFFF, 0x0FFFFFFF];
+const COUNT_MASKS: [u32; 4] = [0x3FFF, 0x3FFF, 0x3FFF, 0xFFFF];
+
+const DMA_INTERRUPTS: [Interrupt; 4] = [Interrupt::DMA0, Interrupt::DMA1, Interrupt::DMA2, Interrupt::DMA3];
+
+// sound FIFO transfers always move 4 words
+const FIFO_TRANSFER_WORDS: u32 = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DMATiming {
+    Immediate = 0,
+    VBlank = 1,
+    HBlank = 2,
+    Special = 3,  // sound FIFO for DMA1 and DMA2, video capture for DMA3
+}
+
+pub struct DMAChannel {
+    // registers as written by the CPU
+    pub source: u32,
+    pub destination: u32,
+    pub count: u16,
+    pub control: u16,
+    // internal registers, loaded when the channel gets enabled
+    internal_source: u32,
+    internal_destination: u32,
+    internal_count: u32,
+}
+
+impl DMAChannel {
+    fn new() -> DMAChannel {
+        DMAChannel {
+            source: 0,
+            destination: 0,
+            count: 0,
+            control: 0,
+            internal_source: 0,
+            internal_destination: 0,
+            internal_count: 0,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        return self.control & ENABLE != 0;
+    }
+
+    fn timing(&self) -> DMATiming {
+        match (self.control & START_TIMING) >> 12 {
+            0 => DMATiming::Immediate,
+            1 => DMATiming::VBlank,
+            2 => DMATiming::HBlank,
+            _ => DMATiming::Special,
+        }
+    }
+
+    fn dest_control(&self) -> u16 {
+        return (self.control & DEST_CONTROL) >> 5;
+    }
+
+    fn source_control(&self) -> u16 {
+        return (self.control & SOURCE_CONTROL) >> 7;
+    }
+
+    // a count of 0 means the maximum amount
+    fn reload_count(&mut self, index: usize) {
+        let count = self.count as u32 & COUNT_MASKS[index];
+        self.internal_count = if count == 0 {COUNT_MASKS[index] + 1} else {count};
+    }
+}
+
+pub struct DMAController {
+    pub channels: [DMAChannel; 4],
+    // the channel whose transfer is in progress
+    active: Option<usize>,
+    // one bit per channel that was started while one with a higher priority was running
+    waiting: u8,
+}
+
+impl DMAController {
+    pub fn new() -> DMAController {
+        DMAController {
+            channels: [DMAChannel::new(), DMAChannel::new(), DMAChannel::new(), DMAChannel::new()],
+            active: None,
+            waiting: 0,
+        }
+    }
+
+    // register reads, offset is relative to DMAxSAD
+    // only the control register can be read back, the others are write only
+    pub fn read_register(&self, index: usize, offset: u32) -> u16 {
+        if offset == 10 {
+            return self.channels[index].control;
+        }
+        return 0;
+    }
+}
+
+pub fn write_register(cpu: &mut CPU, index: usize, offset: u32, value: u16, mask: u16) {
+    let channel = &mut cpu.dma.channels[index];
+    let merge = |old: u16| (old & !mask) | (value & mask);
+    match offset {
+        0 => channel.source = (channel.source & 0xFFFF0000) | merge(channel.source as u16) as u32,
+        2 => channel.source = (channel.source & 0x0000FFFF) | ((merge((channel.source >> 16) as u16) as u32) << 16),
+        4 => channel.destination = (channel.destination & 0xFFFF0000) | merge(channel.destination as u16) as u32,
+        6 => channel.destination = (channel.destination & 0x0000FFFF) | ((merge((channel.destination >> 16) as u16) as u32) << 16),
+        8 => channel.count = merge(channel.count),
+        10 => write_control(cpu, index, merge(cpu.dma.channels[index].control)),
+        _ => {},
+    }
+}
+
+fn write_control(cpu: &mut CPU, index: usize, value: u16) {
+    let channel = &mut cpu.dma.channels[index];
+    let was_enabled = channel.enabled();
+    // the game pak DRQ bit only exists on DMA3
+    channel.control = if index == 3 {value & 0xFFE0} else {value & 0xFFE0 & !GAME_PAK_DRQ};
+    if was_enabled || !channel.enabled() {
+        return;
+    }
+    // rising edge of the enable bit, the internal registers get loaded
+    channel.internal_source = channel.source & SOURCE_MASKS[index];
+    channel.internal_destination = channel.destination & DEST_MASKS[index];
+    channel.reload_count(index);
+    if channel.timing() == DMATiming::Immediate {
+        start(cpu, index);
+    }
+}
+
+// runs the transfer of a channel, unless one with the same or a higher priority is in progress
+fn start(cpu: &mut CPU, index: usize) {
+    if let Some(active) = cpu.dma.active {
+        if active <= index {
+            cpu.dma.waiting |= 1 << index;
+            return;
+        }
+    }
+    let interrupted = cpu.dma.active.replace(index);
+    transfer(cpu, index);
+    cpu.dma.active = interrupted;
+    // the channels that had to wait for this one, as long as they don't have to wait for the interrupted one as well
+    while let Some(next) = (0..4).find(|&next| cpu.dma.waiting & (1 << next) != 0 && interrupted.is_none_or(|active| next < active)) {
+        cpu.dma.waiting &= !(1 << next);
+        if cpu.dma.channels[next].enabled() {
+            start(cpu, next);
+        }
+    }
+}
+
+// starts all enabled channels waiting for VBlank or HBlank, in order of priority
+// called by the display timing when entering the respective period
+pub fn trigger(cpu: &mut CPU, timing: DMATiming) {
+    for index in 0..4 {
+        let channel = &cpu.dma.channels[index];
+        if channel.enabled() && channel.timing() == timing {
+            start(cpu, index);
+        }
+    }
+}
+
+// a sound FIFO drained down to half its size requests a refill
+// fifo_address is the address of FIFO_A or FIFO_B, the channel is picked by its destination address
+pub fn sound_fifo_request(cpu: &mut CPU, fifo_address: u32) {
+    for index in 1..3 {
+        let channel = &cpu.dma.channels[index];
+        if channel.enabled() && channel.timing() == DMATiming::Special && channel.internal_destination == fifo_address {
+            start(cpu, index);
+        }
+    }
+}
+
+// video capture mode of DMA3, the display timing calls this at the HBlank of every line
+// transfers run for the lines 2 to 161, at line 162 the channel stops
+pub fn video_capture(cpu: &mut CPU, line: u32) {
+    let channel = &mut cpu.dma.channels[3];
+    if !channel.enabled() || channel.timing() != DMATiming::Special {
+        return;
+    }
+    if line >= 2 && line < 162 {
+        start(cpu, 3);
+    }
+    else if line == 162 {
+        channel.control &= !ENABLE;
+    }
+}
+
+fn transfer(cpu: &mut CPU, index: usize) {
+    let channel = &cpu.dma.channels[index];
+    let sound_fifo = (index == 1 || index == 2) && channel.timing() == DMATiming::Special;
+    let word = channel.control & WORD_TRANSFER != 0 || sound_fifo;
+    let unit: u32 = if word {4} else {2};
+    let count = if sound_fifo {FIFO_TRANSFER_WORDS} else {channel.internal_count};
+
+    // the game pak ROM can only be read sequentially, so the source always increments there
+    let source_in_rom = channel.internal_source >= 0x08000000 && channel.internal_source < 0x0E000000;
+    let source_step: i64 = match channel.source_control() {
+        _ if source_in_rom => unit as i64,
+        INCREMENT | INCREMENT_RELOAD => unit as i64,
+        DECREMENT => -(unit as i64),
+        _ => 0,
+    };
+    let dest_step: i64 = match channel.dest_control() {
+        _ if sound_fifo => 0,
+        INCREMENT | INCREMENT_RELOAD => unit as i64,
+        DECREMENT => -(unit as i64),
+        FIXED => 0,
+        _ => 0,
+    };
+
+    // the EEPROM can only tell its address width from the length of the request
+    if index == 3 && channel.internal_destination >= 0x0D000000 && channel.internal_destination < 0x0E000000 {
+        cpu.backup.eeprom_transfer_length(count);
+    }
+
+    // transfers are aligned to the unit size, unaligned addresses are forced down
+    let mut source = channel.internal_source & !(unit - 1);
+    let mut destination = channel.internal_destination & !(unit - 1);
+    let (first_source, first_destination) = (source, destination);
+    for _ in 0..count {
+        if word {
+            let value = cpu.memory_read(source, RWType::Word);
+            cpu.memory_write(destination, RWType::Word, value);
+        }
+        else {
+            let value = cpu.memory_read(source, RWType::HalfWord);
+            cpu.memory_write(destination, RWType::HalfWord, value);
+        }
+        source = (source as i64 + source_step) as u32 & SOURCE_MASKS[index];
+        destination = (destination as i64 + dest_step) as u32 & DEST_MASKS[index];
+    }
+
+    let channel = &mut cpu.dma.channels[index];
+    channel.internal_source = source;
+    channel.internal_destination = destination;
+    if channel.control & REPEAT != 0 && channel.timing() != DMATiming::Immediate {
+        // repeating channels stay enabled and reload the count, and the destination if so desired
+        channel.reload_count(index);
+        if channel.dest_control() == INCREMENT_RELOAD {
+            channel.internal_destination = channel.destination & DEST_MASKS[index];
+        }
+    }
+    else {
+        channel.control &= !ENABLE;
+    }
+    if channel.control & IRQ_ENABLE != 0 {
+        cpu.request_interrupt(DMA_INTERRUPTS[index]);
+    }
+
+    // the CPU is halted while the DMA runs, see https://problemkaputt.de/gbatek.htm#dmatransfers
+    // 2 internal cycles (4 if both sides are in the game pak), then a read and a write per unit
+    // the first ones are non-sequential accesses, the rest sequential
+    let rw_type = if word {RWType::Word} else {RWType::HalfWord};
+    let mut cycles = if first_source >= 0x08000000 && first_destination >= 0x08000000 {4} else {2};
+    cycles += cpu.access_cycles(first_source, rw_type, false) + cpu.access_cycles(first_destination, rw_type, false);
+    cycles += (count as u128 - 1) * (cpu.access_cycles(first_source, rw_type, true) + cpu.access_cycles(first_destination, rw_type, true));
+    cpu.add_cycles(cycles);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::cpu::{RWType, CPU, CYCLES_PER_FRAME};
+    use crate::hooks::{AccessFlags, HookAction};
+    use crate::video::CYCLES_PER_LINE;
+
+    const IMMEDIATE_HALFWORDS: u32 = 0x8000;
+    const VBLANK: u32 = 0x9000;
+    const HBLANK_REPEAT: u32 = 0xA200;
+    const CAPTURE_REPEAT: u32 = 0xB200;
+
+    fn write_channel(cpu: &mut CPU, index: u32, source: u32, destination: u32, count: u32, control: u32) {
+        let base = 0x040000B0 + 12 * index;
+        cpu.memory_write(base, RWType::Word, source);
+        cpu.memory_write(base + 4, RWType::Word, destination);
+        cpu.memory_write(base + 8, RWType::Word, control << 16 | count);
+    }
+
+    // notes the addresses of the writes to the given ranges, in order
+    fn record_writes(cpu: &mut CPU, addresses: &[u32]) -> Rc<RefCell<Vec<u32>>> {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        for &address in addresses {
+            let seen = writes.clone();
+            cpu.hooks.add(address..=address, AccessFlags::WRITE, Box::new(move |access| {
+                seen.borrow_mut().push(access.address);
+                return HookAction::Continue;
+            }));
+        }
+        return writes;
+    }
+
+    // DMA3 enables DMA0 with its first halfword, DMA0 runs before the second one
+    #[test]
+    fn higher_priority_interrupts() {
+        let mut cpu = CPU::new();
+        cpu.memory_write(0x03000000, RWType::HalfWord, IMMEDIATE_HALFWORDS);
+        cpu.memory_write(0x040000B0, RWType::Word, 0x03000100);
+        cpu.memory_write(0x040000B4, RWType::Word, 0x03000200);
+        cpu.memory_write(0x040000B8, RWType::HalfWord, 1);
+        let writes = record_writes(&mut cpu, &[0x03000200, 0x040000BC]);
+        cpu.memory_write(0x040000D4, RWType::Word, 0x03000000);
+        cpu.memory_write(0x040000D8, RWType::Word, 0x040000BA);
+        cpu.memory_write(0x040000DC, RWType::HalfWord, 2);
+        cpu.memory_write(0x040000DE, RWType::HalfWord, IMMEDIATE_HALFWORDS);
+        assert_eq!(*writes.borrow(), [0x03000200, 0x040000BC]);
+    }
+
+    // DMA0 enables DMA3 with its first halfword, DMA3 waits for the second one
+    #[test]
+    fn lower_priority_waits() {
+        let mut cpu = CPU::new();
+        cpu.memory_write(0x03000000, RWType::HalfWord, IMMEDIATE_HALFWORDS);
+        cpu.memory_write(0x040000D4, RWType::Word, 0x03000100);
+        cpu.memory_write(0x040000D8, RWType::Word, 0x03000200);
+        cpu.memory_write(0x040000DC, RWType::HalfWord, 1);
+        let writes = record_writes(&mut cpu, &[0x03000200, 0x040000E0]);
+        cpu.memory_write(0x040000B0, RWType::Word, 0x03000000);
+        cpu.memory_write(0x040000B4, RWType::Word, 0x040000DE);
+        cpu.memory_write(0x040000B8, RWType::HalfWord, 2);
+        cpu.memory_write(0x040000BA, RWType::HalfWord, IMMEDIATE_HALFWORDS);
+        assert_eq!(*writes.borrow(), [0x040000E0, 0x03000200]);
+        assert_eq!(cpu.dma.channels[3].control, 0);
+    }
+
+    #[test]
+    fn hblank_and_vblank_start() {
+        let mut cpu = CPU::new();
+        for index in 0..4 {
+            cpu.memory_write(0x03000000 + 2 * index, RWType::HalfWord, 0x100 + index);
+        }
+        write_channel(&mut cpu, 0, 0x03000000, 0x02000000, 1, HBLANK_REPEAT);
+        write_channel(&mut cpu, 1, 0x03000000, 0x02000100, 4, VBLANK);
+        cpu.add_cycles(959);
+        assert_eq!(cpu.memory_read(0x02000000, RWType::HalfWord), 0);
+        cpu.add_cycles(1);
+        assert_eq!(cpu.memory_read(0x02000000, RWType::HalfWord), 0x100);
+        cpu.add_cycles(CYCLES_PER_LINE);
+        assert_eq!(cpu.memory_read(0x02000002, RWType::HalfWord), 0x101);
+        assert_eq!(cpu.memory_read(0x02000100, RWType::HalfWord), 0);
+
+        // one HBlank transfer per visible line, the VBlank one right after the last of them
+        cpu.add_cycles(160 * CYCLES_PER_LINE - cpu.cycles());
+        assert_eq!(cpu.dma.channels[0].internal_destination, 0x02000000 + 2 * 160);
+        assert_eq!(cpu.memory_read(0x02000106, RWType::HalfWord), 0x103);
+        assert_eq!(cpu.dma.channels[1].control & 0x8000, 0);
+        // no HBlank transfers during VBlank, they continue with the next frame
+        cpu.add_cycles(CYCLES_PER_FRAME + 959 - cpu.cycles());
+        assert_eq!(cpu.dma.channels[0].internal_destination, 0x02000000 + 2 * 160);
+        cpu.add_cycles(1);
+        assert_eq!(cpu.dma.channels[0].internal_destination, 0x02000000 + 2 * 161);
+    }
+
+    #[test]
+    fn video_capture_runs_on_lines_2_to_161() {
+        let mut cpu = CPU::new();
+        write_channel(&mut cpu, 3, 0x03000000, 0x02000000, 1, CAPTURE_REPEAT);
+        cpu.add_cycles(CYCLES_PER_LINE + 960);
+        assert_eq!(cpu.dma.channels[3].internal_destination, 0x02000000);
+        cpu.add_cycles(CYCLES_PER_LINE);
+        assert_eq!(cpu.dma.channels[3].internal_destination, 0x02000002);
+        cpu.add_cycles(CYCLES_PER_FRAME - cpu.cycles());
+        assert_eq!(cpu.dma.channels[3].internal_destination, 0x02000000 + 2 * 160);
+        assert_eq!(cpu.dma.channels[3].control & 0x8000, 0);
+    }
+
+    // 2 internal cycles, then non-sequential accesses for the first unit and sequential ones for the rest
+    #[test]
+    fn stall_includes_wait_states() {
+        let cases = [
+            // WAITCNT, source, destination, control, count, cycles
+            (0x0000, 0x03000000, 0x03000100, IMMEDIATE_HALFWORDS, 4, 2 + 4 * (1 + 1)),
+            (0x0000, 0x02000000, 0x03000100, IMMEDIATE_HALFWORDS, 4, 2 + 4 * (3 + 1)),
+            (0x0000, 0x08000000, 0x02000000, 0x8400, 4, 2 + (5 + 3 + 6) + 3 * (3 + 3 + 6)),
+            (0x4317, 0x08000000, 0x03000000, IMMEDIATE_HALFWORDS, 4, 2 + (4 + 1) + 3 * (2 + 1)),
+            (0x0000, 0x0A000000, 0x03000000, IMMEDIATE_HALFWORDS, 2, 2 + (5 + 1) + (5 + 1)),
+            (0x0003, 0x0E000000, 0x03000000, IMMEDIATE_HALFWORDS, 2, 2 + (9 + 1) + (9 + 1)),
+        ];
+        for (waitcnt, source, destination, control, count, cycles) in cases {
+            let mut cpu = CPU::new();
+            cpu.memory_write(0x04000204, RWType::HalfWord, waitcnt);
+            let start = cpu.cycles();
+            write_channel(&mut cpu, 3, source, destination, count, control);
+            assert_eq!(cpu.cycles() - start, cycles, "{:08x} -> {:08x}", source, destination);
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use std::fs::{self, File};
+use std::io::Read;
+use crate::archive::MAX_ROM_SIZE;
+use crate::cartridge::LoadError;
+use crate::cpu::CPU;
+
+// ELF files as devkitARM links them, before objcopy turns them into a plain ROM, see https://refspecs.linuxfoundation.org/elf/elf.pdf
+// only 32 bit little endian ARM executables, the loadable segments are put where the GBA would have them
+// and the symbol table is kept to show function+offset instead of raw addresses
+
+const ELF_MAGIC: &[u8] = b"\x7FELF";
+const CLASS_32: u8 = 1;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_ARM: u16 = 40;
+
+const HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 16;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+// symbol types in the low nibble of st_info
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+// areas segments can be loaded into
+const ROM_START: u32 = 0x08000000;
+const ROM_END: u32 = 0x0DFFFFFF;
+const EWRAM_START: u32 = 0x02000000;
+const EWRAM_END: u32 = 0x0203FFFF;
+const IWRAM_START: u32 = 0x03000000;
+const IWRAM_END: u32 = 0x03007FFF;
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    return bytes.starts_with(ELF_MAGIC);
+}
+
+// recognized by the magic bytes, files that can't be read are left to the ROM loader to report
+pub fn is_elf_file(filename: &str) -> bool {
+    let mut magic = [0u8; 4];
+    return File::open(filename).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && is_elf(&magic);
+}
+
+pub fn load(filename: &str) -> Result<Elf, LoadError> {
+    return Elf::parse(&fs::read(filename)?).map_err(|e| LoadError::InvalidElf(filename.to_string(), e));
+}
+
+pub struct Segment {
+    // load address, for initialized data that's the copy in ROM the startup code copies from
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: SymbolTable,
+}
+
+impl Elf {
+    // the error describes what's wrong with the file
+    pub fn parse(bytes: &[u8]) -> Result<Elf, String> {
+        if bytes.len() < HEADER_SIZE || !is_elf(bytes) {
+            return Err(String::from("not an ELF file"));
+        }
+        if bytes[4] != CLASS_32 || bytes[5] != DATA_LITTLE_ENDIAN {
+            return Err(String::from("only 32 bit little endian ELF files can run on the GBA"));
+        }
+        let reader = Reader { bytes };
+        if reader.half(16)? != TYPE_EXECUTABLE || reader.half(18)? != MACHINE_ARM {
+            return Err(String::from("not an ARM executable"));
+        }
+        let entry = reader.word(24)?;
+        let program_headers = reader.word(28)? as usize;
+        let section_headers = reader.word(32)? as usize;
+        let program_header_count = reader.half(44)? as usize;
+        let section_header_count = reader.half(48)? as usize;
+        // both tables have to be in the file as a whole, not just the fields we use
+        reader.bytes(program_headers, program_header_count * PROGRAM_HEADER_SIZE)?;
+        reader.bytes(section_headers, section_header_count * SECTION_HEADER_SIZE)?;
+
+        let mut segments = Vec::new();
+        for index in 0..program_header_count {
+            let header = program_headers + index * PROGRAM_HEADER_SIZE;
+            if reader.word(header)? != PT_LOAD {
+                continue;
+            }
+            let offset = reader.word(header + 4)? as usize;
+            let physical_address = reader.word(header + 12)?;
+            let file_size = reader.word(header + 16)? as usize;
+            let memory_size = reader.word(header + 20)? as usize;
+            if memory_size == 0 {
+                continue;
+            }
+            // nothing on the GBA holds more than the game pak, don't allocate for a broken header
+            if memory_size > MAX_ROM_SIZE {
+                return Err(format!("segment at {:#010x} with {} bytes is larger than the GBA memory", physical_address, memory_size));
+            }
+            // the rest up to the memory size is .bss, zeroed
+            let mut data = reader.bytes(offset, file_size)?.to_vec();
+            data.resize(memory_size, 0);
+            segments.push(Segment {
+                address: physical_address,
+                data,
+            });
+        }
+
+        let mut symbols = SymbolTable::new();
+        for index in 0..section_header_count {
+            let header = section_headers + index * SECTION_HEADER_SIZE;
+            if reader.word(header + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            // the linked section holds the names
+            let strings_header = section_headers + reader.word(header + 24)? as usize * SECTION_HEADER_SIZE;
+            let strings = reader.bytes(reader.word(strings_header + 16)? as usize, reader.word(strings_header + 20)? as usize)?;
+            let offset = reader.word(header + 16)? as usize;
+            let size = reader.word(header + 20)? as usize;
+            for symbol in reader.bytes(offset, size)?.chunks_exact(SYMBOL_SIZE) {
+                let symbol_reader = Reader { bytes: symbol };
+                let name_offset = symbol_reader.word(0)? as usize;
+                let kind = symbol[12] & 0xF;
+                let section = symbol_reader.half(14)?;
+                // undefined and absolute symbols don't point into the program
+                if section == 0 || section >= 0xFF00 || !matches!(kind, STT_NOTYPE | STT_OBJECT | STT_FUNC) {
+                    continue;
+                }
+                let name = strings.get(name_offset..)
+                    .and_then(|name| name.split(|byte| *byte == 0).next())
+                    .map(|name| String::from_utf8_lossy(name).into_owned())
+                    .unwrap_or_default();
+                // $a, $t and $d only mark ARM code, THUMB code and data
+                if name.is_empty() || name.starts_with('$') {
+                    continue;
+                }
+                symbols.add(name, symbol_reader.word(4)?, symbol_reader.word(8)?, kind == STT_FUNC);
+            }
+        }
+        symbols.sort();
+
+        Ok(Elf {
+            entry,
+            segments,
+            symbols,
+        })
+    }
+
+    // the game pak ROM, made up of all segments that go there
+    pub fn rom(&self) -> Vec<u8> {
+        return self.image(ROM_START, ROM_END);
+    }
+
+    // for multiboot builds, what the BIOS would receive into the board RAM
+    pub fn multiboot_image(&self) -> Vec<u8> {
+        return self.image(EWRAM_START, EWRAM_END);
+    }
+
+    // all segments in the given area, relative to its start
+    fn image(&self, area_start: u32, area_end: u32) -> Vec<u8> {
+        let mut image = Vec::new();
+        for segment in self.segments.iter().filter(|segment| (area_start..=area_end).contains(&segment.address)) {
+            let start = (segment.address - area_start) as usize;
+            if image.len() < start + segment.data.len() {
+                image.resize(start + segment.data.len(), 0);
+            }
+            image[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        return image;
+    }
+
+    // copies the segments for the board and chip RAM into the memory of the CPU
+    pub fn load_ram(&self, cpu: &mut CPU) -> Result<(), String> {
+        for segment in &self.segments {
+            let end = segment.address.saturating_add(segment.data.len() as u32 - 1);
+            let memory: &mut [u32] = if segment.address >= EWRAM_START && end <= EWRAM_END {
+                &mut cpu.board_ram
+            }
+            else if segment.address >= IWRAM_START && end <= IWRAM_END {
+                &mut cpu.chip_ram
+            }
+            else if segment.address >= ROM_START && end <= ROM_END {
+                // that's part of the ROM
+                continue;
+            }
+            else {
+                return Err(format!("segment at {:#010x} with {} bytes doesn't fit into ROM or RAM", segment.address, segment.data.len()));
+            };
+            let start = (segment.address & 0x00FFFFFF) as usize;
+            for (index, byte) in segment.data.iter().enumerate() {
+                let word = &mut memory[(start + index) / 4];
+                let shift = ((start + index) % 4) * 8;
+                *word = (*word & !(0xFF << shift)) | ((*byte as u32) << shift);
+            }
+        }
+        Ok(())
+    }
+}
+
+// reads little endian values, running past the end is an error
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: usize, count: usize) -> Result<&'a [u8], String> {
+        let end = offset.checked_add(count).ok_or_else(|| String::from("ELF file ends unexpectedly"))?;
+        return self.bytes.get(offset..end).ok_or_else(|| String::from("ELF file ends unexpectedly"));
+    }
+
+    fn half(&self, offset: usize) -> Result<u16, String> {
+        let bytes = self.bytes(offset, 2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn word(&self, offset: usize) -> Result<u32, String> {
+        let bytes = self.bytes(offset, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    // 0 for labels, which cover everything up to the next symbol
+    pub size: u32,
+    pub function: bool,
+}
+
+// symbols sorted by address, to find the one an address belongs to
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.symbols.is_empty();
+    }
+
+    fn add(&mut self, name: String, address: u32, size: u32, function: bool) {
+        // THUMB functions have bit 0 set
+        let address = if function {address & !1} else {address};
+        self.symbols.push(Symbol {
+            name,
+            address,
+            size,
+            function,
+        });
+    }
+
+    fn sort(&mut self) {
+        // functions win over labels at the same address
+        self.symbols.sort_by_key(|symbol| (symbol.address, symbol.function));
+    }
+
+    // the symbol containing the address and the offset into it
+    pub fn lookup(&self, address: u32) -> Option<(&Symbol, u32)> {
+        let index = self.symbols.partition_point(|symbol| symbol.address <= address);
+        let symbol = self.symbols.get(index.checked_sub(1)?)?;
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        return Some((symbol, offset));
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u32> {
+        return self.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.address);
+    }
+
+    // function+offset, just the name at offset 0
+    pub fn name(&self, address: u32) -> Option<String> {
+        let (symbol, offset) = self.lookup(address)?;
+        if offset == 0 {
+            return Some(symbol.name.clone());
+        }
+        return Some(format!("{}+{:#x}", symbol.name, offset));
+    }
+
+    // the address with the symbol it's in, for messages
+    pub fn describe(&self, address: u32) -> String {
+        match self.name(address) {
+            Some(name) => return format!("{:#010x} <{}>", address, name),
+            None => return format!("{:#010x}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Elf;
+
+    const ROM_CODE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    const EWRAM_DATA: [u8; 4] = [9, 10, 11, 12];
+    const SYMBOLS: usize = 192;
+
+    fn half(bytes: &mut Vec<u8>, value: u16) {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn word(bytes: &mut Vec<u8>, value: u32) {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_word(bytes: &mut [u8], offset: usize, value: u32) {
+        bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // type, offset, physical address, file and memory size
+    fn program_header(bytes: &mut Vec<u8>, kind: u32, offset: u32, address: u32, file_size: u32, memory_size: u32) {
+        for value in [kind, offset, address, address, file_size, memory_size, 7, 4] {
+            word(bytes, value);
+        }
+    }
+
+    // an executable as devkitARM would link it: code in ROM, data in EWRAM and .bss in IWRAM
+    fn build() -> Vec<u8> {
+        let mut bytes = b"\x7FELF\x01\x01\x01".to_vec();
+        bytes.resize(16, 0);
+        half(&mut bytes, 2);  // executable
+        half(&mut bytes, 40);  // ARM
+        word(&mut bytes, 1);
+        word(&mut bytes, 0x08000000);  // entry
+        word(&mut bytes, 52);  // program headers
+        word(&mut bytes, 0);  // section headers, filled in below
+        word(&mut bytes, 0);
+        for value in [52, 32, 4, 40, 3, 0] {
+            half(&mut bytes, value);
+        }
+
+        program_header(&mut bytes, 1, 180, 0x08000000, ROM_CODE.len() as u32, ROM_CODE.len() as u32);
+        program_header(&mut bytes, 4, 0, 0, 0, 0);  // a note, not loaded
+        program_header(&mut bytes, 1, 188, 0x02000000, EWRAM_DATA.len() as u32, EWRAM_DATA.len() as u32);
+        program_header(&mut bytes, 1, 192, 0x03000000, 0, 16);  // .bss
+        bytes.extend_from_slice(&ROM_CODE);
+        bytes.extend_from_slice(&EWRAM_DATA);
+        bytes.resize(SYMBOLS, 0);
+
+        let names = b"\0$a\0$t\0$d\0main\0label\0data\0undefined\0absolute\0";
+        let name = |text: &str| names.windows(text.len() + 2).position(|window| window[1..] == *format!("{}\0", text).as_bytes()).unwrap() as u32 + 1;
+        // name, value, size, type, section
+        let symbols: [(u32, u32, u32, u8, u16); 9] = [
+            (0, 0, 0, 0, 0),
+            (name("$a"), 0x08000000, 0, 0, 1),
+            (name("$t"), 0x08000004, 0, 0, 1),
+            (name("$d"), 0x08000010, 0, 0, 1),
+            (name("main"), 0x08000005, 4, 2, 1),  // THUMB function
+            (name("label"), 0x08000010, 0, 0, 1),
+            (name("data"), 0x02000000, 4, 1, 2),
+            (name("undefined"), 0x08000000, 0, 2, 0),
+            (name("absolute"), 0x08000000, 0, 0, 0xFFF1),
+        ];
+        for (name, value, size, kind, section) in symbols {
+            word(&mut bytes, name);
+            word(&mut bytes, value);
+            word(&mut bytes, size);
+            bytes.push(kind);
+            bytes.push(0);
+            half(&mut bytes, section);
+        }
+        let strings = bytes.len();
+        bytes.extend_from_slice(names);
+
+        let section_headers = bytes.len();
+        put_word(&mut bytes, 32, section_headers as u32);
+        bytes.resize(section_headers + 40, 0);  // the null section
+        // name, type, flags, address, offset, size, link, info, alignment, entry size
+        for section in [[0, 2, 0, 0, SYMBOLS, symbols.len() * 16, 2, 0, 4, 16], [0, 3, 0, 0, strings, names.len(), 0, 0, 1, 0]] {
+            for value in section {
+                word(&mut bytes, value as u32);
+            }
+        }
+        return bytes;
+    }
+
+    #[test]
+    fn loads_segments() {
+        let elf = Elf::parse(&build()).unwrap();
+        assert_eq!(elf.entry, 0x08000000);
+        let segments: Vec<(u32, &[u8])> = elf.segments.iter().map(|segment| (segment.address, &segment.data[..])).collect();
+        assert_eq!(segments, [(0x08000000, &ROM_CODE[..]), (0x02000000, &EWRAM_DATA[..]), (0x03000000, &[0; 16][..])]);
+        assert_eq!(elf.rom(), ROM_CODE);
+        assert_eq!(elf.multiboot_image(), EWRAM_DATA);
+    }
+
+    #[test]
+    fn symbols() {
+        let symbols = Elf::parse(&build()).unwrap().symbols;
+        // mapping symbols, undefined and absolute ones are left out
+        for name in ["$a", "$t", "$d", "undefined", "absolute"] {
+            assert_eq!(symbols.address_of(name), None);
+        }
+        // THUMB functions are found without their bit 0
+        assert_eq!(symbols.address_of("main"), Some(0x08000004));
+        assert_eq!(symbols.name(0x08000004).as_deref(), Some("main"));
+        assert_eq!(symbols.name(0x08000007).as_deref(), Some("main+0x3"));
+        // sized symbols end, labels reach up to the next symbol
+        assert_eq!(symbols.name(0x08000008), None);
+        assert_eq!(symbols.name(0x08000000), None);
+        assert_eq!(symbols.name(0x08000100).as_deref(), Some("label+0xf0"));
+        assert_eq!(symbols.describe(0x02000002), "0x02000002 <data+0x2>");
+        assert_eq!(symbols.describe(0x02000004), "0x02000004");
+    }
+
+    #[test]
+    fn broken_files_are_errors() {
+        let bytes = build();
+        for length in 0..bytes.len() {
+            assert!(Elf::parse(&bytes[..length]).is_err(), "truncated to {} bytes", length);
+        }
+        // program headers, segment data, section headers and the string table link out of range
+        for (offset, value) in [(28, 0xFFFFFFF0), (52 + 4, 0xFFFFFFFF), (52 + 16, 0xFFFFFFFF), (52 + 20, 0xFFFFFFFF), (32, 0xFFFFFFFF)] {
+            let mut broken = bytes.clone();
+            put_word(&mut broken, offset, value);
+            assert!(Elf::parse(&broken).is_err(), "word at {} set to {:#x}", offset, value);
+        }
+        let link = u32::from_le_bytes(bytes[32..36].try_into().unwrap()) as usize + 40 + 24;
+        let mut broken = bytes.clone();
+        put_word(&mut broken, link, 1000);
+        assert!(Elf::parse(&broken).is_err());
+        let mut wrong_machine = bytes.clone();
+        wrong_machine[18] = 3;
+        assert!(Elf::parse(&wrong_machine).is_err());
+    }
+}
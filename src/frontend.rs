@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+use minifb::{Key, Scale, Window, WindowOptions};
+use gilrs::{Button, Gilrs};
+use crate::keypad::ButtonSet;
+
+// windowed frontend, only compiled in with the "frontend" feature
+// the emulator core hands over a 240x160 framebuffer in 0RGB format, we upscale it in software and push it into the window
+
+pub const SCREEN_WIDTH: usize = 240;
+pub const SCREEN_HEIGHT: usize = 160;
+// 16.78 MHz / 280896 cycles per frame, see https://problemkaputt.de/gbatek.htm#lcdiotimings
+pub const FRAME_RATE: f64 = 59.7275;
+
+// host keyboard key, GBA button
+const KEYBOARD_MAP: [(Key, ButtonSet); 10] = [
+    (Key::X, ButtonSet::A),
+    (Key::Z, ButtonSet::B),
+    (Key::Backspace, ButtonSet::SELECT),
+    (Key::Enter, ButtonSet::START),
+    (Key::Right, ButtonSet::RIGHT),
+    (Key::Left, ButtonSet::LEFT),
+    (Key::Up, ButtonSet::UP),
+    (Key::Down, ButtonSet::DOWN),
+    (Key::S, ButtonSet::R),
+    (Key::A, ButtonSet::L),
+];
+
+// host gamepad button, GBA button
+// gilrs names the face buttons by position, so South/East is A/B on an Xbox pad and B/A on a Nintendo one, matching the GBA layout
+const GAMEPAD_MAP: [(Button, ButtonSet); 10] = [
+    (Button::South, ButtonSet::A),
+    (Button::East, ButtonSet::B),
+    (Button::Select, ButtonSet::SELECT),
+    (Button::Start, ButtonSet::START),
+    (Button::DPadRight, ButtonSet::RIGHT),
+    (Button::DPadLeft, ButtonSet::LEFT),
+    (Button::DPadUp, ButtonSet::UP),
+    (Button::DPadDown, ButtonSet::DOWN),
+    (Button::RightTrigger, ButtonSet::R),
+    (Button::LeftTrigger, ButtonSet::L),
+];
+
+pub struct Frontend {
+    window: Window,
+    // gamepad support is optional, if the backend fails to initialise we just carry on with the keyboard
+    gilrs: Option<Gilrs>,
+    scale: usize,
+    // upscaled copy of the framebuffer that is actually handed to the window
+    buffer: Vec<u32>,
+    frame_duration: Duration,
+    next_frame: Instant,
+}
+
+impl Frontend {
+    pub fn new(title: &str, scale: usize) -> Result<Frontend, minifb::Error> {
+        let scale = scale.max(1);
+        let mut window = Window::new(
+            title,
+            SCREEN_WIDTH * scale,
+            SCREEN_HEIGHT * scale,
+            WindowOptions {
+                scale: Scale::X1,  // we do the integer scaling ourselves, minifb only offers powers of two
+                ..WindowOptions::default()
+            },
+        )?;
+        // frame pacing is done by us, so disable the rate limit of minifb
+        window.set_target_fps(0);
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => Some(gilrs),
+            Err(e) => {
+                eprintln!("[WARNING] Gamepad support unavailable: {}", e);
+                None
+            }
+        };
+        let frame_duration = Duration::from_secs_f64(1.0 / FRAME_RATE);
+        Ok(Frontend {
+            window,
+            gilrs,
+            scale,
+            buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * scale * scale],
+            frame_duration,
+            next_frame: Instant::now() + frame_duration,
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+
+    // returns the currently pressed GBA buttons, combined from keyboard and all connected gamepads
+    pub fn poll_input(&mut self) -> ButtonSet {
+        let mut pressed = ButtonSet::NONE;
+        for (key, button) in KEYBOARD_MAP {
+            if self.window.is_key_down(key) {
+                pressed |= button;
+            }
+        }
+        if let Some(gilrs) = &mut self.gilrs {
+            // the events themselves don't interest us, but they need to be drained for gilrs to update its gamepad state
+            while gilrs.next_event().is_some() {}
+            for (_id, gamepad) in gilrs.gamepads() {
+                for (gamepad_button, button) in GAMEPAD_MAP {
+                    if gamepad.is_pressed(gamepad_button) {
+                        pressed |= button;
+                    }
+                }
+            }
+        }
+        return pressed;
+    }
+
+    // upscales the framebuffer, shows it and then waits until it's time for the next frame
+    pub fn present(&mut self, framebuffer: &[u32]) -> Result<(), minifb::Error> {
+        let scaled_width = SCREEN_WIDTH * self.scale;
+        for y in 0..SCREEN_HEIGHT {
+            let src_line = &framebuffer[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+            // build the first of the scaled lines by repeating each pixel, then copy that line for the remaining ones
+            let first_line = y * self.scale * scaled_width;
+            for (x, pixel) in src_line.iter().enumerate() {
+                let start = first_line + x * self.scale;
+                self.buffer[start..start + self.scale].fill(*pixel);
+            }
+            for line in 1..self.scale {
+                self.buffer.copy_within(first_line..first_line + scaled_width, first_line + line * scaled_width);
+            }
+        }
+        self.window.update_with_buffer(&self.buffer, scaled_width, SCREEN_HEIGHT * self.scale)?;
+        self.wait_for_next_frame();
+        Ok(())
+    }
+
+    fn wait_for_next_frame(&mut self) {
+        let now = Instant::now();
+        if now < self.next_frame {
+            std::thread::sleep(self.next_frame - now);
+            self.next_frame += self.frame_duration;
+        }
+        else if now - self.next_frame > self.frame_duration {
+            // we're more than a frame behind (e.g. the window was dragged), don't try to catch up
+            self.next_frame = now + self.frame_duration;
+        }
+        else {
+            self.next_frame += self.frame_duration;
+        }
+    }
+}
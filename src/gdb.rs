@@ -0,0 +1,540 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::RangeInclusive;
+use std::panic::{self, AssertUnwindSafe};
+use crate::cpu::{RWType, CPU};
+use crate::debugger;
+use crate::hooks::{Access, AccessFlags, HookId};
+
+// stub for the GDB remote serial protocol, see https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+// started with --gdb PORT, then in arm-none-eabi-gdb: target remote localhost:PORT
+// the registers are laid out the way gdb expects them for ARM without a target description:
+// r0-r15, the FPA registers f0-f7 with 12 bytes each and their status register, which the GBA doesn't have, then the CPSR
+
+const FPA_REGISTERS: usize = 8;
+const FPA_REGISTER_SIZE: usize = 12;
+// register numbers for p and P
+const REGISTER_FPS: u32 = 24;
+const REGISTER_CPSR: u32 = 25;
+// offset of the CPSR in the g packet, in bytes
+const CPSR_OFFSET: usize = 16 * 4 + FPA_REGISTERS * FPA_REGISTER_SIZE + 4;
+
+// signals of the stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// instructions between looking for an interrupt from gdb while running
+const INTERRUPT_INTERVAL: u64 = 4096;
+
+// largest packet gdb may send or expect back, memory reads are hex so they can cover half of it
+const PACKET_SIZE: u32 = 0x4000;
+
+// waits for gdb to connect and serves it until it detaches
+pub fn listen(cpu: &mut CPU, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for gdb on port {}", port);
+    let (stream, address) = listener.accept()?;
+    println!("gdb connected from {}", address);
+    return GdbStub::new(stream)?.serve(cpu);
+}
+
+// inserted by gdb, as a breakpoint hook on the memory bus
+struct Watchpoint {
+    range: RangeInclusive<u32>,
+    flags: AccessFlags,
+    hook: HookId,
+}
+
+pub struct GdbStub {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    breakpoints: Vec<u32>,
+    watchpoints: Vec<Watchpoint>,
+    // reply to ?, the reason for the last stop
+    last_stop: String,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> io::Result<GdbStub> {
+        // packets are small and answered one by one
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            last_stop: stop_reply(SIGTRAP),
+        })
+    }
+
+    // handles packets until gdb kills, detaches or disconnects
+    pub fn serve(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            match self.handle(cpu, &packet)? {
+                Some(reply) => self.send(&reply)?,
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    // the reply to the packet, None once the session is over
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> io::Result<Option<String>> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => read_registers(cpu),
+            "G" => write_registers(cpu, args).unwrap_or_else(error),
+            "p" => parse_hex(args).and_then(|register| read_register(cpu, register)).unwrap_or_else(error),
+            "P" => {
+                let result = args.split_once('=').and_then(|(register, value)| write_register(cpu, parse_hex(register)?, value));
+                result.unwrap_or_else(error)
+            },
+            "m" => parse_range(args).and_then(|(address, length)| read_memory(cpu, address, length)).unwrap_or_else(error),
+            "M" => {
+                let result = args.split_once(':').and_then(|(range, data)| write_memory(cpu, parse_range(range)?.0, data));
+                result.unwrap_or_else(error)
+            },
+            "c" | "s" => {
+                // an address to resume at is optional
+                if let Some(address) = parse_hex(args) {
+                    cpu.registers[15] = address;
+                }
+                let stop = self.resume(cpu, command == "s")?;
+                self.last_stop = stop.clone();
+                stop
+            },
+            "Z" | "z" => self.set_point(cpu, command == "Z", args).unwrap_or_else(error),
+            "q" => {
+                if args.starts_with("Supported") {
+                    format!("PacketSize={:x}", PACKET_SIZE)
+                }
+                else if args == "Attached" {
+                    // there is nothing to kill when gdb quits
+                    String::from("1")
+                }
+                else {
+                    String::new()
+                }
+            },
+            "H" => String::from("OK"),
+            "D" => {
+                self.send("OK")?;
+                return Ok(None);
+            },
+            "k" => return Ok(None),
+            // anything else isn't supported, which is an empty reply
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    // runs until a breakpoint, a watchpoint or an interrupt from gdb, or a single instruction
+    fn resume(&mut self, cpu: &mut CPU, step: bool) -> io::Result<String> {
+        let mut executed: u64 = 0;
+        cpu.hooks.take_hit();
+        loop {
+            // a panic of the emulator is shown to gdb like a crash of the program, the state stays there to look at
+            if panic::catch_unwind(AssertUnwindSafe(|| cpu.cycle())).is_err() {
+                return Ok(stop_reply(SIGSEGV));
+            }
+            executed += 1;
+            if let Some(hit) = cpu.hooks.take_hit() {
+                if let Some(watchpoint) = self.watchpoints.iter().find(|watchpoint| watchpoint.hook == hit.id) {
+                    return Ok(watch_reply(watchpoint.flags, hit.access));
+                }
+            }
+            if step || self.breakpoints.contains(&cpu.registers[15]) {
+                return Ok(stop_reply(SIGTRAP));
+            }
+            if executed.is_multiple_of(INTERRUPT_INTERVAL) && self.interrupted()? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+
+    // Z0/z0 and Z1/z1 for breakpoints, Z2 for write, Z3 for read and Z4 for access watchpoints
+    fn set_point(&mut self, cpu: &mut CPU, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let address = parse_hex(fields.next()?)?;
+        // the size of the instruction for breakpoints, the number of bytes for watchpoints
+        let length = parse_hex(fields.next()?.split(';').next()?)?.max(1);
+        let flags = match kind {
+            "0" | "1" => {
+                self.breakpoints.retain(|breakpoint| *breakpoint != address);
+                if insert {
+                    self.breakpoints.push(address);
+                }
+                return Some(String::from("OK"));
+            },
+            "2" => AccessFlags::WRITE,
+            "3" => AccessFlags::READ,
+            "4" => AccessFlags::READ_WRITE,
+            _ => return Some(String::new()),
+        };
+        let range = address..=address.saturating_add(length - 1);
+        let position = self.watchpoints.iter().position(|watchpoint| watchpoint.range == range && watchpoint.flags == flags);
+        match (insert, position) {
+            (true, None) => {
+                let hook = cpu.hooks.add_breakpoint(range.clone(), flags);
+                self.watchpoints.push(Watchpoint {
+                    range,
+                    flags,
+                    hook,
+                });
+            },
+            (false, Some(position)) => {
+                cpu.hooks.remove(self.watchpoints.remove(position).hook);
+            },
+            _ => {},
+        }
+        Some(String::from("OK"))
+    }
+
+    // gdb sends a single 0x03 byte to stop a running program, it's looked for without blocking
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if self.reader.buffer().is_empty() {
+            self.reader.get_ref().set_nonblocking(true)?;
+            let filled = self.reader.fill_buf().map(|buffer| buffer.len());
+            self.reader.get_ref().set_nonblocking(false)?;
+            match filled {
+                // gdb went away, there is nobody left to run for
+                Ok(0) => return Ok(true),
+                Ok(_) => {},
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        // acknowledgements can come in between, packets wait for the stop
+        while let Some(&byte) = self.reader.buffer().first() {
+            if byte == b'$' {
+                break;
+            }
+            self.reader.consume(1);
+            if byte == 0x03 {
+                return Ok(true);
+            }
+        }
+        return Ok(false);
+    }
+
+    // $data#checksum, acknowledged with + or - for a wrong checksum, None when gdb disconnected
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // acknowledgements and interrupts outside of a run are skipped
+            match self.read_byte()? {
+                Some(b'$') => {},
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut sent_checksum = [0u8; 2];
+            if self.reader.read_exact(&mut sent_checksum).is_err() {
+                return Ok(None);
+            }
+            let valid = std::str::from_utf8(&sent_checksum).ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok())
+                .is_some_and(|sent| sent == checksum(&data));
+            if valid {
+                self.writer.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    // sent again until gdb acknowledges it
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.writer.write_all(packet.as_bytes())?;
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') | None => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => {},
+                }
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8; 1];
+        match self.reader.read(&mut byte)? {
+            0 => return Ok(None),
+            _ => return Ok(Some(byte[0])),
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    return data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+}
+
+fn stop_reply(signal: u8) -> String {
+    return format!("S{:02x}", signal);
+}
+
+// T05 with the kind of watchpoint and the address that was accessed
+fn watch_reply(flags: AccessFlags, access: Access) -> String {
+    let kind = match flags {
+        AccessFlags::READ_WRITE => "awatch",
+        AccessFlags::READ => "rwatch",
+        _ => "watch",
+    };
+    return format!("T{:02x}{}:{:08x};", SIGTRAP, kind, access.address);
+}
+
+// errors carry an errno, it's always EFAULT here as gdb only shows that something failed
+fn error() -> String {
+    return String::from("E0e");
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    return u32::from_str_radix(text, 16).ok();
+}
+
+// address,length
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    return Some((parse_hex(address)?, parse_hex(length)?));
+}
+
+// values go over the wire as little endian bytes in hex
+fn hex_word(value: u32) -> String {
+    return value.to_le_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..text.len()).step_by(2).map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok()).collect();
+}
+
+fn parse_word(text: &str) -> Option<u32> {
+    let bytes = parse_bytes(text)?;
+    return Some(u32::from_le_bytes(bytes.try_into().ok()?));
+}
+
+fn read_registers(cpu: &CPU) -> String {
+    let mut reply: String = (0..16).map(|register| hex_word(cpu.register_read_custom(register, cpu.get_mode()))).collect();
+    // f0-f7 and fps
+    reply.push_str(&"0".repeat((FPA_REGISTERS * FPA_REGISTER_SIZE + 4) * 2));
+    reply.push_str(&hex_word(cpu.register_read(16)));
+    return reply;
+}
+
+fn write_registers(cpu: &mut CPU, data: &str) -> Option<String> {
+    let bytes = parse_bytes(data)?;
+    if bytes.len() < CPSR_OFFSET + 4 {
+        return None;
+    }
+    let word = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+    // the CPSR first, so the registers end up in the banks of the new mode
+    cpu.register_write(16, word(CPSR_OFFSET));
+    for register in 0..16 {
+        cpu.register_write(register, word(register as usize * 4));
+    }
+    Some(String::from("OK"))
+}
+
+fn read_register(cpu: &CPU, register: u32) -> Option<String> {
+    match register {
+        0..=15 => return Some(hex_word(cpu.register_read_custom(register, cpu.get_mode()))),
+        16..=23 => return Some("0".repeat(FPA_REGISTER_SIZE * 2)),
+        REGISTER_FPS => return Some(hex_word(0)),
+        REGISTER_CPSR => return Some(hex_word(cpu.register_read(16))),
+        _ => return None,
+    }
+}
+
+fn write_register(cpu: &mut CPU, register: u32, value: &str) -> Option<String> {
+    match register {
+        0..=15 => cpu.register_write(register, parse_word(value)?),
+        REGISTER_CPSR => cpu.register_write(16, parse_word(value)?),
+        // writes to the FPA registers that don't exist are ignored
+        16..=REGISTER_FPS => {},
+        _ => return None,
+    }
+    return Some(String::from("OK"));
+}
+
+fn read_memory(cpu: &mut CPU, address: u32, length: u32) -> Option<String> {
+    if length > PACKET_SIZE / 2 {
+        return None;
+    }
+    let mut reply = String::with_capacity(length as usize * 2);
+    for offset in 0..length {
+        let byte_address = address.wrapping_add(offset);
+        if !debugger::is_mapped(byte_address) {
+            return None;
+        }
+        reply.push_str(&format!("{:02x}", cpu.debug_read(byte_address, RWType::Byte)));
+    }
+    Some(reply)
+}
+
+fn write_memory(cpu: &mut CPU, address: u32, data: &str) -> Option<String> {
+    let bytes = parse_bytes(data)?;
+    for (offset, byte) in bytes.into_iter().enumerate() {
+        let byte_address = address.wrapping_add(offset as u32);
+        if !debugger::is_mapped(byte_address) {
+            return None;
+        }
+        // the ROM can't be written by the program, but gdb can patch it
+        let rom_offset = (byte_address & 0x01FFFFFF) as usize;
+        if (0x08000000..=0x0DFFFFFF).contains(&byte_address) && rom_offset < cpu.game_pak_rom.len() {
+            cpu.game_pak_rom[rom_offset] = byte;
+        }
+        else {
+            cpu.debug_write(byte_address, RWType::Byte, byte as u32);
+        }
+    }
+    Some(String::from("OK"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use crate::cpu::CPU;
+    use crate::instructions::asm;
+    use super::{checksum, GdbStub};
+
+    // plays gdb, sends packets and reads the replies
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn command(&mut self, data: &str) -> String {
+            write!(self.writer, "${}#{:02x}", data, checksum(data.as_bytes())).unwrap();
+            let mut ack = [0u8; 1];
+            self.reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+', "packet {} wasn't acknowledged", data);
+            let mut reply = Vec::new();
+            self.reader.read_until(b'$', &mut reply).unwrap();
+            reply.clear();
+            self.reader.read_until(b'#', &mut reply).unwrap();
+            reply.pop();
+            let mut sent_checksum = [0u8; 2];
+            self.reader.read_exact(&mut sent_checksum).unwrap();
+            assert_eq!(u8::from_str_radix(std::str::from_utf8(&sent_checksum).unwrap(), 16).unwrap(), checksum(&reply));
+            self.writer.write_all(b"+").unwrap();
+            return String::from_utf8(reply).unwrap();
+        }
+    }
+
+    // runs the stub on the program and the script in a client next to it
+    fn session(program: &[&str], script: impl FnOnce(&mut Client) + Send + 'static) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.game_pak_rom = program.iter().flat_map(|text| asm::arm(text).to_le_bytes()).collect();
+        cpu.registers[15] = 0x08000000;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut client = Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            };
+            script(&mut client);
+            client.writer.write_all(b"$k#6b").unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(stream).unwrap().serve(&mut cpu).unwrap();
+        client.join().unwrap();
+        return cpu;
+    }
+
+    const PROGRAM: [&str; 4] = ["add r1, r0, r0, lsl #1", "add r1, r1, r0, lsl #1", "str r1, [r2]", "add r1, r1, r0, lsl #1"];
+
+    #[test]
+    fn registers() {
+        let cpu = session(&PROGRAM, |client| {
+            assert_eq!(client.command("?"), "S05");
+            let registers = client.command("g");
+            assert_eq!(registers.len(), (16 * 4 + 8 * 12 + 4 + 4) * 2);
+            assert_eq!(&registers[15 * 8..16 * 8], "00000008");
+            // user mode, ARM state
+            assert_eq!(&registers[registers.len() - 8..], "10000000");
+            assert_eq!(client.command("P0=05000000"), "OK");
+            assert_eq!(client.command("p0"), "05000000");
+            // THUMB state through the T bit
+            assert_eq!(client.command("P19=30000000"), "OK");
+            assert_eq!(client.command("p19"), "30000000");
+            let mut registers = client.command("g");
+            registers.replace_range(8..16, "07000000");
+            assert_eq!(client.command(&format!("G{}", registers)), "OK");
+            assert_eq!(client.command("p1"), "07000000");
+            assert_eq!(client.command("p1a"), "E0e");
+        });
+        assert_eq!(cpu.registers[0], 5);
+        assert_eq!(cpu.registers[1], 7);
+        assert!(cpu.get_state());
+    }
+
+    #[test]
+    fn memory() {
+        session(&PROGRAM, |client| {
+            assert_eq!(client.command("M3000000,4:78563412"), "OK");
+            assert_eq!(client.command("m3000000,4"), "78563412");
+            assert_eq!(client.command("m8000000,4"), "801080e0");
+            // patching the ROM
+            assert_eq!(client.command("M8000000,1:ff"), "OK");
+            assert_eq!(client.command("m8000000,2"), "ff10");
+            assert_eq!(client.command("m10000000,4"), "E0e");
+            // the reply has to fit into a packet
+            assert_eq!(client.command("m3000000,2000").len(), 0x4000);
+            assert_eq!(client.command("m3000000,2001"), "E0e");
+            assert_eq!(client.command("m3000000,ffffffff"), "E0e");
+        });
+    }
+
+    #[test]
+    fn stepping_and_breakpoints() {
+        session(&PROGRAM, |client| {
+            client.command("P0=01000000");
+            client.command("P2=00000003");
+            assert_eq!(client.command("s"), "S05");
+            assert_eq!(client.command("pf"), "04000008");
+            assert_eq!(client.command("p1"), "03000000");
+            assert_eq!(client.command("Z0,800000c,4"), "OK");
+            assert_eq!(client.command("c"), "S05");
+            assert_eq!(client.command("pf"), "0c000008");
+            assert_eq!(client.command("z0,800000c,4"), "OK");
+            // resuming at an address
+            assert_eq!(client.command("s8000004"), "S05");
+            assert_eq!(client.command("pf"), "08000008");
+        });
+    }
+
+    #[test]
+    fn watchpoints() {
+        session(&PROGRAM, |client| {
+            client.command("P2=00000003");
+            assert_eq!(client.command("Z2,3000000,4"), "OK");
+            assert_eq!(client.command("c"), "T05watch:03000000;");
+            assert_eq!(client.command("pf"), "0c000008");
+            assert_eq!(client.command("z2,3000000,4"), "OK");
+            assert_eq!(client.command("Z3,3000000,4"), "OK");
+            assert_eq!(client.command("Z0,8000010,4"), "OK");
+            // nothing reads it
+            assert_eq!(client.command("c"), "S05");
+            assert_eq!(client.command("pf"), "10000008");
+        });
+    }
+}
@@ -0,0 +1,103 @@
+use gyro::Gyro;
+use rtc::RTC;
+use solar::SolarSensor;
+
+pub mod gyro;
+pub mod rtc;
+pub mod solar;
+
+// general purpose I/O port of the game pak, see https://problemkaputt.de/gbatek.htm#gbacartiogeneralpurposegpio
+// four pins mapped into the ROM area, with the extra hardware of a cart hanging off them
+
+// offsets of the registers into the ROM, at 0x080000C4 to 0x080000C9 and the mirrors
+const DATA: u32 = 0xC4;
+const DIRECTION: u32 = 0xC6;
+const CONTROL: u32 = 0xC8;
+
+const PIN_MASK: u16 = 0b1111;
+
+pub struct Gpio {
+    // state of the pins, as driven by the GBA for outputs and by the hardware for inputs
+    data: u16,
+    // bit set: pin is an output of the GBA
+    direction: u16,
+    // bit 0 set: the registers can be read, otherwise reads see the ROM
+    control: u16,
+    pub rtc: Option<RTC>,
+    pub solar: Option<SolarSensor>,
+    pub gyro: Option<Gyro>,
+}
+
+impl Gpio {
+    pub fn new() -> Gpio {
+        Gpio {
+            data: 0,
+            direction: 0,
+            control: 0,
+            rtc: None,
+            solar: None,
+            gyro: None,
+        }
+    }
+
+    // whether the cart has anything connected, without that the port doesn't exist
+    pub fn present(&self) -> bool {
+        return self.rtc.is_some() || self.solar.is_some() || self.gyro.is_some();
+    }
+
+    fn readable(&self) -> bool {
+        return self.present() && self.control & 1 != 0;
+    }
+
+    // replaces the parts of a ROM word at the given word aligned offset that the registers cover
+    pub fn read_word(&self, offset: u32, rom_value: u32) -> u32 {
+        if !self.readable() {
+            return rom_value;
+        }
+        match offset {
+            DATA => return ((self.direction as u32) << 16) | self.read_data() as u32,
+            CONTROL => return (rom_value & 0xFFFF0000) | self.control as u32,
+            _ => return rom_value,
+        }
+    }
+
+    fn read_data(&self) -> u16 {
+        let mut inputs = 0;
+        if let Some(rtc) = &self.rtc {
+            inputs |= rtc.read_pins() as u16;
+        }
+        if let Some(solar) = &self.solar {
+            inputs |= solar.read_pins() as u16;
+        }
+        if let Some(gyro) = &self.gyro {
+            inputs |= gyro.read_pins() as u16;
+        }
+        return ((self.data & self.direction) | (inputs & !self.direction)) & PIN_MASK;
+    }
+
+    // halfword writes into the ROM area, now is the current cycle count
+    pub fn write(&mut self, offset: u32, value: u16, now: u128) {
+        if !self.present() {
+            return;
+        }
+        match offset & !1 {
+            DATA => {
+                // only the outputs can be changed by the GBA
+                self.data = (self.data & !self.direction) | (value & self.direction & PIN_MASK);
+                let pins = self.data as u8;
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_pins(pins, now);
+                }
+                if let Some(solar) = &mut self.solar {
+                    solar.write_pins(pins);
+                }
+                if let Some(gyro) = &mut self.gyro {
+                    gyro.write_pins(pins);
+                }
+            },
+            DIRECTION => self.direction = value & PIN_MASK,
+            CONTROL => self.control = value & 1,
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// gyro sensor and rumble motor of WarioWare Twisted, see https://problemkaputt.de/gbatek.htm#gbacartgyrosensor
+// a high pin 0 starts a conversion, the 16 bit result is then clocked out MSB first on the falling edges of pin 1
+// and read from pin 2, pin 3 drives the rumble motor
+
+pub const START: u8 = 1 << 0;
+pub const CLOCK: u8 = 1 << 1;
+pub const DATA: u8 = 1 << 2;
+pub const RUMBLE: u8 = 1 << 3;
+
+// the 12 bit sample the sensor gives when the console isn't rotating
+pub const GYRO_CENTER: i32 = 0x6C0;
+
+pub struct Gyro {
+    // rotation speed around the axis facing the player, positive is clockwise
+    rate: i16,
+    sample: u16,
+    output: u8,
+    rumble: bool,
+    last_pins: u8,
+}
+
+impl Gyro {
+    pub fn new() -> Gyro {
+        Gyro {
+            rate: 0,
+            sample: 0,
+            output: 0,
+            rumble: false,
+            last_pins: 0,
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: i16) {
+        self.rate = rate;
+    }
+
+    // whether the game currently has the motor running
+    pub fn rumble(&self) -> bool {
+        return self.rumble;
+    }
+
+    pub fn read_pins(&self) -> u8 {
+        return self.output << 2;
+    }
+
+    pub fn write_pins(&mut self, pins: u8) {
+        let falling_edge = self.last_pins & CLOCK != 0 && pins & CLOCK == 0;
+        self.last_pins = pins;
+        self.rumble = pins & RUMBLE != 0;
+        if pins & START != 0 {
+            self.sample = (GYRO_CENTER + self.rate as i32).clamp(0, 0xFFF) as u16;
+        }
+        if falling_edge {
+            self.output = (self.sample >> 15) as u8;
+            self.sample <<= 1;
+        }
+    }
+}
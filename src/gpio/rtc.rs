@@ -0,0 +1,240 @@
+use chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, Timelike};
+
+// Seiko S-3511 real time clock, see https://problemkaputt.de/gbatek.htm#gbacartrealtimeclockrtc
+// talks over three GPIO pins: bit 0 SCK (clock), bit 1 SIO (data), bit 2 CS (select)
+// a transfer starts with CS going high, then a command byte follows and after it the parameter bytes
+// bits are taken over on the rising edge of SCK, everything is sent LSB first
+
+pub const SCK: u8 = 1 << 0;
+pub const SIO: u8 = 1 << 1;
+pub const CS: u8 = 1 << 2;
+
+// the command byte is 0b0110 in the low nibble, the command in bits 4-6 and bit 7 set for reads
+const COMMAND_FIXED: u8 = 0b0110;
+const COMMAND_READ: u8 = 1 << 7;
+// commands
+const RESET: u8 = 0;
+const DATE_TIME: u8 = 2;
+const FORCE_IRQ: u8 = 3;
+const CONTROL: u8 = 4;
+const TIME: u8 = 6;
+// number of parameter bytes of each command
+const PARAMETER_BYTES: [usize; 8] = [0, 0, 7, 0, 1, 0, 3, 0];
+
+// control register bits
+const CONTROL_24_HOURS: u8 = 1 << 6;
+const CONTROL_POWER_FAIL: u8 = 1 << 7;  // read only
+const CONTROL_WRITABLE: u8 = 0b0110_1010;
+
+// the clock runs at 16.78 MHz, used to advance a fixed clock along with the emulation
+const CPU_FREQUENCY: u128 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ClockSource {
+    // the local time of the host
+    Host,
+    // starts at the given time and advances with the emulated time, so runs can be reproduced
+    Fixed(NaiveDateTime),
+}
+
+pub struct RTC {
+    clock: ClockSource,
+    // seconds the game moved the clock away from its source by setting the time
+    offset: i64,
+    control: u8,
+    last_pins: u8,
+    // the command being executed, None while waiting for the command byte
+    command: Option<u8>,
+    // byte currently being transferred and the number of bits of it done
+    shift: u8,
+    bits: usize,
+    // parameter bytes of the current command, latched when the command arrives
+    parameters: [u8; 7],
+    parameter_index: usize,
+    // level the RTC drives onto SIO while the game reads
+    output: u8,
+}
+
+impl RTC {
+    pub fn new(clock: ClockSource) -> RTC {
+        RTC {
+            clock,
+            offset: 0,
+            control: CONTROL_24_HOURS,
+            last_pins: 0,
+            command: None,
+            shift: 0,
+            bits: 0,
+            parameters: [0; 7],
+            parameter_index: 0,
+            output: 0,
+        }
+    }
+
+    // pins driven by the RTC, only relevant while the game has SIO set to input
+    pub fn read_pins(&self) -> u8 {
+        return self.output << 1;
+    }
+
+    // now is the current cycle count, needed for fixed clocks
+    pub fn write_pins(&mut self, pins: u8, now: u128) {
+        let rising_edge = self.last_pins & SCK == 0 && pins & SCK != 0;
+        self.last_pins = pins;
+        if pins & CS == 0 {
+            // deselecting ends whatever was going on
+            self.command = None;
+            self.shift = 0;
+            self.bits = 0;
+            return;
+        }
+        if !rising_edge {
+            return;
+        }
+
+        if let Some(command) = self.command.filter(|command| command & COMMAND_READ != 0) {
+            // reading, the next bit goes onto SIO
+            if self.parameter_index < PARAMETER_BYTES[Self::index(command)] {
+                self.output = (self.parameters[self.parameter_index] >> self.bits) & 1;
+            }
+            self.next_bit();
+            return;
+        }
+
+        self.shift |= ((pins & SIO) >> 1) << self.bits;
+        if self.bits == 7 {
+            let byte = self.shift;
+            self.next_bit();
+            self.receive_byte(byte, now);
+        }
+        else {
+            self.next_bit();
+        }
+    }
+
+    fn next_bit(&mut self) {
+        self.bits += 1;
+        if self.bits == 8 {
+            self.bits = 0;
+            self.shift = 0;
+            if self.command.is_some() {
+                self.parameter_index += 1;
+            }
+        }
+    }
+
+    fn index(command: u8) -> usize {
+        return ((command >> 4) & 0b111) as usize;
+    }
+
+    fn receive_byte(&mut self, byte: u8, now: u128) {
+        let command = match self.command {
+            Some(command) => command,
+            None => {
+                if byte & 0xF != COMMAND_FIXED {
+                    // not a command, ignore it
+                    return;
+                }
+                self.start_command(byte, now);
+                return;
+            },
+        };
+        // the parameter index has already moved on to the next byte
+        let index = self.parameter_index - 1;
+        if index >= PARAMETER_BYTES[Self::index(command)] {
+            return;
+        }
+        self.parameters[index] = byte;
+        match Self::index(command) as u8 {
+            CONTROL => self.control = (self.control & !CONTROL_WRITABLE) | (byte & CONTROL_WRITABLE),
+            DATE_TIME if index == 6 => self.set_date_time(now),
+            TIME if index == 2 => self.set_time(now),
+            _ => {},
+        }
+    }
+
+    fn start_command(&mut self, command: u8, now: u128) {
+        self.command = Some(command);
+        self.parameter_index = 0;
+        match Self::index(command) as u8 {
+            RESET => {
+                self.control = 0;
+                self.offset = 0;
+                self.command = None;
+            },
+            DATE_TIME | TIME => {
+                let time = self.time(now);
+                self.parameters = self.encode(time);
+                if Self::index(command) as u8 == TIME {
+                    // the time only command starts with the hour
+                    self.parameters.copy_within(4.., 0);
+                }
+            },
+            CONTROL => self.parameters[0] = self.control,
+            FORCE_IRQ => {
+                // would pull the game pak IRQ line, nothing uses that
+                self.command = None;
+            },
+            _ => self.command = None,
+        }
+    }
+
+    pub fn time(&self, now: u128) -> NaiveDateTime {
+        let source = match self.clock {
+            ClockSource::Host => Local::now().naive_local(),
+            ClockSource::Fixed(start) => start + Duration::seconds((now / CPU_FREQUENCY) as i64),
+        };
+        return source + Duration::seconds(self.offset);
+    }
+
+    // year, month, day, day of week, hour, minute, second, all in BCD
+    fn encode(&self, time: NaiveDateTime) -> [u8; 7] {
+        let hour = time.hour() as u8;
+        let mut encoded_hour = if self.control & CONTROL_24_HOURS != 0 {bcd(hour)} else {bcd(hour % 12)};
+        if hour >= 12 {
+            encoded_hour |= 0x80;  // PM flag
+        }
+        return [
+            bcd((time.year() % 100) as u8),
+            bcd(time.month() as u8),
+            bcd(time.day() as u8),
+            time.weekday().num_days_from_sunday() as u8,
+            encoded_hour,
+            bcd(time.minute() as u8),
+            bcd(time.second() as u8),
+        ];
+    }
+
+    fn set_date_time(&mut self, now: u128) {
+        let [year, month, day, _, hour, minute, second] = self.parameters;
+        let date = NaiveDate::from_ymd_opt(2000 + from_bcd(year) as i32, from_bcd(month) as u32, from_bcd(day) as u32);
+        if let Some(date) = date {
+            self.set_to(date, hour, minute, second, now);
+        }
+    }
+
+    fn set_time(&mut self, now: u128) {
+        let [hour, minute, second, ..] = self.parameters;
+        let date = self.time(now).date();
+        self.set_to(date, hour, minute, second, now);
+    }
+
+    fn set_to(&mut self, date: NaiveDate, hour: u8, minute: u8, second: u8, now: u128) {
+        let pm = hour & 0x80 != 0;
+        let mut hour = from_bcd(hour & 0x3F) as u32;
+        if self.control & CONTROL_24_HOURS == 0 && pm {
+            hour += 12;
+        }
+        if let Some(time) = date.and_hms_opt(hour, from_bcd(minute) as u32, from_bcd(second) as u32) {
+            self.offset += (time - self.time(now)).num_seconds();
+        }
+        self.control &= !CONTROL_POWER_FAIL;
+    }
+}
+
+fn bcd(value: u8) -> u8 {
+    return ((value / 10) << 4) | (value % 10);
+}
+
+fn from_bcd(value: u8) -> u8 {
+    return (value >> 4) * 10 + (value & 0xF);
+}
@@ -0,0 +1,56 @@
+// solar sensor of the Boktai games, see https://problemkaputt.de/gbatek.htm#gbacartsolarsensor
+// pin 0 clocks a counter, pin 1 resets it, pin 2 selects the chip (active low)
+// pin 3 is the output of a comparator that flips once the counter passes the measured light level
+// the game counts up until the flag flips, so the brighter the light, the lower the count
+
+pub const CLOCK: u8 = 1 << 0;
+pub const RESET: u8 = 1 << 1;
+pub const CS: u8 = 1 << 2;
+pub const FLAG: u8 = 1 << 3;
+
+// counter values at which the flag flips, for complete darkness and for bright sunlight
+const THRESHOLD_DARK: u32 = 0xE8;
+const THRESHOLD_BRIGHT: u32 = 0x50;
+
+pub struct SolarSensor {
+    // 0 is complete darkness, 255 bright sunlight
+    light: u8,
+    counter: u32,
+    last_pins: u8,
+}
+
+impl SolarSensor {
+    pub fn new() -> SolarSensor {
+        SolarSensor {
+            light: 0,
+            counter: 0,
+            last_pins: 0,
+        }
+    }
+
+    pub fn set_light(&mut self, light: u8) {
+        self.light = light;
+    }
+
+    fn threshold(&self) -> u32 {
+        return THRESHOLD_DARK - (THRESHOLD_DARK - THRESHOLD_BRIGHT) * self.light as u32 / 255;
+    }
+
+    pub fn read_pins(&self) -> u8 {
+        return if self.counter >= self.threshold() {FLAG} else {0};
+    }
+
+    pub fn write_pins(&mut self, pins: u8) {
+        let rising_edge = self.last_pins & CLOCK == 0 && pins & CLOCK != 0;
+        self.last_pins = pins;
+        if pins & CS != 0 {
+            return;
+        }
+        if pins & RESET != 0 {
+            self.counter = 0;
+        }
+        else if rising_edge {
+            self.counter = (self.counter + 1).min(0xFF);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::ops::RangeInclusive;
+
+// hooks on the memory bus, called for reads, writes and instruction fetches in the address ranges they cover
+// debuggers use them for watchpoints, and anything else that wants to know what a game does with its memory can too
+// memory accesses only look at them when there are any, so without hooks they cost nothing
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    pub address: u32,
+    pub kind: AccessKind,
+    // in bytes, 1, 2 or 4
+    pub size: u32,
+    // the value read, written or fetched
+    pub value: u32,
+}
+
+// the kinds of accesses a hook is called for
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AccessFlags {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl AccessFlags {
+    pub const READ: AccessFlags = AccessFlags {read: true, write: false, execute: false};
+    pub const WRITE: AccessFlags = AccessFlags {read: false, write: true, execute: false};
+    pub const READ_WRITE: AccessFlags = AccessFlags {read: true, write: true, execute: false};
+    pub const EXECUTE: AccessFlags = AccessFlags {read: false, write: false, execute: true};
+
+    pub fn contains(&self, kind: AccessKind) -> bool {
+        match kind {
+            AccessKind::Read => return self.read,
+            AccessKind::Write => return self.write,
+            AccessKind::Execute => return self.execute,
+        }
+    }
+}
+
+// what a callback wants to happen after it was called
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HookAction {
+    Continue,
+    // noted as the hit, whoever runs the CPU stops once the instruction is done
+    Stop,
+}
+
+pub type HookId = usize;
+pub type HookCallback = Box<dyn FnMut(&Access) -> HookAction>;
+
+// the hook that asked to stop and the access it was called for
+#[derive(Clone, Copy, Debug)]
+pub struct HookHit {
+    pub id: HookId,
+    pub access: Access,
+}
+
+struct Hook {
+    id: HookId,
+    range: RangeInclusive<u32>,
+    flags: AccessFlags,
+    // breakpoints have none, they always stop
+    callback: Option<HookCallback>,
+}
+
+pub struct Hooks {
+    hooks: Vec<Hook>,
+    next_id: HookId,
+    hit: Option<HookHit>,
+}
+
+impl Hooks {
+    pub fn new() -> Hooks {
+        Hooks {
+            hooks: Vec::new(),
+            next_id: 0,
+            hit: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        return self.hooks.is_empty();
+    }
+
+    // the callback is called for every matching access, the id removes it again
+    pub fn add(&mut self, range: RangeInclusive<u32>, flags: AccessFlags, callback: HookCallback) -> HookId {
+        return self.push(range, flags, Some(callback));
+    }
+
+    // stops on every matching access
+    pub fn add_breakpoint(&mut self, range: RangeInclusive<u32>, flags: AccessFlags) -> HookId {
+        return self.push(range, flags, None);
+    }
+
+    fn push(&mut self, range: RangeInclusive<u32>, flags: AccessFlags, callback: Option<HookCallback>) -> HookId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.hooks.push(Hook {
+            id,
+            range,
+            flags,
+            callback,
+        });
+        return id;
+    }
+
+    // false if there was no hook with the id
+    pub fn remove(&mut self, id: HookId) -> bool {
+        let count = self.hooks.len();
+        self.hooks.retain(|hook| hook.id != id);
+        return self.hooks.len() != count;
+    }
+
+    // the first hook that asked to stop since the last call
+    pub fn take_hit(&mut self) -> Option<HookHit> {
+        return self.hit.take();
+    }
+
+    // called by the CPU for every access while there are hooks
+    // a hook is called when any byte of the access falls into its range
+    pub fn run(&mut self, access: Access) {
+        let last = access.address.saturating_add(access.size - 1);
+        for hook in self.hooks.iter_mut().filter(|hook| hook.flags.contains(access.kind) && access.address <= *hook.range.end() && last >= *hook.range.start()) {
+            let action = match &mut hook.callback {
+                Some(callback) => callback(&access),
+                None => HookAction::Stop,
+            };
+            if action == HookAction::Stop && self.hit.is_none() {
+                self.hit = Some(HookHit {
+                    id: hook.id,
+                    access,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::backup::{eeprom::EEPROM, Backup, BackupChip};
+    use crate::cpu::{RWType, CPU};
+    use crate::instructions::asm;
+    use super::{AccessFlags, AccessKind, HookAction};
+
+    #[test]
+    fn callbacks_see_accesses() {
+        let mut cpu = CPU::new();
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let seen = accesses.clone();
+        cpu.hooks.add(0x03000002..=0x03000005, AccessFlags::READ_WRITE, Box::new(move |access| {
+            seen.borrow_mut().push(*access);
+            return HookAction::Continue;
+        }));
+        // the upper half of the word is in the range
+        cpu.memory_write(0x03000000, RWType::Word, 0x12345678);
+        cpu.memory_read(0x03000002, RWType::HalfWord);
+        // outside of the range
+        cpu.memory_write(0x03000008, RWType::Word, 0);
+        // debuggers look without calling hooks
+        cpu.debug_read(0x03000000, RWType::Word);
+        let accesses = accesses.borrow();
+        assert_eq!(accesses.len(), 2);
+        assert_eq!((accesses[0].kind, accesses[0].address, accesses[0].size, accesses[0].value), (AccessKind::Write, 0x03000000, 4, 0x12345678));
+        assert_eq!((accesses[1].kind, accesses[1].address, accesses[1].size, accesses[1].value), (AccessKind::Read, 0x03000002, 2, 0x1234));
+        assert!(cpu.hooks.take_hit().is_none());
+    }
+
+    #[test]
+    fn debug_reads_leave_the_eeprom_alone() {
+        let mut cpu = CPU::new();
+        let mut eeprom = EEPROM::new();
+        eeprom.data[0] = 0x80;
+        cpu.backup = Backup::new(BackupChip::EEPROM(eeprom));
+        // read request for block 0
+        for bit in [1, 1, 0, 0, 0, 0, 0, 0, 0] {
+            cpu.memory_write(0x0D000000, RWType::HalfWord, bit);
+        }
+        for _ in 0..4 {
+            assert_eq!(cpu.debug_read(0x0D000000, RWType::HalfWord), 0);
+            assert_eq!(cpu.memory_read(0x0D000000, RWType::HalfWord), 0);
+        }
+        // looking at the first data bit as often as we like doesn't shift it out
+        assert_eq!(cpu.debug_read(0x0D000000, RWType::HalfWord), 1);
+        assert_eq!(cpu.debug_read(0x0D000000, RWType::HalfWord), 1);
+        assert_eq!(cpu.memory_read(0x0D000000, RWType::HalfWord), 1);
+        assert_eq!(cpu.memory_read(0x0D000000, RWType::HalfWord), 0);
+    }
+
+    #[test]
+    fn breakpoints_stop() {
+        let mut cpu = CPU::new();
+        cpu.game_pak_rom = ["str r1, [r2]", "ldr r3, [r2]"].iter().flat_map(|text| asm::arm(text).to_le_bytes()).collect();
+        cpu.registers[15] = 0x08000000;
+        cpu.registers[1] = 42;
+        cpu.registers[2] = 0x03000000;
+        let write = cpu.hooks.add_breakpoint(0x03000000..=0x03000003, AccessFlags::WRITE);
+        let execute = cpu.hooks.add_breakpoint(0x08000004..=0x08000004, AccessFlags::EXECUTE);
+        cpu.cycle();
+        let hit = cpu.hooks.take_hit().unwrap();
+        assert_eq!((hit.id, hit.access.kind, hit.access.value), (write, AccessKind::Write, 42));
+        assert!(cpu.hooks.remove(write));
+        assert!(!cpu.hooks.remove(write));
+        // the fetch comes before the read of the load
+        cpu.cycle();
+        let hit = cpu.hooks.take_hit().unwrap();
+        assert_eq!((hit.id, hit.access.kind), (execute, AccessKind::Execute));
+        assert_eq!(cpu.registers[3], 42);
+    }
+}
@@ -1,4 +1,9 @@
 pub mod arm;
 pub mod thumb;
 pub mod masks_32bit;
-pub mod basic_ops;
\ No newline at end of file
+pub mod basic_ops;
+pub mod disasm;
+#[cfg(test)]
+pub mod asm;
+#[cfg(test)]
+pub mod testing;
\ No newline at end of file
@@ -1,33 +1,34 @@
-use std::collections::HashSet;
-use crate::{cpu::{CPUMode, ConditionFlags, Registers::*, CPU}, 
+use crate::{cpu::{RWType, CPUMode, ConditionFlags, Registers::*, CPU}, 
             instructions::masks_32bit::*, 
             instructions::basic_ops::*,
+            instructions::disasm::{self, DisasmFn},
             not_implemented};
 
 // table for opcodes and their handling functions
-// pattern, mask, handler function
+// pattern, mask, handler function, disassembler function
+// the first match is used, so the more specific patterns have to come before the ones they overlap with
 type ProcFnArm = fn(&mut CPU, u32);
 pub fn placeholder_arm(cpu: &mut CPU, opcode: u32) {
-    not_implemented!();
+    cpu.flush_trace();
+    panic!("{} at {} is not implemented yet", disasm::arm(opcode, cpu.registers[R15]), cpu.symbols.describe(cpu.registers[R15]));
 }
-const ARM_OPCODES: [(u32, u32, ProcFnArm); 17] = [
-        (0x00000000, 0x0C000000, data_processing),  // data processing
-        (0x010F0000, 0x0FBF0FFF, mrs),  // MRS
-        (0x0129F000, 0x0FBFFFF0, msr_full),  // MSR
-        (0x0128F000, 0x0DBFF000, msr_flags),  // MSR (flag bits only)
-        (0x00000090, 0x0FC000F0, multiply),  // multiply
-        (0x00800090, 0x0F8000F0, multiply_long),  // multiply long
-        (0x01000090, 0x0FB00FF0, single_data_swap),  // single data swap
-        (0x013FFF10, 0x0FFFFFF0, branch_and_exchange),  // branch and exchange
-        (0x00000090, 0x0E000090, halfword_signed_data_transfer),  // halfword data transfer
-        (0x04000000, 0x0C000000, single_data_transfer),  // single data transfer
-        (0x06000010, 0x0E000010, placeholder_arm),  // undefined
-        (0x08000000, 0x0E000000, block_data_transfer),  // block data transfer
-        (0x0A000000, 0x0E000000, branch),  // branch
-        (0x0C000000, 0x0E000000, placeholder_arm),  // coprocessor data transfer
-        (0x0E000000, 0x0F000000, placeholder_arm),  // coprocessor data operation
-        (0x0E000010, 0x0F000010, placeholder_arm),  // coprocessor register transfer
-        (0x0F000000, 0x0F000000, software_interrupt),  // software interrupt
+pub const ARM_OPCODES: [(u32, u32, ProcFnArm, DisasmFn); 16] = [
+        (0x012FFF10, 0x0FFFFFF0, branch_and_exchange, disasm::branch_and_exchange),  // branch and exchange
+        (0x010F0000, 0x0FBF0FFF, mrs, disasm::mrs),  // MRS
+        (0x00000090, 0x0FC000F0, multiply, disasm::multiply),  // multiply
+        (0x00800090, 0x0F8000F0, multiply_long, disasm::multiply_long),  // multiply long
+        (0x01000090, 0x0FB00FF0, single_data_swap, disasm::single_data_swap),  // single data swap
+        (0x00000090, 0x0E000090, halfword_signed_data_transfer, disasm::halfword_signed_data_transfer),  // halfword data transfer
+        (0x0120F000, 0x0DB0F000, msr, disasm::msr),  // MSR, compares that don't set the flags
+        (0x00000000, 0x0C000000, data_processing, disasm::data_processing),  // data processing
+        (0x06000010, 0x0E000010, placeholder_arm, disasm::undefined),  // undefined
+        (0x04000000, 0x0C000000, single_data_transfer, disasm::single_data_transfer),  // single data transfer
+        (0x08000000, 0x0E000000, block_data_transfer, disasm::block_data_transfer),  // block data transfer
+        (0x0A000000, 0x0E000000, branch, disasm::branch),  // branch
+        (0x0C000000, 0x0E000000, placeholder_arm, disasm::coprocessor_data_transfer),  // coprocessor data transfer
+        (0x0E000000, 0x0F000010, placeholder_arm, disasm::coprocessor_data_operation),  // coprocessor data operation
+        (0x0E000010, 0x0F000010, placeholder_arm, disasm::coprocessor_register_transfer),  // coprocessor register transfer
+        (0x0F000000, 0x0F000000, software_interrupt, disasm::software_interrupt),  // software interrupt
     ];
 
 type ALUFnArm = fn(&mut CPU, bool, u32, u32) -> u32;
@@ -54,10 +55,7 @@ const ARM_DATA_OPS: [ALUFnArm; 16] = [
 // helper function to check whether an op is logical or arithmetical
 // should compile to a O(1) check
 fn logical_op(op: u32) -> bool {
-    match op {
-        0 | 1 | 8 | 9 | 12 | 13 | 14 | 15 => true,
-        _ => false
-    }
+    return matches!(op, 0 | 1 | 8 | 9 | 12 | 13 | 14 | 15);
 }
 
 
@@ -70,7 +68,7 @@ const ARM_SHIFT_TYPES: [ALUFnArm; 4] = [
 
 pub fn process_instruction_arm(cpu: &mut CPU, instruction: u32) {
     let mut handled = false;
-    for (pattern, mask, handler) in ARM_OPCODES
+    for (pattern, mask, handler, _) in ARM_OPCODES
     {
         if (instruction & mask) == pattern
         {
@@ -82,7 +80,8 @@ pub fn process_instruction_arm(cpu: &mut CPU, instruction: u32) {
     if !handled
     {
         println!("Unknown instruction detected!");
-        println!("Instruction occured at {}.", 0);
+        println!("Instruction occured at {}.", cpu.symbols.describe(cpu.registers[15]));
+        println!("Instruction: {}", disasm::arm(instruction, cpu.registers[15]));
         println!("Instruction binary: {:b}", instruction);
     }
 }
@@ -100,8 +99,8 @@ pub fn data_processing(cpu: &mut CPU, instruction: u32) {
     let opcode: u32 = (instruction & B_24_21) >> 21;
 
     // check whether the operation we're supposed to perform is either logical or arithmetic
-    // in case it's logical, we'll use the carry out from the shifting operations, otherwise we ignore it
-    let logical: bool = logical_op(opcode);
+    // in case it's logical and sets flags, we'll use the carry out from the shifting operations, otherwise we ignore it
+    let logical: bool = s && logical_op(opcode);
     
     // resolve first operand
     let op1 = cpu.register_read(rn);
@@ -134,7 +133,7 @@ pub fn data_processing(cpu: &mut CPU, instruction: u32) {
         if bit4 != 0 {
             // in this case, we load in the shift amount from the bottom byte of the register mentioned in bits 11 to 8
             let shift_register_address: u32 = (instruction & B_11_8) >> 8;
-            let shift_register_value: u32 = cpu.register_read(shift_register_address.try_into().unwrap());
+            let shift_register_value: u32 = cpu.register_read(shift_register_address);
             shift_amount = shift_register_value & B_7_0;
         }
         else {
@@ -149,8 +148,10 @@ pub fn data_processing(cpu: &mut CPU, instruction: u32) {
     }
     // now that both operands are known, we can apply the operations onto it
     let res = ARM_DATA_OPS[opcode as usize](cpu, s, op1, op2);
-    // write result
-    cpu.register_write(rd, res);
+    // write result, TST, TEQ, CMP and CMN only set the flags
+    if !(8..=11).contains(&opcode) {
+        cpu.register_write(rd, res);
+    }
     // if s is set and we write to R15, we need to copy over the SPSR into the CPSR
     if s && rd == 15 {
         if cpu.get_mode() == CPUMode::User {
@@ -177,30 +178,15 @@ pub fn mrs(cpu: &mut CPU, instruction: u32) {
     cpu.register_write(rd, cpu.register_read(source_psr));
 }
 
-pub fn msr_full(cpu: &mut CPU, instruction: u32) {
-    // ARM manual p. 61
-    let rm = instruction & B_3_0;
-    let dest_psr;
-    if (instruction & B_22) != 0 {
-        dest_psr = 17;
-        if cpu.get_mode() == CPUMode::User {
-            panic!("Access to SPSR in User mode!")
-        }
-    } 
-    else {
-        dest_psr = 16;
-    }
-    cpu.register_write(dest_psr, cpu.register_read(rm));
-}
-
-pub fn msr_flags(cpu: &mut CPU, instruction: u32) {
+pub fn msr(cpu: &mut CPU, instruction: u32) {
     // ARM manual p. 61
     let i = (instruction & B_25) != 0;
     let op;
     if i {
+        // rotated by twice the amount like in data processing, 0 is no rotation and not RRX here
         let rotate = (instruction & B_11_8) >> 8;
         let immediate = instruction & B_7_0;
-        op = rotate_32bit(cpu, false, immediate, rotate);
+        op = immediate.rotate_right(rotate * 2);
     }
     else {
         op = cpu.register_read(instruction & B_3_0);
@@ -215,23 +201,34 @@ pub fn msr_flags(cpu: &mut CPU, instruction: u32) {
     else {
         dest_psr = 16;
     }
-    // for this method, only the top four bits are written, i.e. the flag bits
-    //      get current psr and null top four bits
-    let nulled_psr = cpu.register_read(dest_psr) & 0x0FFFFFFF;
-    //      or with top four bits of new value and write to destination
-    cpu.register_write(dest_psr, nulled_psr | (op & 0xF000000) );
+    // bits 16 to 19 select the bytes that are written: control, extension, status and flags
+    let fields = (instruction & B_19_16) >> 16;
+    let mut mask = 0;
+    for field in 0..4 {
+        if fields & (1 << field) != 0 {
+            mask |= 0xFF << (8 * field);
+        }
+    }
+    // user mode can only change the flags of the CPSR
+    if dest_psr == 16 && cpu.get_mode() == CPUMode::User {
+        mask &= 0xFF000000;
+    }
+    let kept_psr = cpu.register_read(dest_psr) & !mask;
+    cpu.register_write(dest_psr, kept_psr | (op & mask));
 }
 
 pub fn branch_and_exchange(cpu: &mut CPU, instruction: u32) {
     // ARM manual: p. 48
     // handle registers in other CPU modes
     let rn: u32 = instruction & B_3_0;
-    let t_bit: u32 = instruction & B_0;
     if rn == 15 {
         println!("[WARNING] Branch and exchange instruction into the program counter register (R15), undefined behavior!")
     }
-    cpu.registers[R15] = cpu.register_read(rn) & !0b11;  // null out last two bits
-    cpu.set_state(t_bit != 0);
+    let target = cpu.register_read(rn);
+    // bit 0 selects the state, THUMB code is halfword aligned and ARM code word aligned
+    let thumb = target & 1 != 0;
+    cpu.registers[R15] = if thumb {target & !0b1} else {target & !0b11};
+    cpu.set_state(thumb);
     cpu.branch = true;
 }
 
@@ -239,21 +236,18 @@ pub fn branch(cpu: &mut CPU, instruction: u32) {
     // ARM manual: p. 50
     let bit_24: u32 = instruction & B_24;
     if bit_24 != 0 {
-        // link bit is set, copy old PC into R14
-        cpu.register_write(14, cpu.registers[R15]);
+        // link bit is set, R14 gets the address of the next instruction
+        cpu.register_write(14, cpu.registers[R15].wrapping_add(4));
     }
     let mut offset: u32 = instruction & B_23_0;
-    offset = offset << 2;
+    offset <<= 2;
     let sign = offset & B_23;
     if sign != 0 {
         // negative number
-        offset = offset | 0xFF000000;
+        offset |= 0xFF000000;
     }
-    else {
-        offset = offset | 0x00000000;
-    }
-    cpu.registers[R15] += offset;
-    cpu.branch = true;
+    // the offset is relative to the PC as the instruction sees it, 8 bytes ahead
+    cpu.register_write(15, cpu.register_read(15).wrapping_add(offset));
 }
 
 pub fn multiply(cpu: &mut CPU, instruction: u32) {
@@ -288,7 +282,8 @@ pub fn multiply(cpu: &mut CPU, instruction: u32) {
 
 pub fn multiply_long(cpu: &mut CPU, instruction: u32) {
     // ARM manual p. 67
-    let unsigned = (instruction & B_22) != 0;
+    // the U bit, set for signed
+    let unsigned = (instruction & B_22) == 0;
     let accumulate = (instruction & B_21) != 0;
     let s = (instruction & B_20) != 0;
 
@@ -308,17 +303,17 @@ pub fn multiply_long(cpu: &mut CPU, instruction: u32) {
     let res_hi;
     let res_lo;
     if accumulate {
+        let add = ((cpu.register_read(rd_hi) as u64) << 32) | (cpu.register_read(rd_lo) as u64);
         if unsigned {
             let prod = (cpu.register_read(rm) as u64) * (cpu.register_read(rs) as u64);
-            let add = (cpu.register_read(rd_hi) as u64) << 32 + (cpu.register_read(rd_lo) as u64);
-            let prod1 = prod + add;
+            let prod1 = prod.wrapping_add(add);
             res_lo = prod1 as u32;
             res_hi = (prod1 >> 32) as u32;
         }
         else {
-            let prod = (cpu.register_read(rm) as i64) * (cpu.register_read(rs) as i64);
-            let add = (cpu.register_read(rd_hi) as i64) << 32 + (cpu.register_read(rd_lo) as i64);
-            let prod1 = prod + add;
+            // the operands are sign extended from 32 bits
+            let prod = (cpu.register_read(rm) as i32 as i64) * (cpu.register_read(rs) as i32 as i64);
+            let prod1 = prod.wrapping_add(add as i64);
             res_lo = prod1 as u32;
             res_hi = (prod1 >> 32) as u32;
         }
@@ -330,7 +325,7 @@ pub fn multiply_long(cpu: &mut CPU, instruction: u32) {
             res_hi = (prod >> 32) as u32;
         }
         else {
-            let prod = (cpu.register_read(rm) as i64) * (cpu.register_read(rs) as i64);
+            let prod = (cpu.register_read(rm) as i32 as i64) * (cpu.register_read(rs) as i32 as i64);
             res_lo = prod as u32;
             res_hi = (prod >> 32) as u32;
         }
@@ -390,28 +385,30 @@ pub fn single_data_transfer(cpu: &mut CPU, instruction: u32) {
     else {
         offset = instruction & B_11_0;
     }
-    // calculate offset adress
+    // calculate offset address, post-indexing transfers at the base and only writes the offset address back
     let offset_address;
     if u {
-        offset_address = base_address + offset;
+        offset_address = base_address.wrapping_add(offset);
     }
     else {
-        offset_address = base_address - offset;
-    }
-    // write back offset address if so desired
-    // TODO: look at the w bit in privileged mode
-    if w || !p {
-        cpu.register_write(rn, offset_address);
+        offset_address = base_address.wrapping_sub(offset);
     }
+    let transfer_address = if p {offset_address} else {base_address};
     
     // perform memory transfer
     if l {
-        let load_value = cpu.memory_read(offset_address, if b {0} else {2});
+        let load_value = cpu.memory_read(transfer_address, if b {RWType::Byte} else {RWType::Word});
         cpu.register_write(rd, load_value);
     }
     else {
         let store_value = cpu.register_read(rd);
-        cpu.memory_write(offset_address, if b {0} else {2}, store_value);
+        cpu.memory_write(transfer_address, if b {RWType::Byte} else {RWType::Word}, store_value);
+    }
+
+    // write back offset address if so desired, a load into the base register wins over it
+    // TODO: look at the w bit in privileged mode
+    if (w || !p) && !(l && rn == rd) {
+        cpu.register_write(rn, offset_address);
     }
 
 }
@@ -425,8 +422,9 @@ pub fn halfword_signed_data_transfer(cpu: &mut CPU, instruction: u32) {
     let s = (instruction & B_6) != 0;
     let h = (instruction & B_5) != 0;
 
-    if s && l {
-        panic!("In halfword/signed data transfer instruction {:b} the S and L bits are both set!", instruction);
+    // signed transfers only exist as loads
+    if s && !l {
+        panic!("In halfword/signed data transfer instruction {:b} the S bit is set for a store!", instruction);
     }
     if !s && !h {
         panic!("Swap demanded in halfword/signed data transfer instruction {:b}!", instruction)
@@ -442,8 +440,8 @@ pub fn halfword_signed_data_transfer(cpu: &mut CPU, instruction: u32) {
     if i {
         // immediate offset
         let offset_lower = instruction & B_3_0;
-        let offset_upper = instruction & B_11_8 >> 4;
-        offset = offset_lower + offset_upper;
+        let offset_upper = (instruction & B_11_8) >> 4;
+        offset = offset_lower | offset_upper;
     }
     else {
         // offset from register
@@ -451,18 +449,15 @@ pub fn halfword_signed_data_transfer(cpu: &mut CPU, instruction: u32) {
         offset = cpu.register_read(rm);
     }
     
-    // calculate offset address
+    // calculate offset address, post-indexing transfers at the base and only writes the offset address back
     let offset_address;
     if u {
-        offset_address = base_address + offset;
+        offset_address = base_address.wrapping_add(offset);
     }
     else {
-        offset_address = base_address - offset;
-    }
-    // write back offset address if so desired
-    if w || !p {
-        cpu.register_write(rn, offset_address);
+        offset_address = base_address.wrapping_sub(offset);
     }
+    let transfer_address = if p {offset_address} else {base_address};
 
     // load/store
     if l {
@@ -470,7 +465,7 @@ pub fn halfword_signed_data_transfer(cpu: &mut CPU, instruction: u32) {
         if s {
             if h {
                 // signed halfword load
-                load_data = cpu.memory_read(offset_address, 1);
+                load_data = cpu.memory_read(transfer_address, RWType::HalfWord);
                 let tmp: u16 = load_data as u16;
                 let tmp: i16 = tmp as i16;
                 let tmp: i32 = tmp as i32;
@@ -478,7 +473,7 @@ pub fn halfword_signed_data_transfer(cpu: &mut CPU, instruction: u32) {
             }
             else {
                 // signed byte load
-                load_data = cpu.memory_read(offset_address, 0);
+                load_data = cpu.memory_read(transfer_address, RWType::Byte);
                 // sign extend to all bits
                 let tmp: u8 = load_data as u8;  // works because memory read places the byte into lower 8 bits
                 let tmp: i8 = tmp as i8;
@@ -489,21 +484,26 @@ pub fn halfword_signed_data_transfer(cpu: &mut CPU, instruction: u32) {
         else {
             // no need to deal with h, would be a swap, that's dealt with separately
             // top 16 bits have to be set to 0, this is done in the memory read already
-            load_data = cpu.memory_read(offset_address, 1);
+            load_data = cpu.memory_read(transfer_address, RWType::HalfWord);
             cpu.register_write(rd, load_data);
         }
     }
     else {
         // here we don't need to ask for h or s, since only halfword stores are possible
         // if the flags aren't set for that, the function should already have paniced above
-        cpu.memory_write(offset_address, 1, cpu.register_read(rd) & B_15_0);
+        cpu.memory_write(transfer_address, RWType::HalfWord, cpu.register_read(rd) & B_15_0);
+    }
+
+    // write back offset address if so desired, a load into the base register wins over it
+    if (w || !p) && !(l && rn == rd) {
+        cpu.register_write(rn, offset_address);
     }
 
 }
 
 pub fn single_data_swap(cpu: &mut CPU, instruction: u32) {
     // p.89
-    let b = if (instruction & B_22) != 0 {0} else {2};
+    let b = (instruction & B_22) != 0;
 
     let rn = (instruction & B_19_16) >> 16;
     let rd = (instruction & B_15_12) >> 12;
@@ -515,9 +515,10 @@ pub fn single_data_swap(cpu: &mut CPU, instruction: u32) {
     }
 
     // read word or byte from base address
-    let memory_read = cpu.memory_read(rn, b);
+    let address = cpu.register_read(rn);
+    let memory_read = cpu.memory_read(address, if b {RWType::Byte} else {RWType::Word});
     // write swap register into memory
-    cpu.memory_write(rn, b, cpu.register_read(rm));
+    cpu.memory_write(address, if b {RWType::Byte} else {RWType::Word}, cpu.register_read(rm));
     // overwrite swap register
     cpu.register_write(rd, memory_read);
 }
@@ -538,123 +539,121 @@ pub fn block_data_transfer(cpu: &mut CPU, instruction: u32) {
         panic!("R15 used as base register in Block Data Transfer instruction {:b}!", instruction);
     }
 
+    // the lowest register always goes to the lowest address, so decrementing transfers start at the bottom of the block
+    // see the graphics on p.85 of the PDF
     let base_address = cpu.register_read(rn);
-    let mut cur_address = base_address;
-    if !u {
-        cur_address -= register_list.count_ones();
-        if p {
-            cur_address -= 1;  // correct for the effects of pre-decrement, see graphics on p.85 of the PDF
-        }
-        else {
-            cur_address += 1;  // same as above, just for post-decrement
-        }
+    let size = 4 * register_list.count_ones();
+    let mut cur_address;
+    if u {
+        cur_address = if p {base_address.wrapping_add(4)} else {base_address};
     }
-
-    // check if R15 is present and s is set, we need to change a few things
-    let mut usermode_switch= false;
-    if s {
-        if l {
-            if register_list & B_15 != 0 {
-                // SPSR of mode is transferred to CPSR if R15 in list and s is set
-                cpu.register_write(16, cpu.register_read(17));
-            }
-            else {
-                usermode_switch = true;
-            }   
-        }
-        else {
-            usermode_switch = true;
-        }
+    else {
+        cur_address = if p {base_address.wrapping_sub(size)} else {base_address.wrapping_sub(size).wrapping_add(4)};
     }
 
-    for i in 0..15 {
+    // with s set, R15 in a load list restores the CPSR from the SPSR once the registers are loaded
+    // otherwise the User mode registers are transferred
+    let restore_cpsr = s && l && register_list & B_15 != 0;
+    let usermode_switch = s && !restore_cpsr;
+
+    for i in 0..16 {
         if register_list & (1 << i) == 0 {
             continue;
         }
-        // pre
-        if p {
-            cur_address += 1;
-        }
         if l {
             // load
+            let load_value = cpu.memory_read(cur_address, RWType::Word);
             if usermode_switch {
-                cpu.register_write_custom(i, cpu.memory_read(cur_address, 2), CPUMode::User);
+                cpu.register_write_custom(i, load_value, CPUMode::User);
             }
             else {
-                cpu.register_write(i, cpu.memory_read(cur_address, 2));
+                cpu.register_write(i, load_value);
             }
         }
         else {
             // store
             if usermode_switch {
                 // memory write with the registers being read from User mode instead of current mode
-                cpu.memory_write(cur_address, 2, cpu.register_read_custom(i, CPUMode::User));
+                cpu.memory_write(cur_address, RWType::Word, cpu.register_read_custom(i, CPUMode::User));
             }
             else {
-                cpu.memory_write(cur_address, 2, cpu.register_read(i));
+                cpu.memory_write(cur_address, RWType::Word, cpu.register_read(i));
             }
         }
-        // post
-        if !p {
-            cur_address += 1;
-        }
+        cur_address = cur_address.wrapping_add(4);
     }
 
-    // write back modified address
-    if w {
-        cpu.register_write(rn, if u {base_address + register_list.count_ones()} else {base_address - register_list.count_ones()});
+    // write back modified address, a loaded base register keeps the loaded value
+    if w && !(l && register_list & (1 << rn) != 0) {
+        cpu.register_write(rn, if u {base_address.wrapping_add(size)} else {base_address.wrapping_sub(size)});
+    }
+
+    // the write back above still goes to the registers of the current mode
+    if restore_cpsr {
+        cpu.register_write(16, cpu.register_read(17));
     }
 }
 
 pub fn software_interrupt(cpu: &mut CPU, _instruction: u32) {
-    // change mode to software interrupt
+    // the comment field is only looked at by the handler
+    enter_software_interrupt(cpu);
+}
+
+// shared with THUMB, the BIOS handler always runs in ARM state
+pub fn enter_software_interrupt(cpu: &mut CPU) {
+    let cpsr = cpu.register_read(16);
+    // the handler returns with MOVS PC, R14, so R14 holds the address of the next instruction
+    let return_address = cpu.registers[R15].wrapping_add(if cpu.get_state() {2} else {4});
+    // change mode to supervisor, then save CPSR to SPSR_svc and PC in R14_svc
     cpu.set_mode(CPUMode::Supervisor);
-    // save PC in R14_svc
-    cpu.register_write(14, cpu.register_read(15));
+    cpu.register_write(17, cpsr);
+    cpu.register_write(14, return_address);
+    cpu.set_state(false);
+    cpu.set_irq_disable(true);
     // set PC to 0x08
     cpu.register_write(15, 0x08);
-    // save CPSR to SPSR_svc
-    cpu.register_write(17, cpu.register_read(16));
-    // most likely unfinished
 }
 
-pub fn coprocessor_data_operations(cpu: &mut CPU, instruction: u32) {
+pub fn coprocessor_data_operations(_cpu: &mut CPU, instruction: u32) {
     // p.93
-    let cp_opc = (instruction & B_23_20) >> 20;
-    let crn = (instruction & B_19_16) >> 16;
-    let crd = (instruction & B_15_12) >> 12;
-    let cphash = (instruction & B_11_8) >> 8;
-    let cp = (instruction & B_7_5) >> 5;
-    let crm = (instruction & B_3_0);
+    let _cp_opc = (instruction & B_23_20) >> 20;
+    let _crn = (instruction & B_19_16) >> 16;
+    let _crd = (instruction & B_15_12) >> 12;
+    let _cphash = (instruction & B_11_8) >> 8;
+    let _cp = (instruction & B_7_5) >> 5;
+    let _crm = instruction & B_3_0;
 
     // nothing else to do here apparently 
 }
 
 pub fn coprocessor_data_transfer(cpu: &mut CPU, instruction: u32) {
     // p.95
-    let p = (instruction & B_24) != 0;
-    let u = (instruction & B_23) != 0;
-    let n = (instruction & B_22) != 0;
+    let _p = (instruction & B_24) != 0;
+    let _u = (instruction & B_23) != 0;
+    let _n = (instruction & B_22) != 0;
     let w = (instruction & B_21) != 0;
-    let l = (instruction & B_20) != 0;
+    let _l = (instruction & B_20) != 0;
 
     let rn = (instruction & B_19_16) >> 16;
-    let crd = (instruction & B_15_12) >> 12;
-    let cphash = (instruction & B_11_8) >> 8;
-    let offset = instruction & B_7_0;
+    let _crd = (instruction & B_15_12) >> 12;
+    let _cphash = (instruction & B_11_8) >> 8;
+    let _offset = instruction & B_7_0;
 
     if w && rn == 15 {
         panic!("R15 must not be the base register in coprocessor data transfer with write back enabled, instruction: {:b}.", instruction);
     }
 
+    cpu.flush_trace();
     not_implemented!();
 }
 
-pub fn coprocessor_register_transfer(cpu: &mut CPU, instruction: u32) {
+pub fn coprocessor_register_transfer(cpu: &mut CPU, _instruction: u32) {
+    cpu.flush_trace();
     not_implemented!();
 }
 
-pub fn undefined(cpu: &mut CPU, instruction: u32) {
+pub fn undefined(cpu: &mut CPU, _instruction: u32) {
+    cpu.flush_trace();
     not_implemented!();
 }
 
@@ -667,7 +666,7 @@ fn get_shift_amount(cpu: &mut CPU, instruction: u32) -> u32 {
     if bit4 != 0 {
         // in this case, we load in the shift amount from the bottom byte of the register mentioned in bits 11 to 8
         let shift_register_address: u32 = (instruction & B_11_8) >> 8;
-        let shift_register_value: u32 = cpu.register_read(shift_register_address.try_into().unwrap());
+        let shift_register_value: u32 = cpu.register_read(shift_register_address);
         shift_amount = shift_register_value & B_7_0;
     }
     else {
@@ -675,4 +674,299 @@ fn get_shift_amount(cpu: &mut CPU, instruction: u32) -> u32 {
        shift_amount = (instruction & B_11_7) >> 7; 
     }
     return shift_amount;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{CPUMode, RWType, Registers, CPU};
+    use crate::instructions::testing::{flags, load_arm, run_arm};
+
+    #[test]
+    fn compare_sets_flags() {
+        let mut cpu = CPU::new();
+        cpu.registers[0] = 1;
+        cpu.registers[1] = 2;
+        // nzcv
+        run_arm(&mut cpu, &["cmp r0, r1"]);
+        assert_eq!(flags(&cpu), (true, false, false, false));
+        run_arm(&mut cpu, &["cmp r1, r0"]);
+        assert_eq!(flags(&cpu), (false, false, true, false));
+        run_arm(&mut cpu, &["cmp r0, #1"]);
+        assert_eq!(flags(&cpu), (false, true, true, false));
+        // compares leave the destination field alone
+        assert_eq!((cpu.registers[0], cpu.registers[1]), (1, 2));
+        cpu.registers[2] = 0x7FFFFFFF;
+        run_arm(&mut cpu, &["cmn r2, #1"]);
+        assert_eq!(flags(&cpu), (true, false, false, true));
+        cpu.registers[3] = 0x80000000;
+        run_arm(&mut cpu, &["cmp r3, #1"]);
+        assert_eq!(flags(&cpu), (false, false, true, true));
+    }
+
+    #[test]
+    fn data_processing_sets_flags() {
+        let mut cpu = CPU::new();
+        run_arm(&mut cpu, &["movs r0, #0"]);
+        assert_eq!(flags(&cpu), (false, true, false, false));
+        run_arm(&mut cpu, &["mvns r0, r0"]);
+        assert_eq!(cpu.registers[0], 0xFFFFFFFF);
+        assert_eq!(flags(&cpu), (true, false, false, false));
+        run_arm(&mut cpu, &["adds r1, r0, #1"]);
+        assert_eq!(cpu.registers[1], 0);
+        assert_eq!(flags(&cpu), (false, true, true, false));
+        // the carry out of the shifter
+        run_arm(&mut cpu, &["movs r2, r0, lsr #1"]);
+        assert_eq!(cpu.registers[2], 0x7FFFFFFF);
+        assert_eq!(flags(&cpu), (false, false, true, false));
+        // without s nothing changes
+        run_arm(&mut cpu, &["sub r3, r1, #1"]);
+        assert_eq!(cpu.registers[3], 0xFFFFFFFF);
+        assert_eq!(flags(&cpu), (false, false, true, false));
+        // subtract with carry borrows one when the carry is clear
+        run_arm(&mut cpu, &["cmp r1, #1", "sbcs r4, r1, #0"]);
+        assert_eq!(cpu.registers[4], 0xFFFFFFFF);
+        assert_eq!(flags(&cpu), (true, false, false, false));
+    }
+
+    #[test]
+    fn conditional_execution() {
+        let mut cpu = CPU::new();
+        cpu.registers[0] = 5;
+        cpu.registers[1] = 7;
+        run_arm(&mut cpu, &[
+            "cmp r0, r1",
+            "moveq r2, #1",
+            "movne r3, #1",
+            "movlt r4, #1",
+            "movge r5, #1",
+            "movcc r6, #1",
+            "movhi r7, #1",
+            "movmi r8, #1",
+            "movvs r9, #1",
+        ]);
+        assert_eq!(&cpu.registers[2..10], &[0, 1, 1, 0, 1, 0, 1, 0]);
+        // the condition is checked before every instruction, not just after a compare
+        run_arm(&mut cpu, &["subs r0, r0, #5", "addeq r10, r10, #2", "addne r10, r10, #3"]);
+        assert_eq!(cpu.registers[10], 2);
+    }
+
+    #[test]
+    fn psr_transfers_reach_the_cpsr() {
+        let mut cpu = CPU::new();
+        // user mode can't change the control bits
+        cpu.set_mode(CPUMode::Supervisor);
+        // system mode with all flags set
+        cpu.registers[0] = 0xF000001F;
+        run_arm(&mut cpu, &["msr cpsr_fc, r0", "mrs r1, cpsr"]);
+        assert_eq!(cpu.registers[1], 0xF000001F);
+        assert_eq!(flags(&cpu), (true, true, true, true));
+        // and nothing went into the banked registers
+        assert_eq!(cpu.registers[16], 0);
+    }
+
+    #[test]
+    fn pc_reads_and_branches() {
+        let mut cpu = CPU::new();
+        load_arm(&mut cpu, &[
+            "add r0, pc, #0",
+            "bl 0x08000010",
+            "mov r1, #1",
+            "b 0x0800000c",
+            "mov r2, #3",
+            "mov pc, lr",
+        ]);
+        for _ in 0..7 {
+            cpu.cycle();
+        }
+        // the PC reads 8 bytes ahead, the link register holds the instruction after the call
+        assert_eq!((cpu.registers[0], cpu.registers[14]), (0x08000008, 0x08000008));
+        assert_eq!((cpu.registers[1], cpu.registers[2]), (1, 3));
+        assert_eq!(cpu.registers[15], 0x0800000C);
+    }
+
+    #[test]
+    fn block_transfers() {
+        let mut cpu = CPU::new();
+        cpu.registers[0..4].copy_from_slice(&[1, 2, 3, 4]);
+        cpu.registers[13] = 0x03000100;
+        cpu.registers[10] = 0x03000200;
+        cpu.debug_write(0x030001FC, RWType::Word, 5);
+        cpu.debug_write(0x03000200, RWType::Word, 6);
+        run_arm(&mut cpu, &["stmfd sp!, {r0-r3}", "ldmia sp, {r4-r7}", "ldmfd sp!, {r8, r9}", "stmib r10, {r0, r1}", "ldmda r10, {r11, r12}"]);
+        // the lowest register is at the lowest address
+        assert_eq!(cpu.debug_read(0x030000F0, RWType::Word), 1);
+        assert_eq!(cpu.debug_read(0x030000FC, RWType::Word), 4);
+        assert_eq!(&cpu.registers[4..8], &[1, 2, 3, 4]);
+        assert_eq!((cpu.registers[8], cpu.registers[9], cpu.registers[13]), (1, 2, 0x030000F8));
+        assert_eq!(cpu.debug_read(0x03000204, RWType::Word), 1);
+        assert_eq!(cpu.debug_read(0x03000208, RWType::Word), 2);
+        // ldmda ends at the base, which stmib left alone
+        assert_eq!((cpu.registers[11], cpu.registers[12]), (5, 6));
+    }
+    #[test]
+    fn multiplies() {
+        let mut cpu = CPU::new();
+        cpu.registers[1..4].copy_from_slice(&[7, 6, 100]);
+        cpu.registers[5] = 0xFFFFFFFF;
+        run_arm(&mut cpu, &["mul r0, r1, r2", "mla r4, r1, r2, r3", "muls r6, r5, r1"]);
+        assert_eq!((cpu.registers[0], cpu.registers[4], cpu.registers[6]), (42, 142, 0xFFFFFFF9));
+        assert!(flags(&cpu).0);
+        // 64 bit results, the low word comes first
+        run_arm(&mut cpu, &["umull r7, r8, r5, r5", "smull r9, r10, r5, r1"]);
+        assert_eq!((cpu.registers[7], cpu.registers[8]), (1, 0xFFFFFFFE));
+        assert_eq!((cpu.registers[9], cpu.registers[10]), (0xFFFFFFF9, 0xFFFFFFFF));
+        run_arm(&mut cpu, &["smlal r9, r10, r1, r2", "umlal r7, r8, r1, r2"]);
+        assert_eq!((cpu.registers[9], cpu.registers[10]), (35, 0));
+        assert_eq!((cpu.registers[7], cpu.registers[8]), (43, 0xFFFFFFFE));
+    }
+
+    #[test]
+    fn swaps() {
+        let mut cpu = CPU::new();
+        cpu.registers[0] = 0x03000010;
+        cpu.registers[1] = 0xAABBCCDD;
+        cpu.registers[4] = 0x55;
+        cpu.debug_write(0x03000010, RWType::Word, 0x11223344);
+        run_arm(&mut cpu, &["swp r2, r1, [r0]", "swpb r3, r4, [r0]"]);
+        assert_eq!((cpu.registers[2], cpu.registers[3]), (0x11223344, 0xDD));
+        assert_eq!(cpu.debug_read(0x03000010, RWType::Word), 0xAABBCC55);
+    }
+
+    #[test]
+    fn single_data_transfers() {
+        let mut cpu = CPU::new();
+        cpu.registers[0] = 0x03000020;
+        cpu.registers[1] = 0x03000020;
+        cpu.registers[2] = 0x03000020;
+        cpu.registers[3] = 0x03000024;
+        cpu.registers[4] = 8;
+        cpu.registers[10] = 0x11223344;
+        cpu.debug_write(0x03000020, RWType::Word, 0x12345678);
+        cpu.debug_write(0x03000024, RWType::Word, 0x9ABCDEF0);
+        run_arm(&mut cpu, &[
+            // pre-indexed without write-back
+            "ldr r5, [r0, #4]",
+            // post-indexed, the load is from the base
+            "ldr r6, [r1], #4",
+            // pre-indexed with write-back
+            "ldrb r7, [r2, #3]!",
+            // loading the base register keeps the loaded value
+            "ldr r3, [r3], #4",
+            "str r10, [r0, r4, lsl #1]",
+            "strb r10, [r0, #-1]!",
+        ]);
+        assert_eq!(cpu.registers[5], 0x9ABCDEF0);
+        assert_eq!((cpu.registers[6], cpu.registers[1]), (0x12345678, 0x03000024));
+        assert_eq!((cpu.registers[7], cpu.registers[2]), (0x12, 0x03000023));
+        assert_eq!(cpu.registers[3], 0x9ABCDEF0);
+        assert_eq!(cpu.debug_read(0x03000030, RWType::Word), 0x11223344);
+        assert_eq!(cpu.registers[0], 0x0300001F);
+        assert_eq!(cpu.debug_read(0x0300001C, RWType::Word), 0x44000000);
+        // unaligned word loads rotate the addressed byte to the bottom
+        cpu.registers[8] = 0x03000021;
+        run_arm(&mut cpu, &["ldr r9, [r8]"]);
+        assert_eq!(cpu.registers[9], 0x78123456);
+        // the address wraps around instead of overflowing
+        cpu.registers[11] = 0xFFFFFFFC;
+        cpu.debug_write(0x03000028, RWType::Word, 0x55);
+        run_arm(&mut cpu, &["ldr r12, [r1, r11]", "ldr r9, [r1, -r11]"]);
+        assert_eq!((cpu.registers[12], cpu.registers[9]), (0x12345678, 0x55));
+    }
+
+    #[test]
+    fn halfword_and_signed_transfers() {
+        let mut cpu = CPU::new();
+        cpu.registers[0] = 0x03000020;
+        cpu.registers[6] = 0x03000010;
+        cpu.registers[7] = 0xABCD;
+        cpu.registers[9] = 0x16;
+        cpu.debug_write(0x03000020, RWType::Word, 0x80FF1234);
+        run_arm(&mut cpu, &[
+            "ldrh r1, [r0]",
+            "ldrh r2, [r0, #2]",
+            "ldrsh r3, [r0, #2]",
+            "ldrsb r4, [r0, #3]",
+            // the offset has bits in both of its halves
+            "ldrh r5, [r6, #0x10]!",
+            // post-indexed, the store goes to the base
+            "strh r7, [r0], #-0x14",
+            "ldrh r8, [r0, r9]",
+        ]);
+        assert_eq!(&cpu.registers[1..6], &[0x1234, 0x80FF, 0xFFFF80FF, 0xFFFFFF80, 0x1234]);
+        assert_eq!(cpu.registers[6], 0x03000020);
+        assert_eq!(cpu.debug_read(0x03000020, RWType::Word), 0x80FFABCD);
+        assert_eq!(cpu.registers[0], 0x0300000C);
+        assert_eq!(cpu.registers[8], 0x80FF);
+    }
+
+    #[test]
+    fn psr_flag_writes() {
+        let mut cpu = CPU::new();
+        cpu.set_mode(CPUMode::Supervisor);
+        cpu.registers[0] = 0x4000001F;
+        run_arm(&mut cpu, &["msr cpsr_fc, r0", "msr cpsr_f, #0xA0000000"]);
+        assert_eq!(flags(&cpu), (true, false, true, false));
+        assert!(cpu.get_mode() == CPUMode::System);
+        run_arm(&mut cpu, &["msr cpsr_f, r0"]);
+        assert_eq!(flags(&cpu), (false, true, false, false));
+        assert_eq!(cpu.registers[Registers::CPSR], 0x4000001F);
+        // the SPSR of the current mode
+        cpu.set_mode(CPUMode::Supervisor);
+        cpu.registers[1] = 0x80000010;
+        run_arm(&mut cpu, &["msr spsr_fc, r1", "mrs r2, spsr"]);
+        assert_eq!(cpu.registers[2], 0x80000010);
+        assert_eq!(cpu.register_read_custom(17, CPUMode::Supervisor), 0x80000010);
+    }
+
+    #[test]
+    fn psr_field_masks() {
+        let mut cpu = CPU::new();
+        cpu.set_mode(CPUMode::Supervisor);
+        run_arm(&mut cpu, &["msr cpsr_f, #0xF0000000"]);
+        // only the control byte, the flags stay
+        cpu.registers[0] = 0x00000012;
+        run_arm(&mut cpu, &["msr cpsr_c, r0"]);
+        assert!(cpu.get_mode() == CPUMode::IRQ);
+        assert_eq!(flags(&cpu), (true, true, true, true));
+        run_arm(&mut cpu, &["msr cpsr_fc, #0x1F"]);
+        assert!(cpu.get_mode() == CPUMode::System);
+        assert_eq!(flags(&cpu), (false, false, false, false));
+        // all four fields of the SPSR
+        cpu.set_mode(CPUMode::Supervisor);
+        cpu.registers[1] = 0x80000010;
+        run_arm(&mut cpu, &["msr spsr_fsxc, r1"]);
+        assert_eq!(cpu.register_read_custom(17, CPUMode::Supervisor), 0x80000010);
+        // user mode only gets to change the flags
+        cpu.set_mode(CPUMode::User);
+        cpu.registers[2] = 0x6000001F;
+        run_arm(&mut cpu, &["msr cpsr_fc, r2"]);
+        assert!(cpu.get_mode() == CPUMode::User);
+        assert_eq!(flags(&cpu), (false, true, true, false));
+    }
+
+    #[test]
+    fn branch_and_exchange() {
+        let mut cpu = CPU::new();
+        cpu.registers[0] = 0x08000101;
+        run_arm(&mut cpu, &["bx r0"]);
+        assert_eq!(cpu.registers[15], 0x08000100);
+        assert!(cpu.get_state());
+        cpu.set_state(false);
+        cpu.registers[1] = 0x08000012;
+        run_arm(&mut cpu, &["bx r1"]);
+        assert_eq!(cpu.registers[15], 0x08000010);
+        assert!(!cpu.get_state());
+    }
+
+    #[test]
+    fn software_interrupt() {
+        let mut cpu = CPU::new();
+        cpu.registers[Registers::CPSR] = 0x2000001F;
+        run_arm(&mut cpu, &["swi #0x5"]);
+        // supervisor mode with IRQs disabled at the vector, returning to the next instruction
+        assert_eq!(cpu.registers[15], 0x08);
+        assert!(cpu.get_mode() == CPUMode::Supervisor);
+        assert!(cpu.get_irq_disable());
+        assert_eq!(cpu.register_read_custom(14, CPUMode::Supervisor), 0x08000004);
+        assert_eq!(cpu.register_read_custom(17, CPUMode::Supervisor), 0x2000001F);
+    }
+}
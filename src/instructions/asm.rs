@@ -0,0 +1,762 @@
+use crate::instructions::disasm::{CONDITIONS, DATA_OPS, REGISTERS, SHIFTS, THUMB_ALU_OPS};
+
+// small assembler for tests, so instructions can be written as text instead of bit patterns
+// understands what the disassembler prints, plus the UAL order of the suffixes (addseq as well as addeqs)
+// branch targets are absolute addresses, like the disassembler shows them
+
+// address the instructions are assumed to be at, unless given
+pub const ADDRESS: u32 = 0x08000000;
+
+// panics with the error, meant for tests
+pub fn arm(text: &str) -> u32 {
+    return try_arm(text, ADDRESS).unwrap_or_else(|e| panic!("can't assemble \"{}\": {}", text, e));
+}
+
+// the long branch with link is two instructions, then the second one is in the upper halfword
+pub fn thumb(text: &str) -> u32 {
+    return try_thumb(text, ADDRESS).unwrap_or_else(|e| panic!("can't assemble \"{}\": {}", text, e));
+}
+
+/*
+    operands
+*/
+
+// splits the instruction into the mnemonic and its operands, commas inside [] and {} don't separate operands
+fn split(text: &str) -> (String, Vec<String>) {
+    // the disassembler adds the resolved address as a comment
+    let text = text.split([';', '@']).next().unwrap_or("").trim().to_ascii_lowercase();
+    let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((&text, ""));
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in rest.chars() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            },
+            _ => {},
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        operands.push(current.trim().to_string());
+    }
+    return (mnemonic.to_string(), operands);
+}
+
+fn register(text: &str) -> Result<u32, String> {
+    let text = text.trim();
+    if let Some(index) = REGISTERS.iter().position(|name| *name == text) {
+        return Ok(index as u32);
+    }
+    match text {
+        "r13" => Ok(13),
+        "r14" => Ok(14),
+        "r15" => Ok(15),
+        "fp" => Ok(11),
+        "ip" => Ok(12),
+        _ => Err(format!("{} is not a register", text)),
+    }
+}
+
+fn low_register(text: &str) -> Result<u32, String> {
+    let register = register(text)?;
+    if register > 7 {
+        return Err(format!("{} is not one of r0-r7", text));
+    }
+    Ok(register)
+}
+
+// decimal or hex, with an optional sign
+fn number(text: &str) -> Result<i64, String> {
+    let text = text.trim();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }.map_err(|_| format!("{} is not a number", text))?;
+    Ok(if negative {-value} else {value})
+}
+
+fn immediate(text: &str) -> Result<i64, String> {
+    let value = text.trim().strip_prefix('#').ok_or_else(|| format!("{} is not an immediate", text))?;
+    return number(value);
+}
+
+fn unsigned(value: i64, limit: i64, what: &str) -> Result<u32, String> {
+    if value < 0 || value >= limit {
+        return Err(format!("{} {:#x} is out of range", what, value));
+    }
+    Ok(value as u32)
+}
+
+// coprocessor numbers like p15 and registers like c3, plain numbers for the opcodes
+fn prefixed(text: &str, prefix: char) -> Result<u32, String> {
+    let value = text.trim().strip_prefix(prefix).ok_or_else(|| format!("{} needs to start with {}", text, prefix))?;
+    return unsigned(number(value)?, 16, "coprocessor operand");
+}
+
+fn coprocessor_opcode(text: &str, limit: i64) -> Result<u32, String> {
+    let text = text.trim();
+    return unsigned(number(text.strip_prefix('#').unwrap_or(text))?, limit, "coprocessor opcode");
+}
+
+fn expect_count(operands: &[String], count: usize) -> Result<(), String> {
+    if operands.len() != count {
+        return Err(format!("expected {} operands, got {}", count, operands.len()));
+    }
+    Ok(())
+}
+
+// {r0-r3, lr}, the second value tells whether it ends with ^
+fn register_list(text: &str) -> Result<(u32, bool), String> {
+    let text = text.trim();
+    let (text, caret) = match text.strip_suffix('^') {
+        Some(text) => (text.trim(), true),
+        None => (text, false),
+    };
+    let inner = text.strip_prefix('{').and_then(|text| text.strip_suffix('}')).ok_or_else(|| format!("{} is not a register list", text))?;
+    let mut list = 0;
+    for part in inner.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                for index in register(first)?..=register(last)? {
+                    list |= 1 << index;
+                }
+            },
+            None => list |= 1 << register(part)?,
+        }
+    }
+    Ok((list, caret))
+}
+
+/*
+    ARM
+*/
+
+pub fn try_arm(text: &str, address: u32) -> Result<u32, String> {
+    let (mnemonic, operands) = split(text);
+    if mnemonic == ".word" || mnemonic == "undefined" {
+        expect_count(&operands, 1)?;
+        return Ok(number(&operands[0])? as u32);
+    }
+    for (base, suffixes, encode) in ARM_MNEMONICS {
+        let rest = match mnemonic.strip_prefix(base) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        if let Some((cond, suffix)) = conditional_suffix(rest, suffixes) {
+            return Ok((cond << 28) | encode(base, suffix, &operands, address)?);
+        }
+    }
+    return Err(format!("unknown mnemonic {}", mnemonic));
+}
+
+// condition and suffix in either order, the condition is "al" if there is none
+fn conditional_suffix(rest: &str, suffixes: &[&'static str]) -> Option<(u32, &'static str)> {
+    for suffix in suffixes {
+        for (index, cond) in CONDITIONS.iter().enumerate() {
+            let names: &[&str] = if index == 14 {&["", "al"]} else {&[cond]};
+            for name in names {
+                if rest == format!("{}{}", name, suffix) || rest == format!("{}{}", suffix, name) {
+                    return Some((index as u32, suffix));
+                }
+            }
+        }
+    }
+    return None;
+}
+
+// base mnemonic, allowed suffixes and the function that encodes everything but the condition
+type EncodeFn = fn(&str, &str, &[String], u32) -> Result<u32, String>;
+
+// longer mnemonics first, so bl isn't taken for b with a condition starting with l
+const ARM_MNEMONICS: [(&str, &[&str], EncodeFn); 39] = [
+    ("umull", &["", "s"], multiply_long), ("umlal", &["", "s"], multiply_long),
+    ("smull", &["", "s"], multiply_long), ("smlal", &["", "s"], multiply_long),
+    ("push", &[""], push_pop), ("pop", &[""], push_pop),
+    ("and", &["", "s"], data_processing), ("eor", &["", "s"], data_processing),
+    ("sub", &["", "s"], data_processing), ("rsb", &["", "s"], data_processing),
+    ("add", &["", "s"], data_processing), ("adc", &["", "s"], data_processing),
+    ("sbc", &["", "s"], data_processing), ("rsc", &["", "s"], data_processing),
+    ("tst", &["", "s"], data_processing), ("teq", &["", "s"], data_processing),
+    ("cmp", &["", "s"], data_processing), ("cmn", &["", "s"], data_processing),
+    ("orr", &["", "s"], data_processing), ("mov", &["", "s"], data_processing),
+    ("bic", &["", "s"], data_processing), ("mvn", &["", "s"], data_processing),
+    ("mrs", &[""], mrs), ("msr", &[""], msr),
+    ("mul", &["", "s"], multiply), ("mla", &["", "s"], multiply),
+    ("swp", &["", "b"], single_data_swap),
+    ("ldr", &["", "b", "t", "bt", "h", "sb", "sh"], data_transfer), ("str", &["", "b", "t", "bt", "h"], data_transfer),
+    ("ldm", &["ia", "ib", "da", "db", "fd", "ed", "fa", "ea"], block_data_transfer), ("stm", &["ia", "ib", "da", "db", "fd", "ed", "fa", "ea"], block_data_transfer),
+    ("cdp", &[""], coprocessor_data_operation),
+    ("mrc", &[""], coprocessor_register_transfer), ("mcr", &[""], coprocessor_register_transfer),
+    ("ldc", &["", "l"], coprocessor_data_transfer), ("stc", &["", "l"], coprocessor_data_transfer),
+    ("swi", &[""], software_interrupt), ("svc", &[""], software_interrupt),
+    ("b", &["x", "l", ""], branch),
+];
+
+// rotated 8 bit immediate, the way data processing and MSR encode it
+fn arm_immediate(value: i64) -> Result<u32, String> {
+    let value = value as u32;
+    for rotation in 0..16 {
+        let rotated = value.rotate_left(rotation * 2);
+        if rotated <= 0xFF {
+            return Ok((rotation << 8) | rotated);
+        }
+    }
+    return Err(format!("{:#x} can't be encoded as a rotated 8 bit immediate", value));
+}
+
+// rm with an optional shift, bits 11-0 of the instruction
+fn shifted_register(operands: &[String], allow_register_shift: bool) -> Result<u32, String> {
+    let rm = register(&operands[0])?;
+    let shift = match operands.get(1) {
+        Some(shift) => shift,
+        None => return Ok(rm),
+    };
+    if operands.len() > 2 {
+        return Err(String::from("too many operands"));
+    }
+    if shift == "rrx" {
+        return Ok((3 << 5) | rm);
+    }
+    let (kind, amount) = shift.split_once(char::is_whitespace).ok_or_else(|| format!("{} is not a shift", shift))?;
+    let kind = if kind == "asl" {0} else {SHIFTS.iter().position(|name| *name == kind).ok_or_else(|| format!("{} is not a shift", kind))? as u32};
+    if !amount.trim().starts_with('#') {
+        if !allow_register_shift {
+            return Err(String::from("shift by a register isn't possible here"));
+        }
+        return Ok((register(amount)? << 8) | (kind << 5) | (1 << 4) | rm);
+    }
+    let amount = immediate(amount)?;
+    let encoded = match (kind, amount) {
+        (0, 0..=31) => amount,
+        // a shift right by 32 is encoded as 0
+        (1, 1..=32) | (2, 1..=32) => amount % 32,
+        (3, 1..=31) => amount,
+        _ => return Err(format!("shift by {} is out of range", amount)),
+    };
+    Ok(((encoded as u32) << 7) | (kind << 5) | rm)
+}
+
+fn data_processing(base: &str, suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    let opcode = DATA_OPS.iter().position(|name| *name == base).unwrap() as u32;
+    let (rd, rn, operand) = match opcode {
+        // tst, teq, cmp and cmn always set the flags
+        8..=11 => (0, register(&operands[0])?, &operands[1..]),
+        13 | 15 => (register(&operands[0])?, 0, &operands[1..]),
+        _ => {
+            if operands.len() < 3 {
+                return Err(String::from("expected 3 operands"));
+            }
+            (register(&operands[0])?, register(&operands[1])?, &operands[2..])
+        },
+    };
+    if operand.is_empty() {
+        return Err(String::from("missing the second operand"));
+    }
+    let s = suffix == "s" || (8..=11).contains(&opcode);
+    let operand = if operand[0].starts_with('#') {
+        expect_count(operand, 1)?;
+        (1 << 25) | arm_immediate(immediate(&operand[0])?)?
+    }
+    else {
+        shifted_register(operand, true)?
+    };
+    Ok((opcode << 21) | ((s as u32) << 20) | (rn << 16) | (rd << 12) | operand)
+}
+
+// cpsr or spsr, the second value is the field mask
+fn psr(text: &str) -> Result<(u32, u32), String> {
+    let (name, fields) = text.split_once('_').unwrap_or((text, "all"));
+    let spsr = match name {
+        "cpsr" => 0,
+        "spsr" => 1,
+        _ => return Err(format!("{} is not cpsr or spsr", text)),
+    };
+    let mask = match fields {
+        "all" => 0b1001,
+        "flg" => 0b1000,
+        "ctl" => 0b0001,
+        _ => {
+            let mut mask = 0;
+            for field in fields.chars() {
+                mask |= match field {
+                    'f' => 0b1000,
+                    's' => 0b0100,
+                    'x' => 0b0010,
+                    'c' => 0b0001,
+                    _ => return Err(format!("{} is not a PSR field", field)),
+                };
+            }
+            mask
+        },
+    };
+    Ok((spsr, mask))
+}
+
+fn mrs(_base: &str, _suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    expect_count(operands, 2)?;
+    let (spsr, _) = psr(&operands[1])?;
+    Ok(0x010F0000 | (spsr << 22) | (register(&operands[0])? << 12))
+}
+
+fn msr(_base: &str, _suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    expect_count(operands, 2)?;
+    let (spsr, mask) = psr(&operands[0])?;
+    let source = if operands[1].starts_with('#') {(1 << 25) | arm_immediate(immediate(&operands[1])?)?} else {register(&operands[1])?};
+    Ok(0x0120F000 | (spsr << 22) | (mask << 16) | source)
+}
+
+fn multiply(base: &str, suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    let accumulate = base == "mla";
+    expect_count(operands, if accumulate {4} else {3})?;
+    let rn = if accumulate {register(&operands[3])?} else {0};
+    Ok(((accumulate as u32) << 21) | (((suffix == "s") as u32) << 20) | (register(&operands[0])? << 16) | (rn << 12)
+        | (register(&operands[2])? << 8) | 0x90 | register(&operands[1])?)
+}
+
+fn multiply_long(base: &str, suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    expect_count(operands, 4)?;
+    let signed = base.starts_with('s');
+    let accumulate = base.ends_with("mlal");
+    Ok(0x00800090 | ((signed as u32) << 22) | ((accumulate as u32) << 21) | (((suffix == "s") as u32) << 20)
+        | (register(&operands[1])? << 16) | (register(&operands[0])? << 12) | (register(&operands[3])? << 8) | register(&operands[2])?)
+}
+
+fn single_data_swap(_base: &str, suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    expect_count(operands, 3)?;
+    let rn = operands[2].strip_prefix('[').and_then(|rn| rn.strip_suffix(']')).ok_or("the address of a swap is [rn]")?;
+    Ok(0x01000090 | (((suffix == "b") as u32) << 22) | (register(rn)? << 16) | (register(&operands[0])? << 12) | register(&operands[1])?)
+}
+
+enum Offset {
+    Immediate(u32),
+    // rm and its shift
+    Register(Vec<String>),
+}
+
+struct Address {
+    rn: u32,
+    pre_indexed: bool,
+    up: bool,
+    write_back: bool,
+    offset: Offset,
+}
+
+// [rn, offset]{!} or [rn], offset
+fn address(operands: &[String]) -> Result<Address, String> {
+    let first = operands.first().ok_or("missing the address")?;
+    let write_back = first.ends_with('!');
+    let bracketed = first.trim_end_matches('!').trim();
+    let inner = bracketed.strip_prefix('[').and_then(|inner| inner.strip_suffix(']')).ok_or_else(|| format!("{} is not an address", first))?;
+    let mut parts: Vec<String> = inner.split(',').map(|part| part.trim().to_string()).collect();
+    let rn = register(&parts.remove(0))?;
+    let pre_indexed = operands.len() == 1;
+    let offset_operands = if pre_indexed {parts} else {operands[1..].to_vec()};
+    if offset_operands.is_empty() {
+        return Ok(Address {rn, pre_indexed: true, up: true, write_back, offset: Offset::Immediate(0)});
+    }
+    if offset_operands[0].starts_with('#') {
+        if offset_operands.len() > 1 {
+            return Err(String::from("too many operands"));
+        }
+        let value = immediate(&offset_operands[0])?;
+        // #-0 is a down offset of zero
+        let up = !offset_operands[0].starts_with("#-");
+        return Ok(Address {rn, pre_indexed, up, write_back, offset: Offset::Immediate(value.unsigned_abs() as u32)});
+    }
+    let mut register_operands = offset_operands.clone();
+    let up = !register_operands[0].starts_with('-');
+    register_operands[0] = register_operands[0].trim_start_matches(['-', '+']).to_string();
+    Ok(Address {rn, pre_indexed, up, write_back, offset: Offset::Register(register_operands)})
+}
+
+fn data_transfer(base: &str, suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    let load = base == "ldr";
+    let rd = register(operands.first().ok_or("missing the register")?)?;
+    let address = address(&operands[1..])?;
+    let flags = ((address.pre_indexed as u32) << 24) | ((address.up as u32) << 23) | ((load as u32) << 20) | (address.rn << 16) | (rd << 12);
+    if let "h" | "sb" | "sh" = suffix {
+        let kind = match suffix {
+            "h" => 1,
+            "sb" => 2,
+            _ => 3,
+        };
+        let offset = match address.offset {
+            Offset::Immediate(offset) => {
+                let offset = unsigned(offset as i64, 0x100, "offset")?;
+                (1 << 22) | ((offset & 0xF0) << 4) | (offset & 0xF)
+            },
+            Offset::Register(operands) => {
+                if operands.len() != 1 {
+                    return Err(String::from("halfword transfers can't shift the offset"));
+                }
+                register(&operands[0])?
+            },
+        };
+        return Ok(flags | ((address.write_back as u32) << 21) | 0x90 | (kind << 5) | offset);
+    }
+    let byte = suffix.starts_with('b');
+    // the user mode access is written as post-indexed with write back
+    let user_mode = suffix.ends_with('t');
+    if user_mode && address.pre_indexed {
+        return Err(String::from("the t suffix needs a post-indexed address"));
+    }
+    let offset = match address.offset {
+        Offset::Immediate(offset) => unsigned(offset as i64, 0x1000, "offset")?,
+        Offset::Register(operands) => (1 << 25) | shifted_register(&operands, false)?,
+    };
+    Ok(0x04000000 | flags | ((byte as u32) << 22) | (((address.write_back || user_mode) as u32) << 21) | offset)
+}
+
+fn block_data_transfer(base: &str, suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    expect_count(operands, 2)?;
+    let load = base == "ldm";
+    // the stack names mean the opposite for loads and stores
+    let mode = match (load, suffix) {
+        (_, "ia") | (true, "fd") | (false, "ea") => (false, true),
+        (_, "ib") | (true, "ed") | (false, "fa") => (true, true),
+        (_, "da") | (true, "fa") | (false, "ed") => (false, false),
+        _ => (true, false),
+    };
+    let write_back = operands[0].ends_with('!');
+    let rn = register(operands[0].trim_end_matches('!'))?;
+    let (list, caret) = register_list(&operands[1])?;
+    Ok(0x08000000 | ((mode.0 as u32) << 24) | ((mode.1 as u32) << 23) | ((caret as u32) << 22) | ((write_back as u32) << 21)
+        | ((load as u32) << 20) | (rn << 16) | list)
+}
+
+fn push_pop(base: &str, _suffix: &str, operands: &[String], address: u32) -> Result<u32, String> {
+    expect_count(operands, 1)?;
+    let stack = [String::from("sp!"), operands[0].clone()];
+    if base == "push" {
+        return block_data_transfer("stm", "db", &stack, address);
+    }
+    return block_data_transfer("ldm", "ia", &stack, address);
+}
+
+fn branch(_base: &str, suffix: &str, operands: &[String], address: u32) -> Result<u32, String> {
+    expect_count(operands, 1)?;
+    if suffix == "x" {
+        return Ok(0x012FFF10 | register(&operands[0])?);
+    }
+    let target = number(operands[0].trim_start_matches('#'))? as u32;
+    let offset = target.wrapping_sub(address.wrapping_add(8)) as i32;
+    if offset % 4 != 0 || !(-(1 << 25)..(1 << 25)).contains(&offset) {
+        return Err(format!("branch target {:#010x} is out of reach", target));
+    }
+    Ok(0x0A000000 | (((suffix == "l") as u32) << 24) | ((offset >> 2) as u32 & 0xFFFFFF))
+}
+
+fn software_interrupt(_base: &str, _suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    expect_count(operands, 1)?;
+    let comment = operands[0].trim_start_matches('#');
+    Ok(0x0F000000 | unsigned(number(comment)?, 1 << 24, "comment")?)
+}
+
+fn coprocessor_data_operation(_base: &str, _suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    expect_count(operands, 6)?;
+    Ok(0x0E000000 | (coprocessor_opcode(&operands[1], 16)? << 20) | (prefixed(&operands[3], 'c')? << 16) | (prefixed(&operands[2], 'c')? << 12)
+        | (prefixed(&operands[0], 'p')? << 8) | (coprocessor_opcode(&operands[5], 8)? << 5) | prefixed(&operands[4], 'c')?)
+}
+
+fn coprocessor_register_transfer(base: &str, _suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    expect_count(operands, 6)?;
+    let load = base == "mrc";
+    Ok(0x0E000010 | (coprocessor_opcode(&operands[1], 8)? << 21) | ((load as u32) << 20) | (prefixed(&operands[3], 'c')? << 16)
+        | (register(&operands[2])? << 12) | (prefixed(&operands[0], 'p')? << 8) | (coprocessor_opcode(&operands[5], 8)? << 5)
+        | prefixed(&operands[4], 'c')?)
+}
+
+fn coprocessor_data_transfer(base: &str, suffix: &str, operands: &[String], _address: u32) -> Result<u32, String> {
+    if operands.len() < 3 {
+        return Err(String::from("expected a coprocessor, a register and an address"));
+    }
+    // post-indexed always writes back here, W clear would be the unindexed form
+    let address = address(&operands[2..])?;
+    let offset = match address.offset {
+        Offset::Immediate(offset) if offset % 4 == 0 => unsigned(offset as i64 / 4, 0x100, "offset")?,
+        _ => return Err(String::from("coprocessor transfers need an immediate offset in words")),
+    };
+    Ok(0x0C000000 | ((address.pre_indexed as u32) << 24) | ((address.up as u32) << 23) | (((suffix == "l") as u32) << 22)
+        | (((address.write_back || !address.pre_indexed) as u32) << 21) | (((base == "ldc") as u32) << 20) | (address.rn << 16)
+        | (prefixed(&operands[1], 'c')? << 12) | (prefixed(&operands[0], 'p')? << 8) | offset)
+}
+
+/*
+    THUMB
+*/
+
+pub fn try_thumb(text: &str, address: u32) -> Result<u32, String> {
+    let (mnemonic, operands) = split(text);
+    if mnemonic == ".hword" || mnemonic == "undefined" {
+        expect_count(&operands, 1)?;
+        return Ok(number(&operands[0])? as u32 & 0xFFFF);
+    }
+    if let Some(encoding) = thumb_branch(&mnemonic, &operands, address)? {
+        return Ok(encoding);
+    }
+    // everything but the hi register operations sets the flags anyway, so UAL's s suffix changes nothing
+    let mnemonic = match mnemonic.strip_suffix('s') {
+        Some(base) if THUMB_ALU_OPS.contains(&base) || ["add", "sub", "mov"].contains(&base) => base,
+        _ => &mnemonic,
+    };
+    let ops = &operands;
+    let is_immediate = |index: usize| ops.get(index).is_some_and(|operand| operand.starts_with('#'));
+    match mnemonic {
+        "lsl" | "lsr" | "asr" if ops.len() == 3 => {
+            let opcode = SHIFTS.iter().position(|name| *name == mnemonic).unwrap() as u32;
+            let limit = if opcode == 0 {32} else {33};
+            // a shift right by 32 is encoded as 0
+            let amount = unsigned(immediate(&ops[2])?, limit, "shift")? % 32;
+            return Ok((opcode << 11) | (amount << 6) | (low_register(&ops[1])? << 3) | low_register(&ops[0])?);
+        },
+        "add" | "sub" if ops.len() == 3 && ops[1] != "pc" && ops[1] != "sp" => {
+            let sub = (mnemonic == "sub") as u32;
+            let (immediate_flag, operand) = if is_immediate(2) {(1, unsigned(immediate(&ops[2])?, 8, "immediate")?)} else {(0, low_register(&ops[2])?)};
+            return Ok(0x1800 | (immediate_flag << 10) | (sub << 9) | (operand << 6) | (low_register(&ops[1])? << 3) | low_register(&ops[0])?);
+        },
+        "add" if ops.len() == 3 => {
+            // load address, relative to the PC or SP
+            expect_word_offset(&ops[2], 0x400)?;
+            let sp = (ops[1] == "sp") as u32;
+            return Ok(0xA000 | (sp << 11) | (low_register(&ops[0])? << 8) | (immediate(&ops[2])? as u32 >> 2));
+        },
+        "add" | "sub" if ops.len() == 2 && ops[0] == "sp" => {
+            let value = immediate(&ops[1])?;
+            let value = if mnemonic == "sub" {-value} else {value};
+            expect_word_offset(&format!("#{}", value.abs()), 0x200)?;
+            return Ok(0xB000 | (((value < 0) as u32) << 7) | (value.unsigned_abs() as u32 >> 2));
+        },
+        "mov" | "cmp" | "add" | "sub" if is_immediate(1) => {
+            expect_count(ops, 2)?;
+            let opcode = ["mov", "cmp", "add", "sub"].iter().position(|name| *name == mnemonic).unwrap() as u32;
+            return Ok(0x2000 | (opcode << 11) | (low_register(&ops[0])? << 8) | unsigned(immediate(&ops[1])?, 0x100, "immediate")?);
+        },
+        "add" | "cmp" | "mov" => {
+            expect_count(ops, 2)?;
+            let (rd, rs) = (register(&ops[0])?, register(&ops[1])?);
+            if rd < 8 && rs < 8 {
+                // both low, the hi register form isn't defined for that
+                match mnemonic {
+                    "add" => return Ok(0x1800 | (rs << 6) | (rd << 3) | rd),
+                    "cmp" => return Ok(0x4000 | (10 << 6) | (rs << 3) | rd),
+                    _ => return Ok(0x1C00 | (rs << 3) | rd),
+                }
+            }
+            let opcode = ["add", "cmp", "mov"].iter().position(|name| *name == mnemonic).unwrap() as u32;
+            return Ok(0x4400 | (opcode << 8) | ((rd >> 3) << 7) | (rs << 3) | (rd & 7));
+        },
+        "bx" => {
+            expect_count(ops, 1)?;
+            return Ok(0x4700 | (register(&ops[0])? << 3));
+        },
+        "ldr" | "str" | "ldrb" | "strb" | "ldrh" | "strh" | "ldsb" | "ldsh" | "ldrsb" | "ldrsh" => {
+            return thumb_data_transfer(mnemonic, ops);
+        },
+        "push" | "pop" => {
+            expect_count(ops, 1)?;
+            let (list, _) = register_list(&ops[0])?;
+            // push can also store the LR and pop load the PC
+            let extra = if mnemonic == "push" {1 << 14} else {1 << 15};
+            if list & !(0xFF | extra) != 0 {
+                return Err(format!("{} can only use r0-r7 and {}", mnemonic, if mnemonic == "push" {"lr"} else {"pc"}));
+            }
+            let pop = (mnemonic == "pop") as u32;
+            return Ok(0xB400 | (pop << 11) | (((list & extra != 0) as u32) << 8) | (list & 0xFF));
+        },
+        "stmia" | "ldmia" | "stm" | "ldm" => {
+            expect_count(ops, 2)?;
+            let (list, _) = register_list(&ops[1])?;
+            if list & !0xFF != 0 {
+                return Err(String::from("only r0-r7 can be transferred"));
+            }
+            let load = mnemonic.starts_with("ldm") as u32;
+            return Ok(0xC000 | (load << 11) | (low_register(ops[0].trim_end_matches('!'))? << 8) | list);
+        },
+        "swi" | "svc" => {
+            expect_count(ops, 1)?;
+            return Ok(0xDF00 | unsigned(immediate(&ops[0])?, 0x100, "comment")?);
+        },
+        _ => {},
+    }
+    if let Some(opcode) = THUMB_ALU_OPS.iter().position(|name| *name == mnemonic) {
+        // UAL writes mul with the destination repeated at the end
+        if !(ops.len() == 2 || (mnemonic == "mul" && ops.len() == 3 && ops[2] == ops[0])) {
+            return Err(String::from("expected 2 registers"));
+        }
+        return Ok(0x4000 | ((opcode as u32) << 6) | (low_register(&ops[1])? << 3) | low_register(&ops[0])?);
+    }
+    return Err(format!("unknown mnemonic {}", mnemonic));
+}
+
+fn expect_word_offset(text: &str, limit: i64) -> Result<(), String> {
+    let value = immediate(text)?;
+    if value % 4 != 0 {
+        return Err(format!("{} is not a multiple of 4", text));
+    }
+    unsigned(value, limit, "offset")?;
+    Ok(())
+}
+
+// b, b{cond} and bl, None if it's not a branch
+fn thumb_branch(mnemonic: &str, operands: &[String], address: u32) -> Result<Option<u32>, String> {
+    let cond = match mnemonic.strip_prefix('b') {
+        Some("") | Some("al") => None,
+        Some("l") => {
+            expect_count(operands, 1)?;
+            let target = number(&operands[0])? as u32;
+            let offset = target.wrapping_sub(address.wrapping_add(4)) as i32;
+            if offset % 2 != 0 || !(-(1 << 22)..(1 << 22)).contains(&offset) {
+                return Err(format!("branch target {:#010x} is out of reach", target));
+            }
+            let high = 0xF000 | ((offset >> 12) as u32 & 0x7FF);
+            let low = 0xF800 | ((offset >> 1) as u32 & 0x7FF);
+            return Ok(Some((low << 16) | high));
+        },
+        Some(cond) => match CONDITIONS[..14].iter().position(|name| *name == cond) {
+            Some(index) => Some(index as u32),
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+    expect_count(operands, 1)?;
+    let target = number(&operands[0])? as u32;
+    let offset = target.wrapping_sub(address.wrapping_add(4)) as i32;
+    let range = if cond.is_some() {1 << 8} else {1 << 11};
+    if offset % 2 != 0 || !(-range..range).contains(&offset) {
+        return Err(format!("branch target {:#010x} is out of reach", target));
+    }
+    match cond {
+        Some(cond) => return Ok(Some(0xD000 | (cond << 8) | ((offset >> 1) as u32 & 0xFF))),
+        None => return Ok(Some(0xE000 | ((offset >> 1) as u32 & 0x7FF))),
+    }
+}
+
+fn thumb_data_transfer(mnemonic: &str, operands: &[String]) -> Result<u32, String> {
+    expect_count(operands, 2)?;
+    let rd = low_register(&operands[0])?;
+    let inner = operands[1].strip_prefix('[').and_then(|inner| inner.strip_suffix(']')).ok_or_else(|| format!("{} is not an address", operands[1]))?;
+    let parts: Vec<&str> = inner.split(',').map(str::trim).collect();
+    let base = parts[0];
+    let offset = parts.get(1).copied().unwrap_or("#0");
+    if parts.len() > 2 {
+        return Err(String::from("THUMB addresses are [rb, ro] or [rb, #offset]"));
+    }
+    let load = mnemonic.starts_with("ld") as u32;
+    if !offset.starts_with('#') {
+        // register offset
+        let (rb, ro) = (low_register(base)?, low_register(offset)?);
+        let opcode = match mnemonic {
+            "str" => 0x5000,
+            "strb" => 0x5400,
+            "ldr" => 0x5800,
+            "ldrb" => 0x5C00,
+            "strh" => 0x5200,
+            "ldsb" | "ldrsb" => 0x5600,
+            "ldrh" => 0x5A00,
+            _ => 0x5E00,
+        };
+        return Ok(opcode | (ro << 6) | (rb << 3) | rd);
+    }
+    let value = immediate(offset)?;
+    match (mnemonic, base) {
+        ("ldr", "pc") => {
+            expect_word_offset(offset, 0x400)?;
+            return Ok(0x4800 | (rd << 8) | (value as u32 >> 2));
+        },
+        ("ldr", "sp") | ("str", "sp") => {
+            expect_word_offset(offset, 0x400)?;
+            return Ok(0x9000 | (load << 11) | (rd << 8) | (value as u32 >> 2));
+        },
+        ("ldr", _) | ("str", _) => {
+            expect_word_offset(offset, 0x80)?;
+            return Ok(0x6000 | (load << 11) | ((value as u32 >> 2) << 6) | (low_register(base)? << 3) | rd);
+        },
+        ("ldrb", _) | ("strb", _) => {
+            return Ok(0x7000 | (load << 11) | (unsigned(value, 0x20, "offset")? << 6) | (low_register(base)? << 3) | rd);
+        },
+        ("ldrh", _) | ("strh", _) => {
+            if value % 2 != 0 {
+                return Err(format!("{} is not a multiple of 2", offset));
+            }
+            return Ok(0x8000 | (load << 11) | (unsigned(value / 2, 0x20, "offset")? << 6) | (low_register(base)? << 3) | rd);
+        },
+        _ => return Err(format!("{} has no immediate offset form", mnemonic)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::instructions::arm::process_instruction_arm;
+    use crate::instructions::disasm::{self, tests::{ADDRESS, ARM_CORPUS, THUMB_CORPUS}};
+    use crate::instructions::thumb::process_instruction_thumb;
+
+    #[test]
+    fn arm_round_trip() {
+        for (instruction, text) in ARM_CORPUS {
+            assert_eq!(try_arm(text, ADDRESS), Ok(*instruction), "{}", text);
+        }
+    }
+
+    #[test]
+    fn thumb_round_trip() {
+        // the halves of a long branch with link on their own have no assembler syntax
+        for (instruction, text) in THUMB_CORPUS.iter().filter(|(instruction, _)| *instruction > 0xFFFF || *instruction & 0xF000 != 0xF000) {
+            assert_eq!(try_thumb(text, ADDRESS), Ok(*instruction), "{}", text);
+        }
+    }
+
+    #[test]
+    fn ual_syntax() {
+        assert_eq!(arm("adds r0, r1, r2, lsl #3"), 0xE0910182);
+        assert_eq!(arm("addseq r0, r1, r2"), arm("addeqs r0, r1, r2"));
+        assert_eq!(arm("ldrbne r0, [r1]"), arm("ldrneb r0, [r1]"));
+        assert_eq!(arm("push {r4-r7, lr}"), arm("stmfd sp!, {r4-r7, lr}"));
+        assert_eq!(arm("pop {r4-r7, pc}"), arm("ldmfd sp!, {r4-r7, pc}"));
+        assert_eq!(arm("svc #0x60000"), arm("swi 0x60000"));
+        assert_eq!(thumb("lsls r0, r1, #2"), thumb("lsl r0, r1, #2"));
+        assert_eq!(thumb("muls r4, r5, r4"), thumb("mul r4, r5"));
+        assert_eq!(thumb("bls 0x08000000"), 0xD9FE);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(try_arm("mov r0, #0x101", ADDRESS).is_err());
+        assert!(try_arm("frob r0", ADDRESS).is_err());
+        assert!(try_arm("ldr r0, [r1, #0x1000]", ADDRESS).is_err());
+        assert!(try_thumb("add r8, #1", ADDRESS).is_err());
+        assert!(try_thumb("beq 0x08001000", ADDRESS).is_err());
+        assert!(try_thumb("ldr r0, [r1, #3]", ADDRESS).is_err());
+    }
+
+    #[test]
+    fn through_the_disassembler() {
+        for text in ["rsbs r2, r3, r4, asr r5", "ldrsh r0, [r1, #-0x2]!", "stmib r0!, {r1, r3, r5}^", "bllt 0x07fffff8"] {
+            assert_eq!(disasm::arm(arm(text), ADDRESS), text);
+        }
+        for text in ["ror r1, r2", "ldsh r0, [r1, r2]", "bne 0x07fffffc", "bl 0x08400000"] {
+            assert_eq!(disasm::thumb(thumb(text), ADDRESS), text);
+        }
+    }
+
+    #[test]
+    fn executes() {
+        let mut cpu = CPU::new();
+        cpu.registers[0] = 5;
+        process_instruction_arm(&mut cpu, arm("add r1, r0, r0, lsl #1"));
+        assert_eq!(cpu.registers[1], 15);
+        process_instruction_thumb(&mut cpu, thumb("mul r1, r0"));
+        assert_eq!(cpu.registers[1], 75);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{cpu::{CPUMode, ConditionFlags, Registers::*, CPU}, instructions::masks_32bit::*, not_implemented};
+use crate::{cpu::{ConditionFlags, CPU}, instructions::masks_32bit::*};
 
 /*
     Logical ALU operations
@@ -78,7 +78,7 @@ pub fn mvn_op(cpu: &mut CPU, s: bool, _op1: u32, op2: u32) -> u32 {
 pub fn arithmetic_flag_helper(cpu: &mut CPU, s: bool, carry: bool, overflow: bool, res: u32) {
     if s {
         // z flag
-        if res != 0 {cpu.set_condition_flag(ConditionFlags::Z, true);} else {cpu.set_condition_flag(ConditionFlags::Z, false);} 
+        if res == 0 {cpu.set_condition_flag(ConditionFlags::Z, true);} else {cpu.set_condition_flag(ConditionFlags::Z, false);} 
         // n flag
         if res & B_31 != 0 {cpu.set_condition_flag(ConditionFlags::N, true);} else {cpu.set_condition_flag(ConditionFlags::N, false);}
         // v flag
@@ -89,23 +89,18 @@ pub fn arithmetic_flag_helper(cpu: &mut CPU, s: bool, carry: bool, overflow: boo
 }
 
 pub fn sub_op(cpu: &mut CPU, s: bool, op1: u32, op2: u32) -> u32 {
-    let (res, carry) = op1.overflowing_sub(op2);
+    // for subtractions the carry flag is set when there is no borrow
+    let (res, borrow) = op1.overflowing_sub(op2);
     let op1_sign = (op1 & B_31) != 0;
     let op2_sign = (op2 & B_31) != 0;
     let res_sign = (res & B_31) != 0;
-    let overflow = (op1_sign == op2_sign) && (op1_sign != res_sign);
-    arithmetic_flag_helper(cpu, s, carry, overflow, res);
+    let overflow = (op1_sign != op2_sign) && (op1_sign != res_sign);
+    arithmetic_flag_helper(cpu, s, !borrow, overflow, res);
     return res;
 }
 
 pub fn rsb_op(cpu: &mut CPU, s: bool, op1: u32, op2: u32) -> u32 {
-    let (res, carry) = op2.overflowing_sub(op1);
-    let op1_sign = (op1 & B_31) != 0;
-    let op2_sign = (op2 & B_31) != 0;
-    let res_sign = (res & B_31) != 0;
-    let overflow = (op1_sign == op2_sign) && (op1_sign != res_sign);
-    arithmetic_flag_helper(cpu, s, carry, overflow, res);
-    return res;
+    return sub_op(cpu, s, op2, op1);
 }
 
 pub fn add_op(cpu: &mut CPU, s: bool, op1: u32, op2: u32) -> u32 {
@@ -130,44 +125,28 @@ pub fn adc_op(cpu: &mut CPU, s: bool, op1: u32, op2: u32) -> u32 {
 }
 
 pub fn sbc_op(cpu: &mut CPU, s: bool, op1: u32, op2: u32) -> u32 {
-    let (tmp, carry1) = op1.overflowing_sub(op2);
-    let (res, carry2) = tmp.overflowing_sub(if cpu.get_condition_flag(ConditionFlags::C) {1} else {0} - 1);
+    // a clear carry flag borrows one
+    let (tmp, borrow1) = op1.overflowing_sub(op2);
+    let (res, borrow2) = tmp.overflowing_sub(if cpu.get_condition_flag(ConditionFlags::C) {0} else {1});
     let op1_sign = (op1 & B_31) != 0;
     let op2_sign = (op2 & B_31) != 0;
     let res_sign = (res & B_31) != 0;
-    let overflow = (op1_sign == op2_sign) && (op1_sign != res_sign);
-    arithmetic_flag_helper(cpu, s, carry1 || carry2, overflow, res);
+    let overflow = (op1_sign != op2_sign) && (op1_sign != res_sign);
+    arithmetic_flag_helper(cpu, s, !(borrow1 || borrow2), overflow, res);
     return res;
 }
 
 pub fn rsc_op(cpu: &mut CPU, s: bool, op1: u32, op2: u32) -> u32 {
-    let (tmp, carry1) = op2.overflowing_sub(op1);
-    let (res, carry2) = tmp.overflowing_sub(if cpu.get_condition_flag(ConditionFlags::C) {1} else {0} - 1);
-    let op1_sign = (op1 & B_31) != 0;
-    let op2_sign = (op2 & B_31) != 0;
-    let res_sign = (res & B_31) != 0;
-    let overflow = (op1_sign == op2_sign) && (op1_sign != res_sign);
-    arithmetic_flag_helper(cpu, s, carry1 || carry2, overflow, res);
-    return res;
+    return sbc_op(cpu, s, op2, op1);
 }
 
 pub fn cmp_op(cpu: &mut CPU, _s: bool, op1: u32, op2: u32) -> u32 {
-    let (res, carry) = op1.overflowing_sub(op2);
-    let op1_sign = (op1 & B_31) != 0;
-    let op2_sign = (op2 & B_31) != 0;
-    let res_sign = (res & B_31) != 0;
-    let overflow = (op1_sign == op2_sign) && (op1_sign != res_sign);
-    arithmetic_flag_helper(cpu, true, carry, overflow, res);
-    return 0;
+    sub_op(cpu, true, op1, op2);
+    return 0;  // no write to Rd
 }
 
 pub fn cmn_op(cpu: &mut CPU, _s: bool, op1: u32, op2: u32) -> u32 {
-    let (res, carry) = op1.overflowing_add(op2);
-    let op1_sign = (op1 & B_31) != 0;
-    let op2_sign = (op2 & B_31) != 0;
-    let res_sign = (res & B_31) != 0;
-    let overflow = (op1_sign == op2_sign) && (op1_sign != res_sign);
-    arithmetic_flag_helper(cpu, true, carry, overflow, res);
+    add_op(cpu, true, op1, op2);
     return 0;
 }
 
@@ -191,10 +170,10 @@ pub fn rotate_32bit(cpu: &mut CPU, s: bool, value: u32, amount: u32) -> u32 {
     if amount == 0 {
         carry_out = value & B_0;
         if cpu.get_condition_flag(ConditionFlags::C) {
-            result = ((value >> 1) | (value << (32 - 1))) | (1 << 31);
+            result = value.rotate_right(1) | (1 << 31);
         }
         else {
-            result = ((value >> 1) | (value << (32 - 1))) & !(1 << 31);
+            result = value.rotate_right(1) & !(1 << 31);
         }
         
     }
@@ -203,8 +182,8 @@ pub fn rotate_32bit(cpu: &mut CPU, s: bool, value: u32, amount: u32) -> u32 {
         result = value;
     }
     else {
-        carry_out = (value >> amount - 1) & B_0;
-        result = (value >> amount) | (value << (32 - amount));
+        carry_out = (value >> (amount - 1)) & B_0;
+        result = value.rotate_right(amount);
     }
     
     if s {
@@ -225,9 +204,14 @@ pub fn logical_left_32bit(cpu: &mut CPU, s: bool, value: u32, amount: u32) -> u3
     let carry_out: u32;
     let result: u32;
 
+    // LSL #0 passes the value through, the carry flag is left alone below
+    if amount == 0 {
+        carry_out = 0;
+        result = value;
+    }
     // in case the amount is 32, apply the special rule from the ARM instruction manual:
     // zero result, carry out is first bit of input
-    if amount == 32 {
+    else if amount == 32 {
         carry_out = B_0 & value;
         result = 0;
     }
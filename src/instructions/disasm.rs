@@ -0,0 +1,540 @@
+use crate::instructions::{arm::ARM_OPCODES, thumb::THUMB_OPCODES};
+
+// disassembler in the syntax of the ARM7TDMI data sheet, condition before the other suffixes (ldreqb, addnes)
+// the instruction is decoded with the same tables the CPU uses, each entry names the function that formats it
+// address is where the instruction is, PC relative targets are resolved with it
+
+// instruction word and its address
+pub type DisasmFn = fn(u32, u32) -> String;
+
+pub(crate) const CONDITIONS: [&str; 16] = ["eq", "ne", "cs", "cc", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", "", "nv"];
+pub(crate) const REGISTERS: [&str; 16] = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr", "pc"];
+pub(crate) const SHIFTS: [&str; 4] = ["lsl", "lsr", "asr", "ror"];
+pub(crate) const DATA_OPS: [&str; 16] = ["and", "eor", "sub", "rsb", "add", "adc", "sbc", "rsc", "tst", "teq", "cmp", "cmn", "orr", "mov", "bic", "mvn"];
+pub(crate) const THUMB_ALU_OPS: [&str; 16] = ["and", "eor", "lsl", "lsr", "asr", "adc", "sbc", "ror", "tst", "neg", "cmp", "cmn", "orr", "mul", "bic", "mvn"];
+
+pub fn arm(instruction: u32, address: u32) -> String {
+    match ARM_OPCODES.iter().find(|(pattern, mask, _, _)| instruction & mask == *pattern) {
+        Some((_, _, _, disasm)) => return disasm(instruction, address),
+        None => return format!(".word {:#010x}", instruction),
+    }
+}
+
+// the upper halfword can hold the next instruction, then both halves of a long branch with link are shown as one
+pub fn thumb(instruction: u32, address: u32) -> String {
+    match THUMB_OPCODES.iter().find(|(pattern, mask, _, _)| instruction as u16 & mask == *pattern) {
+        Some((_, _, _, disasm)) => return disasm(instruction, address),
+        None => return format!(".hword {:#06x}", instruction & 0xFFFF),
+    }
+}
+
+fn bits(instruction: u32, high: u32, low: u32) -> u32 {
+    return (instruction >> low) & ((1 << (high - low + 1)) - 1);
+}
+
+fn bit(instruction: u32, bit: u32) -> bool {
+    return instruction & (1 << bit) != 0;
+}
+
+fn cond(instruction: u32) -> &'static str {
+    return CONDITIONS[bits(instruction, 31, 28) as usize];
+}
+
+fn reg(instruction: u32, low: u32) -> &'static str {
+    return REGISTERS[bits(instruction, low + 3, low) as usize];
+}
+
+fn thumb_reg(instruction: u32, low: u32) -> &'static str {
+    return REGISTERS[bits(instruction, low + 2, low) as usize];
+}
+
+// offsets are shown with their sign in front of the hex number
+fn signed_hex(value: u32, up: bool) -> String {
+    return format!("#{}{:#x}", if up {""} else {"-"}, value);
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    return (((value << (32 - bits)) as i32) >> (32 - bits)) as u32;
+}
+
+// {r0-r3, lr}, consecutive registers are merged into a range
+fn register_list(list: u32) -> String {
+    let mut parts = Vec::new();
+    let mut register = 0;
+    while register < 16 {
+        if list & (1 << register) == 0 {
+            register += 1;
+            continue;
+        }
+        let start = register;
+        while register < 16 && list & (1 << register) != 0 {
+            register += 1;
+        }
+        match register - start {
+            1 => parts.push(REGISTERS[start].to_string()),
+            2 => parts.push(format!("{}, {}", REGISTERS[start], REGISTERS[start + 1])),
+            _ => parts.push(format!("{}-{}", REGISTERS[start], REGISTERS[register - 1])),
+        }
+    }
+    return format!("{{{}}}", parts.join(", "));
+}
+
+// 8 bit immediate rotated right by twice the 4 bit rotation
+fn arm_immediate(instruction: u32) -> u32 {
+    return bits(instruction, 7, 0).rotate_right(bits(instruction, 11, 8) * 2);
+}
+
+// register operand with its shift, by an immediate or a register
+fn shifted_register(instruction: u32) -> String {
+    let rm = reg(instruction, 0);
+    let shift = bits(instruction, 6, 5);
+    if bit(instruction, 4) {
+        return format!("{}, {} {}", rm, SHIFTS[shift as usize], reg(instruction, 8));
+    }
+    let amount = bits(instruction, 11, 7);
+    match (shift, amount) {
+        (0, 0) => return rm.to_string(),
+        // a shift right by 0 is encoded as 32
+        (1, 0) | (2, 0) => return format!("{}, {} #32", rm, SHIFTS[shift as usize]),
+        (3, 0) => return format!("{}, rrx", rm),
+        _ => return format!("{}, {} #{}", rm, SHIFTS[shift as usize], amount),
+    }
+}
+
+/*
+    ARM
+*/
+
+pub fn data_processing(instruction: u32, address: u32) -> String {
+    let opcode = bits(instruction, 24, 21);
+    let name = DATA_OPS[opcode as usize];
+    let s = if bit(instruction, 20) {"s"} else {""};
+    let immediate = bit(instruction, 25);
+    let operand = if immediate {format!("#{:#x}", arm_immediate(instruction))} else {shifted_register(instruction)};
+    let rd = reg(instruction, 12);
+    let rn = reg(instruction, 16);
+    match opcode {
+        // tst, teq, cmp and cmn always set the flags and have no destination
+        8..=11 => return format!("{}{} {}, {}", name, cond(instruction), rn, operand),
+        // mov and mvn have no first operand
+        13 | 15 => return format!("{}{}{} {}, {}", name, cond(instruction), s, rd, operand),
+        _ => {},
+    }
+    let text = format!("{}{}{} {}, {}, {}", name, cond(instruction), s, rd, rn, operand);
+    // add and sub on the PC calculate an address
+    if immediate && bits(instruction, 19, 16) == 15 && (opcode == 2 || opcode == 4) {
+        let pc = address.wrapping_add(8);
+        let target = if opcode == 4 {pc.wrapping_add(arm_immediate(instruction))} else {pc.wrapping_sub(arm_immediate(instruction))};
+        return format!("{} ; {:#010x}", text, target);
+    }
+    return text;
+}
+
+pub fn mrs(instruction: u32, _address: u32) -> String {
+    let psr = if bit(instruction, 22) {"spsr"} else {"cpsr"};
+    return format!("mrs{} {}, {}", cond(instruction), reg(instruction, 12), psr);
+}
+
+pub fn msr(instruction: u32, _address: u32) -> String {
+    let psr = if bit(instruction, 22) {"spsr"} else {"cpsr"};
+    let fields: String = [(19, 'f'), (18, 's'), (17, 'x'), (16, 'c')].iter()
+        .filter(|(field_bit, _)| bit(instruction, *field_bit))
+        .map(|(_, field)| *field)
+        .collect();
+    let source = if bit(instruction, 25) {format!("#{:#x}", arm_immediate(instruction))} else {reg(instruction, 0).to_string()};
+    return format!("msr{} {}_{}, {}", cond(instruction), psr, fields, source);
+}
+
+pub fn multiply(instruction: u32, _address: u32) -> String {
+    let s = if bit(instruction, 20) {"s"} else {""};
+    let (rd, rm, rs) = (reg(instruction, 16), reg(instruction, 0), reg(instruction, 8));
+    if bit(instruction, 21) {
+        return format!("mla{}{} {}, {}, {}, {}", cond(instruction), s, rd, rm, rs, reg(instruction, 12));
+    }
+    return format!("mul{}{} {}, {}, {}", cond(instruction), s, rd, rm, rs);
+}
+
+pub fn multiply_long(instruction: u32, _address: u32) -> String {
+    let sign = if bit(instruction, 22) {"s"} else {"u"};
+    let operation = if bit(instruction, 21) {"mlal"} else {"mull"};
+    let s = if bit(instruction, 20) {"s"} else {""};
+    return format!("{}{}{}{} {}, {}, {}, {}", sign, operation, cond(instruction), s,
+        reg(instruction, 12), reg(instruction, 16), reg(instruction, 0), reg(instruction, 8));
+}
+
+pub fn single_data_swap(instruction: u32, _address: u32) -> String {
+    let b = if bit(instruction, 22) {"b"} else {""};
+    return format!("swp{}{} {}, {}, [{}]", cond(instruction), b, reg(instruction, 12), reg(instruction, 0), reg(instruction, 16));
+}
+
+pub fn branch_and_exchange(instruction: u32, _address: u32) -> String {
+    return format!("bx{} {}", cond(instruction), reg(instruction, 0));
+}
+
+// [rn, offset]{!} or [rn], offset, loads relative to the PC also show the address they read
+fn transfer_address(instruction: u32, address: u32, offset: Option<u32>, register_offset: String) -> String {
+    let rn = reg(instruction, 16);
+    let pre_indexed = bit(instruction, 24);
+    let up = bit(instruction, 23);
+    let write_back = if bit(instruction, 21) {"!"} else {""};
+    let offset_text = match offset {
+        Some(offset) => signed_hex(offset, up),
+        None => format!("{}{}", if up {""} else {"-"}, register_offset),
+    };
+    if !pre_indexed {
+        return format!("[{}], {}", rn, offset_text);
+    }
+    if offset == Some(0) {
+        return format!("[{}]{}", rn, write_back);
+    }
+    let text = format!("[{}, {}]{}", rn, offset_text, write_back);
+    if let (Some(offset), 15, "") = (offset, bits(instruction, 19, 16), write_back) {
+        let pc = address.wrapping_add(8);
+        let target = if up {pc.wrapping_add(offset)} else {pc.wrapping_sub(offset)};
+        return format!("{} ; {:#010x}", text, target);
+    }
+    return text;
+}
+
+pub fn halfword_signed_data_transfer(instruction: u32, address: u32) -> String {
+    let load = bit(instruction, 20);
+    let suffix = match (load, bits(instruction, 6, 5)) {
+        (true, 2) => "sb",
+        (true, 3) => "sh",
+        _ => "h",
+    };
+    let offset = if bit(instruction, 22) {Some((bits(instruction, 11, 8) << 4) | bits(instruction, 3, 0))} else {None};
+    let name = if load {"ldr"} else {"str"};
+    return format!("{}{}{} {}, {}", name, cond(instruction), suffix, reg(instruction, 12),
+        transfer_address(instruction, address, offset, reg(instruction, 0).to_string()));
+}
+
+pub fn single_data_transfer(instruction: u32, address: u32) -> String {
+    let name = if bit(instruction, 20) {"ldr"} else {"str"};
+    let b = if bit(instruction, 22) {"b"} else {""};
+    // post-indexed with write back forces a user mode access
+    let t = if !bit(instruction, 24) && bit(instruction, 21) {"t"} else {""};
+    let offset = if bit(instruction, 25) {None} else {Some(bits(instruction, 11, 0))};
+    return format!("{}{}{}{} {}, {}", name, cond(instruction), b, t, reg(instruction, 12),
+        transfer_address(instruction, address, offset, shifted_register(instruction)));
+}
+
+pub fn undefined(instruction: u32, _address: u32) -> String {
+    return format!("undefined {:#010x}", instruction);
+}
+
+pub fn block_data_transfer(instruction: u32, _address: u32) -> String {
+    let name = if bit(instruction, 20) {"ldm"} else {"stm"};
+    let mode = match (bit(instruction, 24), bit(instruction, 23)) {
+        (false, false) => "da",
+        (false, true) => "ia",
+        (true, false) => "db",
+        (true, true) => "ib",
+    };
+    let write_back = if bit(instruction, 21) {"!"} else {""};
+    // user bank transfer, or with the PC in a load the SPSR is restored
+    let s = if bit(instruction, 22) {"^"} else {""};
+    return format!("{}{}{} {}{}, {}{}", name, cond(instruction), mode, reg(instruction, 16), write_back,
+        register_list(bits(instruction, 15, 0)), s);
+}
+
+pub fn branch(instruction: u32, address: u32) -> String {
+    let link = if bit(instruction, 24) {"l"} else {""};
+    let offset = sign_extend(bits(instruction, 23, 0), 24) << 2;
+    return format!("b{}{} {:#010x}", link, cond(instruction), address.wrapping_add(8).wrapping_add(offset));
+}
+
+pub fn coprocessor_data_transfer(instruction: u32, address: u32) -> String {
+    let name = if bit(instruction, 20) {"ldc"} else {"stc"};
+    let long = if bit(instruction, 22) {"l"} else {""};
+    let offset = bits(instruction, 7, 0) << 2;
+    return format!("{}{}{} p{}, c{}, {}", name, cond(instruction), long, bits(instruction, 11, 8), bits(instruction, 15, 12),
+        transfer_address(instruction, address, Some(offset), String::new()));
+}
+
+pub fn coprocessor_data_operation(instruction: u32, _address: u32) -> String {
+    return format!("cdp{} p{}, {}, c{}, c{}, c{}, {}", cond(instruction), bits(instruction, 11, 8), bits(instruction, 23, 20),
+        bits(instruction, 15, 12), bits(instruction, 19, 16), bits(instruction, 3, 0), bits(instruction, 7, 5));
+}
+
+pub fn coprocessor_register_transfer(instruction: u32, _address: u32) -> String {
+    let name = if bit(instruction, 20) {"mrc"} else {"mcr"};
+    return format!("{}{} p{}, {}, {}, c{}, c{}, {}", name, cond(instruction), bits(instruction, 11, 8), bits(instruction, 23, 21),
+        reg(instruction, 12), bits(instruction, 19, 16), bits(instruction, 3, 0), bits(instruction, 7, 5));
+}
+
+pub fn software_interrupt(instruction: u32, _address: u32) -> String {
+    return format!("swi{} #{:#x}", cond(instruction), bits(instruction, 23, 0));
+}
+
+/*
+    THUMB, the PC is 4 bytes ahead
+*/
+
+pub fn thumb_move_shifted_register(instruction: u32, _address: u32) -> String {
+    let opcode = bits(instruction, 12, 11);
+    let mut amount = bits(instruction, 10, 6);
+    if amount == 0 && opcode != 0 {
+        amount = 32;
+    }
+    return format!("{} {}, {}, #{}", SHIFTS[opcode as usize], thumb_reg(instruction, 0), thumb_reg(instruction, 3), amount);
+}
+
+pub fn thumb_add_subtract(instruction: u32, _address: u32) -> String {
+    let name = if bit(instruction, 9) {"sub"} else {"add"};
+    let operand = if bit(instruction, 10) {format!("#{}", bits(instruction, 8, 6))} else {thumb_reg(instruction, 6).to_string()};
+    return format!("{} {}, {}, {}", name, thumb_reg(instruction, 0), thumb_reg(instruction, 3), operand);
+}
+
+pub fn thumb_move_compare_add_subtract_immediate(instruction: u32, _address: u32) -> String {
+    let name = ["mov", "cmp", "add", "sub"][bits(instruction, 12, 11) as usize];
+    return format!("{} {}, #{:#x}", name, thumb_reg(instruction, 8), bits(instruction, 7, 0));
+}
+
+pub fn thumb_alu_operations(instruction: u32, _address: u32) -> String {
+    return format!("{} {}, {}", THUMB_ALU_OPS[bits(instruction, 9, 6) as usize], thumb_reg(instruction, 0), thumb_reg(instruction, 3));
+}
+
+pub fn thumb_hi_register_operations_be(instruction: u32, _address: u32) -> String {
+    // H1 and H2 extend the registers to r8-r15
+    let rd = REGISTERS[(bits(instruction, 2, 0) | (bits(instruction, 7, 7) << 3)) as usize];
+    let rs = REGISTERS[bits(instruction, 6, 3) as usize];
+    match bits(instruction, 9, 8) {
+        0 => return format!("add {}, {}", rd, rs),
+        1 => return format!("cmp {}, {}", rd, rs),
+        2 => return format!("mov {}, {}", rd, rs),
+        _ => return format!("bx {}", rs),
+    }
+}
+
+pub fn thumb_pc_relative_load(instruction: u32, address: u32) -> String {
+    let offset = bits(instruction, 7, 0) << 2;
+    // the PC is word aligned for the load
+    let target = (address.wrapping_add(4) & !2).wrapping_add(offset);
+    return format!("ldr {}, [pc, #{:#x}] ; {:#010x}", thumb_reg(instruction, 8), offset, target);
+}
+
+pub fn thumb_load_store_register_offset(instruction: u32, _address: u32) -> String {
+    let name = ["str", "strb", "ldr", "ldrb"][bits(instruction, 11, 10) as usize];
+    return format!("{} {}, [{}, {}]", name, thumb_reg(instruction, 0), thumb_reg(instruction, 3), thumb_reg(instruction, 6));
+}
+
+pub fn thumb_load_store_sign_extended(instruction: u32, _address: u32) -> String {
+    let name = ["strh", "ldsb", "ldrh", "ldsh"][(bits(instruction, 10, 10) | (bits(instruction, 11, 11) << 1)) as usize];
+    return format!("{} {}, [{}, {}]", name, thumb_reg(instruction, 0), thumb_reg(instruction, 3), thumb_reg(instruction, 6));
+}
+
+pub fn thumb_load_store_immediate_offset(instruction: u32, _address: u32) -> String {
+    let byte = bit(instruction, 12);
+    let name = match (bit(instruction, 11), byte) {
+        (false, false) => "str",
+        (false, true) => "strb",
+        (true, false) => "ldr",
+        (true, true) => "ldrb",
+    };
+    // words are addressed in steps of 4
+    let offset = if byte {bits(instruction, 10, 6)} else {bits(instruction, 10, 6) << 2};
+    return format!("{} {}, [{}, #{:#x}]", name, thumb_reg(instruction, 0), thumb_reg(instruction, 3), offset);
+}
+
+pub fn thumb_load_store_halfword(instruction: u32, _address: u32) -> String {
+    let name = if bit(instruction, 11) {"ldrh"} else {"strh"};
+    return format!("{} {}, [{}, #{:#x}]", name, thumb_reg(instruction, 0), thumb_reg(instruction, 3), bits(instruction, 10, 6) << 1);
+}
+
+pub fn thumb_sp_relative_load_store(instruction: u32, _address: u32) -> String {
+    let name = if bit(instruction, 11) {"ldr"} else {"str"};
+    return format!("{} {}, [sp, #{:#x}]", name, thumb_reg(instruction, 8), bits(instruction, 7, 0) << 2);
+}
+
+pub fn thumb_load_address(instruction: u32, address: u32) -> String {
+    let offset = bits(instruction, 7, 0) << 2;
+    let rd = thumb_reg(instruction, 8);
+    if bit(instruction, 11) {
+        return format!("add {}, sp, #{:#x}", rd, offset);
+    }
+    let target = (address.wrapping_add(4) & !2).wrapping_add(offset);
+    return format!("add {}, pc, #{:#x} ; {:#010x}", rd, offset, target);
+}
+
+pub fn thumb_add_offset_to_stack_pointer(instruction: u32, _address: u32) -> String {
+    return format!("add sp, {}", signed_hex(bits(instruction, 6, 0) << 2, !bit(instruction, 7)));
+}
+
+pub fn thumb_push_pop_registers(instruction: u32, _address: u32) -> String {
+    let mut list = bits(instruction, 7, 0);
+    if bit(instruction, 11) {
+        // pop can also load the PC
+        if bit(instruction, 8) {
+            list |= 1 << 15;
+        }
+        return format!("pop {}", register_list(list));
+    }
+    // and push the LR
+    if bit(instruction, 8) {
+        list |= 1 << 14;
+    }
+    return format!("push {}", register_list(list));
+}
+
+pub fn thumb_multiple_load_store(instruction: u32, _address: u32) -> String {
+    let name = if bit(instruction, 11) {"ldmia"} else {"stmia"};
+    return format!("{} {}!, {}", name, thumb_reg(instruction, 8), register_list(bits(instruction, 7, 0)));
+}
+
+pub fn thumb_conditional_branch(instruction: u32, address: u32) -> String {
+    let condition = bits(instruction, 11, 8);
+    // 0b1110 is undefined, 0b1111 is the software interrupt
+    if condition == 0b1110 {
+        return format!("undefined {:#06x}", instruction & 0xFFFF);
+    }
+    let offset = sign_extend(bits(instruction, 7, 0), 8) << 1;
+    return format!("b{} {:#010x}", CONDITIONS[condition as usize], address.wrapping_add(4).wrapping_add(offset));
+}
+
+pub fn thumb_software_interrupt(instruction: u32, _address: u32) -> String {
+    return format!("swi #{:#x}", bits(instruction, 7, 0));
+}
+
+pub fn thumb_unconditional_branch(instruction: u32, address: u32) -> String {
+    let offset = sign_extend(bits(instruction, 10, 0), 11) << 1;
+    return format!("b {:#010x}", address.wrapping_add(4).wrapping_add(offset));
+}
+
+// two instructions, the first puts the upper part of the offset into the LR, the second adds the lower part and branches
+pub fn thumb_long_branch_with_link(instruction: u32, address: u32) -> String {
+    let offset = bits(instruction, 10, 0);
+    if bit(instruction, 11) {
+        return format!("bl lr, #{:#x}", offset << 1);
+    }
+    let lr = address.wrapping_add(4).wrapping_add(sign_extend(offset, 11) << 12);
+    let next = instruction >> 16;
+    if next & 0xF800 == 0xF800 {
+        return format!("bl {:#010x}", lr.wrapping_add(bits(next, 10, 0) << 1));
+    }
+    return format!("bl ; lr = {:#010x}", lr);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub const ADDRESS: u32 = 0x08000000;
+
+    // encodings checked against the GNU and LLVM assemblers
+    pub const ARM_CORPUS: &[(u32, &str)] = &[
+        (0x00910002, "addeqs r0, r1, r2"),
+        (0xE3A00301, "mov r0, #0x4000000"),
+        (0xE1B0F00E, "movs pc, lr"),
+        (0xE35300FF, "cmp r3, #0xff"),
+        (0xE0810102, "add r0, r1, r2, lsl #2"),
+        (0xE0454736, "sub r4, r5, r6, lsr r7"),
+        (0xE1800041, "orr r0, r0, r1, asr #32"),
+        (0xE0632064, "rsb r2, r3, r4, rrx"),
+        (0xE3C0020F, "bic r0, r0, #0xf0000000"),
+        (0xE1E01462, "mvn r1, r2, ror #8"),
+        (0xE3100001, "tst r0, #0x1"),
+        (0xE28F0010, "add r0, pc, #0x10 ; 0x08000018"),
+        (0xE10F0000, "mrs r0, cpsr"),
+        (0xE14F1000, "mrs r1, spsr"),
+        (0xE129F000, "msr cpsr_fc, r0"),
+        (0xE328F20F, "msr cpsr_f, #0xf0000000"),
+        (0xE169F002, "msr spsr_fc, r2"),
+        (0xE121F000, "msr cpsr_c, r0"),
+        (0xE16FF001, "msr spsr_fsxc, r1"),
+        (0xE329F01F, "msr cpsr_fc, #0x1f"),
+        (0xE0000291, "mul r0, r1, r2"),
+        (0xE0336594, "mlas r3, r4, r5, r6"),
+        (0xE0810392, "umull r0, r1, r2, r3"),
+        (0xE0E54796, "smlal r4, r5, r6, r7"),
+        (0xE1020091, "swp r0, r1, [r2]"),
+        (0xE1453094, "swpb r3, r4, [r5]"),
+        (0xE12FFF1E, "bx lr"),
+        (0x112FFF10, "bxne r0"),
+        (0xE1D100B2, "ldrh r0, [r1, #0x2]"),
+        (0xE04320B4, "strh r2, [r3], #-0x4"),
+        (0xE19540D6, "ldrsb r4, [r5, r6]"),
+        (0xE17871F0, "ldrsh r7, [r8, #-0x10]!"),
+        (0xE5910000, "ldr r0, [r1]"),
+        (0xE5A32004, "str r2, [r3, #0x4]!"),
+        (0xE4554001, "ldrb r4, [r5], #-0x1"),
+        (0xE7976108, "ldr r6, [r7, r8, lsl #2]"),
+        (0xE6010002, "str r0, [r1], -r2"),
+        (0xE4F10001, "ldrbt r0, [r1], #0x1"),
+        (0xE59F0008, "ldr r0, [pc, #0x8] ; 0x08000010"),
+        (0xE92D40F0, "stmdb sp!, {r4-r7, lr}"),
+        (0xE8BD80F0, "ldmia sp!, {r4-r7, pc}"),
+        (0xE9900006, "ldmib r0, {r1, r2}"),
+        (0xE8400001, "stmda r0, {r0}^"),
+        (0xEA000040, "b 0x08000108"),
+        (0xEB000000, "bl 0x08000008"),
+        (0x0AFFFFFE, "beq 0x08000000"),
+        (0xEF060000, "swi #0x60000"),
+        (0xEE2431C5, "cdp p1, 2, c3, c4, c5, 6"),
+        (0xEE110F10, "mrc p15, 0, r0, c1, c0, 0"),
+        (0xEE232EB4, "mcr p14, 1, r2, c3, c4, 5"),
+        (0xED943202, "ldc p2, c3, [r4, #0x8]"),
+        (0xEC643202, "stcl p2, c3, [r4], #-0x8"),
+        (0xE7F000F0, "undefined 0xe7f000f0"),
+    ];
+
+    pub const THUMB_CORPUS: &[(u32, &str)] = &[
+        (0x0088, "lsl r0, r1, #2"),
+        (0x081A, "lsr r2, r3, #32"),
+        (0x106C, "asr r4, r5, #1"),
+        (0x1888, "add r0, r1, r2"),
+        (0x1FE3, "sub r3, r4, #7"),
+        (0x20FF, "mov r0, #0xff"),
+        (0x2910, "cmp r1, #0x10"),
+        (0x3201, "add r2, #0x1"),
+        (0x3F80, "sub r7, #0x80"),
+        (0x4008, "and r0, r1"),
+        (0x425A, "neg r2, r3"),
+        (0x436C, "mul r4, r5"),
+        (0x43FE, "mvn r6, r7"),
+        (0x4480, "add r8, r0"),
+        (0x4548, "cmp r0, r9"),
+        (0x46F4, "mov r12, lr"),
+        (0x4770, "bx lr"),
+        (0x4802, "ldr r0, [pc, #0x8] ; 0x0800000c"),
+        (0x5088, "str r0, [r1, r2]"),
+        (0x5D63, "ldrb r3, [r4, r5]"),
+        (0x5288, "strh r0, [r1, r2]"),
+        (0x5688, "ldsb r0, [r1, r2]"),
+        (0x5A88, "ldrh r0, [r1, r2]"),
+        (0x5E88, "ldsh r0, [r1, r2]"),
+        (0x6848, "ldr r0, [r1, #0x4]"),
+        (0x77DA, "strb r2, [r3, #0x1f]"),
+        (0x8FC8, "ldrh r0, [r1, #0x3e]"),
+        (0x90FF, "str r0, [sp, #0x3fc]"),
+        (0xA002, "add r0, pc, #0x8 ; 0x0800000c"),
+        (0xA904, "add r1, sp, #0x10"),
+        (0xB082, "add sp, #-0x8"),
+        (0xB040, "add sp, #0x100"),
+        (0xB530, "push {r4, r5, lr}"),
+        (0xBD0F, "pop {r0-r3, pc}"),
+        (0xC006, "stmia r0!, {r1, r2}"),
+        (0xCBF1, "ldmia r3!, {r0, r4-r7}"),
+        (0xD006, "beq 0x08000010"),
+        (0xDF05, "swi #0x5"),
+        (0xE7FE, "b 0x08000000"),
+        (0xF800F000, "bl 0x08000004"),
+        (0xFFFEF000, "bl 0x08001000"),
+        (0xF000, "bl ; lr = 0x08000004"),
+        (0xF801, "bl lr, #0x2"),
+    ];
+
+    #[test]
+    fn arm_corpus() {
+        for (instruction, text) in ARM_CORPUS {
+            assert_eq!(arm(*instruction, ADDRESS), *text, "{:#010x}", instruction);
+        }
+    }
+
+    #[test]
+    fn thumb_corpus() {
+        for (instruction, text) in THUMB_CORPUS {
+            assert_eq!(thumb(*instruction, ADDRESS), *text, "{:#06x}", instruction);
+        }
+    }
+}
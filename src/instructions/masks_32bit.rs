@@ -9,7 +9,7 @@ pub const B_23:    u32 = 0x00800000;  // bit 23
 pub const B_23_0:  u32 = 0x00FFFFFF;  // lower 24 bits
 pub const B_25:    u32 = 0x02000000;  // bit 25
 pub const B_20:    u32 = 0x00100000;  // bit 20
-pub const B_24_21: u32 = 0x03E00000;  // bits 24 to 21
+pub const B_24_21: u32 = 0x01E00000;  // bits 24 to 21
 pub const B_23_20: u32 = 0x00F00000;  // bits 23 to 20
 pub const B_19_16: u32 = 0x000F0000;  // bits 19 to 16
 pub const B_15_12: u32 = 0x0000F000;  // bits 15 to 12
@@ -19,12 +19,12 @@ pub const B_3:     u32 = 0x00000008;  // bit 3
 pub const B_4:     u32 = 0x00000010;  // bit 4
 pub const B_5:     u32 = 0x00000020;  // bit 5
 pub const B_6:     u32 = 0x00000040;  // bit 6
-pub const B_7:     u32 = 0x00000040;  // bit 7
+pub const B_7:     u32 = 0x00000080;  // bit 7
 pub const B_9:     u32 = 0x00000200;  // bit 9
 pub const B_10:    u32 = 0x00000400;  // bit 10
 pub const B_6_5:   u32 = 0x00000060;  // bits 6 and 5
-pub const B_11_7:  u32 = 0x00000780;  // bits 11 to 7
-pub const B_11_8:  u32 = 0x00000700;  // bits 11 to 8
+pub const B_11_7:  u32 = 0x00000F80;  // bits 11 to 7
+pub const B_11_8:  u32 = 0x00000F00;  // bits 11 to 8
 pub const B_11_4:  u32 = 0x00000FF0;  // bits 11 to 4
 pub const B_7_0:   u32 = 0x000000FF;  // bits 7 to 0
 pub const B_31:    u32 = 0x80000000;  // bit 31
@@ -0,0 +1,35 @@
+use crate::cpu::{ConditionFlags, CPU};
+use crate::instructions::asm;
+
+// helpers for the instruction tests, programs are assembled into the start of the game pak
+
+// puts the ARM program at the start of the game pak and points the PC at it
+pub fn load_arm(cpu: &mut CPU, program: &[&str]) {
+    cpu.game_pak_rom = program.iter().enumerate().flat_map(|(index, text)| asm::try_arm(text, asm::ADDRESS + 4 * index as u32).unwrap().to_le_bytes()).collect();
+    cpu.registers[15] = asm::ADDRESS;
+}
+
+// runs ARM code without branches, one instruction per cycle
+pub fn run_arm(cpu: &mut CPU, program: &[&str]) {
+    load_arm(cpu, program);
+    for _ in program {
+        cpu.cycle();
+    }
+}
+
+// runs THUMB code without branches, one instruction per cycle
+pub fn run_thumb(cpu: &mut CPU, program: &[&str]) {
+    cpu.game_pak_rom = program.iter().enumerate().flat_map(|(index, text)| (asm::try_thumb(text, asm::ADDRESS + 2 * index as u32).unwrap() as u16).to_le_bytes()).collect();
+    // the game pak is read in words
+    cpu.game_pak_rom.resize((cpu.game_pak_rom.len() + 3) & !3, 0);
+    cpu.registers[15] = asm::ADDRESS;
+    cpu.set_state(true);
+    for _ in program {
+        cpu.cycle();
+    }
+}
+
+// N, Z, C and V
+pub fn flags(cpu: &CPU) -> (bool, bool, bool, bool) {
+    return (cpu.get_condition_flag(ConditionFlags::N), cpu.get_condition_flag(ConditionFlags::Z), cpu.get_condition_flag(ConditionFlags::C), cpu.get_condition_flag(ConditionFlags::V));
+}
@@ -1,32 +1,36 @@
-use crate::{cpu::{CPUMode, ConditionFlags, Registers::*, CPU}, 
+use crate::{cpu::{ConditionFlags, Registers::*, CPU}, 
             instructions::masks_32bit::*, 
-            not_implemented,
-            instructions::basic_ops::*};
+            instructions::basic_ops::*,
+            instructions::arm,
+            instructions::disasm::{self, DisasmFn}};
 
+// pattern, mask, handler function, disassembler function
+// the first match is used, so the more specific patterns have to come before the ones they overlap with
 type ProcFnThumb = fn(&mut CPU, u32);
 pub fn placeholder_thumb(cpu: &mut CPU, opcode: u32) {
-    not_implemented!();
+    cpu.flush_trace();
+    panic!("{} at {} is not implemented yet", disasm::thumb(opcode, cpu.registers[R15]), cpu.symbols.describe(cpu.registers[R15]));
 }
-const THUMB_OPCODES: [(u16, u16, ProcFnThumb); 19] = [
-        (0x0000, 0xE000, move_shifted_register),  // move shifted register
-        (0x1800, 0xF800, add_subtract),  // add/subtract
-        (0x2000, 0xE000, move_compare_add_subtract_immediate),  // move/compare/add/subtract immediate
-        (0x4000, 0xFC00, alu_operations),  // alu operations
-        (0x4400, 0xFC00, hi_register_operations_be),  // hi register operations/branch exchange
-        (0x4800, 0xF800, placeholder_thumb),  // pc relative load
-        (0x5000, 0xF200, placeholder_thumb),  // load/store with register offset
-        (0x5200, 0xF200, placeholder_thumb),  // load/store sign-extended byte/halfword
-        (0x6000, 0xE000, placeholder_thumb),  // load/store with immediate offset
-        (0x8000, 0xF000, placeholder_thumb),  // load/store halfword
-        (0x9000, 0xF000, placeholder_thumb),  // sp-relative load/store
-        (0xA000, 0xF000, placeholder_thumb),  // load address
-        (0xB000, 0xFF00, placeholder_thumb),  // add offset to stack pointer
-        (0xB400, 0xF600, placeholder_thumb),  // push/pop registers
-        (0xC000, 0xF000, placeholder_thumb),  // multiple load/store
-        (0xD000, 0xF000, placeholder_thumb),  // conditional branch
-        (0xDF00, 0xFF00, placeholder_thumb),  // software interrupt
-        (0xE000, 0xF800, placeholder_thumb),  // uncoditional branch
-        (0xF000, 0xF000, placeholder_thumb),  // long branch with link
+pub const THUMB_OPCODES: [(u16, u16, ProcFnThumb, DisasmFn); 19] = [
+        (0x1800, 0xF800, add_subtract, disasm::thumb_add_subtract),  // add/subtract
+        (0x0000, 0xE000, move_shifted_register, disasm::thumb_move_shifted_register),  // move shifted register
+        (0x2000, 0xE000, move_compare_add_subtract_immediate, disasm::thumb_move_compare_add_subtract_immediate),  // move/compare/add/subtract immediate
+        (0x4000, 0xFC00, alu_operations, disasm::thumb_alu_operations),  // alu operations
+        (0x4400, 0xFC00, hi_register_operations_be, disasm::thumb_hi_register_operations_be),  // hi register operations/branch exchange
+        (0x4800, 0xF800, placeholder_thumb, disasm::thumb_pc_relative_load),  // pc relative load
+        (0x5000, 0xF200, placeholder_thumb, disasm::thumb_load_store_register_offset),  // load/store with register offset
+        (0x5200, 0xF200, placeholder_thumb, disasm::thumb_load_store_sign_extended),  // load/store sign-extended byte/halfword
+        (0x6000, 0xE000, placeholder_thumb, disasm::thumb_load_store_immediate_offset),  // load/store with immediate offset
+        (0x8000, 0xF000, placeholder_thumb, disasm::thumb_load_store_halfword),  // load/store halfword
+        (0x9000, 0xF000, placeholder_thumb, disasm::thumb_sp_relative_load_store),  // sp-relative load/store
+        (0xA000, 0xF000, placeholder_thumb, disasm::thumb_load_address),  // load address
+        (0xB000, 0xFF00, placeholder_thumb, disasm::thumb_add_offset_to_stack_pointer),  // add offset to stack pointer
+        (0xB400, 0xF600, placeholder_thumb, disasm::thumb_push_pop_registers),  // push/pop registers
+        (0xC000, 0xF000, placeholder_thumb, disasm::thumb_multiple_load_store),  // multiple load/store
+        (0xDF00, 0xFF00, software_interrupt, disasm::thumb_software_interrupt),  // software interrupt
+        (0xD000, 0xF000, placeholder_thumb, disasm::thumb_conditional_branch),  // conditional branch
+        (0xE000, 0xF800, placeholder_thumb, disasm::thumb_unconditional_branch),  // uncoditional branch
+        (0xF000, 0xF000, placeholder_thumb, disasm::thumb_long_branch_with_link),  // long branch with link
     ];
 
 type ALUFnArm = fn(&mut CPU, bool, u32, u32) -> u32;
@@ -51,11 +55,11 @@ const ALU_OPCODES: [ALUFnArm; 16] = [
 
 pub fn process_instruction_thumb(cpu: &mut CPU, instruction: u32) {
     let mut handled = false;
-    for (pattern, mask, handler) in THUMB_OPCODES
+    for (pattern, mask, handler, _) in THUMB_OPCODES
     {
         if ((instruction as u16) & mask) == pattern
         {
-            handler(cpu, instruction as u32);
+            handler(cpu, instruction);
             handled = true;
             break;
         }
@@ -63,7 +67,8 @@ pub fn process_instruction_thumb(cpu: &mut CPU, instruction: u32) {
     if !handled
     {
         println!("Unknown instruction detected!");
-        println!("Instruction occured at {}.", 0);
+        println!("Instruction occured at {}.", cpu.symbols.describe(cpu.registers[15]));
+        println!("Instruction: {}", disasm::thumb(instruction, cpu.registers[15]));
         println!("Instruction binary: {:b}", instruction);
     }
 }
@@ -101,7 +106,7 @@ pub fn move_shifted_register(cpu: &mut CPU, instruction: u32) {
 
 pub fn add_subtract(cpu: &mut CPU, instruction: u32) {
     // p.113
-    let i = (instruction as u32 & B_10 ) >> 10;
+    let i = (instruction & B_10 ) >> 10;
     let opcode = (instruction & B_9) >> 9;
     let rn_offset3 = (instruction & B_8_6) >> 6;
     let rs = (instruction & B_5_3) >> 3;
@@ -201,4 +206,58 @@ pub fn hi_register_operations_be(cpu: &mut CPU, instruction: u32) {
         cpu.set_state(t_bit != 0);
         cpu.branch = true;
     }
-}
\ No newline at end of file
+}
+
+pub fn software_interrupt(cpu: &mut CPU, _instruction: u32) {
+    // the same exception as in ARM state, its handler runs in ARM state
+    arm::enter_software_interrupt(cpu);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use crate::cpu::{CPUMode, Registers, CPU};
+    use crate::instructions::testing::{flags, run_thumb};
+
+    #[test]
+    fn add_subtract() {
+        let mut cpu = CPU::new();
+        cpu.registers[1] = 5;
+        cpu.registers[2] = 3;
+        run_thumb(&mut cpu, &["add r0, r1, r2", "sub r3, r1, #3", "add r4, r1, #7"]);
+        assert_eq!((cpu.registers[0], cpu.registers[3], cpu.registers[4]), (8, 2, 12));
+        assert_eq!(flags(&cpu), (false, false, false, false));
+        // borrowing clears the carry
+        run_thumb(&mut cpu, &["sub r5, r2, r1"]);
+        assert_eq!(cpu.registers[5], 0xFFFFFFFE);
+        assert_eq!(flags(&cpu), (true, false, false, false));
+        run_thumb(&mut cpu, &["sub r6, r1, r1"]);
+        assert_eq!(flags(&cpu), (false, true, true, false));
+        // the 8 bit immediates
+        run_thumb(&mut cpu, &["mov r7, #200", "add r7, #100", "sub r7, #44", "cmp r7, #0"]);
+        assert_eq!(cpu.registers[7], 256);
+        assert_eq!(flags(&cpu), (false, false, true, false));
+    }
+
+    #[test]
+    fn software_interrupt() {
+        let mut cpu = CPU::new();
+        cpu.registers[Registers::CPSR] = 0x1F;
+        run_thumb(&mut cpu, &["swi #0x0B"]);
+        // the handler runs in ARM state, the SPSR keeps the THUMB bit for the return
+        assert_eq!(cpu.registers[15], 0x08);
+        assert!(!cpu.get_state());
+        assert!(cpu.get_mode() == CPUMode::Supervisor);
+        assert_eq!(cpu.register_read_custom(14, CPUMode::Supervisor), 0x08000002);
+        assert_eq!(cpu.register_read_custom(17, CPUMode::Supervisor), 0x3F);
+    }
+
+    #[test]
+    fn placeholders_can_be_caught() {
+        let mut cpu = CPU::new();
+        // pc relative loads aren't there yet
+        let result = panic::catch_unwind(AssertUnwindSafe(|| run_thumb(&mut cpu, &["ldr r0, [pc, #4]"])));
+        assert!(result.is_err());
+        assert_eq!(cpu.registers[15], 0x08000000);
+    }
+}
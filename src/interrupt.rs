@@ -0,0 +1,131 @@
+// interrupt sources, the values are the bit positions in IE and IF (https://problemkaputt.de/gbatek.htm#gbainterruptcontrol)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interrupt {
+    VBlank = 0,
+    HBlank = 1,
+    VCounter = 2,
+    Timer0 = 3,
+    Timer1 = 4,
+    Timer2 = 5,
+    Timer3 = 6,
+    Serial = 7,
+    DMA0 = 8,
+    DMA1 = 9,
+    DMA2 = 10,
+    DMA3 = 11,
+    Keypad = 12,
+    GamePak = 13,
+}
+
+// only these sources are able to wake the GBA up from STOP mode
+const STOP_WAKEUP_MASK: u16 = (1 << Interrupt::Keypad as u16) | (1 << Interrupt::GamePak as u16) | (1 << Interrupt::Serial as u16);
+
+pub struct InterruptController {
+    pub ie: u16,   // 0x04000200, interrupt enable
+    pub irf: u16,  // 0x04000202, interrupt request flags (IF)
+    pub ime: bool, // 0x04000208, interrupt master enable
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController {
+            ie: 0,
+            irf: 0,
+            ime: false,
+        }
+    }
+
+    pub fn request(&mut self, interrupt: Interrupt) {
+        self.irf |= 1 << (interrupt as u16);
+    }
+
+    // writing a 1 into a bit of IF acknowledges the interrupt and clears the bit
+    pub fn acknowledge(&mut self, bits: u16) {
+        self.irf &= !bits;
+    }
+
+    // true if an enabled interrupt is requested, this is what ends HALT, regardless of IME
+    pub fn pending(&self) -> bool {
+        return self.ie & self.irf & 0x3FFF != 0;
+    }
+
+    // true if the CPU should actually jump into the IRQ handler, the I bit of the CPSR is checked by the CPU
+    pub fn irq_line(&self) -> bool {
+        return self.ime && self.pending();
+    }
+
+    pub fn stop_wakeup(&self) -> bool {
+        return self.ie & self.irf & STOP_WAKEUP_MASK != 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{CPUMode, RWType, CPU, IRQ_HANDLER};
+    use crate::instructions::asm;
+    use super::Interrupt;
+
+    fn assemble(program: &[&str], address: u32) -> Vec<u8> {
+        return program.iter().enumerate().flat_map(|(index, text)| asm::try_arm(text, address + 4 * index as u32).unwrap().to_le_bytes()).collect();
+    }
+
+    #[test]
+    fn irq_reaches_the_game_handler_and_returns() {
+        let mut cpu = CPU::new();
+        // the game idles in a loop and counts interrupts in r5 of the handler
+        cpu.game_pak_rom = assemble(&["b 0x08000000"], 0x08000000);
+        let handler = assemble(&[
+            "mov r1, #0x04000000",
+            "add r1, r1, #0x200",
+            // writing the requested bits back to IF acknowledges them
+            "ldr r2, [r1]",
+            "str r2, [r1]",
+            "add r5, r5, #1",
+            "bx lr",
+        ], 0x03000000);
+        for (index, word) in handler.chunks(4).enumerate() {
+            cpu.memory_write(0x03000000 + 4 * index as u32, RWType::Word, u32::from_le_bytes(word.try_into().unwrap()));
+        }
+        // without a BIOS, booting directly puts a handler at the IRQ vector
+        cpu.skip_bios(0x08000000);
+        let vector = assemble(&[
+            "stmfd sp!, {r0-r3, r12, lr}",
+            "mov r0, #0x04000000",
+            "add lr, pc, #0",
+            "ldr pc, [r0, #-4]",
+            "ldmfd sp!, {r0-r3, r12, lr}",
+            "subs pc, lr, #4",
+        ], 0x18);
+        assert_eq!(vector, IRQ_HANDLER.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>());
+        assert_eq!(cpu.bios[6..12], IRQ_HANDLER);
+        // the handler address goes into the last word of chip RAM, through its mirror
+        cpu.memory_write(0x03FFFFFC, RWType::Word, 0x03000000);
+        assert_eq!(cpu.memory_read(0x03007FFC, RWType::Word), 0x03000000);
+        cpu.memory_write(0x04000200, RWType::HalfWord, 1 << Interrupt::VBlank as u32);
+        cpu.memory_write(0x04000208, RWType::HalfWord, 1);
+        cpu.registers[1] = 0x11111111;
+
+        for round in 1..=2 {
+            cpu.cycle();
+            cpu.request_interrupt(Interrupt::VBlank);
+            for _ in 0..20 {
+                cpu.cycle();
+            }
+            assert_eq!(cpu.registers[5], round);
+            assert_eq!(cpu.interrupts.irf, 0);
+            // back in the loop, in system mode with IRQs enabled and the registers restored
+            assert_eq!(cpu.registers[15], 0x08000000);
+            assert!(cpu.get_mode() == CPUMode::System);
+            assert!(!cpu.get_irq_disable());
+            assert_eq!(cpu.registers[1], 0x11111111);
+            assert_eq!(cpu.register_read_custom(13, CPUMode::IRQ), 0x03007FA0);
+        }
+    }
+    #[test]
+    fn loaded_bios_keeps_its_vector() {
+        let mut cpu = CPU::new();
+        cpu.load_bios(&[0xAB; 0x40]);
+        cpu.skip_bios(0x08000000);
+        assert!(cpu.bios[..16].iter().all(|word| *word == 0xABABABAB));
+    }
+}
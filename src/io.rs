@@ -0,0 +1,135 @@
+use crate::cpu::CPU;
+use crate::{dma, multiboot, timers};
+use crate::sound::apu::{FIFO_A, FIFO_END, SOUND1CNT_L, WAVE_RAM_END};
+
+// addresses of the IO registers, see https://problemkaputt.de/gbatek.htm#gbaiomap
+pub const DISPSTAT: u32 = 0x04000004;
+pub const VCOUNT: u32 = 0x04000006;
+pub const DMA0SAD: u32 = 0x040000B0;
+pub const DMA3CNT_H: u32 = 0x040000DE;
+pub const TM0CNT_L: u32 = 0x04000100;
+pub const TM3CNT_H: u32 = 0x0400010E;
+pub const SIODATA32: u32 = 0x04000120;
+pub const SIOCNT: u32 = 0x04000128;
+pub const KEYINPUT: u32 = 0x04000130;
+pub const KEYCNT: u32 = 0x04000132;
+pub const RCNT: u32 = 0x04000134;
+pub const IE: u32 = 0x04000200;
+pub const IF: u32 = 0x04000202;
+pub const WAITCNT: u32 = 0x04000204;
+pub const IME: u32 = 0x04000208;
+pub const POSTFLG_HALTCNT: u32 = 0x04000300;  // POSTFLG is the low byte, HALTCNT the high byte
+
+// the IO registers are 16 bit wide, the memory bus however works on words
+// reads return the word at the word-aligned address, made up from the two registers it contains
+pub fn io_read(cpu: &CPU, address: u32) -> u32 {
+    let address = address & !0b11;
+    return (io_read_halfword(cpu, address) as u32) | ((io_read_halfword(cpu, address + 2) as u32) << 16);
+}
+
+fn io_read_halfword(cpu: &CPU, address: u32) -> u16 {
+    match address {
+        SOUND1CNT_L..=WAVE_RAM_END => cpu.apu.read_register(address),
+        FIFO_A..=FIFO_END => 0,  // write only
+        DMA0SAD..=DMA3CNT_H => {
+            let (index, offset) = dma_register(address);
+            cpu.dma.read_register(index, offset)
+        },
+        TM0CNT_L..=TM3CNT_H => {
+            let index = ((address - TM0CNT_L) / 4) as usize;
+            if address & 0b10 == 0 {
+                cpu.timers.read_counter(index, cpu.cycles())
+            }
+            else {
+                cpu.timers.timers[index].control
+            }
+        },
+        DISPSTAT => cpu.video.dispstat,
+        VCOUNT => cpu.video.vcount,
+        KEYINPUT => cpu.keypad.keyinput(),
+        KEYCNT => cpu.keypad.keycnt,
+        IE => cpu.interrupts.ie,
+        IF => cpu.interrupts.irf,
+        IME => cpu.interrupts.ime as u16,
+        // registers without special behaviour just read back what was written
+        _ => cpu.io_registers[io_index(address)],
+    }
+}
+
+// writes into the word at the word-aligned address
+// data holds the new value in the position it has in the word, mask has all bits set that are actually written
+// this way byte and halfword writes only affect the registers (and bits) they target
+pub fn io_write(cpu: &mut CPU, address: u32, data: u32, mask: u32) {
+    let address = address & !0b11;
+    if mask & 0x0000FFFF != 0 {
+        io_write_halfword(cpu, address, data as u16, mask as u16);
+    }
+    if mask & 0xFFFF0000 != 0 {
+        io_write_halfword(cpu, address + 2, (data >> 16) as u16, (mask >> 16) as u16);
+    }
+}
+
+fn io_write_halfword(cpu: &mut CPU, address: u32, value: u16, mask: u16) {
+    let merged = (io_read_halfword(cpu, address) & !mask) | (value & mask);
+    match address {
+        // the sound registers are full of write only bits, the APU merges on its own
+        SOUND1CNT_L..=WAVE_RAM_END => cpu.apu.write_register(address, value, mask),
+        FIFO_A..=FIFO_END => cpu.apu.write_fifo(address, value, mask),
+        DMA0SAD..=DMA3CNT_H => {
+            // most of the DMA registers are write only, so they do their own merging
+            let (index, offset) = dma_register(address);
+            dma::write_register(cpu, index, offset, value, mask);
+        },
+        TM0CNT_L..=TM3CNT_H => {
+            let index = ((address - TM0CNT_L) / 4) as usize;
+            if address & 0b10 == 0 {
+                // reads give the counter, not the reload value, so don't merge with it
+                let reload = (cpu.timers.timers[index].reload & !mask) | (value & mask);
+                timers::write_reload(cpu, index, reload);
+            }
+            else {
+                timers::write_control(cpu, index, merged);
+            }
+        },
+        SIOCNT => {
+            cpu.io_registers[io_index(address)] = merged;
+            multiboot::write_siocnt(cpu);
+        },
+        DISPSTAT => cpu.video.write_dispstat(merged),
+        VCOUNT => {},  // read only
+        KEYINPUT => {},  // read only
+        KEYCNT => {
+            cpu.keypad.write_keycnt(merged);
+            // changing the condition can trigger the interrupt just like pressing a button does
+            cpu.check_keypad_irq();
+        },
+        IE => cpu.interrupts.ie = merged & 0x3FFF,
+        IF => cpu.interrupts.acknowledge(value & mask),  // not merged, only the written ones clear their flag
+        IME => cpu.interrupts.ime = merged & 1 != 0,
+        POSTFLG_HALTCNT => {
+            cpu.io_registers[io_index(address)] = merged & 0x00FF;
+            if mask & 0xFF00 != 0 {
+                // bit 7 of HALTCNT: 0 -> halt, 1 -> stop
+                if value & 0x8000 != 0 {
+                    cpu.stopped = true;
+                }
+                else {
+                    cpu.halted = true;
+                }
+            }
+        },
+        _ => cpu.io_registers[io_index(address)] = merged,
+    }
+}
+
+// channel number and offset of the register relative to DMAxSAD
+#[inline]
+fn dma_register(address: u32) -> (usize, u32) {
+    let relative = address - DMA0SAD;
+    return ((relative / 12) as usize, relative % 12);
+}
+
+#[inline]
+fn io_index(address: u32) -> usize {
+    return ((address & 0x3FF) >> 1) as usize;
+}
@@ -0,0 +1,217 @@
+use std::ops::{BitAnd, BitOr, BitOrAssign, Not};
+
+// set of pressed GBA buttons, the bits follow the layout of KEYINPUT and KEYCNT (https://problemkaputt.de/gbatek.htm#gbakeypadinput)
+// in contrast to KEYINPUT, a set bit means that the button is pressed
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ButtonSet(pub u16);
+
+impl ButtonSet {
+    pub const NONE: ButtonSet = ButtonSet(0);
+    pub const A: ButtonSet = ButtonSet(1 << 0);
+    pub const B: ButtonSet = ButtonSet(1 << 1);
+    pub const SELECT: ButtonSet = ButtonSet(1 << 2);
+    pub const START: ButtonSet = ButtonSet(1 << 3);
+    pub const RIGHT: ButtonSet = ButtonSet(1 << 4);
+    pub const LEFT: ButtonSet = ButtonSet(1 << 5);
+    pub const UP: ButtonSet = ButtonSet(1 << 6);
+    pub const DOWN: ButtonSet = ButtonSet(1 << 7);
+    pub const R: ButtonSet = ButtonSet(1 << 8);
+    pub const L: ButtonSet = ButtonSet(1 << 9);
+    pub const ALL: ButtonSet = ButtonSet(0x03FF);
+
+    pub fn contains(self, other: ButtonSet) -> bool {
+        return self.0 & other.0 == other.0;
+    }
+
+    pub fn is_empty(self) -> bool {
+        return self.0 == 0;
+    }
+}
+
+impl BitOr for ButtonSet {
+    type Output = ButtonSet;
+
+    fn bitor(self, rhs: ButtonSet) -> ButtonSet {
+        ButtonSet(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for ButtonSet {
+    fn bitor_assign(&mut self, rhs: ButtonSet) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for ButtonSet {
+    type Output = ButtonSet;
+
+    fn bitand(self, rhs: ButtonSet) -> ButtonSet {
+        ButtonSet(self.0 & rhs.0)
+    }
+}
+
+impl Not for ButtonSet {
+    type Output = ButtonSet;
+
+    fn not(self) -> ButtonSet {
+        ButtonSet(!self.0 & ButtonSet::ALL.0)
+    }
+}
+
+// KEYCNT bits
+const KEYCNT_IRQ_ENABLE: u16 = 1 << 14;
+const KEYCNT_IRQ_AND: u16 = 1 << 15;  // 0: any of the selected buttons, 1: all of the selected buttons
+
+pub struct Keypad {
+    pressed: ButtonSet,
+    pub keycnt: u16,
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad {
+            pressed: ButtonSet::NONE,
+            keycnt: 0,
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: ButtonSet) {
+        self.pressed = buttons & ButtonSet::ALL;
+    }
+
+    pub fn buttons(&self) -> ButtonSet {
+        return self.pressed;
+    }
+
+    // KEYINPUT is active low, 0 means pressed
+    pub fn keyinput(&self) -> u16 {
+        return (!self.pressed).0;
+    }
+
+    pub fn write_keycnt(&mut self, value: u16) {
+        // bits 10 to 13 are unused
+        self.keycnt = value & 0xC3FF;
+    }
+
+    // checks whether the buttons currently pressed fulfill the condition set in KEYCNT
+    pub fn irq_condition(&self) -> bool {
+        if self.keycnt & KEYCNT_IRQ_ENABLE == 0 {
+            return false;
+        }
+        let selected = ButtonSet(self.keycnt) & ButtonSet::ALL;
+        if self.keycnt & KEYCNT_IRQ_AND != 0 {
+            // logical AND mode, all selected buttons need to be pressed
+            // a selection without any buttons never triggers
+            return !selected.is_empty() && self.pressed.contains(selected);
+        }
+        else {
+            // logical OR mode, at least one of the selected buttons needs to be pressed
+            return !(self.pressed & selected).is_empty();
+        }
+    }
+}
+
+// parses button combinations like "A+B+START" or "up+r", handy for scripted input in test harnesses
+impl std::str::FromStr for ButtonSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ButtonSet, String> {
+        let mut buttons = ButtonSet::NONE;
+        for name in s.split('+').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            buttons |= match name.to_ascii_uppercase().as_str() {
+                "A" => ButtonSet::A,
+                "B" => ButtonSet::B,
+                "SELECT" => ButtonSet::SELECT,
+                "START" => ButtonSet::START,
+                "RIGHT" => ButtonSet::RIGHT,
+                "LEFT" => ButtonSet::LEFT,
+                "UP" => ButtonSet::UP,
+                "DOWN" => ButtonSet::DOWN,
+                "R" => ButtonSet::R,
+                "L" => ButtonSet::L,
+                _ => return Err(format!("Unknown button {}", name)),
+            };
+        }
+        Ok(buttons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{RWType, CPU};
+    use crate::instructions::asm;
+    use crate::interrupt::Interrupt;
+    use super::{ButtonSet, Keypad};
+
+    const KEYINPUT: u32 = 0x04000130;
+    const KEYCNT: u32 = 0x04000132;
+
+    #[test]
+    fn keyinput_is_active_low() {
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.memory_read(KEYINPUT, RWType::HalfWord), 0x03FF);
+        cpu.set_buttons(ButtonSet::A | ButtonSet::START | ButtonSet::L);
+        assert_eq!(cpu.memory_read(KEYINPUT, RWType::HalfWord), 0x03FF & !0x0209);
+        // read only
+        cpu.memory_write(KEYINPUT, RWType::HalfWord, 0x03FF);
+        assert_eq!(cpu.memory_read(KEYINPUT, RWType::HalfWord), 0x01F6);
+        // there are only ten buttons
+        cpu.set_buttons(ButtonSet(0xFFFF));
+        assert_eq!(cpu.memory_read(KEYINPUT, RWType::HalfWord), 0);
+        assert_eq!(cpu.keypad.buttons(), ButtonSet::ALL);
+    }
+
+    #[test]
+    fn irq_conditions() {
+        let mut keypad = Keypad::new();
+        let a_or_b = (ButtonSet::A | ButtonSet::B).0;
+        keypad.set_buttons(ButtonSet::A);
+        // selected, but the IRQ isn't enabled
+        keypad.write_keycnt(a_or_b);
+        assert!(!keypad.irq_condition());
+        // OR mode, any of the selected buttons
+        keypad.write_keycnt(0x4000 | a_or_b);
+        assert!(keypad.irq_condition());
+        keypad.set_buttons(ButtonSet::START);
+        assert!(!keypad.irq_condition());
+        // AND mode, all of them, others pressed as well don't matter
+        keypad.write_keycnt(0xC000 | a_or_b);
+        keypad.set_buttons(ButtonSet::A);
+        assert!(!keypad.irq_condition());
+        keypad.set_buttons(ButtonSet::A | ButtonSet::B | ButtonSet::START);
+        assert!(keypad.irq_condition());
+        // AND mode without any selected button never triggers
+        keypad.write_keycnt(0xC000);
+        assert!(!keypad.irq_condition());
+        // bits 10 to 13 don't exist
+        keypad.write_keycnt(0xFFFF);
+        assert_eq!(keypad.keycnt, 0xC3FF);
+    }
+
+    #[test]
+    fn button_combination_wakes_from_stop() {
+        let mut cpu = CPU::new();
+        cpu.game_pak_rom = asm::arm("mov r0, #1").to_le_bytes().to_vec();
+        cpu.registers[15] = 0x08000000;
+        let keypad_irq = 1 << Interrupt::Keypad as u32;
+        cpu.memory_write(0x04000200, RWType::HalfWord, keypad_irq);
+        // A and B together
+        cpu.memory_write(KEYCNT, RWType::HalfWord, 0xC003);
+        cpu.memory_write(0x04000300, RWType::HalfWord, 0x8000);
+        assert!(cpu.stopped);
+        cpu.set_buttons(ButtonSet::A);
+        cpu.cycle();
+        assert!(cpu.stopped);
+        assert_eq!(cpu.interrupts.irf, 0);
+        cpu.set_buttons(ButtonSet::A | ButtonSet::B);
+        assert_eq!(cpu.interrupts.irf as u32, keypad_irq);
+        cpu.cycle();
+        assert!(!cpu.stopped);
+        assert_eq!(cpu.registers[0], 1);
+        // a new condition that already holds raises the IRQ right away
+        cpu.memory_write(0x04000202, RWType::HalfWord, keypad_irq);
+        assert_eq!(cpu.interrupts.irf, 0);
+        cpu.memory_write(KEYCNT, RWType::HalfWord, 0x4001);
+        assert_eq!(cpu.interrupts.irf as u32, keypad_irq);
+    }
+}
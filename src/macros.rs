@@ -1,7 +1,7 @@
 #[macro_export]
 macro_rules! not_implemented {
     () => {{
-        eprintln!("Not implemented yet!");
-        std::process::exit(1);
+        // a panic and not an exit, so the debugger and the gdb stub can catch it and keep the state around
+        panic!("Not implemented yet!");
     }};
 }
\ No newline at end of file
@@ -1,21 +1,220 @@
+// the code spells out returns, late initialisation and address range checks on purpose
+#![allow(clippy::needless_return, clippy::needless_late_init, clippy::manual_range_contains, clippy::new_without_default)]
+
 use std::env;
 use std::error::Error;
-use cartridge::Cartridge;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use backup::{Backup, SaveType};
+use cartridge::{Cartridge, LoadError};
 use cpu::CPU;
+use debugger::Debugger;
+use gpio::gyro::Gyro;
+use gpio::rtc::{ClockSource, RTC};
+use gpio::solar::SolarSensor;
+use script::InputScript;
+use tilt::TiltSensor;
+use sound::resampler::{Interpolation, Resampler};
+use sound::wav::WavWriter;
 
 #[cfg(feature = "logging")]
 use {
-    log::{info, warn},
+    log::info,
     simple_logger::SimpleLogger,
+    std::ops::RangeInclusive,
+    trace::Tracer,
 };
 
 
 
+pub mod archive;
+pub mod backup;
 pub mod cartridge;
 pub mod cpu;
+pub mod debugger;
 pub mod macros;
+pub mod patch;
 pub mod instructions;
 pub mod util;
+pub mod dma;
+pub mod elf;
+pub mod gdb;
+pub mod gpio;
+pub mod hooks;
+pub mod interrupt;
+pub mod io;
+pub mod keypad;
+pub mod multiboot;
+pub mod rom_info;
+pub mod scheduler;
+pub mod script;
+pub mod sound;
+pub mod tilt;
+pub mod timers;
+pub mod video;
+#[cfg(feature = "frontend")]
+pub mod frontend;
+#[cfg(feature = "logging")]
+pub mod trace;
+
+// headless runs without a frame limit stop after 10 seconds
+const DEFAULT_HEADLESS_FRAMES: u64 = 600;
+
+// command line options, first positional argument is the ROM
+struct Options {
+    rom: Option<String>,
+    patch: Option<String>,
+    bios: Option<String>,
+    // the image is recognized by its name, or by the flag
+    multiboot: bool,
+    fix_header: bool,
+    info_json: bool,
+    debug: bool,
+    gdb: Option<u16>,
+    frames: Option<u64>,
+    save_type: Option<SaveType>,
+    // the RTC is enabled for games known to have one, or by the flag
+    rtc: bool,
+    rtc_clock: ClockSource,
+    input_script: Option<String>,
+    dump_audio: Option<String>,
+    audio_rate: u32,
+    interpolation: Interpolation,
+    #[cfg(feature = "frontend")]
+    scale: usize,
+    // per instruction trace file and what goes into it
+    #[cfg(feature = "logging")]
+    trace: Option<String>,
+    #[cfg(feature = "logging")]
+    trace_range: Option<RangeInclusive<u32>>,
+    #[cfg(feature = "logging")]
+    trace_skip: u64,
+    #[cfg(feature = "logging")]
+    trace_count: Option<u64>,
+}
+
+fn parse_args() -> Result<Options, Box<dyn Error>> {
+    let mut options = Options {
+        rom: None,
+        patch: None,
+        bios: None,
+        multiboot: false,
+        fix_header: false,
+        info_json: false,
+        debug: false,
+        gdb: None,
+        frames: None,
+        save_type: None,
+        rtc: false,
+        rtc_clock: ClockSource::Host,
+        input_script: None,
+        dump_audio: None,
+        audio_rate: 48000,
+        interpolation: Interpolation::Sinc,
+        #[cfg(feature = "frontend")]
+        scale: 3,
+        #[cfg(feature = "logging")]
+        trace: None,
+        #[cfg(feature = "logging")]
+        trace_range: None,
+        #[cfg(feature = "logging")]
+        trace_skip: 0,
+        #[cfg(feature = "logging")]
+        trace_count: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                let value = args.next().ok_or("--frames needs a value")?;
+                options.frames = Some(value.parse()?);
+            },
+            "--save-type" => {
+                let value = args.next().ok_or("--save-type needs a value")?;
+                options.save_type = Some(value.parse()?);
+            },
+            "--patch" => {
+                options.patch = Some(args.next().ok_or("--patch needs a file name")?);
+            },
+            "--bios" => {
+                options.bios = Some(args.next().ok_or("--bios needs a file name")?);
+            },
+            "--multiboot" => options.multiboot = true,
+            "--fix-header" => options.fix_header = true,
+            "--info-json" => options.info_json = true,
+            "--debug" => options.debug = true,
+            "--gdb" => {
+                let value = args.next().ok_or("--gdb needs a port")?;
+                options.gdb = Some(value.parse()?);
+            },
+            "--rtc" => options.rtc = true,
+            "--rtc-time" => {
+                // a fixed start time makes runs reproducible, it implies --rtc
+                let value = args.next().ok_or("--rtc-time needs a time like 2004-01-01T12:00:00")?;
+                options.rtc_clock = ClockSource::Fixed(value.parse()?);
+                options.rtc = true;
+            },
+            "--input-script" => {
+                options.input_script = Some(args.next().ok_or("--input-script needs a file name")?);
+            },
+            "--dump-audio" => {
+                options.dump_audio = Some(args.next().ok_or("--dump-audio needs a file name")?);
+            },
+            "--audio-rate" => {
+                let value = args.next().ok_or("--audio-rate needs a value")?;
+                options.audio_rate = value.parse()?;
+            },
+            "--resampler" => {
+                options.interpolation = match args.next().as_deref() {
+                    Some("linear") => Interpolation::Linear,
+                    Some("sinc") => Interpolation::Sinc,
+                    _ => return Err("--resampler needs to be either linear or sinc".into()),
+                };
+            },
+            #[cfg(feature = "frontend")]
+            "--scale" => {
+                let value = args.next().ok_or("--scale needs a value")?;
+                options.scale = value.parse()?;
+            },
+            #[cfg(feature = "logging")]
+            "--trace" => {
+                options.trace = Some(args.next().ok_or("--trace needs a file name")?);
+            },
+            #[cfg(feature = "logging")]
+            "--trace-range" => {
+                let value = args.next().ok_or("--trace-range needs a range like 0x08000000-0x08000FFF")?;
+                options.trace_range = Some(trace::parse_range(&value)?);
+            },
+            #[cfg(feature = "logging")]
+            "--trace-skip" => {
+                let value = args.next().ok_or("--trace-skip needs a value")?;
+                options.trace_skip = value.parse()?;
+            },
+            #[cfg(feature = "logging")]
+            "--trace-count" => {
+                let value = args.next().ok_or("--trace-count needs a value")?;
+                options.trace_count = Some(value.parse()?);
+            },
+            _ => options.rom = Some(arg),
+        }
+    }
+    Ok(options)
+}
+
+// unimplemented instructions panic so the debuggers can stop there, outside of them the run ends with the message as an error
+fn run_frame(cpu: &mut CPU) -> Result<(), Box<dyn Error>> {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| cpu.run_frame())) {
+        // keep what the game saved so far
+        cpu.backup.flush()?;
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload.downcast_ref::<&str>().map_or(String::from("the emulation stopped"), |message| message.to_string()),
+        };
+        return Err(message.into());
+    }
+    cpu.backup.end_frame()?;
+    return Ok(());
+}
 
 fn main() -> Result<(), Box<dyn Error>>
 {
@@ -25,6 +224,191 @@ fn main() -> Result<(), Box<dyn Error>>
     #[cfg(feature = "logging")]
     info!("Emulator start.");
 
+    let options = parse_args()?;
+    // tool mode, only repairs the header checksum of the ROM file
+    if options.fix_header {
+        let rom = options.rom.as_ref().ok_or("--fix-header needs a ROM")?;
+        if cartridge::fix_header_checksum(rom)? {
+            println!("Fixed the header checksum of {}", rom);
+        }
+        else {
+            println!("The header checksum of {} is already correct", rom);
+        }
+        return Ok(());
+    }
+    let mut cpu = CPU::new();
+    #[cfg(feature = "logging")]
+    if let Some(path) = &options.trace {
+        cpu.tracer = Some(Tracer::new(path, options.trace_range.clone(), options.trace_skip, options.trace_count)?);
+    }
+    if let Some(bios) = &options.bios {
+        cpu.load_bios(&fs::read(bios)?);
+    }
+
+    // devkitARM builds can be run without objcopy, the segments go straight into ROM and RAM
+    let mut entry = 0x08000000;
+    let mut elf_rom = None;
+    let mut elf_multiboot_image = None;
+    if let Some(rom) = options.rom.as_ref().filter(|rom| elf::is_elf_file(rom)) {
+        let elf = elf::load(rom)?;
+        elf.load_ram(&mut cpu).map_err(|e| LoadError::InvalidElf(rom.clone(), e))?;
+        entry = elf.entry;
+        elf_rom = Some(elf.rom());
+        elf_multiboot_image = Some(elf.multiboot_image());
+        cpu.symbols = elf.symbols;
+    }
+
+    // an ELF without anything in ROM is a multiboot build
+    let is_multiboot = match (&elf_rom, &options.rom) {
+        (Some(elf_rom), _) => elf_rom.is_empty(),
+        (None, Some(rom)) => options.multiboot || multiboot::is_multiboot(rom),
+        (None, None) => false,
+    };
+    if is_multiboot {
+        // sent over the link cable, there is no game pak and so no save either
+        let rom = options.rom.as_ref().unwrap();
+        let image = match elf_multiboot_image {
+            Some(image) => image,
+            None => archive::read_rom(rom)?,
+        };
+        if options.bios.is_some() {
+            // the BIOS finds no game pak and waits for the image on the link port, another GBA is simulated there
+            cpu.multiboot_sender = Some(multiboot::Sender::new(&image)?);
+            cpu.boot_bios();
+        }
+        else {
+            if elf_rom.is_none() {
+                multiboot::load(&mut cpu, &image)?;
+                entry = multiboot::MULTIBOOT_ENTRY;
+            }
+            cpu.skip_bios(entry);
+        }
+    }
+    else if let Some(rom) = &options.rom {
+        let cart = match elf_rom {
+            Some(elf_rom) => Cartridge::from_bytes(elf_rom)?,
+            None => Cartridge::with_patch(rom.clone(), options.patch.clone())?,
+        };
+        // tool mode, only prints the ROM metadata for scripts
+        if options.info_json {
+            println!("{}", cart.rom_info().to_json());
+            return Ok(());
+        }
+        cart.info();
+        // homebrew often comes with a broken header, which real hardware wouldn't boot
+        let report = cart.validate();
+        if !report.is_valid() {
+            eprintln!("[WARNING] Invalid cartridge header: {}", report);
+        }
+        // the command line wins over the detection
+        let save_type = match options.save_type {
+            Some(save_type) => {
+                println!("Save type set to {}", save_type);
+                save_type
+            },
+            None => cart.save_type(),
+        };
+        cpu.backup = Backup::new(save_type.chip());
+        if options.rtc || cart.has_rtc() {
+            cpu.gpio.rtc = Some(RTC::new(options.rtc_clock));
+        }
+        if cart.has_solar_sensor() {
+            cpu.gpio.solar = Some(SolarSensor::new());
+        }
+        if cart.has_gyro() {
+            cpu.gpio.gyro = Some(Gyro::new());
+        }
+        if cart.has_tilt_sensor() {
+            cpu.tilt = Some(TiltSensor::new());
+        }
+        cpu.game_pak_rom = cart.rom().to_vec();
+        cpu.backup.attach_file(Backup::save_path(rom))?;
+        if options.bios.is_some() {
+            cpu.boot_bios();
+        }
+        else {
+            cpu.skip_bios(entry);
+        }
+    }
+
+    let mut script = match &options.input_script {
+        Some(path) => Some(InputScript::parse(&fs::read_to_string(path)?)?),
+        None => None,
+    };
+
+    // the debugger takes over instead of running frames
+    if options.debug {
+        Debugger::new().run(&mut cpu)?;
+        cpu.backup.flush()?;
+        return Ok(());
+    }
+
+    if let Some(port) = options.gdb {
+        gdb::listen(&mut cpu, port)?;
+        cpu.backup.flush()?;
+        return Ok(());
+    }
+
+    // the message of the panic is reported by run_frame, without the backtrace note of the default hook
+    panic::set_hook(Box::new(|_| {}));
+
+    // headless run that writes the sound output into a WAV file, meant for regression tests
+    if let Some(path) = &options.dump_audio {
+        let mut resampler = Resampler::new(options.audio_rate, options.interpolation);
+        let mut wav = WavWriter::create(path, resampler.output_rate())?;
+        let mut resampled = Vec::new();
+        for frame in 0..options.frames.unwrap_or(DEFAULT_HEADLESS_FRAMES) {
+            if let Some(script) = &mut script {
+                script.apply(&mut cpu, frame);
+            }
+            run_frame(&mut cpu)?;
+            resampled.clear();
+            resampler.process(&cpu.apu.take_samples(), cpu.apu.sample_rate(), &mut resampled);
+            wav.write_samples(&resampled)?;
+        }
+        wav.finish()?;
+        cpu.backup.flush()?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "frontend")]
+    {
+        let title = match &options.rom {
+            Some(rom) => format!("rust_gba_emu - {}", rom),
+            None => String::from("rust_gba_emu"),
+        };
+        let mut frontend = frontend::Frontend::new(&title, options.scale)?;
+        // TODO: replace with the output of the PPU once it exists
+        let framebuffer = vec![0u32; frontend::SCREEN_WIDTH * frontend::SCREEN_HEIGHT];
+        let mut frame = 0;
+        while frontend.is_open() {
+            cpu.set_buttons(frontend.poll_input());
+            if let Some(script) = &mut script {
+                script.apply(&mut cpu, frame);
+            }
+            frame += 1;
+            run_frame(&mut cpu)?;
+            // the window has no audio output yet, the samples are dropped so they don't pile up
+            cpu.apu.take_samples();
+            frontend.present(&framebuffer)?;
+        }
+        cpu.backup.flush()?;
+    }
+
+    // headless run, e.g. to check that a scripted game writes its save
+    #[cfg(not(feature = "frontend"))]
+    if options.rom.is_some() {
+        for frame in 0..options.frames.unwrap_or(DEFAULT_HEADLESS_FRAMES) {
+            if let Some(script) = &mut script {
+                script.apply(&mut cpu, frame);
+            }
+            run_frame(&mut cpu)?;
+            // nothing plays the sound, the samples are dropped so they don't pile up
+            cpu.apu.take_samples();
+        }
+        cpu.backup.flush()?;
+    }
+
     /*
     let args: Vec<String> = env::args().collect();
     let filename = args[1].clone();
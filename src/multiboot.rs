@@ -0,0 +1,312 @@
+use std::path::Path;
+use crate::cartridge::LoadError;
+use crate::cpu::CPU;
+use crate::interrupt::Interrupt;
+use crate::io::{io_read, io_write, RCNT, SIOCNT, SIODATA32};
+use crate::scheduler::EventKind;
+
+// multiboot images are sent over the link cable by another GBA and run from the board RAM, see https://problemkaputt.de/gbatek.htm#biosmultibootsinglegamepak
+// they have the same header as a ROM, but are linked to 0x02000000 and entered at 0x020000C0 after the transfer
+// without a BIOS the image is copied into place directly, with one the BIOS receives it from a simulated sending GBA
+
+pub const MULTIBOOT_ADDRESS: u32 = 0x02000000;
+pub const MULTIBOOT_ENTRY: u32 = 0x020000C0;
+pub const MAX_SIZE: usize = 0x40000;  // all of the board RAM
+
+// the BIOS fills in these header bytes after the transfer
+const BOOT_MODE: usize = 0xC4;
+const SLAVE_ID: usize = 0xC5;
+const BOOT_MODE_MULTIPLAY: u8 = 3;
+
+const HEADER_SIZE: usize = 0xC0;
+// the BIOS takes at least 0x100 bytes after the header, in steps of 16 bytes
+const MIN_SIZE: usize = 0x1C0;
+
+// the sender uses normal mode with 32 bit transfers and clocks them, the BIOS waits on the external clock
+// see https://problemkaputt.de/gbatek.htm#biosmultibootsinglegamepak for the protocol
+const PALETTE: u32 = 0xD1;
+const ENCRYPTION_KEY: u32 = 0x43202F2F;
+const SEED_MULTIPLIER: u32 = 0x6F646573;
+const CRC_START: u32 = 0xC387;
+const CRC_POLYNOMIAL: u32 = 0xC37B;
+
+// SIOCNT bits
+const SIO_INTERNAL_CLOCK: u16 = 1 << 0;
+const SIO_START: u16 = 1 << 7;
+const SIO_MULTIPLAY_OR_UART: u16 = 1 << 13;
+const SIO_IRQ_ENABLE: u16 = 1 << 14;
+// RCNT bit 15 switches the port to general purpose or JOY bus mode
+const RCNT_MODE: u16 = 1 << 15;
+
+// cycles between the BIOS being ready and the transfer, slow enough for it to prepare its answer
+const TRANSFER_CYCLES: u128 = 2048;
+// the sender waits 1/16 seconds after the handshake
+const LENGTH_DELAY: u128 = (16 * 1024 * 1024) / 16;
+
+// .mb is the usual extension, devkitARM names multiboot builds _mb.gba
+pub fn is_multiboot(filename: &str) -> bool {
+    let lowercase = filename.to_ascii_lowercase();
+    let is_mb = Path::new(&lowercase).extension().is_some_and(|extension| extension == "mb");
+    return is_mb || lowercase.ends_with("_mb.gba") || lowercase.ends_with(".mb.gba");
+}
+
+// copies the image into the board RAM, the way it is after the BIOS received it
+pub fn load(cpu: &mut CPU, image: &[u8]) -> Result<(), LoadError> {
+    if image.len() > MAX_SIZE {
+        return Err(LoadError::TooLarge(image.len(), MAX_SIZE));
+    }
+    let mut image = image.to_vec();
+    if image.len() > SLAVE_ID {
+        // received in multiplay mode as the first slave
+        image[BOOT_MODE] = BOOT_MODE_MULTIPLAY;
+        image[SLAVE_ID] = 1;
+    }
+    // the board RAM holds words, pad to a whole one
+    image.resize((image.len() + 3) & !3, 0);
+    for (index, word) in image.chunks(4).enumerate() {
+        cpu.board_ram[index] = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Step {
+    Probe,  // until the BIOS answers 0x7202
+    Announce,
+    Header(usize),  // offset of the next halfword
+    HeaderDone,
+    Info,
+    Palette,  // until the BIOS answers 0x73 with its client data
+    Handshake,
+    Length,
+    Data(usize),  // offset of the next word
+    Finish,  // until the BIOS answers 0x75
+    CrcFollows,
+    Crc,
+    Done,
+}
+
+// the other GBA on the link cable, sending an image the way the MultiBoot SWI of its BIOS does
+pub struct Sender {
+    image: Vec<u8>,
+    step: Step,
+    seed: u32,
+    handshake: u32,
+    final_value: u32,  // goes into the CRC after the data
+    crc: u32,
+}
+
+impl Sender {
+    pub fn new(image: &[u8]) -> Result<Sender, LoadError> {
+        if image.len() > MAX_SIZE {
+            return Err(LoadError::TooLarge(image.len(), MAX_SIZE));
+        }
+        let mut image = image.to_vec();
+        image.resize(((image.len() + 0xF) & !0xF).max(MIN_SIZE), 0);
+        Ok(Sender {
+            image,
+            step: Step::Probe,
+            seed: 0,
+            handshake: 0,
+            final_value: 0,
+            crc: CRC_START,
+        })
+    }
+
+    pub fn done(&self) -> bool {
+        return self.step == Step::Done;
+    }
+
+    // cycles from the BIOS being ready until the next transfer
+    pub fn delay(&self) -> u128 {
+        if self.step == Step::Length {
+            return LENGTH_DELAY;
+        }
+        return TRANSFER_CYCLES;
+    }
+
+    // one transfer, returns what the sender puts on the cable
+    // reply is what the BIOS sent at the same time, the answer to the previous transfer
+    pub fn transfer(&mut self, reply: u32) -> u32 {
+        let answer = reply >> 16;
+        let (send, next) = match self.step {
+            Step::Probe => (0x6202, if answer == 0x7202 {Step::Announce} else {Step::Probe}),
+            Step::Announce => (0x6102, Step::Header(0)),
+            Step::Header(offset) => {
+                let halfword = u16::from_le_bytes([self.image[offset], self.image[offset + 1]]) as u32;
+                (halfword, if offset + 2 < HEADER_SIZE {Step::Header(offset + 2)} else {Step::HeaderDone})
+            },
+            Step::HeaderDone => (0x6200, Step::Info),
+            Step::Info => (0x6202, Step::Palette),
+            Step::Palette => {
+                if answer >> 8 == 0x73 {
+                    let client_data = answer & 0xFF;
+                    self.seed = 0xFFFF0000 | (client_data << 8) | PALETTE;
+                    self.handshake = client_data + 0xF;
+                    (0x6300 | PALETTE, Step::Handshake)
+                }
+                else {
+                    (0x6300 | PALETTE, Step::Palette)
+                }
+            },
+            Step::Handshake => (0x6400 | (self.handshake & 0xFF), Step::Length),
+            Step::Length => {
+                // the BIOS answers the handshake with random data for the CRC
+                self.final_value = 0xFFFF0000 | (((answer & 0xFF) << 8) + self.handshake);
+                (((self.image.len() - MIN_SIZE) / 4) as u32, Step::Data(HEADER_SIZE))
+            },
+            Step::Data(offset) => {
+                let word = u32::from_le_bytes(self.image[offset..offset + 4].try_into().unwrap());
+                self.crc = crc_step(self.crc, word);
+                self.seed = self.seed.wrapping_mul(SEED_MULTIPLIER).wrapping_add(1);
+                let encrypted = word ^ self.seed ^ 0xFE000000u32.wrapping_sub(offset as u32) ^ ENCRYPTION_KEY;
+                if offset + 4 < self.image.len() {
+                    (encrypted, Step::Data(offset + 4))
+                }
+                else {
+                    self.crc = crc_step(self.crc, self.final_value);
+                    (encrypted, Step::Finish)
+                }
+            },
+            Step::Finish => (0x0065, if answer == 0x0075 {Step::CrcFollows} else {Step::Finish}),
+            Step::CrcFollows => (0x0066, Step::Crc),
+            Step::Crc => (self.crc & 0xFFFF, Step::Done),
+            Step::Done => (0, Step::Done),
+        };
+        self.step = next;
+        return send;
+    }
+}
+
+fn crc_step(mut crc: u32, mut word: u32) -> u32 {
+    for _ in 0..32 {
+        let bit = (crc ^ word) & 1;
+        crc >>= 1;
+        if bit != 0 {
+            crc ^= CRC_POLYNOMIAL;
+        }
+        word >>= 1;
+    }
+    return crc;
+}
+
+// the BIOS waits for a transfer in normal mode on the external clock with the start bit set
+fn waiting_for_transfer(cpu: &CPU) -> bool {
+    let siocnt = io_read(cpu, SIOCNT) as u16;
+    let rcnt = io_read(cpu, RCNT) as u16;
+    return rcnt & RCNT_MODE == 0 && siocnt & (SIO_MULTIPLAY_OR_UART | SIO_INTERNAL_CLOCK) == 0 && siocnt & SIO_START != 0;
+}
+
+// called on writes to SIOCNT, the sender clocks the transfer after a while
+pub fn write_siocnt(cpu: &mut CPU) {
+    cpu.scheduler.cancel(EventKind::SerialTransfer);
+    let delay = match &cpu.multiboot_sender {
+        Some(sender) if !sender.done() && waiting_for_transfer(cpu) => sender.delay(),
+        _ => return,
+    };
+    cpu.scheduler.schedule(cpu.cycles() + delay, EventKind::SerialTransfer);
+}
+
+pub fn transfer_event(cpu: &mut CPU) {
+    if !waiting_for_transfer(cpu) {
+        return;
+    }
+    let reply = io_read(cpu, SIODATA32);
+    let send = match cpu.multiboot_sender.as_mut() {
+        Some(sender) => sender.transfer(reply),
+        None => return,
+    };
+    if cpu.multiboot_sender.as_ref().is_some_and(|sender| sender.done()) {
+        cpu.multiboot_sender = None;
+    }
+    io_write(cpu, SIODATA32, send, 0xFFFFFFFF);
+    // clearing the start bit ends the transfer, this doesn't start another one
+    io_write(cpu, SIOCNT, 0, SIO_START as u32);
+    if io_read(cpu, SIOCNT) as u16 & SIO_IRQ_ENABLE != 0 {
+        cpu.request_interrupt(Interrupt::Serial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc_step, Sender, CRC_START, TRANSFER_CYCLES, LENGTH_DELAY, SIO_IRQ_ENABLE, SIO_START};
+    use crate::cpu::{RWType, CPU};
+    use crate::interrupt::Interrupt;
+    use crate::io::{SIOCNT, SIODATA32};
+
+    fn image() -> Vec<u8> {
+        return (0..0x200u32).map(|index| (index * 7) as u8).collect();
+    }
+
+    #[test]
+    fn sends_the_image_like_the_bios_expects() {
+        let image = image();
+        let mut sender = Sender::new(&image).unwrap();
+        // nobody answers at first, the sender keeps probing
+        assert_eq!(sender.transfer(0), 0x6202);
+        assert_eq!(sender.transfer(0x72020000), 0x6202);
+        assert_eq!(sender.transfer(0x72020000), 0x6102);
+        let header: Vec<u8> = (0..0x60).flat_map(|_| (sender.transfer(0x72020000) as u16).to_le_bytes()).collect();
+        assert_eq!(header, image[..0xC0]);
+        assert_eq!(sender.transfer(0), 0x6200);
+        assert_eq!(sender.transfer(0), 0x6202);
+        // the BIOS answers the palette with its client data, the handshake is derived from it
+        assert_eq!(sender.transfer(0x72020000), 0x63D1);
+        assert_eq!(sender.transfer(0x735A0000), 0x63D1);
+        assert_eq!(sender.transfer(0), 0x6469);
+        assert_eq!(sender.delay(), LENGTH_DELAY);
+        assert_eq!(sender.transfer(0x73330000), (0x200 - 0x1C0) / 4);
+        assert_eq!(sender.delay(), TRANSFER_CYCLES);
+
+        // decrypted the way the BIOS does it
+        let mut seed: u32 = 0xFFFF5AD1;
+        let mut crc = CRC_START;
+        for offset in (0xC0..0x200).step_by(4) {
+            seed = seed.wrapping_mul(0x6F646573).wrapping_add(1);
+            let word = sender.transfer(0) ^ seed ^ 0xFE000000u32.wrapping_sub(offset) ^ 0x43202F2F;
+            let offset = offset as usize;
+            assert_eq!(word.to_le_bytes(), image[offset..offset + 4]);
+            crc = crc_step(crc, word);
+        }
+        crc = crc_step(crc, 0xFFFF0000 | ((0x33 << 8) + 0x69));
+
+        assert_eq!(sender.transfer(0), 0x65);
+        assert_eq!(sender.transfer(0x00740000), 0x65);
+        assert_eq!(sender.transfer(0x00750000), 0x65);
+        assert_eq!(sender.transfer(0), 0x66);
+        assert!(!sender.done());
+        assert_eq!(sender.transfer(0), crc & 0xFFFF);
+        assert!(sender.done());
+    }
+
+    #[test]
+    fn short_images_are_padded() {
+        let mut sender = Sender::new(&[0; 0xC4]).unwrap();
+        assert_eq!(sender.image.len(), 0x1C0);
+        sender.step = super::Step::Length;
+        assert_eq!(sender.transfer(0), 0);
+        assert!(Sender::new(&vec![0; 0x40001]).is_err());
+    }
+
+    #[test]
+    fn transfers_through_the_serial_port() {
+        let mut cpu = CPU::new();
+        cpu.multiboot_sender = Some(Sender::new(&image()).unwrap());
+        cpu.memory_write(SIODATA32, RWType::Word, 0x72020000);
+        // normal mode with 32 bits on the external clock, waiting for the sender
+        cpu.memory_write(SIOCNT, RWType::HalfWord, (SIO_IRQ_ENABLE | SIO_START | 1 << 12) as u32);
+        cpu.add_cycles(TRANSFER_CYCLES - 1);
+        assert_eq!(cpu.memory_read(SIODATA32, RWType::Word), 0x72020000);
+        cpu.add_cycles(1);
+        assert_eq!(cpu.memory_read(SIODATA32, RWType::Word), 0x6202);
+        assert_eq!(cpu.memory_read(SIOCNT, RWType::HalfWord) as u16 & SIO_START, 0);
+        assert_eq!(cpu.interrupts.irf, 1 << Interrupt::Serial as u16);
+
+        // nothing happens until the BIOS is ready again
+        cpu.add_cycles(10 * TRANSFER_CYCLES);
+        assert_eq!(cpu.memory_read(SIODATA32, RWType::Word), 0x6202);
+        cpu.memory_write(SIOCNT, RWType::HalfWord, (SIO_START | 1 << 12) as u32);
+        cpu.add_cycles(TRANSFER_CYCLES);
+        assert_eq!(cpu.memory_read(SIODATA32, RWType::Word), 0x6102);
+    }
+}
@@ -0,0 +1,384 @@
+use std::path::{Path, PathBuf};
+
+use crate::archive::MAX_ROM_SIZE;
+
+// soft patching, the ROM is patched in memory while loading and the file on disk stays untouched
+// the format is recognized by the magic bytes, IPS has no checksums, UPS and BPS come with CRC32s of source and target
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// UPS and BPS end with the CRC32s of the source, the target and the patch itself
+const FOOTER_SIZE: usize = 12;
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+// a patch next to the ROM with the same name, e.g. game.ips for game.gba
+pub fn find_patch(rom_filename: &str) -> Option<PathBuf> {
+    return PATCH_EXTENSIONS.iter()
+        .map(|extension| Path::new(rom_filename).with_extension(extension))
+        .find(|path| path.is_file());
+}
+
+// returns the patched ROM, the error describes what's wrong with the patch
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_MAGIC) {
+        return apply_ips(rom, patch);
+    }
+    else if patch.starts_with(UPS_MAGIC) {
+        return apply_ups(rom, patch);
+    }
+    else if patch.starts_with(BPS_MAGIC) {
+        return apply_bps(rom, patch);
+    }
+    return Err(String::from("not an IPS, UPS or BPS patch"));
+}
+
+// reads from the patch, running past its end is an error
+struct PatchReader<'a> {
+    patch: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(patch: &'a [u8], position: usize) -> PatchReader<'a> {
+        PatchReader {
+            patch,
+            position,
+        }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.position + count > self.patch.len() {
+            return Err(String::from("patch ends unexpectedly"));
+        }
+        let bytes = &self.patch[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    // big endian number of the given size, used by IPS
+    fn number(&mut self, size: usize) -> Result<usize, String> {
+        Ok(self.bytes(size)?.iter().fold(0, |value, byte| (value << 8) | *byte as usize))
+    }
+
+    // variable length number of UPS and BPS, 7 bits per byte with the top bit marking the last one
+    // every continuation adds one, so each number has exactly one encoding
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.byte()?;
+            value = value.checked_add((byte & 0x7F) as usize * shift).ok_or("number in patch is too large")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or("number in patch is too large")?;
+            value += shift;
+        }
+    }
+}
+
+// records of a 3 byte offset and 2 byte size, followed by the data
+// a size of 0 marks a run: 2 byte length and the byte to repeat
+// after the EOF marker an optional 3 byte size truncates the ROM
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut target = rom.to_vec();
+    let mut reader = PatchReader::new(patch, IPS_MAGIC.len());
+    loop {
+        if patch[reader.position..].starts_with(IPS_EOF) {
+            reader.position += IPS_EOF.len();
+            break;
+        }
+        let offset = reader.number(3)?;
+        let size = reader.number(2)?;
+        let data = if size == 0 {
+            let length = reader.number(2)?;
+            vec![reader.byte()?; length]
+        }
+        else {
+            reader.bytes(size)?.to_vec()
+        };
+        if target.len() < offset + data.len() {
+            check_target_size(offset + data.len())?;
+            target.resize(offset + data.len(), 0);
+        }
+        target[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    if reader.position + 3 <= patch.len() {
+        let size = reader.number(3)?;
+        target.truncate(size);
+    }
+    Ok(target)
+}
+
+// checks the CRC32s in the footer that are about the patch and the source
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<(u32, usize), String> {
+    if patch.len() < FOOTER_SIZE + 4 {
+        return Err(String::from("patch is too short"));
+    }
+    let footer = patch.len() - FOOTER_SIZE;
+    let crc = |offset: usize| u32::from_le_bytes([patch[offset], patch[offset + 1], patch[offset + 2], patch[offset + 3]]);
+    if crc32fast::hash(&patch[..footer + 8]) != crc(footer + 8) {
+        return Err(String::from("patch is damaged, its checksum doesn't match"));
+    }
+    if crc32fast::hash(rom) != crc(footer) {
+        return Err(String::from("the patch was made for a different ROM, the checksum of the ROM doesn't match"));
+    }
+    Ok((crc(footer + 4), footer))
+}
+
+// the size comes from the patch, it's checked before anything is allocated for it
+fn check_target_size(target_size: usize) -> Result<(), String> {
+    if target_size > MAX_ROM_SIZE {
+        return Err(format!("the patched ROM would have {} bytes, the maximum is {}", target_size, MAX_ROM_SIZE));
+    }
+    Ok(())
+}
+
+fn check_target(target: &[u8], target_crc: u32) -> Result<(), String> {
+    if crc32fast::hash(target) != target_crc {
+        return Err(String::from("checksum of the patched ROM doesn't match"));
+    }
+    Ok(())
+}
+
+// source and target size, then hunks of a relative offset and bytes XORed onto the ROM up to and including a 0
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (target_crc, footer) = check_footer(rom, patch)?;
+    let mut reader = PatchReader::new(&patch[..footer], UPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    if source_size != rom.len() {
+        return Err(format!("the patch is for a ROM of {} bytes, this one has {}", source_size, rom.len()));
+    }
+    check_target_size(target_size)?;
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+    let mut offset: usize = 0;
+    while reader.position < footer {
+        offset = offset.checked_add(reader.varint()?).ok_or("number in patch is too large")?;
+        loop {
+            let byte = reader.byte()?;
+            if offset < target.len() {
+                target[offset] ^= byte;
+            }
+            offset = offset.checked_add(1).ok_or("number in patch is too large")?;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+// source size, target size and metadata, then actions that build the target front to back
+// each action is a number with the kind in the lowest 2 bits and the length - 1 above
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    const SOURCE_READ: usize = 0;
+    const TARGET_READ: usize = 1;
+    const SOURCE_COPY: usize = 2;
+    const TARGET_COPY: usize = 3;
+
+    let (target_crc, footer) = check_footer(rom, patch)?;
+    let mut reader = PatchReader::new(&patch[..footer], BPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(format!("the patch is for a ROM of {} bytes, this one has {}", source_size, rom.len()));
+    }
+    check_target_size(target_size)?;
+
+    let mut target: Vec<u8> = Vec::with_capacity(target_size);
+    // the copy actions move these around relative to their last position
+    let mut source_offset: i64 = 0;
+    let mut target_offset: i64 = 0;
+    let out_of_bounds = || String::from("patch refers to data outside the ROM");
+    // a range of the given length from start, None if it can't exist
+    let range = |start: usize, length: usize| start.checked_add(length).map(|end| start..end);
+    while reader.position < footer {
+        let action = reader.varint()?;
+        let length = (action >> 2) + 1;
+        if length > target_size - target.len() {
+            return Err(String::from("patch writes past the end of the patched ROM"));
+        }
+        match action & 0b11 {
+            SOURCE_READ => {
+                let start = target.len();
+                target.extend_from_slice(range(start, length).and_then(|range| rom.get(range)).ok_or_else(out_of_bounds)?);
+            },
+            TARGET_READ => target.extend_from_slice(reader.bytes(length)?),
+            SOURCE_COPY | TARGET_COPY => {
+                // the offset is relative, with the sign in the lowest bit
+                let data = reader.varint()?;
+                let relative = if data & 1 != 0 {-((data >> 1) as i64)} else {(data >> 1) as i64};
+                if action & 0b11 == SOURCE_COPY {
+                    source_offset = source_offset.checked_add(relative).ok_or_else(out_of_bounds)?;
+                    let start = usize::try_from(source_offset).map_err(|_| out_of_bounds())?;
+                    target.extend_from_slice(range(start, length).and_then(|range| rom.get(range)).ok_or_else(out_of_bounds)?);
+                    source_offset += length as i64;
+                }
+                else {
+                    target_offset = target_offset.checked_add(relative).ok_or_else(out_of_bounds)?;
+                    let start = usize::try_from(target_offset).map_err(|_| out_of_bounds())?;
+                    // byte by byte, the copy may overlap with what it writes
+                    for index in range(start, length).ok_or_else(out_of_bounds)? {
+                        let byte = *target.get(index).ok_or_else(out_of_bounds)?;
+                        target.push(byte);
+                    }
+                    target_offset += length as i64;
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+    if target.len() != target_size {
+        return Err(String::from("patched ROM doesn't have the size the patch expects"));
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply;
+    use crate::archive::MAX_ROM_SIZE;
+
+    fn varint(mut value: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte | 0x80);
+                return bytes;
+            }
+            bytes.push(byte);
+            value -= 1;
+        }
+    }
+
+    // appends the CRC32s of source, target and the patch so far
+    fn footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        return patch;
+    }
+
+    fn header(magic: &[u8], sizes: &[usize]) -> Vec<u8> {
+        let mut patch = magic.to_vec();
+        for size in sizes {
+            patch.extend(varint(*size));
+        }
+        return patch;
+    }
+
+    #[test]
+    fn ips() {
+        let rom = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        // plain record
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // run of 3 bytes
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        // a record past the end grows the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x09, 0x00, 0x01, 0xDD]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0xAA, 0xBB, 0, 0xCC, 0xCC, 0xCC, 0, 0, 0xDD]);
+
+        // the size after EOF truncates
+        patch.extend_from_slice(&[0x00, 0x00, 0x05]);
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0xAA, 0xBB, 0, 0xCC]);
+
+        let cut = &patch[..patch.len() - 12];
+        assert!(apply(&rom, cut).is_err());
+    }
+
+    #[test]
+    fn ups() {
+        let rom = [1u8, 2, 3, 4];
+        let target = [1u8, 7, 3, 4, 9, 0];
+        let mut patch = header(b"UPS1", &[rom.len(), target.len()]);
+        // skip one byte, XOR one and end the hunk on the unchanged byte after it
+        patch.extend(varint(1));
+        patch.extend_from_slice(&[2 ^ 7, 0]);
+        // skip the 4, the 9 lands on the new space
+        patch.extend(varint(1));
+        patch.extend_from_slice(&[9, 0]);
+        let good = footer(patch.clone(), &rom, &target);
+        assert_eq!(apply(&rom, &good).unwrap(), target);
+
+        // wrong ROM, wrong result and a damaged patch
+        assert!(apply(&[1, 2, 3, 5], &good).is_err());
+        assert!(apply(&rom, &footer(patch.clone(), &rom, &[0; 6])).is_err());
+        let mut damaged = good.clone();
+        damaged[6] ^= 1;
+        assert!(apply(&rom, &damaged).is_err());
+
+        let huge = footer(header(b"UPS1", &[rom.len(), MAX_ROM_SIZE + 1]), &rom, &target);
+        assert!(apply(&rom, &huge).is_err());
+        // a hunk that runs past the largest offset
+        let mut far = header(b"UPS1", &[rom.len(), rom.len()]);
+        far.extend(varint(usize::MAX - 1));
+        far.extend_from_slice(&[1, 1, 0]);
+        assert!(apply(&rom, &footer(far, &rom, &rom)).is_err());
+    }
+
+    #[test]
+    fn bps() {
+        const SOURCE_READ: usize = 0;
+        const TARGET_READ: usize = 1;
+        const SOURCE_COPY: usize = 2;
+        const TARGET_COPY: usize = 3;
+        let action = |kind: usize, length: usize| varint(((length - 1) << 2) | kind);
+        let offset = |relative: isize| varint((relative.unsigned_abs() << 1) | (relative < 0) as usize);
+
+        let rom = [1u8, 2, 3, 4];
+        let target = [1u8, 2, 9, 4, 9, 4, 9, 4, 9, 4];
+        let mut patch = header(b"BPS1", &[rom.len(), target.len(), 0]);
+        patch.extend(action(SOURCE_READ, 2));
+        patch.extend(action(TARGET_READ, 1));
+        patch.push(9);
+        patch.extend(action(SOURCE_COPY, 1));
+        patch.extend(offset(3));
+        // overlapping copy of what's being written
+        patch.extend(action(TARGET_COPY, 5));
+        patch.extend(offset(2));
+        patch.extend(action(TARGET_COPY, 1));
+        patch.extend(offset(-2));
+        let good = footer(patch.clone(), &rom, &target);
+        assert_eq!(apply(&rom, &good).unwrap(), target);
+
+        assert!(apply(&[1, 2, 3, 5], &good).is_err());
+        assert!(apply(&rom, &footer(patch.clone(), &rom, &[0; 10])).is_err());
+        let mut damaged = good.clone();
+        damaged[8] ^= 1;
+        assert!(apply(&rom, &damaged).is_err());
+
+        // writing more than the target size, reading outside the source and an absurd size
+        let mut long = header(b"BPS1", &[rom.len(), 2, 0]);
+        long.extend(action(SOURCE_READ, 4));
+        assert!(apply(&rom, &footer(long, &rom, &rom)).is_err());
+        let mut outside = header(b"BPS1", &[rom.len(), 4, 0]);
+        outside.extend(action(SOURCE_COPY, 4));
+        outside.extend(offset(2));
+        assert!(apply(&rom, &footer(outside, &rom, &rom)).is_err());
+        let mut far = header(b"BPS1", &[rom.len(), 4, 0]);
+        far.extend(action(SOURCE_COPY, 1));
+        far.extend(varint(usize::MAX - 1));
+        assert!(apply(&rom, &footer(far, &rom, &rom)).is_err());
+        let huge = header(b"BPS1", &[rom.len(), MAX_ROM_SIZE + 1, 0]);
+        assert!(apply(&rom, &footer(huge, &rom, &rom)).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::fmt;
+use serde::Serialize;
+use crate::backup::SaveType;
+
+// metadata about a ROM, taken from its header and contents
+// serializes to JSON for scripts that catalogue ROMs, Display gives the text shown on startup
+
+// the region is the last letter of the game code, see https://problemkaputt.de/gbatek.htm#gbacartridgeheader
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+pub enum Region {
+    Japan,
+    USA,
+    Europe,
+    Germany,
+    France,
+    Italy,
+    Spain,
+    Netherlands,
+    Australia,
+    Korea,
+    China,
+    Unknown,
+}
+
+impl Region {
+    pub fn from_letter(letter: u8) -> Region {
+        match letter {
+            b'J' => Region::Japan,
+            b'E' => Region::USA,
+            b'P' | b'X' | b'Y' => Region::Europe,
+            b'D' => Region::Germany,
+            b'F' => Region::France,
+            b'I' => Region::Italy,
+            b'S' => Region::Spain,
+            b'H' => Region::Netherlands,
+            b'U' => Region::Australia,
+            b'K' => Region::Korea,
+            b'C' => Region::China,
+            _ => Region::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct RomInfo {
+    pub title: String,
+    pub game_code: String,
+    pub maker_code: String,
+    pub region: Region,
+    pub version: u8,
+    pub size: usize,  // in bytes
+    pub save_type: SaveType,
+    pub gpio: bool,  // RTC or sensors on the GPIO port
+    pub header_valid: bool,
+}
+
+impl RomInfo {
+    pub fn to_json(&self) -> String {
+        // only plain strings, numbers and enums in here, so this can't fail
+        return serde_json::to_string_pretty(self).unwrap();
+    }
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "------------")?;
+        writeln!(f, "ROM Info:")?;
+        writeln!(f, "Title: {}", self.title)?;
+        writeln!(f, "Game code: {}", self.game_code)?;
+        writeln!(f, "Maker code: {}", self.maker_code)?;
+        writeln!(f, "Region: {:?}", self.region)?;
+        writeln!(f, "Software version: {}", self.version)?;
+        writeln!(f, "ROM size: {} KB ({} bytes)", self.size / 1024, self.size)?;
+        writeln!(f, "Save type: {}", self.save_type)?;
+        writeln!(f, "GPIO: {}", if self.gpio {"yes"} else {"no"})?;
+        write!(f, "Header: {}", if self.header_valid {"valid"} else {"invalid"})
+    }
+}
@@ -0,0 +1,49 @@
+// event scheduler
+// instead of ticking every peripheral each cycle, peripherals register the timestamp (in CPU cycles) at which something happens next
+// the CPU checks after each instruction whether an event is due and dispatches it
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    TimerOverflow(usize),
+    ApuSample,
+    ApuFrameSequencer,
+    SerialTransfer,
+    HBlank,
+    Scanline,  // start of the next line
+}
+
+pub struct Scheduler {
+    // kept sorted by timestamp, the earliest event is at the end so it can be popped cheaply
+    // there are only ever a handful of events in flight, so a vector beats a heap here and allows cancelling
+    events: Vec<(u128, EventKind)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            events: Vec::new(),
+        }
+    }
+
+    pub fn schedule(&mut self, timestamp: u128, kind: EventKind) {
+        // events with the same timestamp are dispatched in the order they were scheduled
+        let position = self.events.iter().position(|(t, _)| *t <= timestamp).unwrap_or(self.events.len());
+        self.events.insert(position, (timestamp, kind));
+    }
+
+    pub fn cancel(&mut self, kind: EventKind) {
+        self.events.retain(|(_, k)| *k != kind);
+    }
+
+    // returns the earliest event if its timestamp has been reached
+    pub fn pop_due(&mut self, now: u128) -> Option<(u128, EventKind)> {
+        match self.events.last() {
+            Some((timestamp, _)) if *timestamp <= now => self.events.pop(),
+            _ => None,
+        }
+    }
+
+    pub fn next_timestamp(&self) -> Option<u128> {
+        return self.events.last().map(|(timestamp, _)| *timestamp);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::cpu::CPU;
+use crate::keypad::ButtonSet;
+
+// scripted input for tests, one event per line with the frame it happens in, the input and its values
+// values stay until the script changes them again, lines starting with # are comments
+//   0 buttons A+START
+//   60 light 200        solar sensor, 0 to 255
+//   60 gyro -300        rotation speed
+//   120 tilt 40 -20     x and y
+// inputs for sensors the cart doesn't have are ignored
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ScriptedInput {
+    Buttons(ButtonSet),
+    Light(u8),
+    Gyro(i16),
+    Tilt(i16, i16),
+}
+
+pub struct InputScript {
+    events: Vec<(u64, ScriptedInput)>,
+    next: usize,
+    // scripted buttons are held until changed, even if a frontend polls its own input in between
+    buttons: Option<ButtonSet>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut events = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| format!("Input script line {}: {}", number + 1, message);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                return Err(error("expected a frame, an input and its value"));
+            }
+            let frame: u64 = fields[0].parse().map_err(|_| error("invalid frame"))?;
+            let value = |index: usize| -> Result<i16, String> {
+                fields.get(index).ok_or(error("missing value"))?.parse().map_err(|_| error("invalid value"))
+            };
+            let input = match fields[1] {
+                "buttons" => ScriptedInput::Buttons(fields[2].parse().map_err(|e: String| error(&e))?),
+                "light" => ScriptedInput::Light(fields[2].parse().map_err(|_| error("light needs a value from 0 to 255"))?),
+                "gyro" => ScriptedInput::Gyro(value(2)?),
+                "tilt" => ScriptedInput::Tilt(value(2)?, value(3)?),
+                other => return Err(error(&format!("unknown input {}", other))),
+            };
+            events.push((frame, input));
+        }
+        // stable, so events in the same frame keep their order
+        events.sort_by_key(|(frame, _)| *frame);
+        Ok(InputScript {
+            events,
+            next: 0,
+            buttons: None,
+        })
+    }
+
+    // applies everything that's due up to the given frame, meant to be called before running it
+    pub fn apply(&mut self, cpu: &mut CPU, frame: u64) {
+        while self.next < self.events.len() && self.events[self.next].0 <= frame {
+            match self.events[self.next].1 {
+                ScriptedInput::Buttons(buttons) => self.buttons = Some(buttons),
+                ScriptedInput::Light(light) => {
+                    if let Some(solar) = &mut cpu.gpio.solar {
+                        solar.set_light(light);
+                    }
+                },
+                ScriptedInput::Gyro(rate) => {
+                    if let Some(gyro) = &mut cpu.gpio.gyro {
+                        gyro.set_rate(rate);
+                    }
+                },
+                ScriptedInput::Tilt(x, y) => {
+                    if let Some(tilt) = &mut cpu.tilt {
+                        tilt.set_tilt(x, y);
+                    }
+                },
+            }
+            self.next += 1;
+        }
+        if let Some(buttons) = self.buttons {
+            cpu.set_buttons(buttons);
+        }
+    }
+}
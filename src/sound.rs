@@ -0,0 +1,5 @@
+pub mod apu;
+pub mod fifo;
+pub mod psg;
+pub mod resampler;
+pub mod wav;
\ No newline at end of file
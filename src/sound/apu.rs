@@ -0,0 +1,405 @@
+use crate::cpu::CPU;
+use crate::dma;
+use crate::scheduler::EventKind;
+use crate::sound::fifo::FIFO;
+use crate::sound::psg::{NoiseChannel, SquareChannel, WaveChannel};
+
+// the sound unit, see https://problemkaputt.de/gbatek.htm#gbasoundcontroller
+// the APU takes samples at the rate set in SOUNDBIAS and collects them in a buffer, frontends drain it with take_samples
+
+pub const SOUND1CNT_L: u32 = 0x04000060;
+pub const SOUND1CNT_H: u32 = 0x04000062;
+pub const SOUND1CNT_X: u32 = 0x04000064;
+pub const SOUND2CNT_L: u32 = 0x04000068;
+pub const SOUND2CNT_H: u32 = 0x0400006C;
+pub const SOUND3CNT_L: u32 = 0x04000070;
+pub const SOUND3CNT_H: u32 = 0x04000072;
+pub const SOUND3CNT_X: u32 = 0x04000074;
+pub const SOUND4CNT_L: u32 = 0x04000078;
+pub const SOUND4CNT_H: u32 = 0x0400007C;
+pub const SOUNDCNT_L: u32 = 0x04000080;
+pub const SOUNDCNT_H: u32 = 0x04000082;
+pub const SOUNDCNT_X: u32 = 0x04000084;
+pub const SOUNDBIAS: u32 = 0x04000088;
+pub const WAVE_RAM: u32 = 0x04000090;
+pub const WAVE_RAM_END: u32 = 0x0400009E;
+pub const FIFO_A: u32 = 0x040000A0;
+pub const FIFO_B: u32 = 0x040000A4;
+pub const FIFO_END: u32 = 0x040000A6;
+
+// readable bits of the registers from SOUND1CNT_L to 0x0400008E, the rest is write only or unused
+const READ_MASKS: [u16; 24] = [
+    0x007F, 0xFFC0, 0x4000, 0x0000,  // SOUND1CNT_L, SOUND1CNT_H, SOUND1CNT_X, unused
+    0xFFC0, 0x0000, 0x4000, 0x0000,  // SOUND2CNT_L, unused, SOUND2CNT_H, unused
+    0x00E0, 0xE000, 0x4000, 0x0000,  // SOUND3CNT_L, SOUND3CNT_H, SOUND3CNT_X, unused
+    0xFF00, 0x0000, 0x40FF, 0x0000,  // SOUND4CNT_L, unused, SOUND4CNT_H, unused
+    0xFF77, 0x770F, 0x0080, 0x0000,  // SOUNDCNT_L, SOUNDCNT_H, SOUNDCNT_X, unused
+    0xC3FE, 0x0000, 0x0000, 0x0000,  // SOUNDBIAS, unused
+];
+
+const MASTER_ENABLE: u16 = 1 << 7;
+
+// SOUNDCNT_H bits for the Direct Sound channels, the ones for FIFO B are 4 (volume: 1) further up
+const FIFO_A_FULL_VOLUME: u16 = 1 << 2;
+const FIFO_A_RIGHT: u16 = 1 << 8;
+const FIFO_A_LEFT: u16 = 1 << 9;
+const FIFO_A_TIMER: u16 = 1 << 10;
+const FIFO_A_RESET: u16 = 1 << 11;
+
+// the frame sequencer runs at 512 Hz and clocks length counters, sweep and envelopes
+const FRAME_SEQUENCER_PERIOD: u128 = 32768;
+// at the lowest sampling rate of 32768 Hz, a sample is taken every 512 cycles
+const BASE_SAMPLE_PERIOD: u128 = 512;
+// don't let the buffer grow endlessly if nobody drains it, about 2 seconds at 32768 Hz
+const MAX_BUFFERED_SAMPLES: usize = 65536;
+
+pub struct APU {
+    pub square1: SquareChannel,
+    pub square2: SquareChannel,
+    pub wave: WaveChannel,
+    pub noise: NoiseChannel,
+    pub fifos: [FIFO; 2],
+    // raw values of the registers from SOUND1CNT_L to 0x0400008E, needed to merge byte writes and for reads
+    registers: [u16; 24],
+    frame_sequencer_step: u8,
+    last_sample: u128,
+    samples: Vec<(i16, i16)>,
+}
+
+impl APU {
+    pub fn new() -> APU {
+        let mut apu = APU {
+            square1: SquareChannel::new(true),
+            square2: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            fifos: [FIFO::new(), FIFO::new()],
+            registers: [0; 24],
+            frame_sequencer_step: 0,
+            last_sample: 0,
+            samples: Vec::new(),
+        };
+        // SOUNDBIAS starts out at the middle of the output range
+        apu.registers[register_index(SOUNDBIAS)] = 0x0200;
+        apu
+    }
+
+    fn register(&self, address: u32) -> u16 {
+        return self.registers[register_index(address)];
+    }
+
+    fn master_enabled(&self) -> bool {
+        return self.register(SOUNDCNT_X) & MASTER_ENABLE != 0;
+    }
+
+    // SOUNDBIAS bits 14-15: 32768, 65536, 131072 or 262144 Hz
+    pub fn sample_rate(&self) -> u32 {
+        return 32768 << (self.register(SOUNDBIAS) >> 14);
+    }
+
+    fn sample_period(&self) -> u128 {
+        return BASE_SAMPLE_PERIOD >> (self.register(SOUNDBIAS) >> 14);
+    }
+
+    // hands out all samples taken so far as pairs of left and right, at the rate given by sample_rate
+    pub fn take_samples(&mut self) -> Vec<(i16, i16)> {
+        return std::mem::take(&mut self.samples);
+    }
+
+    pub fn read_register(&self, address: u32) -> u16 {
+        if address >= WAVE_RAM {
+            let offset = (address - WAVE_RAM) as usize;
+            return self.wave.read_wave_ram(offset) as u16 | ((self.wave.read_wave_ram(offset + 1) as u16) << 8);
+        }
+        let index = register_index(address);
+        let mut value = self.registers[index] & READ_MASKS[index];
+        if address == SOUNDCNT_X {
+            // the lower four bits tell whether the channels are currently playing
+            value |= self.square1.enabled as u16
+                | (self.square2.enabled as u16) << 1
+                | (self.wave.enabled as u16) << 2
+                | (self.noise.enabled as u16) << 3;
+        }
+        return value;
+    }
+
+    pub fn write_register(&mut self, address: u32, value: u16, mask: u16) {
+        if address >= WAVE_RAM {
+            let offset = (address - WAVE_RAM) as usize;
+            if mask & 0x00FF != 0 {
+                self.wave.write_wave_ram(offset, value as u8);
+            }
+            if mask & 0xFF00 != 0 {
+                self.wave.write_wave_ram(offset + 1, (value >> 8) as u8);
+            }
+            return;
+        }
+        // while the master enable is off, the PSG registers can't be written
+        if !self.master_enabled() && address <= SOUNDCNT_L {
+            return;
+        }
+        let index = register_index(address);
+        let value = (self.registers[index] & !mask) | (value & mask);
+        self.registers[index] = value;
+        // the length is only (re)loaded if the byte holding it is written, the same goes for the restart bit
+        let low_byte = mask & 0x00FF != 0;
+        let trigger = mask & 0x8000 != 0 && value & 0x8000 != 0;
+        match address {
+            SOUND1CNT_L => self.square1.write_sweep(value),
+            SOUND1CNT_H => self.square1.write_duty_envelope(value, low_byte),
+            SOUND1CNT_X => self.square1.write_frequency(value, trigger),
+            SOUND2CNT_L => self.square2.write_duty_envelope(value, low_byte),
+            SOUND2CNT_H => self.square2.write_frequency(value, trigger),
+            SOUND3CNT_L => self.wave.write_control(value),
+            SOUND3CNT_H => self.wave.write_length_volume(value, low_byte),
+            SOUND3CNT_X => self.wave.write_frequency(value, trigger),
+            SOUND4CNT_L => self.noise.write_length_envelope(value, low_byte),
+            SOUND4CNT_H => self.noise.write_frequency(value, trigger),
+            SOUNDCNT_H => {
+                // the reset bits empty the FIFOs, they are not kept in the register
+                for fifo in 0..2 {
+                    if value & (FIFO_A_RESET << (4 * fifo)) != 0 {
+                        self.fifos[fifo].reset();
+                    }
+                }
+                self.registers[index] &= !(FIFO_A_RESET | (FIFO_A_RESET << 4));
+            },
+            SOUNDCNT_X if value & MASTER_ENABLE == 0 => self.power_off(),
+            _ => {},
+        }
+    }
+
+    // FIFO_A and FIFO_B are write only, every written byte is a sample, the lower one comes first
+    pub fn write_fifo(&mut self, address: u32, value: u16, mask: u16) {
+        let fifo = &mut self.fifos[if address >= FIFO_B {1} else {0}];
+        if mask & 0x00FF != 0 {
+            fifo.push(value as u8 as i8);
+        }
+        if mask & 0xFF00 != 0 {
+            fifo.push((value >> 8) as u8 as i8);
+        }
+    }
+
+    // turning the master enable off resets all PSG registers and silences the channels
+    fn power_off(&mut self) {
+        for address in (SOUND1CNT_L..=SOUNDCNT_L).step_by(2) {
+            self.registers[register_index(address)] = 0;
+        }
+        let wave_ram = self.wave.wave_ram;
+        self.square1 = SquareChannel::new(true);
+        self.square2 = SquareChannel::new(false);
+        self.wave = WaveChannel::new();
+        self.wave.wave_ram = wave_ram;
+        self.noise = NoiseChannel::new();
+        self.frame_sequencer_step = 0;
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        if !self.master_enabled() {
+            return;
+        }
+        // length at 256 Hz, sweep at 128 Hz, envelopes at 64 Hz
+        let step = self.frame_sequencer_step;
+        if step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.square1.clock_sweep();
+        }
+        if step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    fn take_sample(&mut self, timestamp: u128) {
+        let elapsed = (timestamp - self.last_sample) as u32;
+        self.last_sample = timestamp;
+        self.square1.step(elapsed);
+        self.square2.step(elapsed);
+        self.wave.step(elapsed);
+        self.noise.step(elapsed);
+
+        let (mut left, mut right): (i32, i32) = (0, 0);
+        if self.master_enabled() {
+            let soundcnt_l = self.register(SOUNDCNT_L);
+            let outputs = [self.square1.output(), self.square2.output(), self.wave.output(), self.noise.output()];
+            for (channel, output) in outputs.iter().enumerate() {
+                if soundcnt_l & (1 << (8 + channel)) != 0 {
+                    right += *output as i32;
+                }
+                if soundcnt_l & (1 << (12 + channel)) != 0 {
+                    left += *output as i32;
+                }
+            }
+            // master volume of the PSG, 0 to 7 for each side
+            right *= (soundcnt_l & 0b111) as i32 + 1;
+            left *= ((soundcnt_l >> 4) & 0b111) as i32 + 1;
+            // SOUNDCNT_H bits 0-1: 25%, 50%, 100%, 3 is prohibited
+            let psg_shift = [2, 1, 0, 0][(self.register(SOUNDCNT_H) & 0b11) as usize];
+            right >>= psg_shift;
+            left >>= psg_shift;
+
+            // Direct Sound, the 8 bit samples are doubled at full volume
+            let soundcnt_h = self.register(SOUNDCNT_H);
+            for (index, fifo) in self.fifos.iter().enumerate() {
+                let shift = 4 * index as u16;
+                let full_volume = soundcnt_h & (FIFO_A_FULL_VOLUME << index) != 0;
+                let sample = if full_volume {fifo.current as i32 * 2} else {fifo.current as i32};
+                if soundcnt_h & (FIFO_A_RIGHT << shift) != 0 {
+                    right += sample;
+                }
+                if soundcnt_h & (FIFO_A_LEFT << shift) != 0 {
+                    left += sample;
+                }
+            }
+        }
+
+        self.samples.push((self.output_level(left), self.output_level(right)));
+        if self.samples.len() > MAX_BUFFERED_SAMPLES {
+            self.samples.drain(..MAX_BUFFERED_SAMPLES / 2);
+        }
+    }
+
+    // the mixed sample gets the bias added and is clamped to the 10 bit range of the DAC
+    // higher sampling rates come at the cost of amplitude resolution, 9 bits at 32768 Hz down to 6 bits at 262144 Hz
+    fn output_level(&self, sample: i32) -> i16 {
+        let bias = self.register(SOUNDBIAS);
+        let level = (bias & 0x3FE) as i32;
+        let dropped_bits = 1 + (bias >> 14);
+        let clamped = (sample + level).clamp(0, 0x3FF) & !((1 << dropped_bits) - 1);
+        return ((clamped - 0x200) << 6) as i16;
+    }
+}
+
+#[inline]
+fn register_index(address: u32) -> usize {
+    return ((address - SOUND1CNT_L) >> 1) as usize;
+}
+
+// overflows of timer 0 and 1 make the FIFOs using that timer play their next sample
+// if that leaves a FIFO half empty, the sound DMA gets to refill it
+pub fn timer_overflow(cpu: &mut CPU, timer: usize) {
+    if !cpu.apu.master_enabled() {
+        return;
+    }
+    let soundcnt_h = cpu.apu.register(SOUNDCNT_H);
+    for (index, address) in [(0, FIFO_A), (1, FIFO_B)] {
+        let selected_timer = if soundcnt_h & (FIFO_A_TIMER << (4 * index)) != 0 {1} else {0};
+        if selected_timer != timer {
+            continue;
+        }
+        let fifo = &mut cpu.apu.fifos[index];
+        fifo.pop();
+        if fifo.needs_refill() {
+            dma::sound_fifo_request(cpu, address);
+        }
+    }
+}
+
+// the APU runs for as long as the system does, its events are set up once at power on
+pub fn schedule_events(cpu: &mut CPU) {
+    let now = cpu.cycles();
+    cpu.apu.last_sample = now;
+    cpu.scheduler.schedule(now + cpu.apu.sample_period(), EventKind::ApuSample);
+    cpu.scheduler.schedule(now + FRAME_SEQUENCER_PERIOD, EventKind::ApuFrameSequencer);
+}
+
+pub fn sample_event(cpu: &mut CPU, timestamp: u128) {
+    cpu.apu.take_sample(timestamp);
+    cpu.scheduler.schedule(timestamp + cpu.apu.sample_period(), EventKind::ApuSample);
+}
+
+pub fn frame_sequencer_event(cpu: &mut CPU, timestamp: u128) {
+    cpu.apu.step_frame_sequencer();
+    cpu.scheduler.schedule(timestamp + FRAME_SEQUENCER_PERIOD, EventKind::ApuFrameSequencer);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{RWType, CPU};
+    use crate::interrupt::Interrupt;
+    use super::{FIFO_A, FIFO_B, SOUNDCNT_H, SOUNDCNT_X};
+
+    // sound on, FIFO A on timer 0, FIFO B on timer 1
+    fn sound_cpu() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.memory_write(SOUNDCNT_X, RWType::HalfWord, 0x0080);
+        cpu.memory_write(SOUNDCNT_H, RWType::HalfWord, 0x7300);
+        return cpu;
+    }
+
+    // counts up to 0x10000 in 16 cycles
+    fn start_timer(cpu: &mut CPU, index: u32) {
+        cpu.memory_write(0x04000100 + 4 * index, RWType::Word, 0x0080FFF0);
+    }
+
+    #[test]
+    fn timer_overflow_plays_the_next_sample() {
+        let mut cpu = sound_cpu();
+        cpu.memory_write(FIFO_A, RWType::Word, 0x04030201);
+        cpu.memory_write(FIFO_B, RWType::Word, 0xFCFDFEFF);
+        start_timer(&mut cpu, 0);
+        assert_eq!(cpu.apu.fifos[0].current, 0);
+        cpu.add_cycles(15);
+        assert_eq!(cpu.apu.fifos[0].current, 0);
+        cpu.add_cycles(1);
+        assert_eq!(cpu.apu.fifos[0].current, 1);
+        cpu.add_cycles(16);
+        assert_eq!(cpu.apu.fifos[0].current, 2);
+        // FIFO B waits for timer 1
+        assert_eq!(cpu.apu.fifos[1].current, 0);
+        start_timer(&mut cpu, 1);
+        cpu.add_cycles(16);
+        assert_eq!(cpu.apu.fifos[0].current, 3);
+        assert_eq!(cpu.apu.fifos[1].current, -1);
+        // an empty FIFO holds the last sample
+        cpu.add_cycles(2 * 16);
+        assert_eq!(cpu.apu.fifos[0].current, 4);
+
+        // nothing is played while the sound is off
+        let mut cpu = CPU::new();
+        cpu.memory_write(FIFO_A, RWType::Word, 0x04030201);
+        start_timer(&mut cpu, 0);
+        cpu.add_cycles(16);
+        assert_eq!(cpu.apu.fifos[0].current, 0);
+    }
+
+    #[test]
+    fn half_empty_fifo_requests_dma() {
+        let mut cpu = sound_cpu();
+        // 20 samples in the FIFO, the refill has the next 16
+        for word in 0..5 {
+            cpu.memory_write(FIFO_A, RWType::Word, 0x01010101 * (4 * word + 1) + 0x03020100);
+        }
+        for offset in 0..4 {
+            cpu.memory_write(0x03000000 + 4 * offset, RWType::Word, 0x01010101 * (4 * offset + 21) + 0x03020100);
+        }
+        // DMA1 in sound FIFO mode, repeating, with the IRQ to tell when it ran
+        cpu.memory_write(0x040000BC, RWType::Word, 0x03000000);
+        cpu.memory_write(0x040000C0, RWType::Word, FIFO_A);
+        cpu.memory_write(0x040000C4, RWType::Word, 0xF6400000);
+        let start = cpu.cycles();
+        start_timer(&mut cpu, 0);
+
+        let dma_irq = 1 << Interrupt::DMA1 as u16;
+        // down to 17 samples
+        cpu.add_cycles(3 * 16);
+        assert_eq!(cpu.interrupts.irf & dma_irq, 0);
+        // 16 left, that is half empty
+        cpu.add_cycles(16);
+        assert_eq!(cpu.interrupts.irf & dma_irq, dma_irq);
+        // the DMA doesn't stop, and the samples from it play after the ones written before
+        // the transfers stall the CPU, so this goes by the timestamps of the overflows
+        assert_eq!(cpu.dma.channels[1].control & 0x8000, 0x8000);
+        for sample in 5..=36 {
+            cpu.add_cycles(start + 16 * sample as u128 - cpu.cycles());
+            assert_eq!(cpu.apu.fifos[0].current, sample);
+        }
+    }
+}
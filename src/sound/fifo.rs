@@ -0,0 +1,54 @@
+// one of the two Direct Sound FIFOs, see https://problemkaputt.de/gbatek.htm#gbasoundchannelaandbdmasound
+// the CPU or DMA pushes signed 8 bit PCM samples in, the selected timer pops one out on every overflow
+
+const CAPACITY: usize = 32;
+// once the FIFO runs down to this many bytes, it requests a refill from DMA
+const REFILL_THRESHOLD: usize = 16;
+
+pub struct FIFO {
+    buffer: [i8; CAPACITY],
+    read_position: usize,
+    length: usize,
+    // the sample that is currently played, it is held until the next timer overflow
+    pub current: i8,
+}
+
+impl FIFO {
+    pub fn new() -> FIFO {
+        FIFO {
+            buffer: [0; CAPACITY],
+            read_position: 0,
+            length: 0,
+            current: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: i8) {
+        // writes into a full FIFO are lost
+        if self.length == CAPACITY {
+            return;
+        }
+        self.buffer[(self.read_position + self.length) % CAPACITY] = sample;
+        self.length += 1;
+    }
+
+    // moves the next sample into the output, an empty FIFO keeps playing the last one
+    pub fn pop(&mut self) {
+        if self.length == 0 {
+            return;
+        }
+        self.current = self.buffer[self.read_position];
+        self.read_position = (self.read_position + 1) % CAPACITY;
+        self.length -= 1;
+    }
+
+    pub fn reset(&mut self) {
+        self.read_position = 0;
+        self.length = 0;
+        self.current = 0;
+    }
+
+    pub fn needs_refill(&self) -> bool {
+        return self.length <= REFILL_THRESHOLD;
+    }
+}
@@ -0,0 +1,548 @@
+// the four legacy sound channels inherited from the Game Boy, see https://problemkaputt.de/gbatek.htm#gbasoundchannel1tonesweep
+// all timers count CPU cycles, the channels are advanced in bulk whenever the APU takes a sample
+// outputs are signed, roughly in the range of -15 to 15
+
+// duty cycles of the square channels, 12.5%, 25%, 50% and 75%
+const DUTY_PATTERNS: [[bool; 8]; 4] = [
+    [false, false, false, false, false, false, false, true],
+    [true, false, false, false, false, false, false, true],
+    [true, false, false, false, false, true, true, true],
+    [false, true, true, true, true, true, true, false],
+];
+
+// length counter, turns the channel off after a while if enabled
+pub struct LengthCounter {
+    max: u16,
+    counter: u16,
+    pub enabled: bool,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    // the register holds the length as max - counter
+    fn load(&mut self, length: u16) {
+        self.counter = self.max - length;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // clocked at 256 Hz, returns true if the channel has to be turned off
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        return false;
+    }
+}
+
+// volume envelope of the square and noise channels, the register layout is the same for all three
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    step_time: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            step_time: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    // bits 8 to 15 of the register
+    fn write(&mut self, value: u16) {
+        self.step_time = ((value >> 8) & 0b111) as u8;
+        self.increase = value & (1 << 11) != 0;
+        self.initial_volume = (value >> 12) as u8;
+    }
+
+    // with an initial volume of 0 that decreases, the DAC of the channel is off
+    fn dac_enabled(&self) -> bool {
+        return self.initial_volume != 0 || self.increase;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.step_time;
+    }
+
+    // clocked at 64 Hz
+    fn clock(&mut self) {
+        if self.step_time == 0 {
+            return;
+        }
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.step_time;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        }
+        else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+// frequency sweep, only on channel 1
+pub struct Sweep {
+    shift: u8,
+    decrease: bool,
+    time: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            shift: 0,
+            decrease: false,
+            time: 0,
+            timer: 0,
+            shadow: 0,
+            enabled: false,
+        }
+    }
+
+    fn write(&mut self, value: u16) {
+        self.shift = (value & 0b111) as u8;
+        self.decrease = value & (1 << 3) != 0;
+        self.time = ((value >> 4) & 0b111) as u8;
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.decrease {
+            return self.shadow - delta;
+        }
+        return self.shadow + delta;
+    }
+}
+
+pub struct SquareChannel {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    sweep: Option<Sweep>,
+    duty: usize,
+    frequency: u16,
+    timer: u32,
+    position: usize,
+}
+
+impl SquareChannel {
+    pub fn new(with_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if with_sweep {Some(Sweep::new())} else {None},
+            duty: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+        }
+    }
+
+    // one step of the 8 step duty cycle, the tone frequency is 131072 / (2048 - n) Hz
+    fn period(&self) -> u32 {
+        return 16 * (2048 - self.frequency as u32);
+    }
+
+    // SOUND1CNT_L
+    pub fn write_sweep(&mut self, value: u16) {
+        if let Some(sweep) = &mut self.sweep {
+            sweep.write(value);
+        }
+    }
+
+    // SOUND1CNT_H / SOUND2CNT_L
+    pub fn write_duty_envelope(&mut self, value: u16, load_length: bool) {
+        if load_length {
+            self.length.load(value & 0x3F);
+        }
+        self.duty = ((value >> 6) & 0b11) as usize;
+        self.envelope.write(value);
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    // SOUND1CNT_X / SOUND2CNT_H
+    pub fn write_frequency(&mut self, value: u16, trigger: bool) {
+        self.frequency = value & 0x7FF;
+        self.length.enabled = value & (1 << 14) != 0;
+        if trigger {
+            self.trigger();
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.frequency;
+            sweep.timer = if sweep.time == 0 {8} else {sweep.time};
+            sweep.enabled = sweep.time != 0 || sweep.shift != 0;
+            // the overflow check is done right away if there is a shift
+            if sweep.shift != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    // clocked at 128 Hz
+    pub fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        if sweep.timer > 1 {
+            sweep.timer -= 1;
+            return;
+        }
+        sweep.timer = if sweep.time == 0 {8} else {sweep.time};
+        if !sweep.enabled || sweep.time == 0 {
+            return;
+        }
+        let frequency = sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        else if sweep.shift != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // and check again with the new frequency
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i16;
+        return if DUTY_PATTERNS[self.duty][self.position] {volume} else {-volume};
+    }
+}
+
+pub struct WaveChannel {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    dac_enabled: bool,
+    two_banks: bool,
+    bank: usize,  // the bank that is played, the CPU accesses the other one
+    volume: u16,
+    force_volume: bool,
+    frequency: u16,
+    timer: u32,
+    position: usize,
+    pub wave_ram: [[u8; 16]; 2],
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            length: LengthCounter::new(256),
+            dac_enabled: false,
+            two_banks: false,
+            bank: 0,
+            volume: 0,
+            force_volume: false,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            wave_ram: [[0; 16]; 2],
+        }
+    }
+
+    // one sample of the wave, the sample rate is 2097152 / (2048 - n) Hz
+    fn period(&self) -> u32 {
+        return 8 * (2048 - self.frequency as u32);
+    }
+
+    // SOUND3CNT_L
+    pub fn write_control(&mut self, value: u16) {
+        self.two_banks = value & (1 << 5) != 0;
+        self.bank = ((value >> 6) & 1) as usize;
+        self.dac_enabled = value & (1 << 7) != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    // SOUND3CNT_H
+    pub fn write_length_volume(&mut self, value: u16, load_length: bool) {
+        if load_length {
+            self.length.load(value & 0xFF);
+        }
+        self.volume = (value >> 13) & 0b11;
+        self.force_volume = value & (1 << 15) != 0;
+    }
+
+    // SOUND3CNT_X
+    pub fn write_frequency(&mut self, value: u16, trigger: bool) {
+        self.frequency = value & 0x7FF;
+        self.length.enabled = value & (1 << 14) != 0;
+        if trigger {
+            self.enabled = self.dac_enabled;
+            self.length.trigger();
+            self.timer = self.period();
+            self.position = 0;
+        }
+    }
+
+    // WAVE_RAM is mapped to the bank that is not being played
+    pub fn read_wave_ram(&self, offset: usize) -> u8 {
+        return self.wave_ram[self.bank ^ 1][offset];
+    }
+
+    pub fn write_wave_ram(&mut self, offset: usize, value: u8) {
+        self.wave_ram[self.bank ^ 1][offset] = value;
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let samples = if self.two_banks {64} else {32};
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % samples;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        // in two bank mode, the selected bank is played first, then the other one
+        let bank = (self.bank + self.position / 32) % 2;
+        let byte = self.wave_ram[bank][(self.position % 32) / 2];
+        // each byte holds two samples, the upper nibble is played first
+        let nibble = if self.position.is_multiple_of(2) {byte >> 4} else {byte & 0xF};
+        let sample = (nibble as i16 - 8) * 2;
+        // volume in quarters: 0%, 100%, 50%, 25%, or 75% if forced
+        let quarters = if self.force_volume {3} else {[0, 4, 2, 1][self.volume as usize]};
+        return (sample * quarters) / 4;
+    }
+}
+
+pub struct NoiseChannel {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    ratio: u32,
+    narrow: bool,  // 7 bit LFSR instead of 15 bit
+    shift: u32,
+    timer: u32,
+    lfsr: u16,
+    high: bool,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            ratio: 0,
+            narrow: false,
+            shift: 0,
+            timer: 0,
+            lfsr: 0,
+            high: false,
+        }
+    }
+
+    // the LFSR is clocked at 524288 Hz / r / 2^(s+1), with r = 0 counting as 0.5
+    fn period(&self) -> u32 {
+        let divider = if self.ratio == 0 {16} else {32 * self.ratio};
+        return divider << (self.shift + 1);
+    }
+
+    // SOUND4CNT_L
+    pub fn write_length_envelope(&mut self, value: u16, load_length: bool) {
+        if load_length {
+            self.length.load(value & 0x3F);
+        }
+        self.envelope.write(value);
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    // SOUND4CNT_H
+    pub fn write_frequency(&mut self, value: u16, trigger: bool) {
+        self.ratio = (value & 0b111) as u32;
+        self.narrow = value & (1 << 3) != 0;
+        self.shift = ((value >> 4) & 0xF) as u32;
+        self.length.enabled = value & (1 << 14) != 0;
+        if trigger {
+            self.enabled = self.envelope.dac_enabled();
+            self.length.trigger();
+            self.envelope.trigger();
+            self.timer = self.period();
+            self.lfsr = if self.narrow {0x40} else {0x4000};
+            self.high = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            // shift out the lowest bit, if it was set the output goes high and the taps are applied
+            let carry = self.lfsr & 1 != 0;
+            self.lfsr >>= 1;
+            if carry {
+                self.lfsr ^= if self.narrow {0x60} else {0x6000};
+            }
+            self.high = carry;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i16;
+        return if self.high {volume} else {-volume};
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SquareChannel;
+
+    // full volume, 50% duty
+    fn square_channel(length: u16, frequency: u16) -> SquareChannel {
+        let mut channel = SquareChannel::new(false);
+        channel.write_duty_envelope(0xF000 | 2 << 6 | length, true);
+        channel.write_frequency(0x8000 | 0x4000 | frequency, true);
+        return channel;
+    }
+
+    #[test]
+    fn square_period() {
+        // 16 * (2048 - 2040) cycles per duty step
+        let mut channel = square_channel(0, 2040);
+        assert_eq!(channel.output(), 15);
+        channel.step(127);
+        assert_eq!(channel.output(), 15);
+        channel.step(1);
+        assert_eq!(channel.output(), -15);
+        channel.step(4 * 128);
+        assert_eq!(channel.output(), 15);
+        // a whole cycle of the pattern brings it back to the same step
+        channel.step(8 * 128);
+        assert_eq!(channel.output(), 15);
+        channel.step(3 * 128 + 127);
+        assert_eq!(channel.output(), 15);
+        channel.step(1);
+        assert_eq!(channel.output(), -15);
+    }
+
+    #[test]
+    fn length_counter() {
+        // the register holds 64 - length
+        let mut channel = square_channel(60, 0);
+        for _ in 0..3 {
+            channel.clock_length();
+        }
+        assert!(channel.enabled);
+        channel.clock_length();
+        assert!(!channel.enabled);
+        assert_eq!(channel.output(), 0);
+
+        // a restart with the counter at 0 gives the full length
+        channel.write_frequency(0x8000 | 0x4000, true);
+        for _ in 0..63 {
+            channel.clock_length();
+        }
+        assert!(channel.enabled);
+        channel.clock_length();
+        assert!(!channel.enabled);
+
+        // without the length flag the channel keeps playing
+        let mut channel = square_channel(63, 0);
+        channel.write_frequency(0, false);
+        for _ in 0..100 {
+            channel.clock_length();
+        }
+        assert!(channel.enabled);
+    }
+}
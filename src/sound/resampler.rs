@@ -0,0 +1,170 @@
+use std::f64::consts::PI;
+
+// converts the APU output from its hardware sampling rate (32768 to 262144 Hz) to a fixed host rate
+// works on a stream, the input can be handed over in chunks of any size and the input rate may change between chunks
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interpolation {
+    // cheap, but lets some aliasing through when downsampling
+    Linear,
+    // windowed sinc filter, band limited to the lower of the two rates
+    Sinc,
+}
+
+// number of input samples the sinc kernel spans, half of them on each side
+const TAPS: usize = 16;
+// the kernel is precomputed for this many fractional positions between two input samples
+const PHASES: usize = 256;
+
+pub struct Resampler {
+    interpolation: Interpolation,
+    output_rate: u32,
+    input_rate: u32,
+    // input samples not fully consumed yet, with TAPS / 2 samples of history in front
+    history: Vec<(f64, f64)>,
+    // position of the next output sample in history, in input samples
+    position: f64,
+    kernel: Vec<[f64; TAPS]>,
+}
+
+impl Resampler {
+    pub fn new(output_rate: u32, interpolation: Interpolation) -> Resampler {
+        Resampler {
+            interpolation,
+            output_rate,
+            input_rate: 0,
+            // start with silence as history, so the first samples have something to be filtered against
+            history: vec![(0.0, 0.0); TAPS / 2],
+            position: (TAPS / 2) as f64,
+            kernel: Vec::new(),
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        return self.output_rate;
+    }
+
+    // resamples the input taken at input_rate and appends the result to output
+    pub fn process(&mut self, input: &[(i16, i16)], input_rate: u32, output: &mut Vec<(i16, i16)>) {
+        if input_rate != self.input_rate {
+            self.input_rate = input_rate;
+            if self.interpolation == Interpolation::Sinc {
+                self.kernel = build_kernel(input_rate, self.output_rate);
+            }
+        }
+        self.history.extend(input.iter().map(|(left, right)| (*left as f64, *right as f64)));
+
+        let step = self.input_rate as f64 / self.output_rate as f64;
+        // output samples can only be computed once all the input samples they depend on are there
+        while (self.position as usize) + TAPS / 2 < self.history.len() {
+            let (left, right) = match self.interpolation {
+                Interpolation::Linear => self.linear(),
+                Interpolation::Sinc => self.sinc(),
+            };
+            output.push((to_i16(left), to_i16(right)));
+            self.position += step;
+        }
+
+        // throw away what is no longer needed, except for the history in front of the current position
+        let consumed = (self.position as usize).saturating_sub(TAPS / 2);
+        self.history.drain(..consumed);
+        self.position -= consumed as f64;
+    }
+
+    fn linear(&self) -> (f64, f64) {
+        let index = self.position as usize;
+        let fraction = self.position - index as f64;
+        let (left0, right0) = self.history[index];
+        let (left1, right1) = self.history[index + 1];
+        return (left0 + (left1 - left0) * fraction, right0 + (right1 - right0) * fraction);
+    }
+
+    fn sinc(&self) -> (f64, f64) {
+        let index = self.position as usize;
+        let phase = ((self.position - index as f64) * PHASES as f64) as usize;
+        let coefficients = &self.kernel[phase.min(PHASES - 1)];
+        let first = index + 1 - TAPS / 2;
+        let (mut left, mut right) = (0.0, 0.0);
+        for (tap, coefficient) in coefficients.iter().enumerate() {
+            let (l, r) = self.history[first + tap];
+            left += l * coefficient;
+            right += r * coefficient;
+        }
+        return (left, right);
+    }
+}
+
+// Blackman windowed sinc, one set of taps for every phase
+// the cutoff is at the Nyquist frequency of the lower rate, so downsampling doesn't alias
+fn build_kernel(input_rate: u32, output_rate: u32) -> Vec<[f64; TAPS]> {
+    let cutoff = (output_rate as f64 / input_rate as f64).min(1.0);
+    let mut kernel = Vec::with_capacity(PHASES);
+    for phase in 0..PHASES {
+        let fraction = phase as f64 / PHASES as f64;
+        let mut taps = [0.0; TAPS];
+        let mut sum = 0.0;
+        for (tap, coefficient) in taps.iter_mut().enumerate() {
+            // distance between the output position and this input sample
+            let x = tap as f64 - (TAPS / 2 - 1) as f64 - fraction;
+            let sinc = if x == 0.0 {1.0} else {(PI * cutoff * x).sin() / (PI * cutoff * x)};
+            let n = (x + TAPS as f64 / 2.0) / TAPS as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * n).cos() + 0.08 * (4.0 * PI * n).cos();
+            *coefficient = sinc * window;
+            sum += *coefficient;
+        }
+        // normalize, so that a constant signal keeps its level
+        for coefficient in taps.iter_mut() {
+            *coefficient /= sum;
+        }
+        kernel.push(taps);
+    }
+    return kernel;
+}
+
+#[inline]
+fn to_i16(sample: f64) -> i16 {
+    return sample.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interpolation, Resampler, TAPS};
+
+    fn resample(interpolation: Interpolation, input: &[(i16, i16)], input_rate: u32, chunk: usize) -> Vec<(i16, i16)> {
+        let mut resampler = Resampler::new(48000, interpolation);
+        let mut output = Vec::new();
+        for samples in input.chunks(chunk) {
+            resampler.process(samples, input_rate, &mut output);
+        }
+        return output;
+    }
+
+    #[test]
+    fn output_length() {
+        for interpolation in [Interpolation::Linear, Interpolation::Sinc] {
+            for input_rate in [32768, 65536, 262144] {
+                // one second of input, minus the samples held back for the filter
+                let input = vec![(0, 0); input_rate as usize];
+                let output = resample(interpolation, &input, input_rate, input.len());
+                let expected = 48000 - 48000 * (TAPS / 2) / input_rate as usize;
+                assert!(output.len().abs_diff(expected) <= 1, "{:?} {}: {}", interpolation, input_rate, output.len());
+                // the size of the chunks doesn't matter
+                assert_eq!(resample(interpolation, &input, input_rate, 547).len(), output.len());
+            }
+        }
+    }
+
+    #[test]
+    fn dc_gain() {
+        for interpolation in [Interpolation::Linear, Interpolation::Sinc] {
+            for input_rate in [32768, 262144] {
+                let input = vec![(10000, -5000); 8192];
+                let output = resample(interpolation, &input, input_rate, 1000);
+                // after the silence in front has left the filter, a constant level goes through unchanged
+                for (left, right) in &output[TAPS..] {
+                    assert!(left.abs_diff(10000) <= 1 && right.abs_diff(-5000) <= 1, "{:?} {}: {} {}", interpolation, input_rate, left, right);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+// writes 16 bit stereo PCM into a RIFF/WAVE file
+// the sizes in the header are only known at the end, so they get patched in by finish
+
+const HEADER_SIZE: u32 = 44;
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    frames: u32,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, sample_rate: u32) -> std::io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let channels: u16 = 2;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;  // patched in finish
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;  // size of the fmt chunk
+        file.write_all(&1u16.to_le_bytes())?;  // PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&bits_per_sample.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;  // patched in finish
+        Ok(WavWriter {
+            file,
+            frames: 0,
+            finished: false,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[(i16, i16)]) -> std::io::Result<()> {
+        for (left, right) in samples {
+            self.file.write_all(&left.to_le_bytes())?;
+            self.file.write_all(&right.to_le_bytes())?;
+        }
+        self.frames += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let data_size = self.frames * 4;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        // best effort, call finish to see the errors
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WavWriter;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        return u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        return u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+    }
+
+    #[test]
+    fn header_fields() {
+        let path = std::env::temp_dir().join(format!("rust_gba_emu_{}_{}", std::process::id(), "header.wav"));
+        let path = path.to_str().unwrap();
+        let mut writer = WavWriter::create(path, 32768).unwrap();
+        writer.write_samples(&[(1, -1), (0x1234, 0x7FFF)]).unwrap();
+        writer.write_samples(&[(-32768, 0)]).unwrap();
+        writer.finish().unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 12);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 16), 16);
+        assert_eq!(u16_at(&bytes, 20), 1);  // PCM
+        assert_eq!(u16_at(&bytes, 22), 2);  // channels
+        assert_eq!(u32_at(&bytes, 24), 32768);
+        assert_eq!(u32_at(&bytes, 28), 32768 * 4);  // bytes per second
+        assert_eq!(u16_at(&bytes, 32), 4);  // block align
+        assert_eq!(u16_at(&bytes, 34), 16);  // bits per sample
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 12);
+        assert_eq!(&bytes[44..], [1, 0, 0xFF, 0xFF, 0x34, 0x12, 0xFF, 0x7F, 0x00, 0x80, 0, 0]);
+    }
+
+    #[test]
+    fn dropping_finishes_the_file() {
+        let path = std::env::temp_dir().join(format!("rust_gba_emu_{}_{}", std::process::id(), "drop.wav"));
+        let path = path.to_str().unwrap();
+        {
+            let mut writer = WavWriter::create(path, 48000).unwrap();
+            writer.write_samples(&[(0, 0); 10]).unwrap();
+        }
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(u32_at(&bytes, 4), 36 + 40);
+        assert_eq!(u32_at(&bytes, 40), 40);
+    }
+}
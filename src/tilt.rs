@@ -0,0 +1,73 @@
+// tilt sensor of Yoshi Topsy-Turvy and Koro Koro Puzzle, see https://problemkaputt.de/gbatek.htm#gbacarttiltsensor
+// sits in the SRAM area, both games save into an EEPROM so nothing else is there
+// writing 0x55 to 0x0E008000 and 0xAA to 0x0E008100 takes a sample, which is then read from 0x0E008200 to 0x0E008500
+
+const START_1: u32 = 0x8000;
+const START_2: u32 = 0x8100;
+const X_LOW: u32 = 0x8200;
+const X_HIGH: u32 = 0x8300;
+const Y_LOW: u32 = 0x8400;
+const Y_HIGH: u32 = 0x8500;
+
+// bit 7 of the X high byte, set once a sample is ready
+const READY: u8 = 1 << 7;
+
+// the 12 bit samples the sensor gives when the console is held level
+pub const TILT_CENTER: i32 = 0x3A0;
+
+pub struct TiltSensor {
+    // tilt to the right and towards the player, relative to holding the console level
+    x: i16,
+    y: i16,
+    started: bool,
+    sample: Option<(u16, u16)>,
+}
+
+impl TiltSensor {
+    pub fn new() -> TiltSensor {
+        TiltSensor {
+            x: 0,
+            y: 0,
+            started: false,
+            sample: None,
+        }
+    }
+
+    pub fn set_tilt(&mut self, x: i16, y: i16) {
+        self.x = x;
+        self.y = y;
+    }
+
+    // whether the offset into the SRAM area belongs to the sensor
+    pub fn covers(offset: u32) -> bool {
+        let offset = offset & 0xFFFF;
+        return offset >= START_1 && offset < Y_HIGH + 0x100;
+    }
+
+    pub fn read(&self, offset: u32) -> u8 {
+        let (x, y) = match self.sample {
+            Some(sample) => sample,
+            None => return 0,
+        };
+        match offset & 0xFF00 {
+            X_LOW => x as u8,
+            X_HIGH => (x >> 8) as u8 | READY,
+            Y_LOW => y as u8,
+            Y_HIGH => (y >> 8) as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u8) {
+        match (offset & 0xFF00, value) {
+            (START_1, 0x55) => self.started = true,
+            (START_2, 0xAA) if self.started => {
+                self.started = false;
+                let x = (TILT_CENTER + self.x as i32).clamp(0, 0xFFF) as u16;
+                let y = (TILT_CENTER + self.y as i32).clamp(0, 0xFFF) as u16;
+                self.sample = Some((x, y));
+            },
+            _ => self.started = false,
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::cpu::CPU;
+use crate::interrupt::Interrupt;
+use crate::scheduler::EventKind;
+use crate::sound::apu;
+
+// the four hardware timers TM0 to TM3, see https://problemkaputt.de/gbatek.htm#gbatimers
+// a running timer is not ticked, instead we remember when it was started and schedule an event for its overflow
+// the counter value is computed from the elapsed cycles whenever it's read
+
+// TMxCNT_H bits
+const PRESCALER: u16 = 0b11;
+const COUNT_UP: u16 = 1 << 2;
+const IRQ_ENABLE: u16 = 1 << 6;
+const START: u16 = 1 << 7;
+
+// prescaler selection -> shift of the cycle count, i.e. 1, 64, 256 and 1024 cycles per tick
+const PRESCALER_SHIFTS: [u32; 4] = [0, 6, 8, 10];
+
+const TIMER_INTERRUPTS: [Interrupt; 4] = [Interrupt::Timer0, Interrupt::Timer1, Interrupt::Timer2, Interrupt::Timer3];
+
+pub struct Timer {
+    pub reload: u16,
+    pub control: u16,
+    counter: u16,  // counter value at the time given in start
+    start: u128,
+}
+
+impl Timer {
+    fn new() -> Timer {
+        Timer {
+            reload: 0,
+            control: 0,
+            counter: 0,
+            start: 0,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        return self.control & START != 0;
+    }
+
+    // timers in count-up mode are only incremented by the overflow of the previous one
+    fn ticks_on_clock(&self, index: usize) -> bool {
+        return self.enabled() && (index == 0 || self.control & COUNT_UP == 0);
+    }
+
+    fn shift(&self) -> u32 {
+        return PRESCALER_SHIFTS[(self.control & PRESCALER) as usize];
+    }
+
+    fn counter_at(&self, index: usize, now: u128) -> u16 {
+        if !self.ticks_on_clock(index) {
+            return self.counter;
+        }
+        let ticks = (now.saturating_sub(self.start)) >> self.shift();
+        // the overflow event might not have been dispatched yet if we're right at it, never go past 0xFFFF
+        return (self.counter as u128 + ticks).min(0xFFFF) as u16;
+    }
+
+    fn overflow_timestamp(&self) -> u128 {
+        return self.start + ((0x10000 - self.counter as u128) << self.shift());
+    }
+}
+
+pub struct Timers {
+    pub timers: [Timer; 4],
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers {
+            timers: [Timer::new(), Timer::new(), Timer::new(), Timer::new()],
+        }
+    }
+
+    // value of TMxCNT_L on reads, the current counter
+    pub fn read_counter(&self, index: usize, now: u128) -> u16 {
+        return self.timers[index].counter_at(index, now);
+    }
+}
+
+// writes to TMxCNT_L only set the reload value, it is copied into the counter on the next start or overflow
+pub fn write_reload(cpu: &mut CPU, index: usize, value: u16) {
+    cpu.timers.timers[index].reload = value;
+}
+
+pub fn write_control(cpu: &mut CPU, index: usize, value: u16) {
+    let now = cpu.cycles();
+    let timer = &mut cpu.timers.timers[index];
+    let was_enabled = timer.enabled();
+    // freeze the counter with the old settings before changing them
+    timer.counter = timer.counter_at(index, now);
+    timer.start = now;
+    timer.control = value & (PRESCALER | COUNT_UP | IRQ_ENABLE | START);
+    if !was_enabled && timer.enabled() {
+        // a timer that gets started loads the reload value
+        timer.counter = timer.reload;
+    }
+
+    cpu.scheduler.cancel(EventKind::TimerOverflow(index));
+    let timer = &cpu.timers.timers[index];
+    if timer.ticks_on_clock(index) {
+        cpu.scheduler.schedule(timer.overflow_timestamp(), EventKind::TimerOverflow(index));
+    }
+}
+
+// called by the scheduler when a timer running on the clock overflows
+pub fn overflow_event(cpu: &mut CPU, index: usize, timestamp: u128) {
+    overflow(cpu, index, timestamp);
+    let timer = &cpu.timers.timers[index];
+    if timer.ticks_on_clock(index) {
+        cpu.scheduler.schedule(timer.overflow_timestamp(), EventKind::TimerOverflow(index));
+    }
+}
+
+fn overflow(cpu: &mut CPU, index: usize, timestamp: u128) {
+    let timer = &mut cpu.timers.timers[index];
+    timer.counter = timer.reload;
+    timer.start = timestamp;
+    if timer.control & IRQ_ENABLE != 0 {
+        cpu.request_interrupt(TIMER_INTERRUPTS[index]);
+    }
+    if index < 2 {
+        apu::timer_overflow(cpu, index);
+    }
+
+    // count-up timing, the next timer is incremented once
+    if index < 3 {
+        let next = &mut cpu.timers.timers[index + 1];
+        if next.enabled() && next.control & COUNT_UP != 0 {
+            let (counter, wrapped) = next.counter.overflowing_add(1);
+            next.counter = counter;
+            if wrapped {
+                overflow(cpu, index + 1, timestamp);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{RWType, CPU};
+    use crate::io::TM0CNT_L;
+
+    fn write_timer(cpu: &mut CPU, index: u32, reload: u16, control: u16) {
+        cpu.memory_write(TM0CNT_L + 4 * index, RWType::Word, (control as u32) << 16 | reload as u32);
+    }
+
+    fn counter(cpu: &mut CPU, index: u32) -> u32 {
+        return cpu.memory_read(TM0CNT_L + 4 * index, RWType::HalfWord);
+    }
+
+    fn irq(index: u32) -> u16 {
+        return 1 << (3 + index);
+    }
+
+    #[test]
+    fn prescaler() {
+        for (prescaler, cycles_per_tick) in [(0, 1), (1, 64), (2, 256), (3, 1024)] {
+            let mut cpu = CPU::new();
+            write_timer(&mut cpu, 0, 0xFF00, 0xC0 | prescaler);
+            assert_eq!(counter(&mut cpu, 0), 0xFF00);
+            cpu.add_cycles(cycles_per_tick * 10 + cycles_per_tick - 1);
+            assert_eq!(counter(&mut cpu, 0), 0xFF0A);
+            assert_eq!(cpu.interrupts.irf, 0);
+            cpu.add_cycles(cycles_per_tick * 246 - (cycles_per_tick - 1));
+            assert_eq!(counter(&mut cpu, 0), 0xFF00);
+            assert_eq!(cpu.interrupts.irf, irq(0));
+        }
+    }
+
+    #[test]
+    fn count_up_cascade() {
+        let mut cpu = CPU::new();
+        // TM2 counts TM1 overflows, which counts TM0 overflows every 16 cycles
+        write_timer(&mut cpu, 2, 0xFFFF, 0xC4);
+        write_timer(&mut cpu, 1, 0xFFFE, 0x84);
+        write_timer(&mut cpu, 0, 0xFFF0, 0x80);
+        // count-up timers ignore the clock
+        cpu.add_cycles(15);
+        assert_eq!(counter(&mut cpu, 1), 0xFFFE);
+        cpu.add_cycles(1);
+        assert_eq!(counter(&mut cpu, 0), 0xFFF0);
+        assert_eq!(counter(&mut cpu, 1), 0xFFFF);
+        assert_eq!(counter(&mut cpu, 2), 0xFFFF);
+        cpu.add_cycles(16);
+        assert_eq!(counter(&mut cpu, 1), 0xFFFE);
+        assert_eq!(counter(&mut cpu, 2), 0xFFFF);
+        // TM1 has no IRQ enabled, TM2 does
+        assert_eq!(cpu.interrupts.irf, irq(2));
+
+        // the first timer never counts up, it keeps running on the clock
+        let mut cpu = CPU::new();
+        write_timer(&mut cpu, 0, 0, 0x84);
+        cpu.add_cycles(100);
+        assert_eq!(counter(&mut cpu, 0), 100);
+    }
+
+    #[test]
+    fn reload_and_counter_read_back() {
+        let mut cpu = CPU::new();
+        write_timer(&mut cpu, 3, 0xF000, 0x80);
+        cpu.add_cycles(0x100);
+        // a new reload value only takes effect on the next overflow
+        cpu.memory_write(TM0CNT_L + 12, RWType::HalfWord, 0x8000);
+        assert_eq!(counter(&mut cpu, 3), 0xF100);
+        cpu.add_cycles(0xF00);
+        assert_eq!(counter(&mut cpu, 3), 0x8000);
+        cpu.add_cycles(0x10);
+        assert_eq!(counter(&mut cpu, 3), 0x8010);
+
+        // stopping freezes the counter, starting again loads the reload value
+        cpu.memory_write(TM0CNT_L + 14, RWType::HalfWord, 0);
+        cpu.add_cycles(0x100);
+        assert_eq!(counter(&mut cpu, 3), 0x8010);
+        cpu.memory_write(TM0CNT_L + 12, RWType::HalfWord, 0xFFF0);
+        cpu.memory_write(TM0CNT_L + 14, RWType::HalfWord, 0xC0);
+        assert_eq!(counter(&mut cpu, 3), 0xFFF0);
+        cpu.add_cycles(0x10);
+        assert_eq!(counter(&mut cpu, 3), 0xFFF0);
+        assert_eq!(cpu.interrupts.irf, irq(3));
+
+        // changing the prescaler of a running timer keeps the count so far
+        let mut cpu = CPU::new();
+        write_timer(&mut cpu, 0, 0, 0x80);
+        cpu.add_cycles(100);
+        cpu.memory_write(TM0CNT_L + 2, RWType::HalfWord, 0x81);
+        cpu.add_cycles(64 * 3);
+        assert_eq!(counter(&mut cpu, 0), 103);
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use crate::cpu::{RWType, CPU};
+use crate::instructions::disasm;
+
+// per instruction trace, laid out like the traces of mGBA and NanoBoyAdvance so they can be diffed against a reference emulator
+// r0-r15 with r15 as the CPU sees it (8 or 4 bytes ahead), the CPSR, then address, opcode and disassembly:
+// 00000000 ... 08000008 cpsr: 0000001F | 08000000: E3A00000  mov r0, #0x0
+
+pub struct Tracer {
+    out: BufWriter<File>,
+    // only instructions in here are written
+    range: Option<RangeInclusive<u32>>,
+    // instructions executed before the trace starts
+    skip: u64,
+    // instructions still to be written, no limit if None
+    remaining: Option<u64>,
+    executed: u64,
+}
+
+impl Tracer {
+    pub fn new(path: &str, range: Option<RangeInclusive<u32>>, skip: u64, count: Option<u64>) -> io::Result<Tracer> {
+        Ok(Tracer {
+            out: BufWriter::new(File::create(path)?),
+            range,
+            skip,
+            remaining: count,
+            executed: 0,
+        })
+    }
+
+    // whether the trace is still running, once it's done the CPU can drop it
+    pub fn active(&self) -> bool {
+        return self.remaining != Some(0);
+    }
+
+    // called with the instruction that's about to be executed
+    pub fn trace(&mut self, cpu: &mut CPU, address: u32, opcode: u32, thumb: bool) {
+        self.executed += 1;
+        if self.executed <= self.skip || !self.active() {
+            return;
+        }
+        if let Some(range) = &self.range {
+            if !range.contains(&address) {
+                return;
+            }
+        }
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= 1;
+        }
+        let line = Self::format(cpu, address, opcode, thumb);
+        if let Err(e) = writeln!(self.out, "{}", line) {
+            eprintln!("[WARNING] Writing the trace failed, stopping it: {}", e);
+            self.remaining = Some(0);
+            return;
+        }
+        // the last line is out, the file is complete even if the emulator never gets to drop the tracer
+        if !self.active() {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.out.flush() {
+            eprintln!("[WARNING] Writing the trace failed: {}", e);
+        }
+    }
+
+    fn format(cpu: &mut CPU, address: u32, opcode: u32, thumb: bool) -> String {
+        let mut line = String::with_capacity(160);
+        for register in 0..15 {
+            line.push_str(&format!("{:08X} ", cpu.register_read(register)));
+        }
+        let pc = address.wrapping_add(if thumb {4} else {8});
+        line.push_str(&format!("{:08X} cpsr: {:08X} | ", pc, cpu.register_read(16)));
+        if thumb {
+            // the first half of a long branch with link is shown together with the second one
+            let mut instruction = opcode;
+            if opcode & 0xF800 == 0xF000 {
+                instruction |= cpu.debug_read(address.wrapping_add(2), RWType::HalfWord) << 16;
+            }
+            line.push_str(&format!("{:08X}:     {:04X}  {}", address, opcode, disasm::thumb(instruction, address)));
+        }
+        else {
+            line.push_str(&format!("{:08X}: {:08X}  {}", address, opcode, disasm::arm(opcode, address)));
+        }
+        return line;
+    }
+}
+
+// the trace file is flushed when the tracer goes away, so nothing is lost at the end of a run
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// start-end, both inclusive and in hex
+pub fn parse_range(text: &str) -> Result<RangeInclusive<u32>, String> {
+    let error = || format!("{} is not an address range like 0x08000000-0x08000FFF", text);
+    let (start, end) = text.split_once('-').ok_or_else(error)?;
+    let parse = |value: &str| u32::from_str_radix(value.trim().trim_start_matches("0x"), 16).map_err(|_| error());
+    return Ok(parse(start)?..=parse(end)?);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::Tracer;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn file_is_complete_once_the_trace_stops() {
+        let path = std::env::temp_dir().join(format!("rust_gba_emu_{}_{}", std::process::id(), "trace.log"));
+        let mut cpu = CPU::new();
+        let mut tracer = Tracer::new(&path.to_string_lossy(), None, 1, Some(2)).unwrap();
+        for address in [0x08000000, 0x08000004, 0x08000008, 0x0800000C] {
+            tracer.trace(&mut cpu, address, 0xE3A00000, false);
+        }
+        assert!(!tracer.active());
+        // still alive, the lines have to be in the file anyway
+        let trace = fs::read_to_string(&path).unwrap();
+        let addresses: Vec<&str> = trace.lines().map(|line| &line[line.find('|').unwrap() + 2..][..8]).collect();
+        assert_eq!(addresses, ["08000004", "08000008"]);
+        drop(tracer);
+        fs::remove_file(&path).unwrap();
+    }
+}
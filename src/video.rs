@@ -0,0 +1,145 @@
+use crate::cpu::CPU;
+use crate::dma::{self, DMATiming};
+use crate::interrupt::Interrupt;
+use crate::scheduler::EventKind;
+
+// display timing, see https://problemkaputt.de/gbatek.htm#lcddimensionsandtimings
+// nothing is drawn yet, this only runs the scanline counter so that DISPSTAT, VCOUNT, their interrupts
+// and the VBlank, HBlank and video capture DMA start at the right time
+
+pub const CYCLES_PER_LINE: u128 = 1232;
+const HDRAW_CYCLES: u128 = 960;  // the rest of the line is HBlank
+const VISIBLE_LINES: u16 = 160;
+const LINES: u16 = 228;
+
+// DISPSTAT bits, the lowest three are status flags and read only
+const VBLANK_FLAG: u16 = 1;
+const HBLANK_FLAG: u16 = 1 << 1;
+const VCOUNT_FLAG: u16 = 1 << 2;
+const VBLANK_IRQ: u16 = 1 << 3;
+const HBLANK_IRQ: u16 = 1 << 4;
+const VCOUNT_IRQ: u16 = 1 << 5;
+const WRITABLE: u16 = 0xFF38;
+
+pub struct Video {
+    pub dispstat: u16,
+    pub vcount: u16,
+}
+
+impl Video {
+    pub fn new() -> Video {
+        Video {
+            dispstat: 0,
+            vcount: 0,
+        }
+    }
+
+    pub fn write_dispstat(&mut self, value: u16) {
+        self.dispstat = (self.dispstat & !WRITABLE) | (value & WRITABLE);
+    }
+
+    fn vcount_setting(&self) -> u16 {
+        return self.dispstat >> 8;
+    }
+}
+
+// the display runs for as long as the system does, line 0 starts at power on
+pub fn schedule_events(cpu: &mut CPU) {
+    let now = cpu.cycles();
+    cpu.scheduler.schedule(now + HDRAW_CYCLES, EventKind::HBlank);
+    cpu.scheduler.schedule(now + CYCLES_PER_LINE, EventKind::Scanline);
+}
+
+pub fn hblank_event(cpu: &mut CPU, timestamp: u128) {
+    cpu.video.dispstat |= HBLANK_FLAG;
+    if cpu.video.dispstat & HBLANK_IRQ != 0 {
+        cpu.request_interrupt(Interrupt::HBlank);
+    }
+    let line = cpu.video.vcount;
+    // the HBlank IRQ happens on every line, the HBlank DMA only on the visible ones
+    if line < VISIBLE_LINES {
+        dma::trigger(cpu, DMATiming::HBlank);
+    }
+    dma::video_capture(cpu, line as u32);
+    cpu.scheduler.schedule(timestamp + CYCLES_PER_LINE, EventKind::HBlank);
+}
+
+pub fn scanline_event(cpu: &mut CPU, timestamp: u128) {
+    let video = &mut cpu.video;
+    video.dispstat &= !HBLANK_FLAG;
+    video.vcount = (video.vcount + 1) % LINES;
+    // the VBlank flag is cleared on the last line already, even though it's still part of VBlank
+    if video.vcount == VISIBLE_LINES {
+        video.dispstat |= VBLANK_FLAG;
+    }
+    else if video.vcount == LINES - 1 {
+        video.dispstat &= !VBLANK_FLAG;
+    }
+    let vcount_match = video.vcount == video.vcount_setting();
+    if vcount_match {
+        video.dispstat |= VCOUNT_FLAG;
+    }
+    else {
+        video.dispstat &= !VCOUNT_FLAG;
+    }
+
+    let dispstat = video.dispstat;
+    if vcount_match && dispstat & VCOUNT_IRQ != 0 {
+        cpu.request_interrupt(Interrupt::VCounter);
+    }
+    if cpu.video.vcount == VISIBLE_LINES {
+        if dispstat & VBLANK_IRQ != 0 {
+            cpu.request_interrupt(Interrupt::VBlank);
+        }
+        dma::trigger(cpu, DMATiming::VBlank);
+    }
+    cpu.scheduler.schedule(timestamp + CYCLES_PER_LINE, EventKind::Scanline);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{RWType, CPU, CYCLES_PER_FRAME};
+    use crate::interrupt::Interrupt;
+    use super::CYCLES_PER_LINE;
+
+    const DISPSTAT: u32 = 0x04000004;
+    const VCOUNT: u32 = 0x04000006;
+
+    fn irq(interrupt: Interrupt) -> u16 {
+        return 1 << interrupt as u16;
+    }
+
+    #[test]
+    fn dispstat_and_vcount() {
+        let mut cpu = CPU::new();
+        // the flags can't be written, the IRQ enables and the VCOUNT setting can
+        cpu.memory_write(DISPSTAT, RWType::HalfWord, 0xFFFF);
+        assert_eq!(cpu.memory_read(DISPSTAT, RWType::HalfWord), 0xFF38);
+        cpu.memory_write(DISPSTAT, RWType::HalfWord, 0x0538);
+        cpu.memory_write(VCOUNT, RWType::HalfWord, 100);
+
+        cpu.add_cycles(959);
+        assert_eq!(cpu.memory_read(DISPSTAT, RWType::HalfWord) & 0b111, 0);
+        cpu.add_cycles(1);
+        assert_eq!(cpu.memory_read(DISPSTAT, RWType::HalfWord) & 0b111, 0b010);
+        assert_eq!(cpu.interrupts.irf, irq(Interrupt::HBlank));
+        cpu.memory_write(0x04000202, RWType::HalfWord, 0xFFFF);
+
+        cpu.add_cycles(5 * CYCLES_PER_LINE - 960);
+        assert_eq!(cpu.memory_read(VCOUNT, RWType::HalfWord), 5);
+        assert_eq!(cpu.memory_read(DISPSTAT, RWType::HalfWord) & 0b111, 0b100);
+        assert_eq!(cpu.interrupts.irf, irq(Interrupt::HBlank) | irq(Interrupt::VCounter));
+        cpu.memory_write(0x04000202, RWType::HalfWord, 0xFFFF);
+
+        cpu.add_cycles(160 * CYCLES_PER_LINE - cpu.cycles());
+        assert_eq!(cpu.memory_read(VCOUNT, RWType::HalfWord), 160);
+        assert_eq!(cpu.memory_read(DISPSTAT, RWType::HalfWord) & 0b111, 0b001);
+        assert_eq!(cpu.interrupts.irf & irq(Interrupt::VBlank), irq(Interrupt::VBlank));
+        // the VBlank flag is gone on the last line, VCOUNT wraps around after it
+        cpu.add_cycles(227 * CYCLES_PER_LINE - cpu.cycles());
+        assert_eq!(cpu.memory_read(VCOUNT, RWType::HalfWord), 227);
+        assert_eq!(cpu.memory_read(DISPSTAT, RWType::HalfWord) & 0b001, 0);
+        cpu.add_cycles(CYCLES_PER_FRAME - cpu.cycles());
+        assert_eq!(cpu.memory_read(VCOUNT, RWType::HalfWord), 0);
+    }
+}